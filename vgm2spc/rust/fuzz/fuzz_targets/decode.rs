@@ -0,0 +1,19 @@
+//! Decode arbitrary data as the output of the PSG codec (with and without PSG runs) and of the
+//! null codec. The first 32 bytes are used as the long wait LUT.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use vgm2spc_fuzz::core::codec::{nullcodec, psgcodec};
+
+fuzz_target!(|data: &[u8]| {
+    let split = data.len().min(32);
+    let lut: Vec<u16> = data[..split].chunks_exact(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]])).collect();
+    let stream = &data[split..];
+    for runs in [false, true].iter() {
+        if let Ok(events) = psgcodec::decode(stream, &lut, *runs) {
+            assert!(events.iter().all(|(offset, _)| *offset < stream.len()));
+        }
+    }
+    let _ = nullcodec::decode(data);
+});
@@ -0,0 +1,24 @@
+//! Read arbitrary data as a VGM command stream, and check that the events encode back into the
+//! data they were read from (waits aside, which are re-encoded in their shortest form)
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use vgm2spc_fuzz::core::bytestream::ByteStream;
+use vgm2spc_fuzz::core::events::{Events, VgmEvent};
+
+fuzz_target!(|data: &[u8]| {
+    let mut stream = ByteStream::new(data.to_vec());
+    let mut events = Events::new(&mut stream);
+    let mut start = 0;
+    while let Some(Ok(event)) = events.next() {
+        let end = events.position();
+        assert!(start < end && end <= data.len());
+        if !matches!(event, VgmEvent::Wait(_) | VgmEvent::Ym2612DacWrite { .. }) {
+            let mut encoded = Vec::new();
+            event.write_to(&mut encoded);
+            assert_eq!(encoded, &data[start..end]);
+        }
+        start = end;
+    }
+});
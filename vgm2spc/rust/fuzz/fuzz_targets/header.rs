@@ -0,0 +1,18 @@
+//! Parse arbitrary data as a VGM header, and check it against the data like the converter does
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use vgm2spc_fuzz::vgm::gd3::Gd3;
+use vgm2spc_fuzz::vgm::specification::FileHeader;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(header) = FileHeader::parse(data) {
+        let _ = header.data_offset();
+        let _ = header.used_chips();
+        let _ = header.volume_gain_db();
+        if header.validate(data).is_ok() && header.gd3_offset != 0 {
+            let _ = Gd3::parse(&data[header.gd3_offset as usize + 0x14..]);
+        }
+    }
+});
@@ -0,0 +1,46 @@
+//! Encode the events read from arbitrary data with the PSG codec, and check that decoding gives
+//! back the same PSG writes and the same total duration. Commands that the codec can't encode (0x4E
+//! and 0x90-0x9F) are left out, like the `strip-unsupported` pass does.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use vgm2spc_fuzz::core::bytestream::ByteStream;
+use vgm2spc_fuzz::core::codec::{psgcodec, Codec, PsgCodec};
+use vgm2spc_fuzz::core::command::Command;
+use vgm2spc_fuzz::core::events::{Events, VgmEvent};
+
+fuzz_target!(|data: &[u8]| {
+    let (&mode, data) = match data.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    let mut input = ByteStream::new(data.to_vec());
+    let mut events: Vec<VgmEvent> = Events::new(&mut input).map_while(Result::ok)
+        .filter(|event| !matches!(event.command(), Command::NOP | 0x90 ..= 0x9F))
+        .collect();
+    if events.last() != Some(&VgmEvent::End) {
+        events.push(VgmEvent::End);
+    }
+
+    let mut output = ByteStream::new(Vec::new());
+    let mut codec = PsgCodec::new(&mut output);
+    let runs = mode & 1 != 0;
+    if runs {
+        codec.enable_psg_runs();
+    }
+    for event in &events {
+        codec.write_event(event);
+    }
+    codec.flush();
+    let block = codec.get_extra_data(psgcodec::GET_LONG_WAIT_LUT).unwrap();
+    let lut: Vec<u16> = block[7..].chunks_exact(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]])).collect();
+
+    let decoded = psgcodec::decode(output.as_slice(), &lut, runs).expect("The encoded stream couldn't be decoded");
+    let psg_writes = |events: &mut dyn Iterator<Item = &VgmEvent>| -> Vec<u8> {
+        events.filter_map(|event| match event { VgmEvent::PsgWrite(value) => Some(*value), _ => None }).collect()
+    };
+    assert_eq!(psg_writes(&mut decoded.iter().map(|(_, event)| event)), psg_writes(&mut events.iter()));
+    let samples = |events: &mut dyn Iterator<Item = &VgmEvent>| -> u64 { events.map(|event| event.samples() as u64).sum() };
+    assert_eq!(samples(&mut decoded.iter().map(|(_, event)| event)), samples(&mut events.iter()));
+});
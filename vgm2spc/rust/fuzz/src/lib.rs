@@ -0,0 +1,25 @@
+//!
+//! The parts of vgm2spc that the fuzz targets exercise. vgm2spc is a binary crate, so the modules
+//! are compiled from its source tree by path instead of being used as a dependency. They all work
+//! on in-memory data.
+//!
+//! Run a target with `cargo fuzz run <target>` from the directory of the vgm2spc crate.
+//!
+
+extern crate alloc;
+
+#[path = "../../src/core/mod.rs"]
+pub mod core;
+
+#[doc(hidden)]
+#[path = "../../src/vgm/gd3.rs"]
+pub mod gd3;
+#[doc(hidden)]
+#[path = "../../src/vgm/specification.rs"]
+pub mod specification;
+
+/// The VGM modules, under the same path as in vgm2spc
+pub mod vgm {
+    pub use super::gd3;
+    pub use super::specification;
+}
@@ -0,0 +1,14 @@
+//!
+//! The `core` module of vgm2spc, built with `#![no_std]` to check that it only needs `core` and
+//! `alloc`. Like in the fuzz crate, the module is compiled from the source tree of vgm2spc by path.
+//! This crate is a member of the workspace, so `cargo build --workspace` fails if std is used.
+//!
+
+#![cfg_attr(not(test), no_std)]
+
+extern crate alloc;
+
+// ByteStream has no is_empty, which only matters for the public API of a library
+#[allow(clippy::len_without_is_empty)]
+#[path = "../../src/core/mod.rs"]
+pub mod core;
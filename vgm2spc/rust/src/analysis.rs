@@ -0,0 +1,332 @@
+//!
+//! Reports on where the bytes of a packed song go, for figuring out what to cut when a song
+//! doesn't fit in SPC RAM.
+//!
+
+use std::fmt;
+
+use crate::converter::PackedLayout;
+use crate::vgm::Command;
+use crate::vgm::events::VgmEvent;
+use crate::vgm::specification::command_chip;
+
+/// Number of entries listed in each part of a `SizeBreakdown` report
+const TOP_ENTRIES: usize = 5;
+
+/// Length of a frame in samples (1/60 s)
+const FRAME_SAMPLES: u32 = 735;
+
+// The cycle counts below are rough guesses at the cost of the player's code paths. They aren't
+// taken from the instruction timings of the player or measured on it, so the load report only
+// shows which frames are much busier than the rest, not whether the player really keeps up.
+
+/// Estimated SPC700 cycles that the player spends on reading a command and dispatching it
+const DISPATCH_CYCLES: u32 = 24;
+/// Estimated extra cycles for a PSG register write, including the channel state update
+const PSG_WRITE_CYCLES: u32 = 46;
+/// Estimated extra cycles for setting up a wait
+const WAIT_CYCLES: u32 = 30;
+/// Estimated cycles per argument byte of a command that the player skips
+const SKIP_BYTE_CYCLES: u32 = 8;
+
+/// Default for the max number of PSG writes per frame before a frame is reported as busy
+pub const DEFAULT_MAX_FRAME_WRITES: u32 = 16;
+
+/// SPC700 cycles per frame that the player can spend on commands. The rest of the 17066 cycles
+/// of a frame go to the PSG emulation. This is a rough estimate as well.
+pub const FRAME_CYCLE_BUDGET: u32 = 2000;
+
+/// Sizes of the sections of a packed song, in bytes
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SectionSizes {
+    pub header: usize,          // VGM header, or data-only descriptor
+    pub extradata: usize,       // Long wait LUT and player config
+    pub intro: usize,           // Commands before the loop point, excluding data blocks
+    pub loop_body: usize,       // Commands from the loop point and on, excluding data blocks
+    pub data_blocks: usize,
+    pub gd3: usize,
+}
+
+impl SectionSizes {
+    /// Split a packed song with the given layout into sections. `intro_data_blocks` and
+    /// `loop_data_blocks` are the number of data block bytes before and after the loop point.
+    pub fn new(layout: &PackedLayout, intro_data_blocks: usize, loop_data_blocks: usize) -> Self {
+        let loop_offset = layout.loop_offset.unwrap_or(layout.stream_end);
+        SectionSizes {
+            header: layout.data_offset,
+            extradata: layout.stream_offset - layout.data_offset,
+            intro: (loop_offset - layout.stream_offset).saturating_sub(intro_data_blocks),
+            loop_body: (layout.stream_end - loop_offset).saturating_sub(loop_data_blocks),
+            data_blocks: intro_data_blocks + loop_data_blocks,
+            gd3: layout.length - layout.stream_end,
+        }
+    }
+
+    pub fn total(&self) -> usize {
+        self.header + self.extradata + self.intro + self.loop_body + self.data_blocks + self.gd3
+    }
+
+    /// Return the name and size of the largest section
+    pub fn largest(&self) -> (&'static str, usize) {
+        self.rows().iter().cloned().max_by_key(|&(_, size)| size).unwrap()
+    }
+
+    fn rows(&self) -> [(&'static str, usize); 6] {
+        [("Header", self.header), ("Extradata", self.extradata), ("Intro", self.intro),
+         ("Loop", self.loop_body), ("Data blocks", self.data_blocks), ("GD3 tag", self.gd3)]
+    }
+}
+
+impl fmt::Display for SectionSizes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let total = self.total();
+        for (name, size) in self.rows().iter() {
+            let percent = if total > 0 { 100.0 * *size as f64 / total as f64 } else { 0.0 };
+            writeln!(f, "{:<12} {:>6} bytes {:>5.1}%", name, size, percent)?;
+        }
+        write!(f, "{:<12} {:>6} bytes", "Total", total)
+    }
+}
+
+/// Bytes spent on one kind of command in the packed command stream
+#[derive(Clone, Debug, PartialEq)]
+pub struct CommandBytes {
+    pub kind: String,
+    pub count: usize,
+    pub bytes: usize,
+}
+
+/// The biggest contributors to the size of a packed command stream
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SizeBreakdown {
+    pub commands: Vec<CommandBytes>,            // Largest first
+    pub data_blocks: Vec<(u8, usize)>,          // Type and size of each data block, largest first
+    pub uncompressed_long_waits: (usize, usize),    // Count and bytes of long waits that aren't in the LUT
+}
+
+impl SizeBreakdown {
+    /// Break down the packed command stream `stream`, given the events decoded from it along with
+    /// their offsets. Each event is charged for the bytes up to the next one, so the flag bytes and
+    /// padding of the PSG codec are included in the command sizes.
+    pub fn new(stream: &[u8], events: &[(usize, VgmEvent)]) -> Self {
+        let mut breakdown = SizeBreakdown::default();
+        for (i, (offset, event)) in events.iter().enumerate() {
+            let end = events.get(i + 1).map_or(stream.len(), |(next, _)| *next);
+            let bytes = end - offset;
+            let kind = match event {
+                VgmEvent::Wait(_) => match stream[*offset] {
+                    Command::WAIT_LONG => {
+                        breakdown.uncompressed_long_waits.0 += 1;
+                        breakdown.uncompressed_long_waits.1 += bytes;
+                        String::from("Long waits")
+                    }
+                    Command::WAIT_LONG_THRU_LUT ..= 0x9F => String::from("Long waits (LUT)"),
+                    _ => String::from("Short waits"),
+                },
+                VgmEvent::DataBlock { block_type, data } => {
+                    breakdown.data_blocks.push((*block_type, data.len()));
+                    String::from("Data blocks")
+                }
+                VgmEvent::End => String::from("End of sound data"),
+                _ => match command_chip(event.command()) {
+                    Some(chip) => format!("{} writes", chip.name),
+                    None => format!("Command 0x{:02X}", event.command()),
+                },
+            };
+            match breakdown.commands.iter_mut().find(|c| c.kind == kind) {
+                Some(entry) => {
+                    entry.count += 1;
+                    entry.bytes += bytes;
+                }
+                None => breakdown.commands.push(CommandBytes { kind, count: 1, bytes }),
+            }
+        }
+        breakdown.commands.sort_by_key(|c| std::cmp::Reverse(c.bytes));
+        breakdown.data_blocks.sort_by_key(|&(_, size)| std::cmp::Reverse(size));
+        breakdown
+    }
+}
+
+impl fmt::Display for SizeBreakdown {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Largest command types:")?;
+        for c in self.commands.iter().take(TOP_ENTRIES) {
+            writeln!(f, "  {:<20} {:>6} bytes {:>7} commands", c.kind, c.bytes, c.count)?;
+        }
+        if !self.data_blocks.is_empty() {
+            writeln!(f, "Largest data blocks:")?;
+            for (block_type, size) in self.data_blocks.iter().take(TOP_ENTRIES) {
+                writeln!(f, "  Type 0x{:02X}            {:>6} bytes", block_type, size)?;
+            }
+        }
+        let (count, bytes) = self.uncompressed_long_waits;
+        write!(f, "Long waits not in the LUT: {} commands, {} bytes", count, bytes)
+    }
+}
+
+/// Estimated number of SPC700 cycles that the player needs for `event`
+fn command_cycles(event: &VgmEvent) -> u32 {
+    DISPATCH_CYCLES + match event {
+        VgmEvent::PsgWrite(_) => PSG_WRITE_CYCLES,
+        VgmEvent::Wait(_) => WAIT_CYCLES,
+        VgmEvent::End => 0,
+        _ => {
+            let mut bytes = Vec::new();
+            event.write_to(&mut bytes);
+            SKIP_BYTE_CYCLES * (bytes.len() as u32 - 1)
+        }
+    }
+}
+
+/// Sum `cost` over the events of each frame, for the events decoded from a packed command stream
+fn frame_totals(events: &[(usize, VgmEvent)], cost: impl Fn(&VgmEvent) -> u32) -> Vec<u32> {
+    let mut totals: Vec<u32> = vec![0];
+    let mut time: u32 = 0;
+    for (_, event) in events {
+        let frame = (time / FRAME_SAMPLES) as usize;
+        if frame >= totals.len() {
+            totals.resize(frame + 1, 0);
+        }
+        totals[frame] += cost(event);
+        time = time.saturating_add(event.samples());
+    }
+    totals
+}
+
+/// Find the frames with more than `max_writes` PSG writes, which the player may not be able to
+/// service in one tick. Returns the start time (in samples) and number of writes of each frame.
+pub fn busy_frames(events: &[(usize, VgmEvent)], max_writes: u32) -> Vec<(u32, u32)> {
+    let writes = frame_totals(events, |event| matches!(event, VgmEvent::PsgWrite(_)) as u32);
+    writes.iter().enumerate()
+        .filter(|&(_, &count)| count > max_writes)
+        .map(|(frame, &count)| (frame as u32 * FRAME_SAMPLES, count))
+        .collect()
+}
+
+/// Rough estimate of the CPU load of the player for a packed command stream, in SPC700 cycles per
+/// frame
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CpuLoad {
+    pub frames: usize,
+    pub average: f64,
+    pub worst: u32,
+    pub worst_time: u32,                // Start of the most expensive frame, in samples
+    pub frames_over_budget: usize,
+}
+
+impl CpuLoad {
+    /// Estimate the load from the events decoded from a packed command stream
+    pub fn new(events: &[(usize, VgmEvent)]) -> Self {
+        let frame_cycles = frame_totals(events, command_cycles);
+        let (worst_frame, &worst) = frame_cycles.iter().enumerate().max_by_key(|&(i, cycles)| (*cycles, std::cmp::Reverse(i))).unwrap();
+        CpuLoad {
+            frames: frame_cycles.len(),
+            average: frame_cycles.iter().map(|&c| c as f64).sum::<f64>() / frame_cycles.len() as f64,
+            worst,
+            worst_time: worst_frame as u32 * FRAME_SAMPLES,
+            frames_over_budget: frame_cycles.iter().filter(|&&c| c > FRAME_CYCLE_BUDGET).count(),
+        }
+    }
+}
+
+impl fmt::Display for CpuLoad {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Player load, roughly estimated (budget {} cycles/frame, per-command costs not measured on the player):", FRAME_CYCLE_BUDGET)?;
+        writeln!(f, "  Average {:>6.0} cycles/frame", self.average)?;
+        writeln!(f, "  Worst   {:>6} cycles/frame at {:.2} s", self.worst, self.worst_time as f64 / 44100.0)?;
+        write!(f, "  {} of {} frames over budget", self.frames_over_budget, self.frames)
+    }
+}
+
+/// Header of the CSV files written by `-csv`, matching the fields of `CsvRow`
+pub const CSV_HEADER: &str = "file,chips,input_size,output_size,fits,loop_seconds,duration_seconds";
+
+/// Summary of one conversion, for collecting statistics over a whole collection of songs
+#[derive(Clone, Debug, PartialEq)]
+pub struct CsvRow {
+    pub file: String,
+    pub chips: Vec<&'static str>,
+    pub input_size: usize,          // Size of the VGM data
+    pub output_size: usize,         // Size of the packed data
+    pub fits: bool,                 // The packed data fits in SPC RAM along with the player
+    pub loop_samples: u32,
+    pub total_samples: u32,
+}
+
+impl fmt::Display for CsvRow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let quote = |field: &str| if field.contains([',', '"', '\n']) { format!("\"{}\"", field.replace('"', "\"\"")) } else { field.to_string() };
+        write!(f, "{},{},{},{},{},{:.2},{:.2}", quote(&self.file), quote(&self.chips.join(" ")), self.input_size, self.output_size,
+               if self.fits { "yes" } else { "no" }, self.loop_samples as f64 / 44100.0, self.total_samples as f64 / 44100.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_size_breakdown() {
+        let stream = [Command::PSG_WRITE, 0x9F, Command::PSG_WRITE, 0x80, Command::WAIT_LONG, 0x00, 0x10,
+                      Command::DATA_BLOCK, 0x66, 0x00, 2, 0, 0, 0, 0xAA, 0xBB, Command::END_OF_SOUND_DATA];
+        let events = vec![(0, VgmEvent::PsgWrite(0x9F)), (2, VgmEvent::PsgWrite(0x80)), (4, VgmEvent::Wait(0x1000)),
+                          (7, VgmEvent::DataBlock { block_type: 0, data: vec![0xAA, 0xBB] }), (16, VgmEvent::End)];
+        let breakdown = SizeBreakdown::new(&stream, &events);
+        assert_eq!(breakdown.commands[0], CommandBytes { kind: String::from("Data blocks"), count: 1, bytes: 9 });
+        assert_eq!(breakdown.commands[1], CommandBytes { kind: String::from("SN76489 writes"), count: 2, bytes: 4 });
+        assert_eq!(breakdown.commands[2], CommandBytes { kind: String::from("Long waits"), count: 1, bytes: 3 });
+        assert_eq!(breakdown.data_blocks, vec![(0, 2)]);
+        assert_eq!(breakdown.uncompressed_long_waits, (1, 3));
+    }
+
+    #[test]
+    fn test_cpu_load() {
+        let mut events = vec![(0, VgmEvent::PsgWrite(0x9F)), (0, VgmEvent::Wait(735))];
+        for _ in 0..40 {
+            events.push((0, VgmEvent::PsgWrite(0x80)));
+        }
+        events.push((0, VgmEvent::Wait(735)));
+        events.push((0, VgmEvent::End));
+        let load = CpuLoad::new(&events);
+        assert_eq!(load.frames, 3);
+        assert_eq!(load.worst, 40 * (DISPATCH_CYCLES + PSG_WRITE_CYCLES) + DISPATCH_CYCLES + WAIT_CYCLES);
+        assert_eq!(load.worst_time, 735);
+        assert_eq!(load.frames_over_budget, 1);
+        assert_eq!(command_cycles(&VgmEvent::DataBlock { block_type: 0, data: vec![0; 4] }), DISPATCH_CYCLES + 10 * SKIP_BYTE_CYCLES);
+    }
+
+    #[test]
+    fn test_busy_frames() {
+        let mut events = vec![(0, VgmEvent::PsgWrite(0x9F)), (0, VgmEvent::Wait(1470))];
+        for _ in 0..5 {
+            events.push((0, VgmEvent::PsgWrite(0x80)));
+        }
+        events.push((0, VgmEvent::Wait(735)));
+        assert_eq!(busy_frames(&events, 4), vec![(1470, 5)]);
+        assert_eq!(busy_frames(&events, 5), vec![]);
+        assert_eq!(busy_frames(&events, 0), vec![(0, 1), (1470, 5)]);
+    }
+
+    #[test]
+    fn test_csv_row() {
+        let mut row = CsvRow { file: String::from("a.vgm"), chips: vec!["SN76489", "YM2413"], input_size: 1000, output_size: 800,
+                               fits: true, loop_samples: 44100, total_samples: 66150 };
+        assert_eq!(row.to_string(), "a.vgm,SN76489 YM2413,1000,800,yes,1.00,1.50");
+        row.file = String::from("Song \"1\", intro.vgm");
+        row.fits = false;
+        assert_eq!(row.to_string(), "\"Song \"\"1\"\", intro.vgm\",SN76489 YM2413,1000,800,no,1.00,1.50");
+        assert_eq!(CSV_HEADER.split(',').count(), row.to_string().split(',').count() - 1);
+    }
+
+    #[test]
+    fn test_section_sizes() {
+        let layout = PackedLayout { data_offset: 0x40, stream_offset: 0x50, stream_end: 0x200, lut_offset: None, loop_offset: Some(0x100), length: 0x280 };
+        let sizes = SectionSizes::new(&layout, 0x20, 0);
+        assert_eq!(sizes, SectionSizes { header: 0x40, extradata: 0x10, intro: 0x90, loop_body: 0x100, data_blocks: 0x20, gd3: 0x80 });
+        assert_eq!(sizes.total(), layout.length);
+        assert_eq!(sizes.largest(), ("Loop", 0x100));
+
+        let layout = PackedLayout { loop_offset: None, ..layout };
+        assert_eq!(SectionSizes::new(&layout, 0, 0).loop_body, 0);
+    }
+}
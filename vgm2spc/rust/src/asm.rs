@@ -0,0 +1,91 @@
+//!
+//! Export of packed VGM data as an assembly include file, for homebrew sound drivers that
+//! embed the data themselves.
+//!
+
+use crate::converter::PackedLayout;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AsmSyntax {
+    Ca65,
+    WlaDx,
+    Tass64,
+}
+
+impl AsmSyntax {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "ca65" => Some(AsmSyntax::Ca65),
+            "wla" | "wla-dx" => Some(AsmSyntax::WlaDx),
+            "64tass" => Some(AsmSyntax::Tass64),
+            _ => None,
+        }
+    }
+
+    fn define(&self, name: &str, value: usize) -> String {
+        match self {
+            AsmSyntax::WlaDx => format!(".DEFINE {} ${:04X}\n", name, value),
+            _ => format!("{} = ${:04X}\n", name, value),
+        }
+    }
+
+    fn label(&self, name: &str) -> String {
+        match self {
+            AsmSyntax::Tass64 => format!("{}\n", name),
+            _ => format!("{}:\n", name),
+        }
+    }
+
+    fn byte_directive(&self) -> &str {
+        match self {
+            AsmSyntax::WlaDx => ".DB",
+            _ => ".byte",
+        }
+    }
+}
+
+const BYTES_PER_LINE: usize = 16;
+
+/// Return `data` as an assembly source file using the given syntax. Symbols for the data length
+/// and (when `layout` is given) the offsets of the loop point and long wait LUT are defined too.
+pub fn emit(data: &[u8], layout: Option<&PackedLayout>, syntax: AsmSyntax, source_name: &str) -> String {
+    let mut out = format!("; Generated by vgm2spc from {}\n\n", source_name);
+
+    out.push_str(&syntax.define("VGM_DATA_LENGTH", data.len()));
+    if let Some(layout) = layout {
+        out.push_str(&syntax.define("VGM_DATA_OFFSET", layout.data_offset));
+        if let Some(loop_offset) = layout.loop_offset {
+            out.push_str(&syntax.define("VGM_LOOP_OFFSET", loop_offset));
+        }
+        if let Some(lut_offset) = layout.lut_offset {
+            out.push_str(&syntax.define("VGM_LUT_OFFSET", lut_offset));
+        }
+    }
+    out.push('\n');
+
+    out.push_str(&syntax.label("vgm_data"));
+    for line in data.chunks(BYTES_PER_LINE) {
+        let bytes: Vec<String> = line.iter().map(|b| format!("${:02X}", b)).collect();
+        out.push_str(&format!("    {} {}\n", syntax.byte_directive(), bytes.join(",")));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_emit() {
+        let layout = PackedLayout { data_offset: 0x40, stream_offset: 0x77, stream_end: 0x100, lut_offset: Some(0x47), loop_offset: None, length: 3 };
+        let ca65 = emit(&[1, 2, 0xAB], Some(&layout), AsmSyntax::Ca65, "test.vgm");
+        assert!(ca65.contains("VGM_DATA_LENGTH = $0003\n"));
+        assert!(ca65.contains("VGM_LUT_OFFSET = $0047\n"));
+        assert!(!ca65.contains("VGM_LOOP_OFFSET"));
+        assert!(ca65.contains("vgm_data:\n    .byte $01,$02,$AB\n"));
+
+        let wla = emit(&[1, 2, 0xAB], None, AsmSyntax::WlaDx, "test.vgm");
+        assert!(wla.contains(".DEFINE VGM_DATA_LENGTH $0003\n"));
+        assert!(wla.contains("    .DB $01,$02,$AB\n"));
+    }
+}
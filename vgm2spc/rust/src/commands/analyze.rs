@@ -0,0 +1,22 @@
+//!
+//! `vgm2spc analyze <song.vgm> [options]`
+//!
+//! Print what converting a VGM file would give as JSON, without writing anything: the header,
+//! GD3 tag and chips, and the packed size with each codec along with whether it fits. Meant for
+//! frontends that want to show whether a song fits before converting it.
+//!
+
+use std::path::Path;
+use std::process;
+
+use crate::converter::Converter;
+
+pub fn run(args: &[String]) {
+    if args.len() != 1 {
+        println!("Usage: vgm2spc analyze <song.vgm>");
+        process::exit(1);
+    }
+    let converter = Converter::builder().build().unwrap();
+    let analysis = converter.analyze(Path::new(&args[0])).unwrap_or_else(|e| panic!("Failed to analyze {}: {}", args[0], e));
+    println!("{}", serde_json::to_string_pretty(&analysis).unwrap());
+}
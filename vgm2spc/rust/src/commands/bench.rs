@@ -0,0 +1,142 @@
+//!
+//! `vgm2spc bench <dir>`
+//!
+//! Pack every VGM file in a directory with each codec, and print a table of the packed sizes,
+//! compression ratios and encoding times. The files are packed as raw data, so songs that don't
+//! fit in SPC RAM are measured too.
+//!
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
+
+use crate::converter::Converter;
+use crate::options::{Codec, OutputFormat};
+
+/// The codecs that are measured, in the order of the table columns
+const CODECS: [Codec; 3] = [Codec::Psg, Codec::PsgRuns, Codec::Null];
+
+/// Size and encoding time of a song packed with one codec
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Measurement {
+    size: usize,
+    encoding: Duration,
+}
+
+/// The measurements for one file: the size of the VGM data, and one entry per codec in `CODECS`
+/// (`None` if packing failed)
+struct Row {
+    name: String,
+    input_size: usize,
+    results: Vec<Option<Measurement>>,
+}
+
+pub fn run(args: &[String]) {
+    if args.len() != 1 {
+        println!("Usage: vgm2spc bench <dir>");
+        process::exit(1);
+    }
+    let mut paths: Vec<PathBuf> = fs::read_dir(&args[0]).expect("Failed to read directory")
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("vgm") || ext.eq_ignore_ascii_case("vgz")))
+        .collect();
+    paths.sort();
+    if paths.is_empty() {
+        println!("No VGM files found in {}", args[0]);
+        process::exit(1);
+    }
+
+    // The converters are reused for all files, so that their buffers are only allocated once
+    let mut converters: Vec<Converter> = CODECS.iter()
+        .map(|codec| Converter::builder().codec(*codec).output_format(OutputFormat::Raw).build().unwrap())
+        .collect();
+    let rows: Vec<Row> = paths.iter().map(|path| measure(&mut converters, path)).collect();
+    println!();
+    for line in format_table(&rows) {
+        println!("{}", line);
+    }
+}
+
+/// Pack the file at `path` with each codec
+fn measure(converters: &mut [Converter], path: &Path) -> Row {
+    let mut row = Row {
+        name: path.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+        input_size: 0,
+        results: Vec::new(),
+    };
+    for (codec, converter) in CODECS.iter().zip(converters.iter_mut()) {
+        let result = match converter.pack_file(path) {
+            Ok(packed) => Some(packed.len()),
+            Err(e) => {
+                println!("Warning: {} ({}): {}", row.name, codec.name(), e);
+                None
+            }
+        };
+        let encoding = converter.timings().iter().find(|timing| timing.stage == "encoding");
+        if let (Some(_), Some(timing)) = (result, encoding) {
+            row.input_size = timing.bytes;
+        }
+        row.results.push(result.map(|size| Measurement { size, encoding: encoding.map_or(Duration::ZERO, |timing| timing.elapsed) }));
+    }
+    row
+}
+
+/// Format the measurements as a table, with a row per file and a total row
+fn format_table(rows: &[Row]) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut header = format!("{:<32} {:>9}", "File", "VGM size");
+    for codec in CODECS.iter() {
+        header += &format!(" | {:>22}", codec.name());
+    }
+    lines.push(header);
+
+    let format_row = |name: &str, input_size: usize, results: &[Option<Measurement>]| {
+        let mut line = format!("{:<32} {:>9}", name, input_size);
+        for result in results {
+            line += &match result {
+                Some(m) => format!(" | {:>8} {:>5.1}% {:>5.1} ms", m.size, 100.0 * m.size as f64 / input_size.max(1) as f64,
+                                   m.encoding.as_secs_f64() * 1000.0),
+                None => format!(" | {:>22}", "failed"),
+            };
+        }
+        line
+    };
+    for row in rows {
+        lines.push(format_row(&row.name, row.input_size, &row.results));
+    }
+
+    // Totals only cover the files that all codecs could pack, so the columns are comparable
+    let complete: Vec<&Row> = rows.iter().filter(|row| row.results.iter().all(Option::is_some)).collect();
+    let totals: Vec<Option<Measurement>> = (0..CODECS.len()).map(|i| {
+        Some(Measurement {
+            size: complete.iter().map(|row| row.results[i].unwrap().size).sum(),
+            encoding: complete.iter().map(|row| row.results[i].unwrap().encoding).sum(),
+        })
+    }).collect();
+    let total_input = complete.iter().map(|row| row.input_size).sum();
+    lines.push(format_row(&format!("Total ({} files)", complete.len()), total_input, &totals));
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_table() {
+        let measurement = |size| Some(Measurement { size, encoding: Duration::from_millis(2) });
+        let rows = vec![
+            Row { name: String::from("a.vgm"), input_size: 1000, results: vec![measurement(500), measurement(400), measurement(900)] },
+            Row { name: String::from("b.vgm"), input_size: 2000, results: vec![measurement(1000), None, measurement(1800)] },
+        ];
+        let lines = format_table(&rows);
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("File"));
+        assert!(lines[1].contains("     500  50.0%   2.0 ms"));
+        assert!(lines[2].contains("failed"));
+        // b.vgm is left out of the totals, since one of the codecs failed on it
+        assert!(lines[3].starts_with("Total (1 files)"));
+        assert!(lines[3].contains("     400  40.0%"));
+    }
+}
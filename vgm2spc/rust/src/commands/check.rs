@@ -0,0 +1,45 @@
+//!
+//! `vgm2spc check <file.spc> [<source.vgm>]`
+//!
+//! Print the CRC32 of the VGM data that an SPC was created from, and optionally compare it
+//! against a VGM file to find out whether the SPC is a conversion of that file.
+//!
+
+use std::fs;
+use std::path::Path;
+use std::process;
+
+use crate::spc::id666;
+use crate::vgm::read_vgm_file;
+
+pub fn run(args: &[String]) {
+    if args.is_empty() || args.len() > 2 {
+        println!("Usage: vgm2spc check <file.spc> [<source.vgm>]");
+        process::exit(1);
+    }
+
+    let spc = fs::read(&args[0]).expect("Failed to read SPC file");
+    if spc.len() < 0x100 {
+        panic!("{} is not an SPC file", args[0]);
+    }
+    let spc_crc = match id666::read_source_crc(&spc) {
+        Some(crc) => crc,
+        None => {
+            println!("{}: no source CRC found", args[0]);
+            process::exit(1);
+        }
+    };
+    println!("{}: source CRC {:08X}", args[0], spc_crc);
+
+    if let Some(vgm_path) = args.get(1) {
+        let mut vgm_data = Vec::new();
+        read_vgm_file(Path::new(vgm_path), &mut vgm_data, false).expect("Failed to read VGM file");
+        let vgm_crc = crc32fast::hash(&vgm_data);
+        if vgm_crc == spc_crc {
+            println!("{}: CRC {:08X} (match)", vgm_path, vgm_crc);
+        } else {
+            println!("{}: CRC {:08X} (no match)", vgm_path, vgm_crc);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,170 @@
+//!
+//! `vgm2spc diff <a.spc> <b.spc>`
+//!
+//! Compare two SPC files: the ID666 and xid6 tags, the SPC700 registers, the RAM images and the
+//! DSP registers. RAM differences are reported by region (player, packed data or the free RAM
+//! after it), based on where the packed data is in the first file.
+//!
+
+use std::path::Path;
+use std::process;
+
+use crate::commands::extract::find_packed_data;
+use crate::spc::reader::{self, Registers};
+use crate::spc::SpcFile;
+
+pub fn run(args: &[String]) {
+    if args.len() != 2 {
+        println!("Usage: vgm2spc diff <a.spc> <b.spc>");
+        process::exit(1);
+    }
+    let read = |path: &String| reader::read_file(Path::new(path)).unwrap_or_else(|e| panic!("Failed to read SPC file: {}", e));
+    let (a, b) = (read(&args[0]), read(&args[1]));
+
+    let mut differences = diff_tags(&a, &b);
+    differences.extend(diff_registers(&a.registers, &b.registers));
+    differences.extend(diff_ram(&a.ram, &b.ram));
+    for (register, (x, y)) in a.dsp.iter().zip(&b.dsp).enumerate() {
+        if x != y {
+            differences.push(format!("DSP register ${:02X}: ${:02X} -> ${:02X}", register, x, y));
+        }
+    }
+
+    if differences.is_empty() {
+        println!("No differences");
+    } else {
+        for difference in &differences {
+            println!("{}", difference);
+        }
+        process::exit(1);
+    }
+}
+
+/// Compare the ID666 tags and xid6 chunks of the SPC files `a` and `b`
+fn diff_tags(a: &SpcFile, b: &SpcFile) -> Vec<String> {
+    let (tag_a, tag_b) = (a.tag(), b.tag());
+    let format_a = a.id666.as_ref().map(|(_, format)| *format);
+    let format_b = b.id666.as_ref().map(|(_, format)| *format);
+    let mut differences = Vec::new();
+    let mut compare = |name: &str, x: String, y: String| {
+        if x != y {
+            differences.push(format!("ID666 {}: {:?} -> {:?}", name, x, y));
+        }
+    };
+    compare("format", format!("{:?}", format_a), format!("{:?}", format_b));
+    compare("title", tag_a.song_title, tag_b.song_title);
+    compare("game", tag_a.game_title, tag_b.game_title);
+    compare("artist", tag_a.artist, tag_b.artist);
+    compare("dumper", tag_a.dumper, tag_b.dumper);
+    compare("comment", tag_a.comment, tag_b.comment);
+    compare("date", format!("{:?}", tag_a.dump_date), format!("{:?}", tag_b.dump_date));
+    compare("length", tag_a.play_seconds.to_string(), tag_b.play_seconds.to_string());
+    compare("fade", tag_a.fade_ms.to_string(), tag_b.fade_ms.to_string());
+    compare("source CRC", format!("{:08X?}", tag_a.source_crc), format!("{:08X?}", tag_b.source_crc));
+
+    let xid6_a = a.xid6.clone().unwrap_or_default();
+    let xid6_b = b.xid6.clone().unwrap_or_default();
+    let mut ids: Vec<u8> = xid6_a.items.iter().chain(&xid6_b.items).map(|item| item.id).collect();
+    ids.sort_unstable();
+    ids.dedup();
+    for id in ids {
+        let (x, y) = (xid6_a.get(id), xid6_b.get(id));
+        if x != y {
+            let describe = |item: Option<&crate::spc::xid6::Item>| match item {
+                Some(item) => format!("{:02X?}", item.data),
+                None => String::from("none"),
+            };
+            differences.push(format!("xid6 item ${:02X}: {} -> {}", id, describe(x), describe(y)));
+        }
+    }
+    differences
+}
+
+/// Compare the SPC700 registers `a` and `b`
+fn diff_registers(a: &Registers, b: &Registers) -> Vec<String> {
+    let mut differences = Vec::new();
+    if a.pc != b.pc {
+        differences.push(format!("PC: ${:04X} -> ${:04X}", a.pc, b.pc));
+    }
+    for (name, x, y) in [("A", a.a, b.a), ("X", a.x, b.x), ("Y", a.y, b.y), ("PSW", a.psw, b.psw), ("SP", a.sp, b.sp)] {
+        if x != y {
+            differences.push(format!("{}: ${:02X} -> ${:02X}", name, x, y));
+        }
+    }
+    differences
+}
+
+/// Return the ranges of addresses (start, end) where `a` and `b` differ
+fn diff_ranges(a: &[u8], b: &[u8]) -> Vec<(usize, usize)> {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for (address, _) in a.iter().zip(b).enumerate().filter(|(_, (x, y))| x != y) {
+        match ranges.last_mut() {
+            Some((_, end)) if *end == address => *end = address + 1,
+            _ => ranges.push((address, address + 1)),
+        }
+    }
+    ranges
+}
+
+/// Compare the RAM images `a` and `b`, reporting the differences by region
+fn diff_ram(a: &[u8], b: &[u8]) -> Vec<String> {
+    let (data_start, data_end) = match find_packed_data(a) {
+        Some((start, length)) => (start, start + length),
+        None => (0, 0),
+    };
+    let region = |address: usize| {
+        if address < data_start {
+            "player"
+        } else if address < data_end {
+            "data"
+        } else if data_end > 0 {
+            "free"
+        } else {
+            "RAM"
+        }
+    };
+
+    let mut differences = Vec::new();
+    if find_packed_data(a) != find_packed_data(b) {
+        differences.push(format!("Packed data: {} -> {}", describe_location(find_packed_data(a)), describe_location(find_packed_data(b))));
+    }
+    for (start, end) in diff_ranges(a, b) {
+        // Split ranges that cross the region boundaries
+        let mut start = start;
+        for boundary in [data_start, data_end, end] {
+            if boundary > start && boundary <= end {
+                differences.push(format!("RAM ${:04X}-${:04X} ({}): {} bytes differ", start, boundary - 1, region(start), boundary - start));
+                start = boundary;
+            }
+        }
+    }
+    differences
+}
+
+fn describe_location(location: Option<(usize, usize)>) -> String {
+    match location {
+        Some((start, length)) => format!("${:04X} ({} bytes)", start, length),
+        None => String::from("not found"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_ranges() {
+        let a = [0, 1, 2, 3, 4, 5, 6];
+        let b = [0, 9, 9, 3, 4, 9, 9];
+        assert_eq!(diff_ranges(&a, &b), vec![(1, 3), (5, 7)]);
+        assert!(diff_ranges(&a, &a).is_empty());
+    }
+
+    #[test]
+    fn test_diff_registers() {
+        let a = Registers { pc: 0x0400, sp: 0xEF, ..Default::default() };
+        let b = Registers { pc: 0x0500, x: 1, sp: 0xEF, ..Default::default() };
+        assert_eq!(diff_registers(&a, &b), vec![String::from("PC: $0400 -> $0500"), String::from("X: $00 -> $01")]);
+        assert!(diff_registers(&a, &a).is_empty());
+    }
+}
@@ -0,0 +1,297 @@
+//!
+//! `vgm2spc disasm <song.vgm>`
+//! `vgm2spc disasm-c <file.spc|data.bin>`
+//!
+//! Print the command stream of a VGM file, one command per line: the file offset, the time at
+//! which the command is executed (in samples and seconds), and the command with its arguments.
+//! PSG writes are decoded into the channel and register that they set.
+//!
+//! `disasm-c` does the same for a song packed by the converter, given as an SPC or as the raw
+//! output (`-format raw` or `extract -o`), so that the output of the codecs can be checked
+//! without stepping through the player. Offsets are relative to the start of the packed song.
+//!
+
+use std::fs;
+use std::io::{self, Error, ErrorKind, Write};
+use std::path::Path;
+use std::process;
+
+use crate::commands::extract::{find_packed_data, PackedSong};
+use crate::core::bytestream::ByteStream;
+use crate::spc::reader;
+use crate::vgm::events::{Events, VgmEvent};
+use crate::vgm::read_vgm_file;
+use crate::vgm::specification::{command_chip, Command, FileHeader};
+
+pub fn run(args: &[String]) {
+    if args.len() != 1 {
+        println!("Usage: vgm2spc disasm <song.vgm>");
+        process::exit(1);
+    }
+    let mut data = Vec::new();
+    read_vgm_file(Path::new(&args[0]), &mut data, false).expect("Failed to read VGM file");
+    print_listing(listing(&data));
+}
+
+/// `vgm2spc disasm-c <file.spc|data.bin>`
+pub fn run_packed(args: &[String]) {
+    if args.len() != 1 {
+        println!("Usage: vgm2spc disasm-c <file.spc|data.bin>");
+        process::exit(1);
+    }
+    let data = fs::read(&args[0]).expect("Failed to read input file");
+    let packed = if data.starts_with(reader::SPC_MAGIC) {
+        let spc = reader::read(&data).unwrap_or_else(|e| panic!("Failed to read SPC file: {}", e));
+        match find_packed_data(&spc.ram) {
+            Some((start, length)) => spc.ram[start..start + length].to_vec(),
+            None => {
+                println!("{}: no packed VGM data found", args[0]);
+                process::exit(1);
+            }
+        }
+    } else {
+        data
+    };
+    print_listing(packed_listing(&packed));
+}
+
+fn print_listing(listing: Result<Vec<String>, (Vec<String>, Error)>) {
+    let (lines, error) = match listing {
+        Ok(lines) => (lines, None),
+        Err((lines, e)) => (lines, Some(e)),
+    };
+    // Stop quietly if the output is piped into e.g. head
+    let mut out = io::stdout().lock();
+    for line in &lines {
+        if writeln!(out, "{}", line).is_err() {
+            return;
+        }
+    }
+    if let Some(e) = error {
+        println!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+/// The lines of a listing, and the time at which the next command is executed
+struct Listing {
+    lines: Vec<String>,
+    time: u64,
+    loop_position: Option<usize>,
+}
+
+impl Listing {
+    fn new(loop_position: Option<usize>) -> Listing {
+        Listing { lines: vec![String::from("Offset    Samples    Time      Command")], time: 0, loop_position }
+    }
+
+    /// Add a line for the command at `offset`, which waits for `samples`
+    fn add(&mut self, offset: usize, text: &str, samples: u32) {
+        if Some(offset) == self.loop_position {
+            self.lines.push(String::from("; Loop point"));
+        }
+        self.lines.push(format!("{:08X}  {:>9}  {:>8.3}  {}", offset, self.time, self.time as f64 / 44100.0, text));
+        self.time += samples as u64;
+    }
+
+    /// Add the VGM commands from the current position of `stream` up to the end of sound data
+    /// command
+    fn add_events(mut self, stream: &mut ByteStream) -> Result<Vec<String>, (Vec<String>, Error)> {
+        let mut events = Events::new(stream);
+        loop {
+            let offset = events.position();
+            match events.next() {
+                Some(Ok(event)) => self.add(offset, &describe(&event), event.samples()),
+                Some(Err(e)) => return Err((self.lines, e.into())),
+                None => return Ok(self.lines),
+            }
+        }
+    }
+}
+
+/// Disassemble the command stream of the VGM file `data`. On errors, the lines up to the point
+/// of the error are returned along with it.
+fn listing(data: &[u8]) -> Result<Vec<String>, (Vec<String>, Error)> {
+    let header = FileHeader::parse(data).map_err(|e| (Vec::new(), e))?;
+    let loop_position = if header.loop_offset != 0 { Some(header.loop_offset as usize + 0x1C) } else { None };
+    let mut stream = ByteStream::new(data.to_vec());
+    stream.skip(std::cmp::min(header.data_offset(), data.len()));
+    Listing::new(loop_position).add_events(&mut stream)
+}
+
+/// Disassemble the command stream of the packed song `packed`. For the PSG codecs, the flag
+/// bytes, run headers and padding are listed along with the commands, and the commands of each
+/// group are indented.
+fn packed_listing(packed: &[u8]) -> Result<Vec<String>, (Vec<String>, Error)> {
+    let song = PackedSong::parse(packed).map_err(|e| (Vec::new(), e))?;
+    let mut listing = Listing::new(song.loop_offset());
+    let mut stream = ByteStream::new(packed[..song.stream_end].to_vec());
+    stream.skip(song.stream_offset);
+    let lut = match &song.lut {
+        Some(lut) => lut,
+        None => return listing.add_events(&mut stream),
+    };
+
+    let psg_write = |value: u8| format!("  PSG ${:02X}  {}", value, describe_psg_write(value));
+    while stream.available() > 0 {
+        let pos = stream.get_pos();
+        let flags = stream.read();
+        if song.runs && flags == 0xFF && stream.available() > 0 {
+            let groups = stream.read() as usize;
+            listing.add(pos, &format!("PSG run, {} groups", groups), 0);
+            for _ in 0..groups * 8 {
+                if stream.available() == 0 {
+                    break;
+                }
+                let pos = stream.get_pos();
+                listing.add(pos, &psg_write(stream.read()), 0);
+            }
+            continue;
+        }
+        listing.add(pos, &format!("flags ${:02X}", flags), 0);
+        for i in 0..8 {
+            if stream.available() == 0 {
+                break;
+            }
+            let pos = stream.get_pos();
+            if flags & (1 << i) != 0 {
+                listing.add(pos, &psg_write(stream.read()), 0);
+                continue;
+            }
+            match stream.read() {
+                Command::NOP => listing.add(pos, "  padding", 0),
+                c @ Command::WAIT_LONG_THRU_LUT ..= 0x9F => match lut.get((c & 0x0F) as usize) {
+                    Some(&samples) => listing.add(pos, &format!("  wait {} (LUT entry {})", samples, c & 0x0F), samples as u32),
+                    None => return Err((listing.lines, Error::new(ErrorKind::InvalidData, format!("LUT wait 0x{:02X} is outside of the LUT", c)))),
+                },
+                _ => {
+                    stream.reset();
+                    stream.skip(pos);
+                    let event = match Events::new(&mut stream).next() {
+                        Some(Ok(event)) => event,
+                        Some(Err(e)) => return Err((listing.lines, e.into())),
+                        None => break,
+                    };
+                    listing.add(pos, &format!("  {}", describe(&event)), event.samples());
+                    if event == VgmEvent::End {
+                        return Ok(listing.lines);
+                    }
+                }
+            }
+        }
+    }
+    Err((listing.lines, Error::new(ErrorKind::UnexpectedEof, "No end of sound data command found")))
+}
+
+/// Describe a PSG write: a latch byte selects a channel and register, a data byte sets the upper
+/// bits of the last latched tone register
+fn describe_psg_write(value: u8) -> String {
+    if value & 0x80 == 0 {
+        return format!("data ${:02X}", value & 0x3F);
+    }
+    let channel = (value >> 5) & 3;
+    let register = match (channel, value & 0x10 != 0) {
+        (_, true) => "attenuation",
+        (3, false) => "noise mode",
+        (_, false) => "tone",
+    };
+    format!("latch ch{} {} ${:X}", channel, register, value & 0x0F)
+}
+
+/// Return a description of `event`
+fn describe(event: &VgmEvent) -> String {
+    let hex = |bytes: &[u8]| bytes.iter().map(|b| format!("${:02X}", b)).collect::<Vec<_>>().join(" ");
+    match event {
+        VgmEvent::PsgWrite(value) => format!("SN76489 ${:02X}  {}", value, describe_psg_write(*value)),
+        VgmEvent::GgStereo(value) => format!("SN76489 GG stereo ${:02X}", value),
+        VgmEvent::Wait(samples) => format!("wait {}", samples),
+        VgmEvent::WaitOverride { command, samples } => format!("set the length of ${:02X} to {}", command, samples),
+        VgmEvent::Ym2612DacWrite { wait } => format!("YM2612 DAC write, wait {}", wait),
+        VgmEvent::DataBlock { block_type, data } => format!("data block type ${:02X}, {} bytes", block_type, data.len()),
+        VgmEvent::SeekPcm(offset) => format!("seek PCM data bank to ${:X}", offset),
+        VgmEvent::Command { command, args } => {
+            let name = match *command {
+                Command::DAC_STREAM_SETUP => "DAC stream setup",
+                Command::DAC_STREAM_SET_DATA => "DAC stream set data",
+                Command::DAC_STREAM_SET_FREQUENCY => "DAC stream set frequency",
+                Command::DAC_STREAM_START => "DAC stream start",
+                Command::DAC_STREAM_STOP => "DAC stream stop",
+                Command::DAC_STREAM_START_FAST => "DAC stream start fast",
+                Command::PCM_WRITE => "PCM RAM write",
+                command => match command_chip(command) {
+                    Some(chip) => chip.name,
+                    None => return format!("reserved ${:02X} {}", command, hex(args)),
+                },
+            };
+            match (*command, &args[..]) {
+                (0x51 ..= 0x5F | 0xA0 ..= 0xBF, [register, value]) => {
+                    // The odd commands of the dual-port chips write to the second port
+                    let port = if matches!(*command, 0x53 | 0x57 | 0x59 | 0x5F) { " port 1" } else { "" };
+                    format!("{}{} reg ${:02X} = ${:02X}", name, port, register, value)
+                }
+                _ => format!("{} {}", name, hex(args)).trim_end().to_string(),
+            }
+        }
+        VgmEvent::Unknown(command) => format!("unknown ${:02X}", command),
+        VgmEvent::End => String::from("end of sound data"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::codec::psgcodec;
+    use crate::player::CONFIG_BLOCK_TYPE;
+    use crate::vgm::testing::VgmBuilder;
+
+    #[test]
+    fn test_listing() {
+        let mut data = VgmBuilder::new().loop_at(0x43)
+            .commands(&[Command::PSG_WRITE, 0x9F, Command::WAIT_NTSC_FRAME, Command::YM2612_HI_WRITE, 0x30, 0x71,
+                        Command::WAIT_LONG, 0x44, 0xAC, Command::END_OF_SOUND_DATA])
+            .build();
+        assert_eq!(listing(&data).unwrap()[1..], [
+            "00000040          0     0.000  SN76489 $9F  latch ch0 attenuation $F",
+            "00000042          0     0.000  wait 735",
+            "; Loop point",
+            "00000043        735     0.017  YM2612 port 1 reg $30 = $71",
+            "00000046        735     0.017  wait 44100",
+            "00000049      44835     1.017  end of sound data",
+        ]);
+
+        data.truncate(data.len() - 3);
+        let (lines, _) = listing(&data).unwrap_err();
+        assert_eq!(lines.len(), 5);
+    }
+
+    #[test]
+    fn test_packed_listing() {
+        let mut packed = VgmBuilder::new()
+            .commands(&[Command::DATA_BLOCK, 0x66, psgcodec::LONG_WAIT_LUT_BLOCK_TYPE, 32, 0, 0, 0])
+            .commands(&[0x34, 0x12])
+            .commands(&[0; 30])
+            .commands(&[Command::DATA_BLOCK, 0x66, CONFIG_BLOCK_TYPE, 1, 0, 0, 0, 4])
+            .commands(&[0x01, 0x9F, Command::WAIT_LONG_THRU_LUT, Command::END_OF_SOUND_DATA])
+            .build();
+        let eof_offset = packed.len() as u32 - 4;
+        assert_eq!(packed_listing(&packed).unwrap()[1..], [
+            "0000006F          0     0.000  flags $01",
+            "00000070          0     0.000    PSG $9F  latch ch0 attenuation $F",
+            "00000071          0     0.000    wait 4660 (LUT entry 0)",
+            "00000072       4660     0.106    end of sound data",
+        ]);
+
+        packed.pop();
+        packed[0x04..0x08].copy_from_slice(&(eof_offset - 1).to_le_bytes());
+        let (lines, e) = packed_listing(&packed).unwrap_err();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_describe_psg_write() {
+        assert_eq!(describe_psg_write(0x8E), "latch ch0 tone $E");
+        assert_eq!(describe_psg_write(0xE4), "latch ch3 noise mode $4");
+        assert_eq!(describe_psg_write(0x3F), "data $3F");
+    }
+}
@@ -0,0 +1,268 @@
+//!
+//! `vgm2spc extract <file.spc> [-o <data.bin>]`
+//!
+//! Locate the packed VGM data in an SPC created by this tool and write it to a file, e.g. for
+//! inspecting it or re-converting it. Without `-o`, only the location is printed. If the output
+//! file is a .vgm or .vgz, the packed song is decoded back into a playable VGM file.
+//!
+
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::process;
+
+use crate::core::codec::{nullcodec, psgcodec};
+use crate::player::{CONFIG_BLOCK_TYPE, RAM_LIMIT, SEEK_TABLE_BLOCK_TYPE, SONG_INDEX_MAGIC};
+use crate::spc::SpcFile;
+use crate::spc::reader;
+use crate::vgm::Gd3;
+use crate::vgm::specification::{Command, FileHeader, VGM_MAGIC};
+use crate::vgm::writer;
+
+/// The original version of a packed song is lost (the converter marks packed songs by changing the
+/// version), so unpacked songs are labelled with the last version whose header fields we know
+const UNPACKED_VERSION: u8 = 0x51;
+
+pub fn run(args: &[String]) {
+    let (spc_path, output_path) = match args {
+        [spc] => (spc, None),
+        [spc, opt, out] if opt == "-o" => (spc, Some(out)),
+        _ => {
+            println!("Usage: vgm2spc extract <file.spc> [-o <data.bin>]");
+            process::exit(1);
+        }
+    };
+
+    let spc = reader::read_file(Path::new(spc_path)).unwrap_or_else(|e| panic!("Failed to read SPC file: {}", e));
+    if spc.tag().source_crc.is_none() {
+        println!("Warning: {} doesn't look like it was created by vgm2spc", spc_path);
+    }
+
+    let ram = &spc.ram;
+    let (start, length) = match find_packed_data(ram) {
+        Some(location) => location,
+        None => {
+            println!("{}: no packed VGM data found", spc_path);
+            process::exit(1);
+        }
+    };
+    println!("{}: packed data at ${:04X}-${:04X} ({} bytes)", spc_path, start, start + length - 1, length);
+
+    if let Some(output_path) = output_path {
+        let packed = &ram[start..start + length];
+        let path = Path::new(output_path);
+        let is_vgm = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("vgm") || ext.eq_ignore_ascii_case("vgz"));
+        if is_vgm {
+            let vgm = unpack_song(packed).unwrap_or_else(|e| panic!("Failed to decode the packed data: {}", e));
+            writer::write_vgm_file(path, &vgm).expect("Failed to write output file");
+        } else {
+            fs::write(path, packed).expect("Failed to write output file");
+        }
+    }
+}
+
+/// Where the command stream of a packed song is, and how it's encoded
+pub struct PackedSong {
+    pub header: FileHeader,
+    pub stream_offset: usize,
+    pub stream_end: usize,
+    pub lut: Option<Vec<u16>>,          // The long wait LUT, if the song was packed with a PSG codec
+    pub runs: bool,                     // The command stream has PSG runs
+}
+
+impl PackedSong {
+    /// Parse the header and extradata of the packed song `packed`. The LUT block (PSG codecs
+    /// only) comes first, followed by the player config block.
+    pub fn parse(packed: &[u8]) -> Result<PackedSong, Error> {
+        if packed.starts_with(SONG_INDEX_MAGIC) {
+            return Err(Error::new(ErrorKind::InvalidData, "Decoding SPCs with more than one song isn't supported"));
+        }
+        let header = FileHeader::parse(packed)?;
+        let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, msg.to_string());
+
+        let mut pos = header.data_offset();
+        let mut lut = None;
+        let mut runs = false;
+        loop {
+            let block = packed.get(pos..pos + 7).ok_or_else(|| invalid("No player config found"))?;
+            let size = u32::from_le_bytes([block[3], block[4], block[5], block[6]]) as usize;
+            let contents = packed.get(pos + 7..pos + 7 + size).ok_or_else(|| invalid("Truncated data block in the extradata"))?;
+            if block[0] != Command::DATA_BLOCK {
+                return Err(invalid("No player config found"));
+            }
+            pos += 7 + size;
+            match block[2] {
+                CONFIG_BLOCK_TYPE => break,
+                SEEK_TABLE_BLOCK_TYPE => {}
+                block_type @ (psgcodec::LONG_WAIT_LUT_BLOCK_TYPE | psgcodec::PSG_RUNS_LUT_BLOCK_TYPE) if lut.is_none() => {
+                    if size != psgcodec::LONG_WAIT_LUT_SIZE * 2 {
+                        return Err(Error::new(ErrorKind::InvalidData, format!("The long wait LUT has {} bytes instead of {}", size, psgcodec::LONG_WAIT_LUT_SIZE * 2)));
+                    }
+                    lut = Some(contents.chunks_exact(2).map(|w| u16::from_le_bytes([w[0], w[1]])).collect::<Vec<u16>>());
+                    runs = block_type == psgcodec::PSG_RUNS_LUT_BLOCK_TYPE;
+                }
+                other => return Err(Error::new(ErrorKind::InvalidData, format!("Unexpected data block of type 0x{:02X} in the extradata", other))),
+            }
+        }
+        let stream_end = header.eof_offset as usize + 4;
+        if stream_end < pos || stream_end > packed.len() {
+            return Err(invalid("The end of file offset is outside of the data"));
+        }
+        Ok(PackedSong { header, stream_offset: pos, stream_end, lut, runs })
+    }
+
+    /// Return the offset that playback jumps to when looping, if the song loops
+    pub fn loop_offset(&self) -> Option<usize> {
+        match self.header.loop_offset {
+            0 => None,
+            offset => Some(offset as usize + 0x1C),
+        }
+    }
+}
+
+/// Decode a packed song back into a VGM file. The extradata (long wait LUT and player config) is
+/// removed, and the command stream is decoded with the codec that the presence of a LUT implies.
+/// Songs that didn't loop keep the silent ending added by the converter.
+pub fn unpack_song(packed: &[u8]) -> Result<Vec<u8>, Error> {
+    let song = PackedSong::parse(packed)?;
+    let header = &song.header;
+    let data_offset = header.data_offset();
+    let pos = song.stream_offset;
+    let stream = &packed[pos..song.stream_end];
+    let events = match &song.lut {
+        Some(lut) => psgcodec::decode(stream, lut, song.runs)?,
+        None => nullcodec::decode(stream)?,
+    };
+    // The codec is flushed at the loop point, so the loop offset is the start of an event
+    let loop_index = song.loop_offset().and_then(|offset| {
+        let loop_pos = offset.saturating_sub(pos);
+        events.iter().position(|(offset, _)| *offset >= loop_pos)
+    });
+    let events: Vec<_> = events.into_iter().map(|(_, event)| event).collect();
+
+    let gd3 = match header.gd3_offset {
+        0 => None,
+        offset => Some(Gd3::parse(packed.get(offset as usize + 0x14..).unwrap_or_default())?),
+    };
+    let mut vgm_header = packed[..data_offset].to_vec();
+    vgm_header[8] = UNPACKED_VERSION;
+    if header.vgm_data_offset == 0 {
+        vgm_header[0x34..0x38].copy_from_slice(&(data_offset as u32 - 0x34).to_le_bytes());
+    }
+    Ok(writer::write_vgm(&vgm_header, &events, loop_index, gd3.as_ref()))
+}
+
+/// Return the start and length of the packed data (a single song, or a song index table followed
+/// by the songs) in an SPC RAM image.
+pub fn find_packed_data(ram: &[u8]) -> Option<(usize, usize)> {
+    let start = ram.windows(4).position(|w| w == VGM_MAGIC.as_bytes() || w == SONG_INDEX_MAGIC)?;
+    let data = &ram[start..];
+    let length = if data.starts_with(SONG_INDEX_MAGIC) {
+        // The songs are stored in order, so the last one ends where the data ends
+        let count = *data.get(4)? as usize;
+        let entry = 5 + count.checked_sub(1)? * 2;
+        let last = u16::from_le_bytes([*data.get(entry)?, *data.get(entry + 1)?]) as usize;
+        last + song_length(data.get(last..)?)?
+    } else {
+        song_length(data)?
+    };
+    if length > data.len() { None } else { Some((start, length)) }
+}
+
+/// Return the end of the RAM that packed data can use in the SPC file `spc`: the IPL ROM area, or
+/// the start of the echo buffer if echo writes are enabled
+pub fn data_limit(spc: &SpcFile) -> usize {
+    if spc.dsp[0x6C] & 0x20 == 0 {
+        std::cmp::min(RAM_LIMIT, spc.dsp[0x6D] as usize * 0x100)
+    } else {
+        RAM_LIMIT
+    }
+}
+
+/// Return the length of the packed song at the start of `data`, i.e. the end of the command
+/// stream or the GD3 tag, whichever comes last
+fn song_length(data: &[u8]) -> Option<usize> {
+    let u32_at = |offset: usize| data.get(offset..offset + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize);
+    let stream_end = u32_at(0x04)? + 4;
+    match u32_at(0x14)? {
+        0 => Some(stream_end),
+        gd3_offset => {
+            let gd3_start = gd3_offset + 0x14;
+            Some(std::cmp::max(stream_end, gd3_start + 12 + u32_at(gd3_start + 8)?))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::player::song_index_table;
+    use crate::spc::reader::RAM_SIZE;
+
+    fn song(stream_length: usize, gd3_length: usize) -> Vec<u8> {
+        let mut data = VGM_MAGIC.as_bytes().to_vec();
+        data.resize(stream_length, 0x66);
+        data[0x04..0x08].copy_from_slice(&(stream_length as u32 - 4).to_le_bytes());
+        data[0x14..0x18].copy_from_slice(&(stream_length as u32 - 0x14).to_le_bytes());
+        data.extend(b"Gd3 \x00\x01\x00\x00");
+        data.extend(&(gd3_length as u32).to_le_bytes());
+        data.resize(data.len() + gd3_length, 0);
+        data
+    }
+
+    #[test]
+    fn test_find_single_song() {
+        let mut ram = vec![0u8; 0x1700];
+        ram.extend(song(0x80, 0x20));
+        ram.resize(RAM_SIZE, 0);
+        assert_eq!(find_packed_data(&ram), Some((0x1700, 0x80 + 12 + 0x20)));
+    }
+
+    #[test]
+    fn test_unpack_song() {
+        use crate::core::bytestream::ByteStream;
+        use crate::core::codec::Codec;
+        use crate::player::PlayerConfig;
+        use crate::vgm::events::{read_stream, VgmEvent};
+        use crate::vgm::testing::VgmBuilder;
+
+        let events = vec![VgmEvent::PsgWrite(0x9F), VgmEvent::Wait(1000), VgmEvent::PsgWrite(0x80),
+                          VgmEvent::Wait(1000), VgmEvent::Wait(3), VgmEvent::End];
+        let mut stream = ByteStream::new(Vec::new());
+        let mut codec = psgcodec::PsgCodec::new(&mut stream);
+        codec.write_event(&events[0]);
+        codec.flush();
+        let loop_offset = codec.output_len();
+        for event in &events[1..] {
+            codec.write_event(event);
+        }
+        codec.flush();
+        let mut extradata = codec.get_extra_data(psgcodec::GET_LONG_WAIT_LUT).unwrap();
+        extradata.extend(PlayerConfig::from_header(&FileHeader::default()).to_data_block());
+
+        let mut packed = VgmBuilder::new().version(0x152).loop_at(0x40 + extradata.len() + loop_offset)
+            .commands(&extradata).commands(stream.as_slice()).build();
+
+        let vgm = unpack_song(&packed).unwrap();
+        let header = FileHeader::parse(&vgm).unwrap();
+        assert_eq!(header.version, 0x151);
+        assert_eq!(header.total_samples, 2003);
+        assert_eq!(header.loop_samples, 2003);
+        assert_eq!(read_stream(&vgm, &header).unwrap(), (events, Some(1)));
+
+        // A LUT block of the wrong size is an error
+        packed[0x40 + 3] = 0x1F;
+        assert_eq!(unpack_song(&packed).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_find_multiple_songs() {
+        let songs = vec![song(0x80, 0x20), song(0x100, 0)];
+        let mut ram = vec![0u8; 0x1700];
+        ram.extend(song_index_table(&songs).unwrap());
+        for s in &songs { ram.extend(s); }
+        let length = ram.len() - 0x1700;
+        ram.resize(RAM_SIZE, 0);
+        assert_eq!(find_packed_data(&ram), Some((0x1700, length)));
+    }
+}
@@ -0,0 +1,35 @@
+//!
+//! Subcommands other than the default conversion (`vgm2spc <subcommand> ...`)
+//!
+
+pub mod analyze;
+pub mod bench;
+pub mod check;
+pub mod diff;
+pub mod disasm;
+pub mod extract;
+pub mod play;
+pub mod repatch;
+pub mod replace;
+pub mod selftest;
+pub mod tags;
+
+/// Run the subcommand `name` with `args`. Returns false if there's no such subcommand.
+pub fn run(name: &str, args: &[String]) -> bool {
+    match name {
+        "check" => check::run(args),
+        "tags" => tags::run(args),
+        "extract" => extract::run(args),
+        "play" => play::run(args),
+        "diff" => diff::run(args),
+        "disasm" => disasm::run(args),
+        "disasm-c" => disasm::run_packed(args),
+        "bench" => bench::run(args),
+        "analyze" => analyze::run(args),
+        "replace" => replace::run(args),
+        "repatch" => repatch::run(args),
+        "selftest" => selftest::run(args),
+        _ => return false,
+    }
+    true
+}
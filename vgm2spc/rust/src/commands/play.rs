@@ -0,0 +1,77 @@
+//!
+//! `vgm2spc play <song.vgm> [-player <command>]`
+//!
+//! Convert a VGM file to a temporary SPC and open it with an external player, for quick
+//! auditioning. The player command is taken from `-player`, or else from the `VGM2SPC_PLAYER`
+//! environment variable. `{}` in the command is replaced with the path of the SPC; if there's no
+//! `{}`, the path is added as the last argument.
+//!
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::{self, Command};
+
+use crate::converter::Converter;
+use crate::observer::ConsoleObserver;
+
+/// Environment variable holding the default player command
+const PLAYER_VARIABLE: &str = "VGM2SPC_PLAYER";
+
+pub fn run(args: &[String]) {
+    let (vgm_path, player) = match args {
+        [vgm] => (vgm, env::var(PLAYER_VARIABLE).ok()),
+        [vgm, opt, player] if opt == "-player" => (vgm, Some(player.clone())),
+        _ => {
+            println!("Usage: vgm2spc play <song.vgm> [-player <command>]");
+            process::exit(1);
+        }
+    };
+    let player = match player {
+        Some(player) if !player.trim().is_empty() => player,
+        _ => {
+            println!("No player configured. Use -player <command>, or set {}", PLAYER_VARIABLE);
+            process::exit(1);
+        }
+    };
+
+    let spc_path = env::temp_dir().join(format!("vgm2spc-{}.spc", process::id()));
+    let mut converter = Converter::builder().build().expect("Invalid options").with_observer(Box::new(ConsoleObserver));
+    converter.convert(Path::new(vgm_path), &spc_path).expect("Failed");
+
+    let command_line = player_command(&player, &spc_path.to_string_lossy());
+    let status = Command::new(&command_line[0]).args(&command_line[1..]).status();
+    let _ = fs::remove_file(&spc_path);
+    match status {
+        Ok(status) if !status.success() => println!("Warning: {} exited with {}", command_line[0], status),
+        Ok(_) => {}
+        Err(e) => {
+            println!("Failed to run {}: {}", command_line[0], e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Split the player command into the program and its arguments, and insert `spc_path`
+pub fn player_command(player: &str, spc_path: &str) -> Vec<String> {
+    let mut words: Vec<String> = player.split_whitespace().map(String::from).collect();
+    if words.iter().any(|word| word.contains("{}")) {
+        for word in words.iter_mut() {
+            *word = word.replace("{}", spc_path);
+        }
+    } else {
+        words.push(spc_path.to_string());
+    }
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_player_command() {
+        assert_eq!(player_command("spcplay", "/tmp/a.spc"), vec!["spcplay", "/tmp/a.spc"]);
+        assert_eq!(player_command("gme_player -i {} --loop", "/tmp/a.spc"), vec!["gme_player", "-i", "/tmp/a.spc", "--loop"]);
+    }
+}
@@ -0,0 +1,112 @@
+//!
+//! `vgm2spc repatch <file.spc> [-player <player.bin>]`
+//!
+//! Replace the player in an SPC created by this tool with another player binary (by default the
+//! one next to the executable), laid out according to its manifest. The packed data is moved to
+//! right after the new player; the tags, DSP registers and the initial IO port values are kept.
+//! Bytes changed with `-patch` when the SPC was created are lost.
+//!
+
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use crate::core::codec::psgcodec;
+use crate::commands::extract::{data_limit, find_packed_data};
+use crate::options::Codec;
+use crate::player::{self, Player, SONG_INDEX_MAGIC};
+use crate::spc::{reader, writer, SpcFile};
+use crate::vgm::specification::{Command, FileHeader};
+
+pub fn run(args: &[String]) {
+    let (spc_path, player_path) = match args {
+        [spc] => (spc, Player::find_default().unwrap_or_else(|e| panic!("{}", e))),
+        [spc, opt, player] if opt == "-player" => (spc, PathBuf::from(player)),
+        _ => {
+            println!("Usage: vgm2spc repatch <file.spc> [-player <player.bin>]");
+            process::exit(1);
+        }
+    };
+    let mut spc = reader::read_file(Path::new(spc_path)).unwrap_or_else(|e| panic!("Failed to read SPC file: {}", e));
+    let player = Player::load(&player_path).unwrap_or_else(|e| panic!("Failed to load {}: {}", player_path.display(), e));
+    let (old_address, new_address) = repatch(&mut spc, &player).unwrap_or_else(|e| panic!("{}: {}", spc_path, e));
+    fs::write(spc_path, writer::write(&spc)).expect("Failed to write SPC file");
+    println!("Replaced the player with {} ({} bytes). The packed data moved from ${:04X} to ${:04X}",
+             player_path.display(), player.size(), old_address, new_address);
+}
+
+/// Replace the player in `spc` with `player`. Returns the old and new address of the packed data.
+fn repatch(spc: &mut SpcFile, player: &Player) -> Result<(usize, usize), Error> {
+    let invalid = |msg: String| Error::new(ErrorKind::InvalidData, msg);
+    let limit = data_limit(spc);
+    let ram = &mut spc.ram;
+    let (start, length) = find_packed_data(ram).ok_or_else(|| invalid(String::from("no packed VGM data found")))?;
+    let packed = ram[start..start + length].to_vec();
+
+    for codec in song_codecs(&packed) {
+        if !player.supports_codec(codec.name()) {
+            return Err(invalid(format!("the new player doesn't support the {} codec", codec.name())));
+        }
+    }
+    let new_start = player.data_address();
+    if new_start + packed.len() > std::cmp::min(limit, new_start + player.data_capacity()) {
+        return Err(invalid(format!("the packed data ({} bytes) doesn't fit after the new player", packed.len())));
+    }
+
+    let mut new_ram = player.ram_image(&packed);
+    new_ram[player::IO_PORTS..player::IO_PORTS + 4].copy_from_slice(&ram[player::IO_PORTS..player::IO_PORTS + 4]);
+    // Keep the echo buffer (and anything else) above the old and new data
+    let end = std::cmp::max(start + length, new_start + packed.len());
+    new_ram[end..].copy_from_slice(&ram[end..]);
+    ram.copy_from_slice(&new_ram);
+    spc.registers.pc = player.manifest.entry_point;
+    Ok((start, new_start))
+}
+
+/// Return the codec of each song in `packed`, based on the type of the LUT data block that
+/// follows the header of songs packed with the PSG codecs
+fn song_codecs(packed: &[u8]) -> Vec<Codec> {
+    let offsets: Vec<usize> = if packed.starts_with(SONG_INDEX_MAGIC) {
+        let count = packed.get(4).copied().unwrap_or(0) as usize;
+        (0..count).filter_map(|i| packed.get(5 + i * 2..7 + i * 2)).map(|o| u16::from_le_bytes([o[0], o[1]]) as usize).collect()
+    } else {
+        vec![0]
+    };
+    offsets.iter().filter_map(|&offset| {
+        let song = packed.get(offset..)?;
+        let block = song.get(FileHeader::parse(song).ok()?.data_offset()..)?;
+        Some(match block {
+            [Command::DATA_BLOCK, _, psgcodec::LONG_WAIT_LUT_BLOCK_TYPE, ..] => Codec::Psg,
+            [Command::DATA_BLOCK, _, psgcodec::PSG_RUNS_LUT_BLOCK_TYPE, ..] => Codec::PsgRuns,
+            _ => Codec::Null,
+        })
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vgm::testing::VgmBuilder;
+
+    #[test]
+    fn test_repatch() {
+        let song = VgmBuilder::new().commands(&[Command::DATA_BLOCK, 0x66, psgcodec::LONG_WAIT_LUT_BLOCK_TYPE, 0]).build();
+
+        let mut spc = SpcFile::new();
+        spc.dsp[0x6C] = 0x20;
+        spc.ram[..0x300].fill(0xEA);
+        spc.ram[0x300..0x344].copy_from_slice(&song);
+        spc.ram[player::IO_PORTS] = 2;
+
+        let mut player = Player { binary: vec![0xFF; 0x400], ..Default::default() };
+        player.manifest.entry_point = 0x0123;
+        assert_eq!(repatch(&mut spc, &player).unwrap(), (0x300, 0x400));
+        assert_eq!(&spc.ram[0x3FF..0x404], &[0xFF, b'V', b'g', b'm', b' ']);
+        assert_eq!(spc.ram[player::IO_PORTS], 2);
+        assert_eq!(spc.registers.pc, 0x0123);
+
+        player.manifest.codecs = vec![String::from("none")];
+        assert!(repatch(&mut spc, &player).is_err());
+    }
+}
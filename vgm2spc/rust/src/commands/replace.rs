@@ -0,0 +1,117 @@
+//!
+//! `vgm2spc replace <file.spc> <song.vgm>`
+//!
+//! Replace the packed song in an SPC created by this tool with a new conversion of `song.vgm`.
+//! The player, the tags and the DSP registers are kept as they are; only the packed data, the
+//! play and fade lengths and the source CRC change. The lengths are set as a new conversion would
+//! set them. The new data is placed at the same address as the old one.
+//!
+
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::process;
+
+use crate::commands::extract::{data_limit, find_packed_data};
+use crate::converter::Converter;
+use crate::observer::ConsoleObserver;
+use crate::options::OutputFormat;
+use crate::player::SONG_INDEX_MAGIC;
+use crate::spc::xid6;
+use crate::spc::{reader, writer, Id666, Id666Format, SpcFile};
+
+pub fn run(args: &[String]) {
+    if args.len() != 2 {
+        println!("Usage: vgm2spc replace <file.spc> <song.vgm>");
+        process::exit(1);
+    }
+    let mut spc = reader::read_file(Path::new(&args[0])).unwrap_or_else(|e| panic!("Failed to read SPC file: {}", e));
+
+    let mut converter = Converter::builder().output_format(OutputFormat::Raw).build().unwrap().with_observer(Box::new(ConsoleObserver));
+    let packed = converter.pack_file(Path::new(&args[1])).expect("Failed to convert VGM file").to_vec();
+    let (start, old_length) = replace_packed_data(&mut spc, &packed).unwrap_or_else(|e| panic!("{}: {}", args[0], e));
+    update_tag(&mut spc, &converter.id666_tag());
+    fs::write(&args[0], writer::write(&spc)).expect("Failed to write SPC file");
+    println!("Replaced {} bytes of packed data at ${:04X} with {} bytes", old_length, start, packed.len());
+}
+
+/// Put `packed` in place of the packed song in `spc`, clearing what's left of the old data.
+/// Returns the address and length of the old data.
+fn replace_packed_data(spc: &mut SpcFile, packed: &[u8]) -> Result<(usize, usize), Error> {
+    let invalid = |msg: String| Error::new(ErrorKind::InvalidData, msg);
+    let limit = data_limit(spc);
+    let ram = &mut spc.ram;
+    let (start, old_length) = find_packed_data(ram).ok_or_else(|| invalid(String::from("no packed VGM data found")))?;
+    if ram[start..].starts_with(SONG_INDEX_MAGIC) {
+        return Err(invalid(String::from("replacing the songs of a multi-song SPC isn't supported")));
+    }
+    if start + packed.len() > limit {
+        return Err(invalid(format!("the new data is {} bytes, but only {} bytes are available", packed.len(), limit.saturating_sub(start))));
+    }
+    ram[start..start + old_length].fill(0);
+    ram[start..start + packed.len()].copy_from_slice(packed);
+    Ok((start, old_length))
+}
+
+/// Copy the play and fade lengths and the source CRC of `new_tag` into the tags of `spc`, keeping
+/// the rest of them
+fn update_tag(spc: &mut SpcFile, new_tag: &Id666) {
+    let (mut tag, format) = spc.id666.take().unwrap_or((Id666::new(), Id666Format::Text));
+    tag.play_seconds = new_tag.play_seconds;
+    tag.fade_ms = new_tag.fade_ms;
+    tag.source_crc = new_tag.source_crc;
+    if let Some(xid6) = spc.xid6.as_mut() {
+        if xid6.get(xid6::ID_INTRO_LENGTH).is_some() {
+            xid6.set_integer(xid6::ID_INTRO_LENGTH, tag.play_seconds * xid6::TICKS_PER_SECOND);
+        }
+        if xid6.get(xid6::ID_FADE_LENGTH).is_some() {
+            xid6.set_integer(xid6::ID_FADE_LENGTH, tag.fade_ms * (xid6::TICKS_PER_SECOND / 1000));
+        }
+    }
+    spc.id666 = Some((tag, format));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spc::Xid6;
+    use crate::vgm::testing::VgmBuilder;
+
+    fn packed_song(length: usize) -> Vec<u8> {
+        VgmBuilder::new().commands(&vec![0; length - 0x40]).build()
+    }
+
+    #[test]
+    fn test_replace_packed_data() {
+        let mut spc = SpcFile::new();
+        spc.ram[..0x200].fill(0xEA);    // Player
+        spc.ram[0x200..0x300].copy_from_slice(&packed_song(0x100));
+        spc.dsp[0x6C] = 0x20;
+
+        let new_song = packed_song(0x80);
+        assert_eq!(replace_packed_data(&mut spc, &new_song).unwrap(), (0x200, 0x100));
+        assert_eq!(&spc.ram[0x1FF..0x200], &[0xEA]);
+        assert_eq!(&spc.ram[0x200..0x280], &new_song[..]);
+        assert!(spc.ram[0x280..0x300].iter().all(|&b| b == 0));
+
+        // An echo buffer at $0300 leaves no room for a bigger song
+        spc.dsp[0x6C] = 0x00;
+        spc.dsp[0x6D] = 0x03;
+        assert!(replace_packed_data(&mut spc, &packed_song(0x101)).is_err());
+    }
+
+    #[test]
+    fn test_update_tag() {
+        let old_tag = Id666 { song_title: String::from("Title"), play_seconds: 180, fade_ms: 10000, source_crc: Some(1), ..Id666::new() };
+        let mut xid6 = Xid6::default();
+        xid6.set_integer(xid6::ID_INTRO_LENGTH, 180 * xid6::TICKS_PER_SECOND);
+        let mut spc = SpcFile { id666: Some((old_tag.clone(), Id666Format::Binary)), xid6: Some(xid6), ..SpcFile::new() };
+
+        let new_tag = Id666 { song_title: String::from("New title"), play_seconds: 42, source_crc: Some(2), ..Id666::new() };
+        update_tag(&mut spc, &new_tag);
+        assert_eq!(spc.id666, Some((Id666 { play_seconds: 42, fade_ms: 0, source_crc: Some(2), ..old_tag }, Id666Format::Binary)));
+        let xid6 = spc.xid6.unwrap();
+        assert_eq!(xid6.get_integer(xid6::ID_INTRO_LENGTH), Some(42 * xid6::TICKS_PER_SECOND));
+        assert!(xid6.get(xid6::ID_FADE_LENGTH).is_none());
+    }
+}
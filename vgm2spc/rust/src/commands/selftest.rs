@@ -0,0 +1,157 @@
+//!
+//! `vgm2spc selftest <corpus_dir> [-update]`
+//!
+//! Pack every VGM file in a directory, and compare a hash of the packed data against the manifest
+//! `selftest.json` in the same directory, to catch changes in the output between releases. The
+//! packed data is hashed rather than the SPC, so the result doesn't depend on the player binary or
+//! the date. `-update` writes the current results to the manifest instead.
+//!
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use serde::{Deserialize, Serialize};
+
+use crate::converter::Converter;
+use crate::options::OutputFormat;
+
+/// Name of the manifest file in the corpus directory
+const MANIFEST_NAME: &str = "selftest.json";
+
+/// The expected result for one file of the corpus
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+struct Expected {
+    size: usize,
+    crc32: String,
+}
+
+/// How the result for a file compares to the manifest
+#[derive(Clone, Debug, PartialEq)]
+enum Outcome {
+    Unchanged,
+    Changed { expected: Expected, actual: Expected },
+    Failed(String),
+    New(Expected),
+    Skipped(String),                    // Not in the manifest, and can't be packed
+    Missing,
+}
+
+impl Outcome {
+    fn is_regression(&self) -> bool {
+        matches!(self, Outcome::Changed { .. } | Outcome::Failed(_) | Outcome::Missing)
+    }
+}
+
+pub fn run(args: &[String]) {
+    let (dir, update) = match args {
+        [dir] => (dir, false),
+        [dir, opt] if opt == "-update" => (dir, true),
+        _ => {
+            println!("Usage: vgm2spc selftest <corpus_dir> [-update]");
+            process::exit(1);
+        }
+    };
+    let manifest_path = Path::new(dir).join(MANIFEST_NAME);
+    let manifest: BTreeMap<String, Expected> = match fs::read_to_string(&manifest_path) {
+        Ok(json) => serde_json::from_str(&json).unwrap_or_else(|e| panic!("{}: {}", manifest_path.display(), e)),
+        Err(_) if update => BTreeMap::new(),
+        Err(e) => panic!("Failed to read {}: {} (run with -update to create it)", manifest_path.display(), e),
+    };
+
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir).expect("Failed to read directory")
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("vgm") || ext.eq_ignore_ascii_case("vgz")))
+        .collect();
+    paths.sort();
+    // One converter is reused for all files, so that its buffers are only allocated once
+    let mut converter = Converter::builder().output_format(OutputFormat::Raw).build().unwrap();
+    let results: BTreeMap<String, Result<Expected, String>> = paths.iter()
+        .map(|path| (path.file_name().unwrap_or_default().to_string_lossy().into_owned(), measure(&mut converter, path)))
+        .collect();
+
+    if update {
+        let current: BTreeMap<&String, &Expected> = results.iter()
+            .filter_map(|(name, result)| result.as_ref().ok().map(|expected| (name, expected)))
+            .collect();
+        fs::write(&manifest_path, serde_json::to_string_pretty(&current).unwrap() + "\n").expect("Failed to write manifest");
+        println!("Wrote the results for {} files to {}", current.len(), manifest_path.display());
+        return;
+    }
+
+    let outcomes = compare(&manifest, results);
+    println!();
+    for (name, outcome) in &outcomes {
+        match outcome {
+            Outcome::Unchanged => println!("ok        {}", name),
+            Outcome::Changed { expected, actual } => println!("CHANGED   {} ({} bytes, CRC {} -> {} bytes, CRC {})",
+                                                              name, expected.size, expected.crc32, actual.size, actual.crc32),
+            Outcome::Failed(e) => println!("FAILED    {}: {}", name, e),
+            Outcome::New(_) => println!("new       {} (not in the manifest)", name),
+            Outcome::Skipped(e) => println!("skipped   {}: {}", name, e),
+            Outcome::Missing => println!("MISSING   {} (in the manifest, but not in the corpus)", name),
+        }
+    }
+    let regressions = outcomes.values().filter(|outcome| outcome.is_regression()).count();
+    println!("{} files, {} regressions", outcomes.len(), regressions);
+    if regressions > 0 {
+        process::exit(1);
+    }
+}
+
+/// Pack the file at `path` with the default settings
+fn measure(converter: &mut Converter, path: &Path) -> Result<Expected, String> {
+    let packed = converter.pack_file(path).map_err(|e| e.to_string())?;
+    Ok(Expected { size: packed.len(), crc32: format!("{:08X}", crc32fast::hash(packed)) })
+}
+
+/// Compare the results for the files in the corpus with the manifest
+fn compare(manifest: &BTreeMap<String, Expected>, results: BTreeMap<String, Result<Expected, String>>) -> BTreeMap<String, Outcome> {
+    let mut outcomes: BTreeMap<String, Outcome> = manifest.keys()
+        .filter(|name| !results.contains_key(*name))
+        .map(|name| (name.clone(), Outcome::Missing))
+        .collect();
+    for (name, result) in results {
+        let outcome = match (manifest.get(&name), result) {
+            (None, Err(e)) => Outcome::Skipped(e),
+            (Some(_), Err(e)) => Outcome::Failed(e),
+            (None, Ok(actual)) => Outcome::New(actual),
+            (Some(expected), Ok(actual)) if *expected == actual => Outcome::Unchanged,
+            (Some(expected), Ok(actual)) => Outcome::Changed { expected: expected.clone(), actual },
+        };
+        outcomes.insert(name, outcome);
+    }
+    outcomes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare() {
+        let expected = |size, crc: &str| Expected { size, crc32: String::from(crc) };
+        let manifest: BTreeMap<String, Expected> = vec![
+            (String::from("a.vgm"), expected(100, "12345678")),
+            (String::from("b.vgm"), expected(200, "9ABCDEF0")),
+            (String::from("c.vgm"), expected(300, "00000000")),
+            (String::from("gone.vgm"), expected(1, "00000000")),
+        ].into_iter().collect();
+        let results = vec![
+            (String::from("a.vgm"), Ok(expected(100, "12345678"))),
+            (String::from("b.vgm"), Ok(expected(201, "9ABCDEF1"))),
+            (String::from("c.vgm"), Err(String::from("too large"))),
+            (String::from("new.vgm"), Ok(expected(5, "00000001"))),
+            (String::from("bad.vgm"), Err(String::from("not a VGM file"))),
+        ].into_iter().collect();
+        let outcomes = compare(&manifest, results);
+        assert_eq!(outcomes["a.vgm"], Outcome::Unchanged);
+        assert_eq!(outcomes["b.vgm"], Outcome::Changed { expected: expected(200, "9ABCDEF0"), actual: expected(201, "9ABCDEF1") });
+        assert_eq!(outcomes["c.vgm"], Outcome::Failed(String::from("too large")));
+        assert_eq!(outcomes["new.vgm"], Outcome::New(expected(5, "00000001")));
+        assert_eq!(outcomes["gone.vgm"], Outcome::Missing);
+        assert_eq!(outcomes["bad.vgm"], Outcome::Skipped(String::from("not a VGM file")));
+        assert_eq!(outcomes.values().filter(|outcome| outcome.is_regression()).count(), 3);
+    }
+}
@@ -0,0 +1,151 @@
+//!
+//! `vgm2spc tags show <file.spc>`
+//! `vgm2spc tags edit <file.spc> [options]`
+//!
+//! Print or change the ID666 tag of an SPC file (created by this tool or not). The format of the
+//! existing tag is kept unless another one is requested. String items that also exist in the
+//! xid6 chunk are updated there as well, since players prefer those over the ID666 fields.
+//!
+
+use std::fs;
+use std::path::Path;
+use std::process;
+
+use crate::date::Date;
+use crate::spc::xid6;
+use crate::spc::{reader, writer, Id666, Id666Format, Xid6};
+
+const USAGE: &str = "Usage: vgm2spc tags show <file.spc>
+       vgm2spc tags edit <file.spc> [options]
+Options:
+  -title <text>         Song title
+  -game <text>          Game title
+  -artist <text>        Artist
+  -dumper <name>        Dumped by
+  -comment <text>       Comment
+  -date <YYYY-MM-DD>    Dump date
+  -length <time>        Play time before fading out, in seconds or as m:ss
+  -fade <ms>            Fade length in milliseconds
+  -text | -binary       Rewrite the tag in the given format";
+
+fn usage() -> ! {
+    println!("{}", USAGE);
+    process::exit(1);
+}
+
+pub fn run(args: &[String]) {
+    if args.len() < 2 {
+        usage();
+    }
+    let path = &args[1];
+    let mut spc = reader::read_file(Path::new(path)).unwrap_or_else(|e| panic!("Failed to read SPC file: {}", e));
+    let (mut tag, mut format) = spc.id666.take().unwrap_or((Id666::new(), Id666Format::Text));
+    let mut xid6 = spc.xid6.take();
+
+    match args[0].as_str() {
+        "show" => {
+            show(&tag, format, xid6.as_ref());
+            return;
+        }
+        "edit" => {}
+        _ => usage(),
+    }
+
+    let mut options = args[2..].iter().cloned();
+    while let Some(opt) = options.next() {
+        if opt == "-text" || opt == "-binary" {
+            format = if opt == "-text" { Id666Format::Text } else { Id666Format::Binary };
+            continue;
+        }
+        let value = match options.next() {
+            Some(value) => value,
+            None => panic!("Missing value for option {}", opt),
+        };
+        let (field, xid6_id) = match opt.as_str() {
+            "-title" => (&mut tag.song_title, xid6::ID_SONG_NAME),
+            "-game" => (&mut tag.game_title, xid6::ID_GAME_NAME),
+            "-artist" => (&mut tag.artist, xid6::ID_ARTIST),
+            "-dumper" => (&mut tag.dumper, xid6::ID_DUMPER),
+            "-comment" => (&mut tag.comment, xid6::ID_COMMENTS),
+            "-date" => {
+                tag.dump_date = Some(Date::parse(&value).unwrap_or_else(|| panic!("Invalid date: {} (expected YYYY-MM-DD)", value)));
+                continue;
+            }
+            "-length" => {
+                tag.play_seconds = parse_length(&value).unwrap_or_else(|| panic!("Invalid length: {}", value));
+                if let Some(xid6) = xid6.as_mut().filter(|x| x.get(xid6::ID_INTRO_LENGTH).is_some()) {
+                    xid6.set_integer(xid6::ID_INTRO_LENGTH, tag.play_seconds * xid6::TICKS_PER_SECOND);
+                }
+                continue;
+            }
+            "-fade" => {
+                tag.fade_ms = value.parse().unwrap_or_else(|_| panic!("Invalid fade length: {}", value));
+                if let Some(xid6) = xid6.as_mut().filter(|x| x.get(xid6::ID_FADE_LENGTH).is_some()) {
+                    xid6.set_integer(xid6::ID_FADE_LENGTH, tag.fade_ms * (xid6::TICKS_PER_SECOND / 1000));
+                }
+                continue;
+            }
+            _ => panic!("Unknown option: {}", opt),
+        };
+        *field = value.clone();
+        if let Some(xid6) = xid6.as_mut().filter(|x| x.get(xid6_id).is_some()) {
+            xid6.set_string(xid6_id, &value);
+        }
+    }
+    // Keep the full text of fields that the ID666 tag can't hold as they are
+    if let Some(full_text) = tag.to_xid6() {
+        let xid6 = xid6.get_or_insert_with(Xid6::default);
+        for item in &full_text.items {
+            xid6.set_string(item.id, &full_text.get_string(item.id).unwrap_or_default());
+        }
+    }
+
+    spc.id666 = Some((tag.clone(), format));
+    spc.xid6 = xid6;
+    fs::write(path, writer::write(&spc)).expect("Failed to write SPC file");
+    show(&tag, format, None);
+}
+
+/// Parse a length given in seconds or as minutes:seconds
+fn parse_length(text: &str) -> Option<u32> {
+    match text.split_once(':') {
+        Some((minutes, seconds)) => {
+            let seconds: u32 = seconds.parse().ok().filter(|&s| s < 60)?;
+            Some(minutes.parse::<u32>().ok()? * 60 + seconds)
+        }
+        None => text.parse().ok(),
+    }
+}
+
+fn show(tag: &Id666, format: Id666Format, xid6: Option<&Xid6>) {
+    println!("Format:   {:?}", format);
+    println!("Title:    {}", tag.song_title);
+    println!("Game:     {}", tag.game_title);
+    println!("Artist:   {}", tag.artist);
+    println!("Dumper:   {}", tag.dumper);
+    println!("Comment:  {}", tag.comment);
+    println!("Date:     {}", tag.dump_date.map(|d| d.to_string()).unwrap_or_default());
+    println!("Length:   {}:{:02}, fade {} ms", tag.play_seconds / 60, tag.play_seconds % 60, tag.fade_ms);
+    if let Some(xid6) = xid6 {
+        println!("xid6:     {} items", xid6.items.len());
+        if let Some(title) = xid6.get_string(xid6::ID_SONG_NAME) {
+            println!("  Title:  {}", title);
+        }
+        if let Some(ticks) = xid6.get_integer(xid6::ID_INTRO_LENGTH) {
+            println!("  Length: {:.2} s", ticks as f64 / xid6::TICKS_PER_SECOND as f64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_length() {
+        assert_eq!(parse_length("180"), Some(180));
+        assert_eq!(parse_length("2:05"), Some(125));
+        assert_eq!(parse_length("2:60"), None);
+        assert_eq!(parse_length("abc"), None);
+    }
+}
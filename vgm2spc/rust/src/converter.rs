@@ -1,368 +1,1595 @@
 use std::io::{Error,ErrorKind};
 use std::io::prelude::*;
-use std::fs::File;
-use std::path::Path;
-
-use crate::bytestream::ByteStream;
-use crate::codec::codec::Codec;
-use crate::codec::nullcodec::NullCodec;
-use crate::codec::psgcodec::PsgCodec;
-use crate::codec::psgcodec;
-use crate::vgm::specification::Command;
+use std::fmt;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::analysis::{self, CpuLoad, SectionSizes, SizeBreakdown};
+use crate::core::bytestream::ByteStream;
+use crate::core::error;
+use crate::core::psg::PsgState;
+use crate::date::Date;
+use crate::dsp;
+use crate::observer::{Observer, SilentObserver, SongStats};
+use crate::options::{Codec, ConverterBuilder, DualPsgMapping, Options, OutputFormat};
+use crate::player;
+use crate::player::{Player, PlayerConfig, SeekPoint, RAM_LIMIT};
+use crate::sfc;
+use crate::spc::{Id666, SpcWriter};
+use crate::spc::reader::Registers;
+use crate::core::codec::{self, Codec as _, NullCodec, PsgCodec};
+use crate::core::codec::{nullcodec, psgcodec};
+use crate::vgm::{Gd3, read_vgm_file};
 use crate::vgm::specification;
-use crate::vgm::reader;
+use crate::vgm::edit;
+use crate::vgm::writer;
+use crate::vgm::events::{Events, VgmEvent};
+use crate::passes::{self, EventList, Pass, PassKind};
+
+/// Positions of the various parts of a packed song, relative to the start of the packed data
+#[derive(Clone, Debug, PartialEq)]
+pub struct PackedLayout {
+    pub data_offset: usize,             // Start of the extradata, followed by the command stream
+    pub stream_offset: usize,           // Start of the command stream
+    pub stream_end: usize,              // End of the command stream (i.e. start of the GD3 tag, if any)
+    pub lut_offset: Option<usize>,      // Start of the long wait LUT entries (after the data block header)
+    pub loop_offset: Option<usize>,     // The command that playback jumps to when looping
+    pub length: usize,
+}
+
+/// Estimated size of a song packed with one codec, as found by `Converter::analyze`
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CodecEstimate {
+    pub codec: &'static str,
+    pub size: Option<usize>,            // None if the song couldn't be packed with the codec
+    pub fits: bool,
+    pub supported_by_player: bool,
+}
+
+/// What `Converter::analyze` found out about a VGM file
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Analysis {
+    pub header: specification::FileHeader,
+    pub chips: Vec<&'static str>,
+    pub gd3: Gd3,
+    pub capacity: Option<usize>,        // Space for the packed data, if the player could be loaded
+    pub codecs: Vec<CodecEstimate>,
+}
+
+/// A way of making the packed data smaller, tried by `-auto-fit` when a song doesn't fit
+struct FitStep {
+    description: &'static str,
+    apply: fn(&mut Options) -> bool,    // Returns false if the step was already applied
+}
+
+/// Steps tried by `-auto-fit`, in order. Each step is kept when trying the next one. None of them
+/// change what the player plays. The long wait LUT has a fixed size in the player, so there's no
+/// larger one to try, and the player can't play PCM, so PCM data is removed instead of downsampled.
+const FIT_STEPS: [FitStep; 3] = [
+    FitStep { description: "the GD3 tag removed from the packed data", apply: |o| !std::mem::replace(&mut o.strip_gd3, true) },
+    FitStep { description: "the automatically selected codec instead of none", apply: compress },
+    FitStep { description: "PCM data and writes to chips that the player doesn't play removed", apply: strip_unplayed },
+];
 
-bitflags! {
-    pub struct ConverterFlags: u32 {
-        const NULL_CODEC = 0x00000000;
-        const PSG_CODEC  = 0x00000001;
-        const ASSUME_VGZ = 0x00000004;
-        const RAW_OUTPUT = 0x00000008;
+fn compress(options: &mut Options) -> bool {
+    if options.codec != Codec::Null {
+        return false;
     }
+    options.codec = Codec::Auto;
+    true
 }
 
+fn strip_unplayed(options: &mut Options) -> bool {
+    // The preprocessed VGM file is played with all of its chips
+    if options.output_format == OutputFormat::Vgm {
+        return false;
+    }
+    !std::mem::replace(&mut options.psg_only, true)
+}
+
+/// Time spent in one stage of the conversion
+#[derive(Clone, Debug)]
+pub struct StageTiming {
+    pub stage: &'static str,
+    pub elapsed: Duration,
+    pub bytes: usize,           // Number of bytes processed by the stage
+}
+
+impl StageTiming {
+    /// Return the throughput in bytes per second
+    pub fn throughput(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 { self.bytes as f64 / secs } else { 0.0 }
+    }
+}
+
+/// What a conversion warning is about
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WarningKind {
+    TruncatedInput,             // The file or its command stream ends early
+    LoopPoint,                  // The loop offset is moved or ignored
+    Timing,                     // The song doesn't play for as long as it should
+    DroppedCommands,            // Commands that are skipped or removed
+    StrippedChip,               // Writes to a chip that the player doesn't support
+    QuantizedWaits,             // Long waits replaced with LUT entries of a different length
+    PlayerLoad,                 // Frames that may be too busy for the player
+    TruncatedTag,               // Tag fields that don't fit in the ID666 tag
+    InvalidTag,                 // A GD3 tag that can't be read
+    TooLarge,                   // Packed data that doesn't fit, even with -auto-fit
+    ClockMismatch,              // Joined songs with different PSG clocks
+    VolumeClipped,              // Volume writes that are limited to full volume by the volume modifier
+}
+
+impl WarningKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            WarningKind::TruncatedInput => "truncated input",
+            WarningKind::LoopPoint => "loop point",
+            WarningKind::Timing => "timing",
+            WarningKind::DroppedCommands => "dropped commands",
+            WarningKind::StrippedChip => "stripped chip",
+            WarningKind::QuantizedWaits => "quantized waits",
+            WarningKind::PlayerLoad => "player load",
+            WarningKind::TruncatedTag => "truncated tag",
+            WarningKind::InvalidTag => "invalid tag",
+            WarningKind::TooLarge => "too large",
+            WarningKind::ClockMismatch => "clock mismatch",
+            WarningKind::VolumeClipped => "volume clipped",
+        }
+    }
+}
+
+/// A non-fatal problem found during a conversion
+#[derive(Clone, Debug, PartialEq)]
+pub struct Warning {
+    pub kind: WarningKind,
+    pub file: Option<PathBuf>,          // The input file that the warning is about, if any
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}: {} ({})", file.display(), self.message, self.kind.name()),
+            None => write!(f, "{} ({})", self.message, self.kind.name()),
+        }
+    }
+}
+
+/// The result of a successful conversion
+#[derive(Clone, Debug)]
+pub struct ConversionResult {
+    pub size: usize,                    // Size of the packed data
+    pub free_ram: Option<usize>,        // SPC RAM left after the packed data, if the output is loaded into SPC RAM
+    pub warnings: Vec<Warning>,
+}
+
+/// Check that the offsets in the header of `packed` (a packed song with the given layout) point at
+/// what they claim to: the end of the command stream, the GD3 tag, and the start of a command at
+/// the loop point. Both the whole stream and the loop must decode up to an end of sound data
+/// command. Returns a description of the first problem found.
+fn check_invariants(packed: &[u8], layout: &PackedLayout) -> Result<(), String> {
+    let u32_at = |offset: usize| packed.get(offset..offset + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize).unwrap_or(0);
+    if packed.len() != layout.length {
+        return Err(format!("the packed data is {} bytes, but the layout says {}", packed.len(), layout.length));
+    }
+    if !packed.starts_with(specification::VGM_MAGIC.as_bytes()) {
+        return Err(String::from("the packed data doesn't start with the VGM magic"));
+    }
+
+    let stream_end = u32_at(0x04) + 4;
+    if stream_end != layout.stream_end {
+        return Err(format!("the EOF offset points at 0x{:X}, but the command stream ends at 0x{:X}", stream_end, layout.stream_end));
+    }
+    if stream_end > packed.len() || layout.stream_offset > stream_end {
+        return Err(format!("the command stream (0x{:X}-0x{:X}) is outside of the packed data", layout.stream_offset, stream_end));
+    }
+
+    match u32_at(0x14) {
+        0 if stream_end != layout.length => return Err(String::from("there's data after the command stream, but no GD3 offset")),
+        0 => {}
+        offset => {
+            let gd3_start = offset + 0x14;
+            if gd3_start != stream_end {
+                return Err(format!("the GD3 offset points at 0x{:X}, not at the end of the command stream", gd3_start));
+            }
+            Gd3::parse(&packed[gd3_start..]).map_err(|e| format!("the GD3 offset doesn't point at a valid GD3 tag: {}", e))?;
+        }
+    }
+
+    let lut = match layout.lut_offset {
+        Some(offset) => {
+            let block = offset.checked_sub(7).and_then(|start| packed.get(start..offset)).unwrap_or_default();
+            if block.len() != 7 || block[0] != specification::Command::DATA_BLOCK
+                || (block[2] != psgcodec::LONG_WAIT_LUT_BLOCK_TYPE && block[2] != psgcodec::PSG_RUNS_LUT_BLOCK_TYPE) {
+                return Err(format!("the LUT offset 0x{:X} doesn't follow a LUT data block header", offset));
+            }
+            let lut = packed.get(offset..offset + psgcodec::LONG_WAIT_LUT_SIZE * 2)
+                .ok_or_else(|| format!("the LUT at 0x{:X} is outside of the packed data", offset))?;
+            let lut = lut.chunks_exact(2).map(|w| u16::from_le_bytes([w[0], w[1]])).collect::<Vec<u16>>();
+            Some((lut, block[2] == psgcodec::PSG_RUNS_LUT_BLOCK_TYPE))
+        }
+        None => None,
+    };
+
+    let decode = |start: usize| match &lut {
+        Some((lut, runs)) => psgcodec::decode(&packed[start..stream_end], lut, *runs),
+        None => nullcodec::decode(&packed[start..stream_end]),
+    };
+    decode(layout.stream_offset).map_err(|e| format!("the command stream can't be decoded: {}", e))?;
+
+    let loop_offset = match u32_at(0x1C) {
+        0 => None,
+        offset => Some(offset + 0x1C),
+    };
+    if loop_offset != layout.loop_offset {
+        return Err(format!("the loop offset in the header is {:X?}, but the layout says {:X?}", loop_offset, layout.loop_offset));
+    }
+    if let Some(offset) = loop_offset {
+        if offset < layout.stream_offset || offset >= stream_end {
+            return Err(format!("the loop offset 0x{:X} is outside of the command stream", offset));
+        }
+        decode(offset).map_err(|e| format!("the commands at the loop offset 0x{:X} can't be decoded: {}", offset, e))?;
+    }
+    Ok(())
+}
+
+/// Return the path of the temporary file that the output is written to before it's renamed to
+/// `output_path`. It's in the same directory, so that the rename doesn't cross file systems, and
+/// has the same extension, which decides how some formats are written.
+fn temp_output_path(output_path: &Path) -> PathBuf {
+    // Numbered, so that converters running in parallel threads never share a temporary file
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let name = output_path.file_name().unwrap_or_default().to_string_lossy();
+    let number = COUNTER.fetch_add(1, Ordering::Relaxed);
+    output_path.with_file_name(format!(".vgm2spc-{}-{}-{}", std::process::id(), number, name))
+}
+
+/// Make sure that `output_path` isn't one of the input files, which would be overwritten. Paths
+/// are compared after resolving links and relative components; an output file that doesn't exist
+/// yet can't be an input.
+fn check_distinct_paths(input_paths: &[&Path], output_path: &Path) -> Result<(), Error> {
+    let output = match output_path.canonicalize() {
+        Ok(path) => path,
+        Err(_) => return Ok(()),
+    };
+    for input_path in input_paths {
+        if input_path.canonicalize().is_ok_and(|input| input == output) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("The output file {} is also an input file", output_path.display())));
+        }
+    }
+    Ok(())
+}
+
+/// Return the codec to use for a VGM file with the given header. The PSG codec is only used when
+/// the PSG is the only chip, since it can't represent writes to other chips.
+fn select_codec(header: &specification::FileHeader, psg_only: bool, observer: &dyn Observer) -> Codec {
+    let chips = header.used_chips();
+    if !chips.contains(&"SN76489") {
+        observer.on_message("Codec: none (the file doesn't use the SN76489)");
+        Codec::Null
+    } else if chips.len() == 1 {
+        observer.on_message("Codec: PSG");
+        Codec::Psg
+    } else if psg_only {
+        observer.on_message("Codec: PSG (writes to other chips are removed)");
+        Codec::Psg
+    } else {
+        observer.on_message(&format!("Codec: none (the file also uses {})", chips[1..].join(", ")));
+        Codec::Null
+    }
+}
+
+/// Point out `-repair` for a truncated file that can't be edited as it is. Without the edits, the
+/// file would be converted as far as it goes.
+fn suggest_repair(error: Error) -> Error {
+    match error.kind() {
+        ErrorKind::UnexpectedEof => Error::new(ErrorKind::UnexpectedEof, format!("{}. Use -repair to end the song after the last complete command", error)),
+        _ => error,
+    }
+}
+
+/// Storage that is kept between conversions, so that converting a batch of files doesn't allocate
+/// it again for each file
+#[derive(Default)]
+struct Buffers {
+    song: EventList,
+    output: Vec<u8>,
+}
+
+/// Converts VGM files. A converter keeps no state outside of itself: the player is loaded from
+/// the path in its options, and all results of a conversion are stored in the converter, so
+/// converters on different threads can work in parallel.
 pub struct Converter {
-    loop_offset: usize,
-    codec_used: ConverterFlags,
-    song_title: String,
-    game_title: String,
-    artist: String,
+    timings: Vec<StageTiming>,
+    warnings: Vec<Warning>,
+    input_path: Option<PathBuf>,        // The input file being packed, for attributing warnings
+    source_crc: crc32fast::Hasher,
+    layout: Option<PackedLayout>,
+    section_sizes: Option<SectionSizes>,
+    size_breakdown: Option<SizeBreakdown>,
+    cpu_load: Option<CpuLoad>,
+    packed: Vec<u8>,
+    codec_used: Codec,
+    codecs_used: Vec<Codec>,            // Codecs used for all songs in the last conversion
+    gd3: Gd3,
+    header: specification::FileHeader,
+    song_samples: (u32, Option<u32>),   // Total and loop length of the last packed song
+    preprocessed: Option<Vec<u8>>,      // The last packed song as a VGM file, after preprocessing
+    observer: Box<dyn Observer>,
+    options: Options,
+    buffers: Buffers,
 }
 
 impl Converter {
-    pub fn new() -> Self {
+    pub fn builder() -> ConverterBuilder {
+        ConverterBuilder::new()
+    }
+
+    /// Create a converter with the given settings. Use `builder` to get validated settings.
+    pub fn with_options(options: Options) -> Self {
         Converter {
-            loop_offset: 0,
-            codec_used: ConverterFlags::NULL_CODEC,
-            song_title: String::from(""),
-            game_title: String::from(""),
-            artist: String::from(""),
+            timings: Vec::new(),
+            warnings: Vec::new(),
+            input_path: None,
+            source_crc: crc32fast::Hasher::new(),
+            layout: None,
+            section_sizes: None,
+            size_breakdown: None,
+            cpu_load: None,
+            packed: Vec::new(),
+            codec_used: Codec::Null,
+            codecs_used: Vec::new(),
+            gd3: Gd3::new(),
+            header: Default::default(),
+            song_samples: (0, None),
+            preprocessed: None,
+            observer: Box::new(SilentObserver),
+            options,
+            buffers: Buffers::default(),
         }
     }
-    
-    pub fn convert(&mut self, input_path: &Path, output_path: &Path, flags: ConverterFlags) -> Result<usize, std::io::Error> {
-        self.codec_used = flags & (ConverterFlags::NULL_CODEC | ConverterFlags::PSG_CODEC);
-        
-        let mut input_data = Vec::new();
-        reader::read_vgm_file(input_path, &mut input_data, flags.contains(ConverterFlags::ASSUME_VGZ))?;
-        if input_data.len() < 32 {
-            Error::new(ErrorKind::UnexpectedEof, "The file did not contain sufficient data");
+
+    /// Send the progress reports to `observer`. They are ignored by default
+    pub fn with_observer(mut self, observer: Box<dyn Observer>) -> Self {
+        self.observer = observer;
+        self
+    }
+
+    /// Return the GD3 tag read during the last conversion
+    pub fn gd3(&self) -> &Gd3 {
+        &self.gd3
+    }
+
+    /// Return the VGM header read during the last conversion (of the first song, for multi-song
+    /// conversions)
+    pub fn header(&self) -> &specification::FileHeader {
+        &self.header
+    }
+
+    /// Return the command stream of the last packed song (the first song, for multi-song
+    /// conversions) as a VGM file, as it was after preprocessing. This is only available if the
+    /// converter was built with `keep_preprocessed`.
+    pub fn preprocessed_vgm(&self) -> Option<&[u8]> {
+        self.preprocessed.as_deref()
+    }
+
+    /// Return the packed data produced by the last conversion, i.e. what gets placed after the
+    /// player in the SPC (or the whole output in raw mode)
+    pub fn packed_data(&self) -> &[u8] {
+        &self.packed
+    }
+
+    /// Return the layout of the packed data produced by the last conversion. This is `None` for
+    /// multi-song conversions, since each song has its own layout.
+    pub fn layout(&self) -> Option<&PackedLayout> {
+        self.layout.as_ref()
+    }
+
+    /// Return the sizes of the sections of the packed data produced by the last conversion. Like
+    /// `layout`, this is `None` for multi-song conversions.
+    pub fn section_sizes(&self) -> Option<&SectionSizes> {
+        self.section_sizes.as_ref()
+    }
+
+    /// Return the biggest contributors to the size of the command stream packed by the last
+    /// conversion. Like `layout`, this is `None` for multi-song conversions.
+    pub fn size_breakdown(&self) -> Option<&SizeBreakdown> {
+        self.size_breakdown.as_ref()
+    }
+
+    /// Return the estimated CPU load of the player for the song packed by the last conversion.
+    /// Like `layout`, this is `None` for multi-song conversions.
+    pub fn cpu_load(&self) -> Option<&CpuLoad> {
+        self.cpu_load.as_ref()
+    }
+
+    /// Return the time spent in each stage of the last conversion
+    pub fn timings(&self) -> &[StageTiming] {
+        &self.timings
+    }
+
+    /// Return the CRC32 of the decompressed VGM data read during the last conversion (of all
+    /// input files, in order, for multi-song conversions)
+    pub fn source_crc(&self) -> u32 {
+        self.source_crc.clone().finalize()
+    }
+
+    /// Convert the VGM file at `input_path`, and write the output to `output_path`
+    pub fn convert(&mut self, input_path: &Path, output_path: &Path) -> Result<ConversionResult, std::io::Error> {
+        check_distinct_paths(&[input_path], output_path)?;
+        self.timings.clear();
+        self.packed = self.pack_to_fit(|converter| converter.pack(input_path))?;
+        self.timed_write_output(output_path)
+    }
+
+    /// Find out what converting the VGM file at `input_path` would give, without writing anything:
+    /// the header and GD3 tag, the chips used, and the packed size with each codec (using the
+    /// other settings of this converter), along with whether it fits.
+    pub fn analyze(&self, input_path: &Path) -> Result<Analysis, std::io::Error> {
+        let player = self.load_player().ok();
+        let capacity = player.as_ref().map(|player| self.capacity(player));
+        let mut analysis = None;
+        let mut codecs = Vec::new();
+        for codec in Codec::ALL.iter().filter(|&&codec| codec != Codec::Auto) {
+            let mut options = self.options.clone();
+            options.codec = *codec;
+            options.native_dsp = false;
+            options.output_format = OutputFormat::Raw;
+            let mut converter = Converter::with_options(options);
+            let size = match converter.pack_file(input_path) {
+                Ok(packed) => Some(packed.len()),
+                Err(e) if e.kind() == ErrorKind::NotFound => return Err(e),
+                Err(_) => None,
+            };
+            if size.is_some() && analysis.is_none() {
+                let header = converter.header().clone();
+                analysis = Some(Analysis { chips: header.used_chips(), header, gd3: converter.gd3().clone(), capacity, codecs: Vec::new() });
+            }
+            codecs.push(CodecEstimate {
+                codec: codec.name(),
+                size,
+                fits: size.zip(capacity).is_some_and(|(size, capacity)| size <= capacity),
+                supported_by_player: player.as_ref().is_some_and(|player| player.supports_codec(codec.name())),
+            });
+        }
+        let mut analysis = analysis.ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("{} couldn't be packed with any codec", input_path.display())))?;
+        analysis.codecs = codecs;
+        Ok(analysis)
+    }
+
+    /// Pack the VGM file at `input_path` without writing any output, e.g. for measuring codecs.
+    /// The packed data is not checked against the capacity of the player.
+    pub fn pack_file(&mut self, input_path: &Path) -> Result<&[u8], std::io::Error> {
+        self.timings.clear();
+        self.packed = self.pack_to_fit(|converter| converter.pack(input_path))?;
+        Ok(&self.packed)
+    }
+
+    /// Pack several songs into a single output, preceded by a song index table (see `player::song_index_table`).
+    /// The ID666 tag is based on the GD3 tag of the first song.
+    pub fn convert_multi(&mut self, input_paths: &[&Path], output_path: &Path) -> Result<ConversionResult, std::io::Error> {
+        check_distinct_paths(input_paths, output_path)?;
+        if self.options.output_format == OutputFormat::Vgm {
+            return Err(Error::new(ErrorKind::InvalidInput, "Several songs can't be packed into a VGM file. Use -join to play them one after another"));
+        }
+        if self.native_dsp() {
+            return Err(Error::new(ErrorKind::InvalidInput, "The native S-DSP mode plays a single song. Use -join to play several songs one after another"));
+        }
+        if input_paths.len() > player::MAX_SONGS {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("{} songs were given, but at most {} can be packed into one SPC file", input_paths.len(), player::MAX_SONGS)));
+        }
+        self.timings.clear();
+        self.packed = self.pack_to_fit(|converter| converter.pack_multi(input_paths))?;
+        self.observer.on_message(&format!("Packed {} songs, total size: {} bytes", input_paths.len(), self.packed.len()));
+        self.timed_write_output(output_path)
+    }
+
+    fn pack_multi(&mut self, input_paths: &[&Path]) -> Result<Vec<u8>, std::io::Error> {
+        let mut songs = Vec::new();
+        let mut first_song = None;
+        for input_path in input_paths {
+            songs.push(self.pack(input_path)?);
+            if first_song.is_none() {
+                first_song = Some((self.gd3.clone(), self.header.clone(), self.song_samples, self.preprocessed.clone()));
+            }
+        }
+        if let Some((gd3, header, samples, preprocessed)) = first_song {
+            self.gd3 = gd3;
+            self.header = header;
+            self.song_samples = samples;
+            self.preprocessed = preprocessed;
+        }
+        self.layout = None;
+        self.section_sizes = None;
+        self.size_breakdown = None;
+        self.cpu_load = None;
+
+        let mut packed = player::song_index_table(&songs)?;
+        for song in songs {
+            packed.extend(song);
+        }
+        Ok(packed)
+    }
+
+    /// Join several songs into one (see `vgm::edit::join`) and convert the result. The ID666 tag is
+    /// based on the GD3 tag of the first song.
+    pub fn convert_joined(&mut self, input_paths: &[&Path], output_path: &Path) -> Result<ConversionResult, std::io::Error> {
+        check_distinct_paths(input_paths, output_path)?;
+        self.timings.clear();
+        self.packed = self.pack_to_fit(|converter| {
+            let mut files = Vec::new();
+            for input_path in input_paths {
+                files.push(converter.read_input(input_path)?);
+            }
+            let clocks = files.iter().map(|file| specification::FileHeader::parse(file).map(|header| header.psg_clock)).collect::<Result<Vec<_>, _>>()?;
+            for (input_path, &clock) in input_paths.iter().zip(&clocks).skip(1).filter(|(_, &clock)| clock != clocks[0]) {
+                converter.add_warning(Warning {
+                    kind: WarningKind::ClockMismatch,
+                    file: Some(input_path.to_path_buf()),
+                    message: format!("the song has a different PSG clock than the first song ({} vs {} Hz)", clock, clocks[0]),
+                });
+            }
+            let joined = edit::join(&files, converter.options.loop_last)?;
+            converter.observer.on_message(&format!("Joined {} songs", input_paths.len()));
+            converter.pack_data(input_paths[0], joined)
+        })?;
+        self.timed_write_output(output_path)
+    }
+
+    /// Run `pack` to get the packed data. With `-auto-fit`, the packing is retried with the steps
+    /// in `FIT_STEPS` while the data is too large to fit in SPC RAM. The options are restored
+    /// afterwards.
+    fn pack_to_fit<F>(&mut self, mut pack: F) -> Result<Vec<u8>, std::io::Error>
+        where F: FnMut(&mut Self) -> Result<Vec<u8>, std::io::Error> {
+        self.source_crc = crc32fast::Hasher::new();
+        self.codecs_used.clear();
+        self.warnings.clear();
+        let mut packed = pack(self)?;
+        if !self.options.auto_fit {
+            return Ok(packed);
+        }
+
+        let limit = self.packed_size_limit()?;
+        let original_options = self.options.clone();
+        let mut applied = Vec::new();
+        for step in FIT_STEPS.iter() {
+            if packed.len() <= limit {
+                break;
+            }
+            if !(step.apply)(&mut self.options) {
+                continue;
+            }
+            self.observer.on_message(&format!("The packed data is {} bytes too large. Retrying with {}", packed.len() - limit, step.description));
+            applied.push(step.description);
+            self.source_crc = crc32fast::Hasher::new();
+            self.codecs_used.clear();
+            self.warnings.clear();
+            packed = pack(self)?;
+        }
+        self.options = original_options;
+
+        if packed.len() > limit {
+            self.warn(WarningKind::TooLarge, format!("the packed data is still {} bytes too large, with {}", packed.len() - limit, applied.join(", ")));
+        } else if !applied.is_empty() {
+            self.observer.on_message(&format!("The packed data fits with {}", applied.join(", ")));
+        }
+        Ok(packed)
+    }
+
+    /// Return the maximum size of the packed data for the output format
+    fn packed_size_limit(&self) -> Result<usize, std::io::Error> {
+        if !self.includes_player() {
+            return Ok(RAM_LIMIT);
         }
-        let vgm_header: specification::FileHeader = unsafe { std::ptr::read(input_data.as_ptr() as *const _) };
-        let data_offset = if vgm_header.version >= 0x00000150 {
-            std::cmp::max(vgm_header.vgm_data_offset, 0x40)
+        Ok(self.capacity(&self.load_player()?))
+    }
+
+    /// Returns true if the PSG writes are translated to S-DSP writes, i.e. in native S-DSP mode and
+    /// for S-DSP register logs
+    fn native_dsp(&self) -> bool {
+        self.options.native_dsp || self.options.output_format == OutputFormat::DspLog
+    }
+
+    /// Return the player for the output format (the built-in driver in native S-DSP mode), or an
+    /// empty one for formats without the player
+    pub fn load_player(&self) -> Result<Player, std::io::Error> {
+        if self.options.native_dsp {
+            Ok(dsp::driver())
+        } else if self.includes_player() {
+            match &self.options.player_binary {
+                Some(path) => Player::load(path),
+                None => Player::load_default(),
+            }
         } else {
-            0x40
-        };
-        
+            Ok(Player::default())
+        }
+    }
+
+    /// Return the number of bytes available for the packed data when it's placed after `player`
+    /// in SPC RAM, i.e. everything up to the IPL ROM area (or the first RAM region reserved by the
+    /// player's manifest) that the player doesn't use. For output formats without the player, the
+    /// player is ignored.
+    pub fn capacity(&self, player: &Player) -> usize {
+        if !self.includes_player() {
+            return RAM_LIMIT;
+        }
+        match self.options.echo {
+            Some(edl) => {
+                let echo_start = player::echo_region(edl).start as usize;
+                std::cmp::min(player.data_capacity(), echo_start.saturating_sub(player.data_address()))
+            }
+            None => player.data_capacity(),
+        }
+    }
+
+    fn includes_player(&self) -> bool {
+        matches!(self.options.output_format, OutputFormat::Spc | OutputFormat::Sfc)
+    }
+
+    /// Write the output to a temporary file next to `output_path`, and rename it into place once
+    /// it's complete, so that a failed or interrupted conversion never leaves a partial file behind
+    fn timed_write_output(&mut self, output_path: &Path) -> Result<ConversionResult, std::io::Error> {
+        if self.options.output_format == OutputFormat::Spc {
+            for (name, size, text) in self.id666_tag().cut_fields() {
+                self.warn(WarningKind::TruncatedTag, format!("the ID666 {} is cut to {} bytes: \"{}\"", name, size, text));
+            }
+        }
+        let start = self.start_stage("writing");
+        let temp_path = temp_output_path(output_path);
+        let result = self.write_output(&temp_path, &self.packed).and_then(|result| fs::rename(&temp_path, output_path).map(|_| result));
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        self.add_timing("writing", start, self.packed.len());
+        result.map(|free_ram| ConversionResult { size: self.packed.len(), free_ram, warnings: self.warnings.clone() })
+    }
+
+    /// Tell the observer that `stage` starts, and return the start time for `add_timing`
+    fn start_stage(&self, stage: &'static str) -> Instant {
+        self.observer.on_stage_start(stage);
+        Instant::now()
+    }
+
+    fn add_timing(&mut self, stage: &'static str, start: Instant, bytes: usize) {
+        self.timings.push(StageTiming { stage, elapsed: start.elapsed(), bytes });
+    }
+
+    /// Add a warning about the input file being packed
+    fn warn(&mut self, kind: WarningKind, message: String) {
+        self.add_warning(Warning { kind, file: self.input_path.clone(), message });
+    }
+
+    fn add_warning(&mut self, warning: Warning) {
+        self.observer.on_warning(&warning);
+        self.warnings.push(warning);
+    }
+
+    /// Read and compress the VGM file given by `input_path`, returning the packed VGM data
+    /// (header, extradata, command stream and GD3 tag).
+    fn pack(&mut self, input_path: &Path) -> Result<Vec<u8>, std::io::Error> {
+        let input_data = self.read_input(input_path)?;
+        self.pack_data(input_path, input_data)
+    }
+
+    /// Read and decompress the VGM file given by `input_path`
+    fn read_input(&mut self, input_path: &Path) -> Result<Vec<u8>, std::io::Error> {
+        let start = self.start_stage("decompression");
+        let mut input_data = Vec::new();
+        let sizes = read_vgm_file(input_path, &mut input_data, self.options.assume_vgz)?;
+        if sizes.decompressed {
+            self.observer.on_message(&format!("Deflating.. done ({} -> {} bytes).", sizes.file_size, sizes.data_size));
+        }
+        self.add_timing("decompression", start, input_data.len());
+        self.source_crc.update(&input_data);
+        Ok(input_data)
+    }
+
+    /// Compress the VGM data `input_data`, which was read from `input_path`. Warnings raised
+    /// meanwhile are attributed to `input_path`.
+    fn pack_data(&mut self, input_path: &Path, input_data: Vec<u8>) -> Result<Vec<u8>, std::io::Error> {
+        self.input_path = Some(input_path.to_path_buf());
+        let result = self.pack_input(input_path, input_data);
+        self.input_path = None;
+        result
+    }
+
+    fn pack_input(&mut self, input_path: &Path, mut input_data: Vec<u8>) -> Result<Vec<u8>, std::io::Error> {
+        if self.options.repair {
+            if let Some(repaired) = edit::repair_truncated(&input_data)? {
+                let header = specification::FileHeader::parse(&repaired)?;
+                self.warn(WarningKind::TruncatedInput, format!("the file is truncated. The song was ended after {} samples{}", header.total_samples,
+                                                               if header.loop_offset == 0 { ", without a loop" } else { "" }));
+                input_data = repaired;
+            }
+        }
+        specification::FileHeader::parse(&input_data)?.validate(&input_data)?;
+        if let Some((old, new)) = edit::align_loop_offset(&mut input_data)? {
+            self.warn(WarningKind::LoopPoint, format!("the loop offset (0x{:X}) points into the middle of a command. Moved it back to 0x{:X}", old, new));
+        }
+        if self.options.trim_silence {
+            let (trimmed, leading, trailing) = edit::trim_silence(&input_data).map_err(suggest_repair)?;
+            if leading + trailing > 0 {
+                self.observer.on_message(&format!("Trimmed {:.2} s of leading and {:.2} s of trailing silence", leading as f64 / 44100.0, trailing as f64 / 44100.0));
+            }
+            input_data = trimmed;
+        }
+        if self.options.reset_chips {
+            input_data = edit::inject_reset(&input_data).map_err(suggest_repair)?;
+        }
+        let vgm_header = specification::FileHeader::parse(&input_data)?;
+        let data_offset = vgm_header.data_offset();
+
         let mut input_stream = ByteStream::new(input_data);
         let input_size = input_stream.len();
                 
-        println!("Converting {}", input_path.file_name().unwrap().to_str().unwrap());
+        self.observer.on_message(&format!("Converting {}", input_path.file_name().unwrap_or_default().to_string_lossy()));
+
+        self.codec_used = match self.options.codec {
+            Codec::Auto => select_codec(&vgm_header, self.options.psg_only, self.observer.as_ref()),
+            codec => codec,
+        };
+        if !self.native_dsp() && !self.codecs_used.contains(&self.codec_used) {
+            self.codecs_used.push(self.codec_used);
+        }
         
         let extradata_offset = data_offset;
         let mut extradata_block: Vec<u8> = Vec::new();
 
-        self.loop_offset = (vgm_header.loop_offset + 0x1C) as usize;
-              
-        input_stream = self.preprocess(&mut input_stream, data_offset as usize, &vgm_header);
-        let mut output_stream = ByteStream::new(input_stream.read_n(data_offset as usize));
+        let mut output = std::mem::take(&mut self.buffers.output);
+        output.clear();
+        output.reserve(input_size);
+        output.extend_from_slice(&input_stream.as_slice()[..data_offset]);
+        input_stream.skip(data_offset);
+        let mut output_stream = ByteStream::new(output);
         output_stream.replace_at(8, 0x52);    // To identify the VGM as compressed
 
-        let mut new_loop_offset = self.loop_offset;
+        let start = self.start_stage("preprocessing");
+        let mut song = self.read_events(&mut input_stream, &vgm_header)?;
+        let mut expected_samples = song.samples();
+        if expected_samples != vgm_header.total_samples as u64 {
+            self.warn(WarningKind::Timing, format!("the commands add up to {} samples, but the VGM header says {}", expected_samples, vgm_header.total_samples));
+        }
+        self.preprocess(&mut song, &vgm_header)?;
+        if vgm_header.volume_modifier != 0 {
+            // The SN76489 attenuates in 2 dB steps
+            let gain = vgm_header.volume_gain_db();
+            let (changed, clipped) = song.adjust_psg_attenuation(-(gain / 2.0).round() as i32);
+            self.observer.on_message(&format!("Applied the volume modifier ({:+.1} dB) to {} PSG volume writes", gain, changed));
+            if clipped > 0 {
+                self.warn(WarningKind::VolumeClipped, format!("{} PSG volume writes are louder than full volume after applying the volume modifier", clipped));
+            }
+            // The gain is now part of the volume writes, so it must not be applied again
+            output_stream.replace_at(0x7C, 0);
+        }
+        let mut loop_samples = Some(vgm_header.loop_samples);
+        if song.loop_index.is_none() {
+            if self.options.loop_all {
+                self.observer.on_message("The song doesn't loop. Looping the whole song");
+                song.loop_index = Some(0);
+                loop_samples = Some(vgm_header.total_samples);
+            } else {
+                self.observer.on_message("The song doesn't loop. Playback stops at the end");
+                song.end_in_silence();
+                expected_samples += passes::SILENT_ENDING_SAMPLES as u64;
+                loop_samples = None;
+            }
+        }
+        let looping = loop_samples.is_some();
+        self.song_samples = (vgm_header.total_samples, loop_samples);
+        self.header = vgm_header.clone();
+        if self.options.keep_preprocessed || self.options.output_format == OutputFormat::Vgm {
+            let input_data = input_stream.as_slice();
+            let gd3 = edit::read_gd3(input_data, &vgm_header);
+            let mut header = input_data[..data_offset].to_vec();
+            if vgm_header.volume_modifier != 0 {
+                header[0x7C] = 0;
+            }
+            self.preprocessed = Some(writer::write_vgm(&header, &song.events, song.loop_index, gd3.as_ref()));
+        }
+        self.add_timing("preprocessing", start, input_size);
+        if self.native_dsp() {
+            let packed = self.pack_dsp(&song, &vgm_header, input_stream.as_slice());
+            self.buffers.song = song;
+            return Ok(packed);
+        }
+
+        let start = self.start_stage("encoding");
+        let mut long_waits = Vec::new();
+        if self.codec_used.is_psg() {
+            long_waits = psgcodec::select_long_waits(&song.events);
+            if self.options.wait_tolerance > 0 {
+                let (intro, looped) = song.section_samples();
+                let (replaced, change) = psgcodec::quantize_long_waits(&mut song.events, &long_waits, self.options.wait_tolerance);
+                if replaced > 0 {
+                    self.warn(WarningKind::QuantizedWaits, format!("replaced {} long waits with LUT entries (the song is now {} samples {})", replaced, change.abs(),
+                                                                   if change < 0 { "shorter" } else { "longer" }));
+                    // The lengths for the ID666 tag change by as much as each part of the song did
+                    let (new_intro, new_looped) = song.section_samples();
+                    let adjust = |samples: u32, change: i64| (samples as i64 + change).clamp(0, u32::MAX as i64) as u32;
+                    let (intro_change, loop_change) = (new_intro as i64 - intro as i64, new_looped as i64 - looped as i64);
+                    self.song_samples = match self.song_samples {
+                        (total, Some(loop_samples)) => (adjust(total, intro_change + loop_change), Some(adjust(loop_samples, loop_change))),
+                        // The loop point of a song without loop is at its silent ending
+                        (total, None) => (adjust(total, intro_change), None),
+                    };
+                }
+                expected_samples = (expected_samples as i64 + change) as u64;
+            }
+        }
+        let mut new_loop_offset = None;
+        let mut lut_offset = None;
+        let mut lut = Vec::new();
+        let mut data_block_bytes = [0usize; 2];     // Before and after the loop point
+        let mut seek_points = Vec::new();
 
         {
             // Now do the encoding stage
-            let mut codec: Box<dyn Codec> = match self.codec_used {
-                ConverterFlags::PSG_CODEC => Box::new(PsgCodec::new(&mut output_stream)),
+            let mut codec: Box<dyn codec::Codec> = match self.codec_used {
+                Codec::Psg | Codec::PsgRuns => {
+                    let mut psg_codec = PsgCodec::new(&mut output_stream);
+                    psg_codec.preload_long_waits(&long_waits);
+                    if self.codec_used == Codec::PsgRuns {
+                        psg_codec.enable_psg_runs();
+                    }
+                    Box::new(psg_codec)
+                }
                 _ => Box::new(NullCodec::new(&mut output_stream)),
             };
 
-            let mut eod = false;
-            while !eod {
-                if input_stream.get_pos() == vgm_header.loop_offset as usize {
+            let seek_interval = self.options.seek_interval.map(|seconds| seconds as u64 * 44100);
+            let mut psg = PsgState::default();
+            let mut gg_stereo = 0xFF;
+            let mut time = 0u64;
+            for (i, event) in song.events.iter().enumerate() {
+                if song.loop_index == Some(i) {
                     codec.flush();
-                    new_loop_offset = codec.output_len();
+                    new_loop_offset = Some(codec.output_len());
                 }
-
-                let c = input_stream.read();
-                codec.write(c);
-
-                match c {
-                    Command::GG_STEREO | Command::PSG_WRITE | 0x30 => {
-                        codec.write(input_stream.read());
-                    }
-
-                    Command::YM2413_WRITE ... Command::YM2151_WRITE => {
-                        codec.write(input_stream.read());
-                        codec.write(input_stream.read());
-                    }
-
-                    Command:: WAIT_LONG => {
-                        codec.write(input_stream.read());
-                        codec.write(input_stream.read());
-                    }
-
-                    Command::END_OF_SOUND_DATA => {
+                if let Some(interval) = seek_interval {
+                    // A long wait can span several entries, which then all point at the next command
+                    while seek_points.len() as u64 * interval <= time {
                         codec.flush();
-                        eod = true;
+                        let offset = (codec.output_len() - data_offset) as u32;
+                        seek_points.push(SeekPoint { time: time as u32, offset, psg: psg.clone(), gg_stereo });
                     }
-
-                    Command::DATA_BLOCK => {
-                        if input_stream.peek() == 0x66 {
-                            let data_block_size = input_stream.peek_u32_at(2);
-                            for _ in 0..data_block_size+6 {
-                                codec.write(input_stream.read());
-                            }
-                        } else {
-                            panic!("Illegal command: 0x67 0x{:X} at offset 0x{:X}", input_stream.peek(), input_stream.get_pos());
-                        }
+                    match event {
+                        VgmEvent::PsgWrite(value) => psg.write(*value),
+                        VgmEvent::GgStereo(value) => gg_stereo = *value,
+                        _ => {}
                     }
-
-                    Command::SEEK_PCM => {
-                        for _ in 0..4 { codec.write(input_stream.read()); }
-                    }
-                    _ => {}
+                    time += event.samples() as u64;
+                }
+                if let VgmEvent::DataBlock { data, .. } = event {
+                    let looped = song.loop_index.is_some_and(|loop_index| i >= loop_index);
+                    data_block_bytes[looped as usize] += data.len() + 7;
                 }
+                codec.write_event(event);
             }
+            codec.flush();
 
-            let long_wait_lut = codec.get_extra_data(psgcodec::GET_LONG_WAIT_LUT);
-            if long_wait_lut.is_some() { extradata_block = long_wait_lut.unwrap(); }
+            if let Some(long_wait_lut) = codec.get_extra_data(psgcodec::GET_LONG_WAIT_LUT) {
+                lut = long_wait_lut[7..].chunks_exact(2).map(|w| u16::from_le_bytes([w[0], w[1]])).collect();
+                extradata_block = long_wait_lut;
+                lut_offset = Some(extradata_offset + 7);
+            }
+        }
+        self.buffers.song = song;
+        if let Some(seconds) = self.options.seek_interval {
+            extradata_block.extend(player::seek_table_block(seconds, &seek_points));
+        }
+        self.add_timing("encoding", start, input_stream.len());
+
+        let start = self.start_stage("timing check");
+        let stream = &output_stream.as_slice()[data_offset..];
+        let decoded = self.check_timing(stream, &lut, expected_samples);
+        self.size_breakdown = decoded.as_ref().map(|events| SizeBreakdown::new(stream, events));
+        self.cpu_load = decoded.as_ref().map(|events| CpuLoad::new(events));
+        if let Some(load) = self.cpu_load.clone().filter(|load| load.frames_over_budget > 0) {
+            self.warn(WarningKind::PlayerLoad, format!("{} frames may be too busy for the player (roughly estimated worst: {} cycles at {:.2} s, budget {})",
+                                                       load.frames_over_budget, load.worst, load.worst_time as f64 / 44100.0, analysis::FRAME_CYCLE_BUDGET));
+        }
+        let busy = decoded.as_ref().map(|events| analysis::busy_frames(events, self.options.max_frame_writes)).unwrap_or_default();
+        if !busy.is_empty() {
+            let mut frames: Vec<String> = busy.iter().take(10).map(|(time, writes)| format!("{:.2} s: {} writes", *time as f64 / 44100.0, writes)).collect();
+            if busy.len() > 10 {
+                frames.push(format!("...and {} more", busy.len() - 10));
+            }
+            self.warn(WarningKind::PlayerLoad, format!("{} frames have more than {} PSG writes ({})", busy.len(), self.options.max_frame_writes, frames.join(", ")));
         }
+        self.add_timing("timing check", start, output_stream.len() - data_offset);
+        let mut config = PlayerConfig::from_header(&vgm_header);
+        config.stop_at_end = !looping;
+        config.seek_table = self.options.seek_interval.is_some();
+        extradata_block.extend(config.to_data_block());
 
         let eof_offset = output_stream.len() + extradata_block.len() - 4;
         output_stream.replace_u32_at(4, eof_offset as u32);
+        let stream_end = output_stream.len() + extradata_block.len();
 
         // Read rest of data, if any (GD3)
-        if input_stream.available() > 0 {
+        if input_stream.available() > 0 && !self.options.strip_gd3 {
             output_stream.write_n(&input_stream.read_available());
         }
 
         let mut gd3_offset = vgm_header.gd3_offset as usize;
         if gd3_offset != 0 {
-            gd3_offset -= input_size - (output_stream.len() + extradata_block.len());
-            output_stream.replace_u32_at(0x14, gd3_offset as u32);
+            if self.options.strip_gd3 {
+                output_stream.replace_u32_at(0x14, 0);
+            } else {
+                gd3_offset = gd3_offset + output_stream.len() + extradata_block.len() - input_size;
+                output_stream.replace_u32_at(0x14, gd3_offset as u32);
+            }
 
-            output_stream.skip(gd3_offset + 0x14 + 0x0C - extradata_block.len());
-            let mut dummy = String::from("");
-            Self::read_gd3_string(&mut output_stream, &mut self.song_title);
-            Self::read_gd3_string(&mut output_stream, &mut dummy);  // Skip japanese title
-            Self::read_gd3_string(&mut output_stream, &mut self.game_title);
-            Self::read_gd3_string(&mut output_stream, &mut dummy);  // Skip japanese game title
-            Self::read_gd3_string(&mut output_stream, &mut dummy);  // Skip system name
-            Self::read_gd3_string(&mut output_stream, &mut dummy);  // Skip japanese system name
-            Self::read_gd3_string(&mut output_stream, &mut self.artist);
-            output_stream.reset();
+            let gd3_start = vgm_header.gd3_offset as usize + 0x14;
+            self.gd3 = match Gd3::parse(input_stream.as_slice().get(gd3_start..).unwrap_or_default()) {
+                Ok(gd3) => gd3,
+                Err(e) => {
+                    self.warn(WarningKind::InvalidTag, e.to_string());
+                    Gd3::new()
+                }
+            };
+            self.observer.on_message(&format!("Title: {}, Game: {}, Artist: {}", self.gd3.track_name, self.gd3.game_name, self.gd3.author));
+        }
 
-            println!("Title: {}, Game: {}, Artist: {}", self.song_title, self.game_title, self.artist);	
+        if let Some(offset) = new_loop_offset.as_mut() {
+            *offset += extradata_block.len();
+            output_stream.replace_u32_at(0x1C, (*offset - 0x1C) as u32);
         }
+        self.layout = Some(PackedLayout {
+            data_offset: extradata_offset,
+            stream_offset: extradata_offset + extradata_block.len(),
+            stream_end,
+            lut_offset,
+            loop_offset: new_loop_offset,
+            length: output_stream.len() + extradata_block.len(),
+        });
 
-        if self.loop_offset > 0x1C {
-            new_loop_offset += extradata_block.len();
-            new_loop_offset -= 0x1C;
-            output_stream.replace_u32_at(0x1C, new_loop_offset as u32);
+        self.observer.on_stats(&SongStats { input_size, output_size: output_stream.len() + extradata_block.len(), dsp_writes: None });
+
+        let output = output_stream.into_vec();
+        let mut packed = Vec::with_capacity(output.len() + extradata_block.len());
+        packed.extend_from_slice(&output[..extradata_offset]);
+        packed.extend_from_slice(&extradata_block);
+        packed.extend_from_slice(&output[extradata_offset..]);
+        self.buffers.output = output;
+
+        if self.options.check_invariants {
+            if let Err(e) = check_invariants(&packed, self.layout.as_ref().unwrap()) {
+                return Err(Error::new(ErrorKind::InvalidData, format!("Invariant check failed: {}", e)));
+            }
         }
 
-        println!("Input size: {} bytes, output size: {} bytes ({}%)", input_size, output_stream.len() + extradata_block.len(), 100 * (output_stream.len() + extradata_block.len()) / input_size);
+        if self.options.output_format == OutputFormat::DataOnly {
+            packed = self.strip_header(&packed);
+        }
+        self.section_sizes = self.layout.as_ref().map(|layout| SectionSizes::new(layout, data_block_bytes[0], data_block_bytes[1]));
+        Ok(packed)
+    }
 
-        let mut player = match flags.contains(ConverterFlags::RAW_OUTPUT) {
-            true => Vec::new(),
-            false => Self::read_player_binary()?,
+    /// Translate `song` into a stream for the native S-DSP driver. `input_data` is the VGM file
+    /// that the song was read from.
+    fn pack_dsp(&mut self, song: &EventList, header: &specification::FileHeader, input_data: &[u8]) -> Vec<u8> {
+        let start = self.start_stage("encoding");
+        let log = dsp::translate(&song.events, song.loop_index, header.psg_clock, self.options.echo.is_some());
+        let packed = if self.options.output_format == OutputFormat::DspLog {
+            dsp::write_log(&log)
+        } else {
+            dsp::encode(&log, dsp::driver().data_address() as u16)
         };
+        self.add_timing("encoding", start, input_data.len());
 
-        if (output_stream.len() + extradata_block.len()) > (0xFFC0 - player.len()) {
-            Error::new(ErrorKind::InvalidInput, format!("The vgm data is too large to fit. The maximum size after packing is {} bytes", 0xFFC0 - player.len()));
+        if let Some(gd3) = edit::read_gd3(input_data, header) {
+            self.gd3 = gd3;
+            self.observer.on_message(&format!("Title: {}, Game: {}, Artist: {}", self.gd3.track_name, self.gd3.game_name, self.gd3.author));
         }
+        self.observer.on_stats(&SongStats { input_size: input_data.len(), output_size: packed.len(), dsp_writes: Some(log.writes.len()) });
+        self.layout = None;
+        self.section_sizes = None;
+        self.size_breakdown = None;
+        self.cpu_load = None;
+        packed
+    }
 
-        let mut output_file = File::create(output_path)?;
-        let mut spc_ram_remain = 0x10000;
-        if !flags.contains(ConverterFlags::RAW_OUTPUT) {
-            output_file.write_all("SNES-SPC700 Sound File Data v0.30".as_bytes())?;
-            output_file.write_all(&[26, 26, 26, 30])?;
+    /// Replace the VGM header of `packed` with a data-only descriptor, and drop everything after
+    /// the command stream. The descriptor consists of four little-endian words:
+    ///
+    /// <loop_offset> <stream_offset> <length> <lut_offset>
+    ///
+    /// All offsets are relative to the start of the descriptor. Missing loop and LUT offsets are
+    /// given as 0xFFFF.
+    fn strip_header(&mut self, packed: &[u8]) -> Vec<u8> {
+        const DESCRIPTOR_SIZE: usize = 8;
+        let layout = self.layout.as_mut().unwrap();
+        let relocate = |offset: usize| offset - layout.data_offset + DESCRIPTOR_SIZE;
 
-            // SPC registers:           PC       A     X     Y     PSW   SP     reserved
-            output_file.write_all(&[0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00])?;
-            // Write ID666 tag
-            output_file.write_all(&Self::as_id666_buffer(self.song_title.as_bytes(), 32))?;
-            output_file.write_all(&Self::as_id666_buffer(self.game_title.as_bytes(), 32))?;
-            output_file.write_all(&Self::as_id666_buffer("Unknown".as_bytes(), 16))?;
-            output_file.write_all(&Self::as_id666_buffer("Created with VGM2SPC".as_bytes(), 32))?;
-            output_file.write_all(&Self::as_id666_buffer("01/01/1990".as_bytes(), 11))?;
+        let length = layout.stream_end - layout.data_offset + DESCRIPTOR_SIZE;
+        let mut stripped = Vec::with_capacity(length);
+        for offset in &[layout.loop_offset.map(relocate), Some(relocate(layout.stream_offset)), Some(length), layout.lut_offset.map(relocate)] {
+            stripped.extend(&(offset.unwrap_or(0xFFFF) as u16).to_le_bytes());
+        }
+        stripped.extend_from_slice(&packed[layout.data_offset..layout.stream_end]);
 
-            // Fade start/length (none)
-            output_file.write_all(&vec![0; 8])?;
+        *layout = PackedLayout {
+            data_offset: DESCRIPTOR_SIZE,
+            stream_offset: relocate(layout.stream_offset),
+            stream_end: length,
+            lut_offset: layout.lut_offset.map(relocate),
+            loop_offset: layout.loop_offset.map(relocate),
+            length,
+        };
+        stripped
+    }
 
-            output_file.write_all(&Self::as_id666_buffer(self.artist.as_bytes(), 32))?;
-        
-            // Channel disable (none), emulator used for dumping (unknown)
-            output_file.write_all(&vec![0, 0])?;
-            // Reserved
-            output_file.write_all(&vec![0; 45])?;
+    /// Write `packed` to `output_path`, either as-is or embedded in an SPC file together with the player.
+    /// Returns the number of bytes of SPC RAM left after the packed data, or `None` for the outputs
+    /// that aren't loaded into SPC RAM.
+    fn write_output(&self, output_path: &Path, packed: &[u8]) -> Result<Option<usize>, std::io::Error> {
+        if self.options.output_format == OutputFormat::Vgm {
+            writer::write_vgm_file(output_path, self.preprocessed.as_deref().unwrap_or_default())?;
+            return Ok(None);
+        }
+        if self.options.output_format == OutputFormat::DspLog {
+            File::create(output_path)?.write_all(packed)?;
+            return Ok(None);
+        }
+        let player = self.load_player()?;
+        let capacity = self.capacity(&player);
+        if packed.len() > capacity {
+            let mut message = format!("The packed data is {} bytes, which is {} bytes more than the {} bytes available",
+                                      packed.len(), packed.len() - capacity, capacity);
+            if let Some(sizes) = &self.section_sizes {
+                let (section, size) = sizes.largest();
+                message += &format!(". The largest section is {} ({} bytes)", section.to_lowercase(), size);
+            }
+            if !self.options.auto_fit {
+                message += ". -auto-fit may be able to make it fit";
+            }
+            return Err(Error::new(ErrorKind::InvalidData, message));
+        }
 
-            if player.len() >= 0xF0 { player[0xF0] = 0x0A; }    // SPC_TEST = 0x0A (enable timers, enable spc700)
-            let player_bytes_used = std::cmp::min(player.len(), spc_ram_remain);
-            output_file.write_all(&player[..player_bytes_used])?;
-            spc_ram_remain -= player_bytes_used;
+        if !self.includes_player() {
+            File::create(output_path)?.write_all(packed)?;
+            return Ok(Some(capacity - packed.len()));
         }
+        if let Some(codec) = self.codecs_used.iter().find(|codec| !player.supports_codec(codec.name())) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("The player doesn't support the {} codec. Supported codecs: {}",
+                                                                   codec.name(), player.manifest.codecs.join(", "))));
+        }
+        let mut ram = player.ram_image(packed);
+        for (port, value) in self.options.io_ports.iter().enumerate() {
+            if let Some(value) = value {
+                ram[player::IO_PORTS + port] = *value;
+            }
+        }
+        for &(address, value) in &self.options.patches {
+            ram[address as usize] = value;
+        }
+        let entry_point = player.manifest.entry_point;
 
-        output_stream.reset();
-        output_file.write_all(&output_stream.read_n(extradata_offset as usize))?;
-        output_file.write_all(&extradata_block)?;
-        output_file.write_all(&output_stream.read_available())?;
-        spc_ram_remain -= output_stream.len() + extradata_block.len();
+        if self.options.output_format == OutputFormat::Sfc {
+            let rom = sfc::build_test_rom(&ram[..player.data_address() + packed.len()], entry_point, &self.gd3.track_name);
+            File::create(output_path)?.write_all(&rom)?;
+            return Ok(Some(capacity - packed.len()));
+        }
 
-        if !flags.contains(ConverterFlags::RAW_OUTPUT) {
-            // Pad SPC RAM block
-            if spc_ram_remain > 0 {
-                output_file.write_all(&vec![0; spc_ram_remain])?;
+        let mut spc = SpcWriter::new();
+        spc.set_registers(Registers { pc: entry_point, ..Default::default() })
+            .set_id666(self.id666_tag(), self.options.id666_format)
+            .load_ram(0, &ram);
+        match self.options.echo {
+            Some(edl) => {
+                spc.set_dsp(0x6C, 0x00)     // FLG = 0x00 (enable echo buffer writes)
+                    .set_dsp(0x6D, (player::echo_region(edl).start >> 8) as u8)    // ESA
+                    .set_dsp(0x7D, edl);
+            }
+            None => {
+                spc.set_dsp(0x6C, 0x20);    // FLG = 0x20 (disable echo buffer writes)
             }
-
-            let mut dsp_regs: Vec<u8> = vec![0; 128];
-            dsp_regs[0x6C] = 0x20;  // FLG = 0x20 (disable echo buffer writes);
-            output_file.write_all(&dsp_regs[..])?;
-            // Re-use as padding
-            dsp_regs[0x6C] = 0;
-            output_file.write_all(&dsp_regs[..])?;
         }
+        spc.write_to(&mut File::create(output_path)?)?;
 
-        Ok(0)
+        Ok(Some(capacity - packed.len()))
     }
     
-    fn read_player_binary() -> Result<Vec<u8>, std::io::Error> {
-        let mut player = Vec::new();
-        let mut file = File::open("s-smp_player.bin")?;
-        file.read_to_end(&mut player)?;
-        Ok(player)
-    }
-
-    /// Return a vector of length `target_len` consisting of the data from `bytes`, plus as many padding zero-bytes as necessary
-    fn as_id666_buffer(bytes: &[u8], target_len: usize) -> Vec<u8> {
-        let mut result: Vec<u8> = Vec::new();
-        let bytes_used = std::cmp::min(bytes.len(), target_len);
-        result.extend_from_slice(&bytes[..bytes_used]);
-        if bytes_used < target_len { result.extend_from_slice(&vec![0; target_len - bytes_used]); }
-        result
+    /// Build the ID666 tag from the GD3 tag and the user-supplied settings.
+    /// Fade length, channel disables and emulator are left as zero (none/unknown).
+    pub fn id666_tag(&self) -> Id666 {
+        let use_gd3 = self.options.gd3_provenance;
+        let mut tag = Id666::new();
+        tag.song_title = self.options.title.clone().unwrap_or_else(|| self.gd3.track_name.clone());
+        tag.game_title = self.gd3.game_name.clone();
+        tag.dumper = Self::id666_text(&self.options.dumper, &self.gd3.ripper, use_gd3, "Unknown").to_owned();
+        tag.comment = Self::id666_text(&self.options.comment, &self.gd3.notes, use_gd3, "Created with VGM2SPC").to_owned();
+        tag.dump_date = Some(self.options.dump_date.unwrap_or_else(Date::today));
+        match self.song_samples {
+            // A song without loop is played once, without fade
+            (total_samples, None) => tag.play_seconds = total_samples.div_ceil(44100),
+            (total_samples, Some(loop_samples)) => if let Some(loops) = self.options.loops {
+                let play_samples = total_samples as u64 + (loops as u64 - 1) * loop_samples as u64;
+                tag.play_seconds = play_samples.div_ceil(44100) as u32;
+            }
+        }
+        tag.artist = self.gd3.author.clone();
+        tag.source_crc = Some(self.source_crc());
+        tag
     }
 
-    /// Read a GD3 tag string from the given stream, ignoring the high-order byte of each character
-    fn read_gd3_string(bs: &mut ByteStream, str: &mut String) {
-        for _ in 0..32 {
-            if bs.available() == 0 { break; }
-            let b = bs.peek();
-            if b == 0 { break; }
-            str.push(b as char);
-            bs.skip(2);
-        }
-        while bs.available() != 0 && bs.peek() != 0 { bs.skip(2); }
-        bs.skip(2);
-    }
-
-    fn preprocess(&mut self, input_stream: &mut ByteStream, starting_offset: usize, header: &specification::FileHeader) -> ByteStream {
-        let mut preprocessed_data = ByteStream::new(input_stream.read_n(starting_offset));
-
-        let mut ym_ch3_mode: u8 = 0;
-        let mut last_pcm_offset: u32 = 0xFFFFFFFF;
-
-        // Run a pre-processing stage to remove redundant commands
-        let mut eod = false;
-        while !eod {
-            if input_stream.get_pos() == (header.loop_offset as usize) + 0x1C {
-                self.loop_offset = preprocessed_data.len()
-            }
-
-            let c = input_stream.read();
-            
-            match c {
-                Command::YM2612_LO_WRITE => {
-                    let arg1 = input_stream.read();
-                    if arg1 == 0x27 {
-                        let arg2 = input_stream.read();
-                        if (arg2 >> 6) != ym_ch3_mode {
-                            ym_ch3_mode = arg2 >> 6;
-                            preprocessed_data.write(c);
-                            preprocessed_data.write(arg1);
-                            preprocessed_data.write(arg2);
-                        }
-                    } else if arg1 == 0x25 || arg1 == 0x26 {
-                        let _ = input_stream.read();
-                    } else {
-                        preprocessed_data.write(c);
-                        preprocessed_data.write(arg1);
-                        preprocessed_data.write(input_stream.read());
-                    }
-                }
-                
-                Command::END_OF_SOUND_DATA => {
-                    preprocessed_data.write(c);
-                    eod = true;
+    /// Pick the text for an ID666 field: an explicitly set value first, then the corresponding
+    /// GD3 field (if `use_gd3` is set and the field isn't empty), and lastly `default`.
+    fn id666_text<'b>(explicit: &'b Option<String>, gd3_field: &'b str, use_gd3: bool, default: &'b str) -> &'b str {
+        match explicit {
+            Some(text) => text,
+            None if use_gd3 && !gd3_field.is_empty() => gd3_field,
+            None => default,
+        }
+    }
+
+    /// Run the preprocessing passes on `song`. The dual-PSG mapping is only applied if `header`
+    /// says that there are two PSGs.
+    fn preprocess(&mut self, song: &mut EventList, header: &specification::FileHeader) -> Result<(), Error> {
+        let mut passes = self.options.passes.clone();
+        if self.options.psg_only && !passes.contains(&PassKind::StripNonPsg) {
+            passes.insert(0, PassKind::StripNonPsg);
+        }
+        let mut passes: Vec<Box<dyn Pass>> = passes.into_iter().map(|kind| kind.create(self.codec_used)).collect();
+        if header.is_dual_psg() {
+            passes.insert(0, Box::new(passes::DualPsg { mapping: self.options.dual_psg }));
+        }
+        for mut pass in passes {
+            pass.run(song).map_err(|e| Error::new(e.kind(), format!("Preprocessing pass {} failed: {}", pass.name(), e)))?;
+            for message in pass.warnings() {
+                self.warn(WarningKind::DroppedCommands, message);
+            }
+        }
+        Ok(())
+    }
+
+    /// Decode the packed command stream and check that it plays for `expected_samples`. A mismatch
+    /// means that preprocessing or encoding broke the timing of the song. Returns the decoded
+    /// events and their offsets, if decoding succeeded.
+    fn check_timing(&mut self, stream: &[u8], lut: &[u16], expected_samples: u64) -> Option<Vec<(usize, VgmEvent)>> {
+        let decoded = match self.codec_used {
+            Codec::Psg => psgcodec::decode(stream, lut, false),
+            Codec::PsgRuns => psgcodec::decode(stream, lut, true),
+            _ => nullcodec::decode(stream),
+        };
+        match decoded {
+            Ok(events) => {
+                let samples: u64 = events.iter().map(|(_, e)| e.samples() as u64).sum();
+                if samples != expected_samples {
+                    self.warn(WarningKind::Timing, format!("timing check failed: the packed data plays for {} samples, but the input for {}", samples, expected_samples));
                 }
-                
-                Command::DATA_BLOCK => {
-                    if input_stream.peek() == 0x66 {
-                        let data_block_size = input_stream.peek_u32_at(2);
-                        preprocessed_data.write(c);
-                        for _ in 0..data_block_size+6 {
-                            preprocessed_data.write(input_stream.read());
-                        }
-                    } else {
-                        panic!("Illegal command: 0x67 0x{:X} at offset 0x{:X}", input_stream.peek(), input_stream.get_pos());
+                Some(events)
+            }
+            Err(e) => {
+                self.warn(WarningKind::Timing, format!("timing check failed: the packed data couldn't be decoded: {}", e));
+                None
+            }
+        }
+    }
+
+    /// Read the command stream from the current position of `input_stream` up to and including
+    /// the end of sound data command, and check it for commands that the player can't handle
+    fn read_events(&mut self, input_stream: &mut ByteStream, header: &specification::FileHeader) -> Result<EventList, Error> {
+        let loop_position = if header.loop_offset != 0 { Some(header.loop_offset as usize + 0x1C) } else { None };
+        let mut song = std::mem::take(&mut self.buffers.song);
+        song.clear();
+        let mut reserved_commands = 0;
+        let mut undefined_commands = 0;
+        let mut ignored_writes: BTreeMap<specification::Chip, usize> = BTreeMap::new();
+
+        // Where the command stream ends if there's no end of sound data command
+        let stream_end = if header.gd3_offset != 0 { header.gd3_offset as usize + 0x14 } else { header.eof_offset as usize + 0x04 };
+
+        let mut events = Events::new(input_stream);
+        loop {
+            let position = events.position();
+            let event = if position >= stream_end {
+                self.warn(WarningKind::TruncatedInput, format!("no end of sound data command found. The song ends at offset 0x{:X}", position));
+                VgmEvent::End
+            } else {
+                match events.next() {
+                    Some(Err(e)) if e.kind() == error::ErrorKind::UnexpectedEof => {
+                        self.warn(WarningKind::TruncatedInput, format!("{}. The song ends at offset 0x{:X}", e, position));
+                        VgmEvent::End
                     }
+                    Some(event) => event?,
+                    None => break,
                 }
+            };
+            if Some(position) == loop_position {
+                song.loop_index = Some(song.events.len());
+            }
+            // The new durations are already applied to the waits, which are written with 0x61 if
+            // they don't match the standard 0x62/0x63 durations
+            if let VgmEvent::WaitOverride { .. } = event {
+                continue;
+            }
 
-                Command::YM2612_WRITE_LO_WAIT_0 ... Command::YM2612_WRITE_LO_WAIT_15 => {
-                    let mut wait = c & 0x0F;
-                    // Merge as many adjacent 0x8n and 0x7n commands as possible into one 0x8n command
-                    if (input_stream.peek() & 0xF0) == Command::WAIT_1 {
-                        while (input_stream.peek() & 0xF0) == Command::WAIT_1 {
-                            if (wait + (input_stream.peek() & 0x0F) + 1) < 0x10 {
-                                wait += (input_stream.read() & 0x0F) + 1;
-                            } else {
-                                break;
-                            }
-                        }
-                        preprocessed_data.write(0x80 | wait);
-                    } else if preprocessed_data.len() > 0 {
-                        let last = preprocessed_data.last().unwrap().clone();
-                        if (last & 0xF0) == Command::WAIT_1 {
-                            if (wait + (last & 0x0F) + 1) < 0x10 {
-                                wait += (last & 0x0F) + 1;
-                            }
-                            let last_index = preprocessed_data.len() - 1;
-                            preprocessed_data.reset();
-                            preprocessed_data.skip(last_index);
-                            preprocessed_data.replace_at(0, 0x80 | wait);
-                            preprocessed_data.skip(1);
-                        } else {
-                            preprocessed_data.write(0x80 | wait);
-                        }
-                    } else {
-                        preprocessed_data.write(0x80 | wait);
-                    }
+            let c = event.command();
+            if let Some(chip) = specification::command_chip(c) {
+                if !player::SUPPORTED_CHIPS.contains(&chip.name) {
+                    *ignored_writes.entry(chip).or_insert(0) += 1;
+                }
+            }
+            if specification::is_reserved(c) || specification::is_undefined(c) {
+                if self.options.strict {
+                    return Err(Error::new(ErrorKind::InvalidData,
+                        format!("Unsupported command: 0x{:02X} at offset 0x{:X}", c, position)));
                 }
+                if specification::is_reserved(c) { reserved_commands += 1; } else { undefined_commands += 1; }
+            }
+            let end = event == VgmEvent::End;
+            song.events.push(event);
+            if end {
+                break;
+            }
+        }
 
-                Command::SEEK_PCM => {
-                    let pcm_offset = input_stream.peek_u32_at(0);
-                    if pcm_offset != 0 && self.codec_used == ConverterFlags::NULL_CODEC {
-                        preprocessed_data.write(c);
-                        for _ in 0..4 {
-                            preprocessed_data.write(input_stream.read());
-                        }
-                        last_pcm_offset = pcm_offset;
-                    }
-                }        
+        if loop_position.is_some() && song.loop_index.is_none() {
+            self.warn(WarningKind::LoopPoint, String::from("the loop offset doesn't point to the start of a command. The song won't loop"));
+        }
+        if reserved_commands > 0 {
+            self.warn(WarningKind::DroppedCommands, format!("skipped {} commands from reserved ranges", reserved_commands));
+        }
+        if undefined_commands > 0 {
+            self.warn(WarningKind::DroppedCommands, format!("found {} undefined commands. They were assumed to have no arguments", undefined_commands));
+        }
+        if !self.options.psg_only {
+            // The second PSG is only kept in VGM output, since the player plays a single PSG
+            let dual_psg_kept = self.options.dual_psg != DualPsgMapping::Drop;
+            for (chip, count) in ignored_writes.iter().filter(|(chip, _)| !(dual_psg_kept && chip.name == "SN76489 #2")) {
+                self.warn(WarningKind::StrippedChip, format!("{} {} writes ignored: this file will be missing {}", count, chip.name, chip.channels));
+            }
+        }
+        Ok(song)
+    }
+}
 
-                _ => {
-                    preprocessed_data.write(c);
-                    for _ in 0..specification::num_argument_bytes(c) {
-                        preprocessed_data.write(input_stream.read());
-                    }
-                }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vgm::Command;
+    use crate::vgm::testing::{vgm_file, VgmBuilder};
+
+    fn packed_song() -> (Vec<u8>, PackedLayout) {
+        let packed = VgmBuilder::new().loop_at(0x42)
+            .commands(&[Command::PSG_WRITE, 0x9F, Command::WAIT_NTSC_FRAME, Command::END_OF_SOUND_DATA]).build();
+        let layout = PackedLayout { data_offset: 0x40, stream_offset: 0x40, stream_end: 0x44, lut_offset: None, loop_offset: Some(0x42), length: 0x44 };
+        (packed, layout)
+    }
+
+    #[test]
+    fn test_capacity() {
+        let player = Player { binary: vec![0; 0x1700], ..Default::default() };
+        assert_eq!(Converter::builder().build().unwrap().capacity(&player), 0xFFC0 - 0x1700);
+        assert_eq!(Converter::builder().output_format(OutputFormat::Raw).build().unwrap().capacity(&player), 0xFFC0);
+    }
+
+    #[test]
+    fn test_check_distinct_paths() {
+        let dir = std::env::temp_dir();
+        let input = dir.join(format!("vgm2spc-test-{}.vgm", std::process::id()));
+        std::fs::write(&input, b"Vgm ").unwrap();
+        let same = dir.join(".").join(input.file_name().unwrap());
+        let result = check_distinct_paths(&[Path::new("other.vgm"), &input], &same);
+        std::fs::remove_file(&input).unwrap();
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert!(check_distinct_paths(&[&input], &dir.join("vgm2spc-missing.spc")).is_ok());
+    }
+
+    #[test]
+    fn test_temp_output_path() {
+        let path = temp_output_path(Path::new("out/song.vgz"));
+        assert_eq!(path.parent(), Some(Path::new("out")));
+        assert_eq!(path.extension().unwrap(), "vgz");
+        assert!(path.file_name().unwrap().to_string_lossy().starts_with(".vgm2spc-"));
+    }
+
+    #[test]
+    fn test_parallel_conversions() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Converter>();
+
+        let vgm = vgm_file(&[Command::PSG_WRITE, 0x90, Command::WAIT_NTSC_FRAME, Command::END_OF_SOUND_DATA], 735);
+        let dir = std::env::temp_dir();
+        let input = dir.join(format!("vgm2spc-parallel-{}.vgm", std::process::id()));
+        std::fs::write(&input, &vgm).unwrap();
+
+        let outputs: Vec<Vec<u8>> = (0..4).map(|i| {
+            let input = input.clone();
+            let output = dir.join(format!("vgm2spc-parallel-{}-{}.bin", std::process::id(), i));
+            std::thread::spawn(move || {
+                let mut converter = Converter::builder().output_format(OutputFormat::Raw).build().unwrap();
+                converter.convert(&input, &output).unwrap();
+                let data = std::fs::read(&output).unwrap();
+                std::fs::remove_file(&output).unwrap();
+                data
+            })
+        }).collect::<Vec<_>>().into_iter().map(|thread| thread.join().unwrap()).collect();
+        std::fs::remove_file(&input).unwrap();
+        assert!(!outputs[0].is_empty());
+        assert!(outputs.iter().all(|output| *output == outputs[0]));
+    }
+
+    #[test]
+    fn test_reused_converter() {
+        // A converter that packed another (longer) song first gives the same output as a new one
+        let dir = std::env::temp_dir();
+        let short = dir.join(format!("vgm2spc-reuse-short-{}.vgm", std::process::id()));
+        let long = dir.join(format!("vgm2spc-reuse-long-{}.vgm", std::process::id()));
+        std::fs::write(&short, vgm_file(&[Command::PSG_WRITE, 0x90, Command::WAIT_NTSC_FRAME, Command::END_OF_SOUND_DATA], 735)).unwrap();
+        let mut commands = Vec::new();
+        for note in 0..16u8 {
+            commands.extend(&[Command::PSG_WRITE, 0x80 | note, Command::PSG_WRITE, 0x90 + note, Command::WAIT_NTSC_FRAME]);
+        }
+        commands.push(Command::END_OF_SOUND_DATA);
+        std::fs::write(&long, vgm_file(&commands, 16 * 735)).unwrap();
+
+        let new_converter = || Converter::builder().codec(Codec::Psg).output_format(OutputFormat::Raw).build().unwrap();
+        let expected = new_converter().pack_file(&short).map(<[u8]>::to_vec);
+        let mut converter = new_converter();
+        let first = converter.pack_file(&long).map(<[u8]>::to_vec);
+        let reused = converter.pack_file(&short).map(<[u8]>::to_vec);
+        std::fs::remove_file(&short).unwrap();
+        std::fs::remove_file(&long).unwrap();
+        assert!(first.unwrap().len() > expected.as_ref().unwrap().len());
+        assert_eq!(reused.unwrap(), expected.unwrap());
+        assert_eq!(converter.song_samples, (735, None));
+    }
+
+    #[test]
+    fn test_dual_psg_mapping() {
+        let events = vec![VgmEvent::GgStereo(0xFF), VgmEvent::PsgWrite(0x90), VgmEvent::Wait(735), VgmEvent::GgStereo(0x3C), VgmEvent::End];
+        let mut vgm = vgm_file(&[], 735);
+        let mut converter = Converter::builder().dual_psg(DualPsgMapping::Pan).output_format(OutputFormat::Vgm).passes(&[]).build().unwrap();
+        // A single PSG keeps its stereo masks
+        let mut song = EventList { events: events.clone(), loop_index: None };
+        converter.preprocess(&mut song, &specification::FileHeader::parse(&vgm).unwrap()).unwrap();
+        assert_eq!(song.events, events);
+
+        vgm[0x0F] |= 0x40;
+        let mut song = EventList { events: events.clone(), loop_index: None };
+        converter.preprocess(&mut song, &specification::FileHeader::parse(&vgm).unwrap()).unwrap();
+        assert_eq!((&song.events[0], &song.events[3]), (&VgmEvent::GgStereo(0xF0), &VgmEvent::GgStereo(0x30)));
+    }
+
+    #[test]
+    fn test_warnings() {
+        // The header length doesn't match the single frame wait
+        let vgm = vgm_file(&[Command::PSG_WRITE, 0x90, Command::WAIT_NTSC_FRAME, Command::END_OF_SOUND_DATA], 1000);
+        let dir = std::env::temp_dir();
+        let input = dir.join(format!("vgm2spc-warnings-{}.vgm", std::process::id()));
+        let output = dir.join(format!("vgm2spc-warnings-{}.bin", std::process::id()));
+        std::fs::write(&input, &vgm).unwrap();
+
+        #[derive(Default)]
+        struct Recorder(std::sync::Mutex<Vec<String>>);
+        impl Observer for std::sync::Arc<Recorder> {
+            fn on_stage_start(&self, stage: &'static str) {
+                self.0.lock().unwrap().push(String::from(stage));
+            }
+            fn on_warning(&self, warning: &Warning) {
+                self.0.lock().unwrap().push(format!("warning: {}", warning.kind.name()));
+            }
+            fn on_stats(&self, stats: &SongStats) {
+                self.0.lock().unwrap().push(format!("stats: {}", stats.output_size));
             }
         }
-        if input_stream.available() > 0 {
-            preprocessed_data.write_n(&input_stream.read_available());
+        let recorder = std::sync::Arc::new(Recorder::default());
+
+        let mut converter = Converter::builder().output_format(OutputFormat::Raw).build().unwrap().with_observer(Box::new(recorder.clone()));
+        let result = converter.convert(&input, &output);
+        std::fs::remove_file(&input).unwrap();
+        let _ = std::fs::remove_file(&output);
+        let result = result.unwrap();
+        assert_eq!(result.size, converter.packed_data().len());
+        assert_eq!(result.warnings.len(), 1);
+        assert_eq!(result.warnings[0].kind, WarningKind::Timing);
+        assert_eq!(result.warnings[0].file.as_deref(), Some(input.as_path()));
+        assert_eq!(*recorder.0.lock().unwrap(), vec![String::from("decompression"), String::from("preprocessing"), String::from("warning: timing"),
+                                                     String::from("encoding"), String::from("timing check"), format!("stats: {}", result.size),
+                                                     String::from("writing")]);
+    }
+
+    #[test]
+    fn test_too_many_songs() {
+        let paths: Vec<PathBuf> = (0..=player::MAX_SONGS).map(|i| PathBuf::from(format!("song{}.vgm", i))).collect();
+        let paths: Vec<&Path> = paths.iter().map(PathBuf::as_path).collect();
+        let mut converter = Converter::builder().build().unwrap();
+        let e = converter.convert_multi(&paths, Path::new("songs.spc")).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_free_ram() {
+        let vgm = vgm_file(&[Command::PSG_WRITE, 0x90, Command::WAIT_NTSC_FRAME, Command::END_OF_SOUND_DATA], 735);
+        let dir = std::env::temp_dir();
+        let input = dir.join(format!("vgm2spc-free-ram-{}.vgm", std::process::id()));
+        let output = dir.join(format!("vgm2spc-free-ram-{}.out", std::process::id()));
+        std::fs::write(&input, &vgm).unwrap();
+
+        let convert = |format| {
+            let mut converter = Converter::builder().output_format(format).build().unwrap();
+            let result = converter.convert(&input, &output);
+            let capacity = converter.load_player().map(|player| converter.capacity(&player));
+            (result, capacity)
+        };
+        let (raw, capacity) = convert(OutputFormat::Raw);
+        let (vgm, _) = convert(OutputFormat::Vgm);
+        std::fs::remove_file(&input).unwrap();
+        let _ = std::fs::remove_file(&output);
+        let raw = raw.unwrap();
+        assert_eq!(raw.free_ram, Some(capacity.unwrap() - raw.size));
+        assert_eq!(vgm.unwrap().free_ram, None);
+    }
+
+    #[test]
+    fn test_quantized_length() {
+        // 16 long waits that fill the LUT, then a loop with a wait that is 3 samples longer than one of them
+        let mut commands = Vec::new();
+        for k in 0..16u16 {
+            commands.extend(&[Command::PSG_WRITE, 0x90, Command::WAIT_LONG]);
+            commands.extend(&(1000 + 10 * k).to_le_bytes());
         }
-        
-        preprocessed_data
-    }    
+        let loop_position = 0x40 + commands.len();
+        commands.extend(&[Command::PSG_WRITE, 0x91, Command::WAIT_LONG]);
+        commands.extend(&1003u16.to_le_bytes());
+        commands.push(Command::END_OF_SOUND_DATA);
+        let total = (0..16).map(|k| 1000 + 10 * k).sum::<u32>() + 1003;
+        let mut vgm = vgm_file(&commands, total);
+        vgm[0x1C..0x20].copy_from_slice(&(loop_position as u32 - 0x1C).to_le_bytes());
+        vgm[0x20..0x24].copy_from_slice(&1003u32.to_le_bytes());
+        let input = std::env::temp_dir().join(format!("vgm2spc-quantized-{}.vgm", std::process::id()));
+        std::fs::write(&input, &vgm).unwrap();
+
+        let mut converter = Converter::builder().codec(Codec::Psg).wait_tolerance(4).output_format(OutputFormat::Raw).build().unwrap();
+        let packed = converter.pack_file(&input).map(<[u8]>::to_vec);
+        std::fs::remove_file(&input).unwrap();
+        packed.unwrap();
+        assert_eq!(converter.warnings.iter().map(|w| w.kind).collect::<Vec<_>>(), vec![WarningKind::QuantizedWaits]);
+        assert_eq!(converter.song_samples, (total - 3, Some(1000)));
+    }
+
+    #[test]
+    fn test_truncated_input() {
+        // The file is cut off in the middle of a command, so the EOF offset points past its end
+        let mut vgm = vgm_file(&[Command::PSG_WRITE, 0x90, Command::WAIT_NTSC_FRAME, Command::PSG_WRITE, 0x9F, Command::END_OF_SOUND_DATA], 735);
+        vgm.truncate(vgm.len() - 2);
+        let input = std::env::temp_dir().join(format!("vgm2spc-truncated-{}.vgm", std::process::id()));
+        std::fs::write(&input, &vgm).unwrap();
+
+        let mut converter = Converter::builder().output_format(OutputFormat::Raw).build().unwrap();
+        let packed = converter.pack_file(&input).map(<[u8]>::to_vec);
+        let mut trimming = Converter::builder().output_format(OutputFormat::Raw).trim_silence(true).build().unwrap();
+        let trimmed = trimming.pack_file(&input).map(<[u8]>::to_vec);
+        std::fs::remove_file(&input).unwrap();
+        assert!(packed.is_ok());
+        assert_eq!(converter.warnings.iter().filter(|w| w.kind == WarningKind::TruncatedInput).count(), 1);
+        let error = trimmed.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+        assert!(error.to_string().contains("-repair"));
+    }
+
+    #[test]
+    fn test_volume_modifier() {
+        // A 1.60 header with a +6 dB volume modifier, which makes the first write louder than full volume
+        let mut vgm = vgm_file(&[Command::PSG_WRITE, 0x91, Command::PSG_WRITE, 0xB5, Command::WAIT_NTSC_FRAME, Command::END_OF_SOUND_DATA], 735);
+        vgm.splice(0x40..0x40, vec![0; 0x40]);
+        let eof_offset = vgm.len() as u32 - 4;
+        vgm[0x04..0x08].copy_from_slice(&eof_offset.to_le_bytes());
+        vgm[0x08..0x0C].copy_from_slice(&0x160u32.to_le_bytes());
+        vgm[0x34..0x38].copy_from_slice(&0x4Cu32.to_le_bytes());
+        vgm[0x7C] = 0x20;
+        let input = std::env::temp_dir().join(format!("vgm2spc-volume-{}.vgm", std::process::id()));
+        std::fs::write(&input, &vgm).unwrap();
+
+        let mut converter = Converter::builder().output_format(OutputFormat::Vgm).build().unwrap();
+        let packed = converter.pack_file(&input).map(<[u8]>::to_vec);
+        std::fs::remove_file(&input).unwrap();
+        let packed = packed.unwrap();
+        assert_eq!(converter.warnings.iter().filter(|w| w.kind == WarningKind::VolumeClipped).count(), 1);
+        // The gain is baked into the volume writes and cleared from both headers
+        assert_eq!(packed[0x7C], 0);
+        let preprocessed = converter.preprocessed_vgm().unwrap();
+        assert_eq!(preprocessed[0x7C], 0);
+        assert_eq!(&preprocessed[0x80..0x84], &[Command::PSG_WRITE, 0x90, Command::PSG_WRITE, 0xB2]);
+    }
+
+    #[test]
+    fn test_auto_fit() {
+        // Too large without compression, so the codec is switched to the one selected for the song
+        let mut commands = Vec::new();
+        for n in 0..40000u32 {
+            commands.extend(&[Command::PSG_WRITE, 0x90 | (n % 15) as u8]);
+        }
+        commands.push(Command::END_OF_SOUND_DATA);
+        let input = std::env::temp_dir().join(format!("vgm2spc-fit-{}.vgm", std::process::id()));
+        std::fs::write(&input, vgm_file(&commands, 0)).unwrap();
+
+        let builder = Converter::builder().codec(Codec::Null).output_format(OutputFormat::Raw);
+        let too_large = builder.clone().build().unwrap().pack_file(&input).map(|packed| packed.len());
+        let mut converter = builder.auto_fit(true).build().unwrap();
+        let fitted = converter.pack_file(&input).map(|packed| packed.len());
+        std::fs::remove_file(&input).unwrap();
+        assert!(too_large.unwrap() > RAM_LIMIT);
+        assert!(fitted.unwrap() <= RAM_LIMIT);
+        assert_eq!(converter.codec_used, Codec::Psg);
+        assert!(converter.warnings.iter().all(|w| w.kind != WarningKind::TooLarge));
+        assert_eq!(converter.options.codec, Codec::Null);
+    }
+
+    #[test]
+    fn test_seek_table() {
+        // Six half-second notes on channel 0
+        let mut commands = Vec::new();
+        for note in 0..6u8 {
+            commands.extend(&[Command::PSG_WRITE, 0x80 | note, Command::PSG_WRITE, 0x05, Command::PSG_WRITE, 0x90 + note]);
+            commands.extend(&[Command::WAIT_LONG, 0x22, 0x56]);
+        }
+        commands.push(Command::END_OF_SOUND_DATA);
+        let input = std::env::temp_dir().join(format!("vgm2spc-seek-{}.vgm", std::process::id()));
+        std::fs::write(&input, vgm_file(&commands, 6 * 22050)).unwrap();
+
+        let mut converter = Converter::builder().codec(Codec::Psg).seek_table(1).output_format(OutputFormat::Raw).build().unwrap();
+        let packed = converter.pack_file(&input).map(<[u8]>::to_vec);
+        std::fs::remove_file(&input).unwrap();
+        let packed = packed.unwrap();
+        let layout = converter.layout().unwrap().clone();
+        let lut: Vec<u16> = packed[layout.lut_offset.unwrap()..][..32].chunks(2).map(|w| u16::from_le_bytes([w[0], w[1]])).collect();
+
+        let decode = |offset: usize| psgcodec::decode(&packed[layout.stream_offset + offset..layout.stream_end], &lut, false).unwrap();
+        let length: u32 = decode(0).iter().map(|(_, e)| e.samples()).sum();
+
+        let table_start = layout.lut_offset.unwrap() + 32;
+        assert_eq!(&packed[table_start..table_start + 3], &[Command::DATA_BLOCK, 0x66, player::SEEK_TABLE_BLOCK_TYPE]);
+        let table = &packed[table_start + 7..];
+        // Entries at 0, 1 and 2 s, at the end of the notes, and in the silence added at the end
+        assert_eq!(&table[..2], &[1, 0]);
+        assert!(u16::from_le_bytes([table[2], table[3]]) >= 4);
+        for (n, entry) in table[4..4 + 3 * 20].chunks(20).enumerate() {
+            let time = u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]);
+            let offset = u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]) as usize;
+            assert_eq!(time, n as u32 * 44100);
+            // Decoding from the offset plays the rest of the song, starting with the next note
+            let events = decode(offset);
+            assert_eq!(events.iter().map(|(_, e)| e.samples()).sum::<u32>(), length - time);
+            assert_eq!(events[0].1, VgmEvent::PsgWrite(0x80 | (2 * n as u8)));
+            // The snapshot holds the state left by the previous note
+            if n > 0 {
+                assert_eq!(&entry[8..10], &[0x50 | (2 * n as u8 - 1), 0x00]);
+                assert_eq!(entry[15], 2 * n as u8 - 1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_check_invariants() {
+        let (packed, layout) = packed_song();
+        assert_eq!(check_invariants(&packed, &layout), Ok(()));
+
+        let mut bad_eof = packed.clone();
+        bad_eof[0x04] += 1;
+        assert!(check_invariants(&bad_eof, &layout).is_err());
+
+        let mut bad_loop = packed.clone();
+        bad_loop[0x1C] += 1;
+        assert!(check_invariants(&bad_loop, &layout).is_err());
+
+        let mut bad_gd3 = packed.clone();
+        bad_gd3[0x14] = 0x30;
+        assert!(check_invariants(&bad_gd3, &layout).is_err());
+
+        // A corrupt layout is reported instead of panicking
+        for lut_offset in [Some(3), Some(layout.length - 4)] {
+            assert!(check_invariants(&packed, &PackedLayout { lut_offset, ..layout.clone() }).is_err());
+        }
+        assert!(check_invariants(&packed, &PackedLayout { stream_offset: layout.length + 1, ..layout.clone() }).is_err());
+    }
 }
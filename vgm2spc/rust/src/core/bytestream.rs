@@ -1,3 +1,5 @@
+use alloc::vec::Vec;
+
 pub struct ByteStream {
     data: Vec<u8>,
     pos: usize,
@@ -22,23 +24,6 @@ impl ByteStream {
         self.pos
     }
     
-    pub fn last(&self) -> Option<&u8> {
-        self.data.last()
-    }
-    
-    /// Return the next available byte without updating the position    
-    pub fn peek(&self) -> u8 {
-        self.data[self.pos]
-    }
-
-    /// Return the (little-endian) u32 at `pos_offset`. The offset is relative to the current position.
-    pub fn peek_u32_at(&self, pos_offset: usize) -> u32 {
-        (self.data[self.pos + pos_offset] as u32) |
-        (self.data[self.pos + pos_offset + 1] as u32) << 8 |
-        (self.data[self.pos + pos_offset + 2] as u32) << 16 |
-        (self.data[self.pos + pos_offset + 3] as u32) << 24        
-    }
-
     /// Replace the byte at `pos_offset` with `val`. The offset is relative to the current position.
     pub fn replace_at(&mut self, pos_offset: usize, val: u8) {
         self.data[self.pos + pos_offset] = val;
@@ -52,6 +37,16 @@ impl ByteStream {
         self.data[self.pos + pos_offset + 3] = (val >> 24) as u8;
     }
     
+    /// Return all of the data, regardless of the position
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Return all of the data, regardless of the position, without copying it
+    pub fn into_vec(self) -> Vec<u8> {
+        self.data
+    }
+
     /// Reset the position
     pub fn reset(&mut self) {
         self.pos = 0;
@@ -0,0 +1,29 @@
+use alloc::vec::Vec;
+use crate::core::bytestream::ByteStream;
+use crate::core::events::VgmEvent;
+
+pub trait Codec<'a> {
+    fn new(output: &'a mut ByteStream) -> Self where Self: Sized;
+
+    fn output_len(&self) -> usize;
+
+    /// Add one byte of data without doing any processing on it.
+    fn passthrough(&mut self, c: u8);
+
+    /// Add several bytes of data without doing any processing on them. By default the bytes are
+    /// added one at a time with `passthrough`; codecs can override this to copy them in one go.
+    fn write_slice(&mut self, data: &[u8]) {
+        for &c in data {
+            self.passthrough(c);
+        }
+    }
+
+    /// Add one complete command. The command has already been parsed, so codecs don't need to
+    /// keep track of where commands and their arguments start and end.
+    fn write_event(&mut self, event: &VgmEvent);
+
+    /// Ensure that all data processed by the codec is written to its output.
+    fn flush(&mut self);    
+
+    fn get_extra_data(&self, what: u32) -> Option<Vec<u8>>;	
+}
\ No newline at end of file
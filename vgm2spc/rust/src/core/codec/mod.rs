@@ -2,6 +2,7 @@ pub use self::codec::Codec;
 pub use self::nullcodec::NullCodec;
 pub use self::psgcodec::PsgCodec;
 
+#[allow(clippy::module_inception)]
 pub mod codec;
 pub mod nullcodec;
 pub mod psgcodec;
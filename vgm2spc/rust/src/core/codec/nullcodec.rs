@@ -0,0 +1,59 @@
+//!
+//! A dummy codec that outputs the input data as-is.
+//!
+
+use alloc::vec::Vec;
+use crate::core::error::Error;
+use crate::core::codec::Codec;
+use crate::core::bytestream::ByteStream;
+use crate::core::events::{Events, VgmEvent};
+
+pub struct NullCodec<'a> {
+    output: &'a mut ByteStream,
+}
+
+impl<'a> Codec<'a> for NullCodec<'a> {
+    fn new(out: &'a mut ByteStream) -> NullCodec<'a> {
+        NullCodec { output: out }
+    }
+    
+    fn output_len(&self) -> usize {
+        self.output.len()
+    }
+
+    fn passthrough(&mut self, c: u8) {
+        self.output.write(c);
+    }
+
+    fn write_slice(&mut self, data: &[u8]) {
+        self.output.write_n(data);
+    }
+
+    fn write_event(&mut self, event: &VgmEvent) {
+        let mut bytes = Vec::new();
+        event.write_to(&mut bytes);
+        self.write_slice(&bytes);
+    }
+
+    fn flush(&mut self) {
+    }
+
+    fn get_extra_data(&self, _what: u32) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+/// Decode the output of the codec, which is just a VGM command stream. Each event is paired with
+/// its offset in `data`.
+pub fn decode(data: &[u8]) -> Result<Vec<(usize, VgmEvent)>, Error> {
+    let mut stream = ByteStream::new(data.to_vec());
+    let mut events = Events::new(&mut stream);
+    let mut result = Vec::new();
+    loop {
+        let pos = events.position();
+        match events.next() {
+            Some(event) => result.push((pos, event?)),
+            None => return Ok(result),
+        }
+    }
+}
@@ -0,0 +1,449 @@
+//!
+//! A VGM compressor focusing mainly on PSG commands (0x50 0xnn).
+//! Each group of 8 commands is prepended with a flag byte, where each bit specifies
+//! if the corresponding command is a PSG command or not. The command byte (0x50) is stripped
+//! and only the argument byte is written to the output.
+//!
+//! The compressor also tries to shorten long wait commands (0x61 0xmm 0xnn) down to one byte.
+//! A table with 16 entries is filled with the most common wait lengths (0xnnmm) in the VGM (see
+//! `select_long_waits`), or with distinct wait lengths as they are found if the table hasn't been
+//! filled in advance. A long wait command for which the length is found in the table is replaced
+//! by the byte 0x9n, where n is the position in the table.
+//! The table is stored in the output as a data block, right after the VGM header (i.e. offset 0x40).
+//! Commands 0x4E and 0x90-0x9F can't be passed through, since they would be read as padding and LUT
+//! waits. VGM only uses them for DAC stream control (0x90-0x95), which the `strip-unsupported` pass
+//! removes along with the reserved and undefined ones.
+//!
+//! With PSG runs enabled (`-codec psg-runs`), a flag byte of 0xFF is followed by a count byte N,
+//! and means that the next N groups are all PSG writes: 8*N argument bytes follow, without flag
+//! bytes of their own. The LUT block then has the type `PSG_RUNS_LUT_BLOCK_TYPE`, which tells the
+//! player which format the stream is in.
+//!
+//! Mic, 2010,2019
+//!
+
+use alloc::format;
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::core::error::{Error, ErrorKind};
+use crate::core::bytestream::ByteStream;
+use crate::core::codec::Codec;
+use crate::core::events::{Events, VgmEvent};
+use crate::core::command::Command;
+
+pub const GET_LONG_WAIT_LUT: u32 = 0;
+
+/// Data block type of the long wait LUT
+pub const LONG_WAIT_LUT_BLOCK_TYPE: u8 = 0x02;
+
+/// Data block type of the long wait LUT, for command streams with PSG runs
+pub const PSG_RUNS_LUT_BLOCK_TYPE: u8 = 0x04;
+
+/// Max number of groups in a PSG run
+const MAX_RUN_GROUPS: usize = 255;
+
+/// Number of entries in the long wait LUT
+pub const LONG_WAIT_LUT_SIZE: usize = 16;
+
+pub struct PsgCodec<'a> {
+    output: &'a mut ByteStream, // The codec's output data
+    pending_data: Vec<u8>,      // Data that has been written to the codec but not yet been fully processed
+    long_wait_table: Vec<u16>,  // A lookup table for compression of long wait VGM commands
+    flags: u8,
+    num_flags: u8,
+    runs: bool,                 // Encode consecutive all-PSG groups as runs
+    pending_run: Vec<u8>,       // Arguments of the all-PSG groups of the current run
+}
+
+impl<'a> PsgCodec<'a> {
+    /// Fill the long wait LUT in advance, e.g. with the result of `select_long_waits`. Waits that
+    /// aren't in `waits` are still added to the LUT as they're found, while there's space left.
+    pub fn preload_long_waits(&mut self, waits: &[u16]) {
+        self.long_wait_table = waits.iter().take(LONG_WAIT_LUT_SIZE).cloned().collect();
+    }
+
+    /// Encode consecutive groups of 8 PSG writes as runs (see the module documentation)
+    pub fn enable_psg_runs(&mut self) {
+        self.runs = true;
+    }
+
+    /// Write out the current group, if it has any commands. All-PSG groups are added to the
+    /// current run instead when runs are enabled.
+    fn flush_group(&mut self) {
+        if self.num_flags == 0 {
+            return;
+        }
+        while self.num_flags < 8 {
+            self.pending_data.push(Command::NOP);
+            self.num_flags += 1;
+        }
+        if self.runs && self.flags == 0xFF {
+            self.pending_run.append(&mut self.pending_data);
+            if self.pending_run.len() == MAX_RUN_GROUPS * 8 {
+                self.flush_run();
+            }
+        } else {
+            self.flush_run();
+            self.output.write(self.flags);
+            self.output.write_n(&self.pending_data);
+            self.pending_data.clear();
+        }
+        self.flags = 0;
+        self.num_flags = 0;
+    }
+
+    /// Write out the current run, if there is one
+    fn flush_run(&mut self) {
+        if !self.pending_run.is_empty() {
+            self.output.write(0xFF);
+            self.output.write((self.pending_run.len() / 8) as u8);
+            self.output.write_n(&self.pending_run);
+            self.pending_run.clear();
+        }
+    }
+
+    /// Add the argument of a PSG write (0x50) to the current group
+    fn write_psg(&mut self, value: u8) {
+        if self.num_flags == 8 {
+            self.flush_group();
+        }
+        self.flags |= 1 << self.num_flags;
+        self.pending_data.push(value);
+        self.num_flags += 1;
+    }
+
+    /// Add a command other than a PSG write to the current group, as is
+    fn write_command(&mut self, command: &[u8]) {
+        if self.num_flags == 8 {
+            self.flush_group();
+        }
+        self.pending_data.extend_from_slice(command);
+        self.num_flags += 1;
+    }
+
+    /// Add a long wait (0x61) to the current group, through the LUT if possible
+    fn write_long_wait(&mut self, duration: u16) {
+        let pos = self.long_wait_table.iter().position(|&x| x == duration);
+        if let Some(idx) = pos {
+            self.write_command(&[Command::WAIT_LONG_THRU_LUT | (idx as u8)]);
+        } else if self.long_wait_table.len() < LONG_WAIT_LUT_SIZE {
+            // No match found, but there's space left in the LUT, so add the current value
+            self.write_command(&[Command::WAIT_LONG_THRU_LUT | (self.long_wait_table.len() as u8)]);
+            self.long_wait_table.push(duration);
+        } else {
+            // No match could be found in the table. Store the entire command uncompressed.
+            let [lo, hi] = duration.to_le_bytes();
+            self.write_command(&[Command::WAIT_LONG, lo, hi]);
+        }
+    }
+}
+
+impl<'a> Codec<'a> for PsgCodec<'a> {
+    fn new(out: &'a mut ByteStream) -> PsgCodec<'a> {
+        PsgCodec {
+            output: out,
+            pending_data: Vec::new(),
+            long_wait_table: Vec::new(),
+            flags: 0,
+            num_flags: 0,
+            runs: false,
+            pending_run: Vec::new(),
+        }
+    }
+    
+    fn get_extra_data(&self, what: u32) -> Option<Vec<u8>> {
+        match what {
+            GET_LONG_WAIT_LUT => {
+                let block_type = if self.runs { PSG_RUNS_LUT_BLOCK_TYPE } else { LONG_WAIT_LUT_BLOCK_TYPE };
+                let mut table = vec![Command::DATA_BLOCK, 0x66, block_type, 0x20, 0x00, 0x00, 0x00];
+                table.resize(LONG_WAIT_LUT_SIZE * 2 + 7, 0);
+                for (i, wait) in self.long_wait_table.iter().enumerate() {
+                    table[7 + i*2] = (wait & 0xFF) as u8;
+                    table[7 + i*2 + 1] = (wait >> 8) as u8;
+                }
+                Some(table)
+            }
+            _ => None
+        }
+    }
+
+    fn output_len(&self) -> usize {
+        self.output.len()
+    }
+
+    fn passthrough(&mut self, c: u8) {
+        self.output.write(c);
+    }
+
+    fn write_event(&mut self, event: &VgmEvent) {
+        let mut bytes = Vec::new();
+        match event {
+            VgmEvent::PsgWrite(value) => self.write_psg(*value),
+            VgmEvent::Wait(_) => {
+                // A wait can be encoded as several commands, each of which takes up one flag
+                event.write_to(&mut bytes);
+                let mut i = 0;
+                while i < bytes.len() {
+                    if bytes[i] == Command::WAIT_LONG {
+                        self.write_long_wait(u16::from_le_bytes([bytes[i + 1], bytes[i + 2]]));
+                        i += 3;
+                    } else {
+                        self.write_command(&bytes[i..i + 1]);
+                        i += 1;
+                    }
+                }
+            }
+            _ => {
+                event.write_to(&mut bytes);
+                self.write_command(&bytes);
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        self.flush_group();
+        self.flush_run();
+    }
+}
+
+/// Return the long wait durations that save the most bytes when placed in the LUT for encoding
+/// `events`. Every long wait that is found in the LUT saves the same number of bytes, so these are
+/// the (up to) 16 most frequent durations. They're returned in the order they first appear, to
+/// keep the LUT the same as with first-come-first-served assignment when all durations fit.
+pub fn select_long_waits(events: &[VgmEvent]) -> Vec<u16> {
+    let mut counts: Vec<(u16, usize)> = Vec::new();
+    let mut bytes = Vec::new();
+    for event in events {
+        if let VgmEvent::Wait(_) = event {
+            bytes.clear();
+            event.write_to(&mut bytes);
+            let mut i = 0;
+            while i < bytes.len() {
+                if bytes[i] == Command::WAIT_LONG {
+                    let duration = u16::from_le_bytes([bytes[i + 1], bytes[i + 2]]);
+                    match counts.iter_mut().find(|(d, _)| *d == duration) {
+                        Some((_, count)) => *count += 1,
+                        None => counts.push((duration, 1)),
+                    }
+                    i += 3;
+                } else {
+                    i += 1;
+                }
+            }
+        }
+    }
+
+    // Stable sort, so that ties go to the duration that appears first
+    let mut by_frequency: Vec<usize> = (0..counts.len()).collect();
+    by_frequency.sort_by(|&a, &b| counts[b].1.cmp(&counts[a].1));
+    by_frequency.truncate(LONG_WAIT_LUT_SIZE);
+    by_frequency.sort_unstable();
+    by_frequency.iter().map(|&i| counts[i].0).collect()
+}
+
+/// Replace the long waits in `events` that aren't in `lut` with the closest LUT entry, if it's at
+/// most `tolerance` samples off. Returns the number of waits replaced, and the change in the length
+/// of the song in samples.
+pub fn quantize_long_waits(events: &mut [VgmEvent], lut: &[u16], tolerance: u16) -> (usize, i64) {
+    let mut replaced = 0;
+    let mut change = 0i64;
+    for event in events.iter_mut() {
+        let samples = match *event {
+            VgmEvent::Wait(samples) if event.command() == Command::WAIT_LONG && samples <= 0xFFFF => samples,
+            _ => continue,
+        };
+        if lut.contains(&(samples as u16)) {
+            continue;
+        }
+        let closest = lut.iter().min_by_key(|&&entry| (entry as i64 - samples as i64).abs());
+        if let Some(&entry) = closest {
+            if (entry as i64 - samples as i64).abs() <= tolerance as i64 {
+                *event = VgmEvent::Wait(entry as u32);
+                replaced += 1;
+                change += entry as i64 - samples as i64;
+            }
+        }
+    }
+    (replaced, change)
+}
+
+/// Decode the output of the codec back into VGM events, up to and including the end of sound
+/// data command. `lut` is the long wait LUT, and `runs` tells if the stream has PSG runs. Each
+/// event is paired with its offset in `data`.
+pub fn decode(data: &[u8], lut: &[u16], runs: bool) -> Result<Vec<(usize, VgmEvent)>, Error> {
+    let mut stream = ByteStream::new(data.to_vec());
+    let mut events = Vec::new();
+    while stream.available() > 0 {
+        let flags = stream.read();
+        if runs && flags == 0xFF {
+            if stream.available() == 0 {
+                break;
+            }
+            for _ in 0..stream.read() as usize * 8 {
+                if stream.available() == 0 {
+                    break;
+                }
+                let pos = stream.get_pos();
+                events.push((pos, VgmEvent::PsgWrite(stream.read())));
+            }
+            continue;
+        }
+        for i in 0..8 {
+            if stream.available() == 0 {
+                break;
+            }
+            let pos = stream.get_pos();
+            if flags & (1 << i) != 0 {
+                events.push((pos, VgmEvent::PsgWrite(stream.read())));
+                continue;
+            }
+            match stream.read() {
+                Command::NOP => {}
+                c @ Command::WAIT_LONG_THRU_LUT ..= 0x9F => match lut.get((c & 0x0F) as usize) {
+                    Some(&samples) => events.push((pos, VgmEvent::Wait(samples as u32))),
+                    None => return Err(Error::new(ErrorKind::InvalidData, format!("LUT wait 0x{:02X} at offset 0x{:X} is outside of the LUT", c, pos))),
+                },
+                _ => {
+                    stream.reset();
+                    stream.skip(pos);
+                    let event = Events::new(&mut stream).next().unwrap()?;
+                    let end = event == VgmEvent::End;
+                    events.push((pos, event));
+                    if end {
+                        return Ok(events);
+                    }
+                }
+            }
+        }
+    }
+    Err(Error::new(ErrorKind::UnexpectedEof, "No end of sound data command found"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_psg() {
+        let mut bs = ByteStream::new(Vec::new());
+        let mut codec = PsgCodec::new(&mut bs);
+        assert_eq!(codec.flags, 0);
+        codec.write_event(&VgmEvent::PsgWrite(0x12));
+        assert_eq!(codec.output.len(), 0);
+        assert_eq!(codec.pending_data, vec![0x12]);
+        assert_eq!(codec.flags, 1);
+        assert_eq!(codec.num_flags, 1);
+    }
+
+    #[test]
+    fn test_write_long_wait() {
+        let mut bs = ByteStream::new(Vec::new());
+        let mut codec = PsgCodec::new(&mut bs);
+        codec.write_event(&VgmEvent::Wait(0x3412));
+        assert_eq!(codec.pending_data, vec![Command::WAIT_LONG_THRU_LUT]);
+        assert_eq!(codec.long_wait_table, vec![0x3412]);
+        assert_eq!(codec.num_flags, 1);
+
+        // Longer than a single 0x61 command
+        codec.write_event(&VgmEvent::Wait(0xFFFF + 0x3412));
+        assert_eq!(codec.pending_data, vec![Command::WAIT_LONG_THRU_LUT, Command::WAIT_LONG_THRU_LUT + 1, Command::WAIT_LONG_THRU_LUT]);
+        assert_eq!(codec.num_flags, 3);
+    }
+    
+    #[test]
+    fn test_implicit_flush() {
+        let mut bs = ByteStream::new(Vec::new());
+        let mut codec = PsgCodec::new(&mut bs);
+        assert_eq!(codec.flags, 0);
+        for _ in 0..9 {
+            codec.write_event(&VgmEvent::PsgWrite(0x12));
+        }
+        assert_eq!(codec.output.len(), 9);
+        assert_eq!(codec.pending_data.len(), 1);
+        assert_eq!(codec.flags, 1);
+        assert_eq!(codec.num_flags, 1);
+    }
+
+    #[test]
+    fn test_data_block() {
+        let mut bs = ByteStream::new(Vec::new());
+        let mut codec = PsgCodec::new(&mut bs);
+        let events = vec![VgmEvent::PsgWrite(0x9F), VgmEvent::DataBlock { block_type: 0, data: vec![0x50, 0x61, 0x66, 0x00] },
+                          VgmEvent::Command { command: Command::YM2612_LO_WRITE, args: vec![0x2A, 0x80] }, VgmEvent::End];
+        for event in &events {
+            codec.write_event(event);
+        }
+        codec.flush();
+        let decoded = decode(bs.as_slice(), &[], false).unwrap();
+        assert_eq!(decoded.into_iter().map(|(_, e)| e).collect::<Vec<_>>(), events);
+    }
+
+    #[test]
+    fn test_select_long_waits() {
+        // 17 durations used once, followed by one used three times
+        let mut events: Vec<VgmEvent> = (0..17).map(|i| VgmEvent::Wait(1000 + i)).collect();
+        events.extend(vec![VgmEvent::Wait(2000); 3]);
+        events.push(VgmEvent::Wait(735));
+        let waits = select_long_waits(&events);
+        assert_eq!(waits.len(), 16);
+        assert_eq!(waits[..15], (1000..1015).collect::<Vec<u16>>()[..]);
+        assert_eq!(waits[15], 2000);
+
+        let mut bs = ByteStream::new(Vec::new());
+        let mut codec = PsgCodec::new(&mut bs);
+        codec.preload_long_waits(&waits);
+        for event in &events {
+            codec.write_event(event);
+        }
+        codec.flush();
+        assert_eq!(codec.long_wait_table, waits);
+    }
+
+    #[test]
+    fn test_quantize_long_waits() {
+        let mut events = vec![VgmEvent::Wait(1000), VgmEvent::Wait(1003), VgmEvent::Wait(1010),
+                              VgmEvent::Wait(735), VgmEvent::Wait(740)];
+        assert_eq!(quantize_long_waits(&mut events, &[1000, 738], 3), (2, -3 - 2));
+        assert_eq!(events, vec![VgmEvent::Wait(1000), VgmEvent::Wait(1000), VgmEvent::Wait(1010),
+                                VgmEvent::Wait(735), VgmEvent::Wait(738)]);
+    }
+
+    #[test]
+    fn test_decode() {
+        let mut bs = ByteStream::new(Vec::new());
+        let mut codec = PsgCodec::new(&mut bs);
+        let events = vec![VgmEvent::PsgWrite(0x9F), VgmEvent::Wait(1000), VgmEvent::Wait(735), VgmEvent::PsgWrite(0x80),
+                          VgmEvent::Wait(1000), VgmEvent::Wait(3), VgmEvent::End];
+        for event in &events {
+            codec.write_event(event);
+        }
+        codec.flush();
+        let lut = codec.long_wait_table.clone();
+        let decoded = decode(bs.as_slice(), &lut, false).unwrap();
+        assert_eq!(decoded.iter().map(|(_, e)| e.clone()).collect::<Vec<_>>(), events);
+        assert_eq!(decoded[0].0, 1);    // After the flag byte
+    }
+
+    #[test]
+    fn test_psg_runs() {
+        let mut bs = ByteStream::new(Vec::new());
+        let mut codec = PsgCodec::new(&mut bs);
+        codec.enable_psg_runs();
+        let mut events: Vec<VgmEvent> = (0..24).map(VgmEvent::PsgWrite).collect();
+        events.push(VgmEvent::Wait(735));
+        events.extend((0..15).map(VgmEvent::PsgWrite));
+        events.push(VgmEvent::End);
+        for event in &events {
+            codec.write_event(event);
+        }
+        codec.flush();
+        assert_eq!(codec.get_extra_data(GET_LONG_WAIT_LUT).unwrap()[2], PSG_RUNS_LUT_BLOCK_TYPE);
+        // A run of 3 groups, a group with the wait, a run of 1 group, and a group with the end
+        assert_eq!(&bs.as_slice()[..3], &[0xFF, 3, 0]);
+        assert_eq!(&bs.as_slice()[26..29], &[0xFE, Command::WAIT_NTSC_FRAME, 0]);
+        assert_eq!(&bs.as_slice()[35..38], &[0xFF, 1, 7]);
+        assert_eq!(bs.len(), 2 + 24 + 9 + 2 + 8 + 9);
+        let decoded = decode(bs.as_slice(), &[], true).unwrap();
+        assert_eq!(decoded.into_iter().map(|(_, e)| e).collect::<Vec<_>>(), events);
+    }
+}
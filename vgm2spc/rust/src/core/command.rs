@@ -0,0 +1,114 @@
+//!
+//! The VGM command bytes, and how many argument bytes each command has.
+//!
+
+/// Enumeration of the VGM commands used by the converter (see https://vgmrips.net/wiki/VGM_Specification).
+/// Commands that are only ever skipped are covered by `num_argument_bytes`.
+#[allow(non_snake_case, dead_code)]
+pub mod Command {
+    pub const UNDEFINED: u8 = 0;          // not part of the VGM spec
+    pub const NOP: u8 = 0x4E;             // not part of the VGM spec
+	pub const PSG2_WRITE: u8 = 0x30;
+	pub const AY8910_STEREO_MASK: u8 = 0x31;
+	pub const PSG2_GG_STEREO: u8 = 0x3F;
+	pub const GG_STEREO: u8 = 0x4F;
+	pub const PSG_WRITE: u8 = 0x50;
+	pub const YM2413_WRITE: u8 = 0x51;
+	pub const YM2612_LO_WRITE: u8 = 0x52;
+	pub const YM2612_HI_WRITE: u8 = 0x53;
+	pub const YM2151_WRITE: u8 = 0x54;
+	pub const YM2203_WRITE: u8 = 0x55;
+	pub const YM2608_LO_WRITE: u8 = 0x56;
+	pub const YM2608_HI_WRITE: u8 = 0x57;
+	pub const YM3812_WRITE: u8 = 0x5A;
+	pub const YM3526_WRITE: u8 = 0x5B;
+	pub const YMF262_LO_WRITE: u8 = 0x5E;
+	pub const YMF262_HI_WRITE: u8 = 0x5F;
+	pub const WAIT_LONG: u8 = 0x61;
+	pub const WAIT_NTSC_FRAME: u8 = 0x62;
+	pub const WAIT_PAL_FRAME: u8 = 0x63;
+	pub const WAIT_OVERRIDE: u8 = 0x64;
+	pub const END_OF_SOUND_DATA: u8 = 0x66;
+	pub const DATA_BLOCK: u8 = 0x67;
+	pub const PCM_WRITE: u8 = 0x68;
+	pub const WAIT_1: u8 = 0x70;
+	pub const WAIT_16: u8 = 0x7F;
+	pub const YM2612_WRITE_LO_WAIT_0: u8 = 0x80; 
+	pub const YM2612_WRITE_LO_WAIT_15: u8 = 0x8F;
+    pub const WAIT_LONG_THRU_LUT: u8 = 0x90; // not part of the VGM spec
+	pub const DAC_STREAM_SETUP: u8 = 0x90;
+	pub const DAC_STREAM_SET_DATA: u8 = 0x91;
+	pub const DAC_STREAM_SET_FREQUENCY: u8 = 0x92;
+	pub const DAC_STREAM_START: u8 = 0x93;
+	pub const DAC_STREAM_STOP: u8 = 0x94;
+	pub const DAC_STREAM_START_FAST: u8 = 0x95;
+	pub const AY8910_WRITE: u8 = 0xA0;
+	pub const SEEK_PCM: u8 = 0xE0;
+}
+
+/// Returns the number of argument bytes expected by VGM command `cmd`, as of VGM 1.71.
+///
+/// Data blocks (0x67) have a variable size and are reported as having no arguments; the caller
+/// has to read the block size itself.
+pub fn num_argument_bytes(cmd: u8) -> u32 {
+    match cmd {
+        0x30 ..= 0x3F => 1,
+        0x40 => 2,
+        0x41 ..= 0x4E => 2,
+        Command::GG_STEREO | Command::PSG_WRITE => 1,
+        0x51 ..= 0x5F => 2,
+        Command::WAIT_LONG => 2,
+        Command::WAIT_OVERRIDE => 3,
+        Command::PCM_WRITE => 11,
+        Command::DAC_STREAM_SETUP => 4,
+        Command::DAC_STREAM_SET_DATA => 4,
+        Command::DAC_STREAM_SET_FREQUENCY => 5,
+        Command::DAC_STREAM_START => 10,
+        Command::DAC_STREAM_STOP => 1,
+        Command::DAC_STREAM_START_FAST => 4,
+        0xA0 ..= 0xBF => 2,
+        0xC0 ..= 0xDF => 3,
+        0xE0 ..= 0xFF => 4,
+        _ => 0,
+    }
+}
+
+/// Returns true if `cmd` is in one of the ranges that the VGM spec (1.71) reserves for future use.
+/// The spec defines the argument count for these, so they can be skipped safely.
+pub fn is_reserved(cmd: u8) -> bool {
+    matches!(cmd, 0x32 ..= 0x3E | 0x41 ..= 0x4E | 0xC9 ..= 0xCF | 0xD7 ..= 0xDF | 0xE2 ..= 0xFF)
+}
+
+/// Returns true if `cmd` isn't defined by the VGM spec at all, i.e. its argument count is unknown.
+pub fn is_undefined(cmd: u8) -> bool {
+    matches!(cmd, 0x00 ..= 0x2F | 0x65 | 0x69 ..= 0x6F | 0x96 ..= 0x9F)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_num_argument_bytes() {
+        assert_eq!(num_argument_bytes(Command::PSG_WRITE), 1);
+        assert_eq!(num_argument_bytes(Command::AY8910_STEREO_MASK), 1);
+        assert_eq!(num_argument_bytes(Command::YM2151_WRITE), 2);
+        assert_eq!(num_argument_bytes(0x5F), 2);
+        assert_eq!(num_argument_bytes(Command::WAIT_NTSC_FRAME), 0);
+        assert_eq!(num_argument_bytes(Command::WAIT_1), 0);
+        assert_eq!(num_argument_bytes(Command::YM2612_WRITE_LO_WAIT_15), 0);
+        assert_eq!(num_argument_bytes(Command::DAC_STREAM_START), 10);
+        assert_eq!(num_argument_bytes(Command::AY8910_WRITE), 2);
+        assert_eq!(num_argument_bytes(0xC0), 3);
+        assert_eq!(num_argument_bytes(0xE1), 4);
+    }
+
+    #[test]
+    fn test_command_classes() {
+        assert!(!is_reserved(Command::PSG_WRITE) && !is_undefined(Command::PSG_WRITE));
+        assert!(is_reserved(0x32) && is_reserved(0xFF));
+        assert!(!is_reserved(0xE1));
+        assert!(is_undefined(0x00) && is_undefined(0x65) && is_undefined(0x96));
+        assert!(!is_undefined(Command::DAC_STREAM_START_FAST));
+    }
+}
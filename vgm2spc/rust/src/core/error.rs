@@ -0,0 +1,39 @@
+//!
+//! Errors from parsing and decoding command streams. `std::io::Error` isn't available without std,
+//! so the core modules report this type instead.
+//!
+
+use alloc::string::String;
+use core::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The data ends in the middle of a command, or without an end of sound data command
+    UnexpectedEof,
+    /// The data can't be parsed
+    InvalidData,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Error {
+    kind: ErrorKind,
+    message: String,
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Error { kind, message: message.into() }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl core::error::Error for Error {}
@@ -0,0 +1,274 @@
+//!
+//! Parsed representation of the VGM command stream.
+//!
+//! `Events` reads commands from a `ByteStream` and yields them as `VgmEvent`s, so that code which
+//! works on the command stream doesn't have to know the argument layout of every command.
+//!
+
+use alloc::format;
+use alloc::vec::Vec;
+
+use crate::core::bytestream::ByteStream;
+use crate::core::command::{self, Command};
+use crate::core::error::{Error, ErrorKind};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum VgmEvent {
+    PsgWrite(u8),
+    GgStereo(u8),
+    /// Wait for a number of samples (0x61, 0x62, 0x63 and 0x7n)
+    Wait(u32),
+    /// Change the number of samples that a 0x62 or 0x63 command waits (0x64). `Events` applies
+    /// the new duration to the `Wait`s that follow.
+    WaitOverride { command: u8, samples: u16 },
+    /// Write the next byte of the YM2612 PCM data bank to the DAC, then wait 0-15 samples (0x8n)
+    Ym2612DacWrite { wait: u8 },
+    DataBlock { block_type: u8, data: Vec<u8> },
+    SeekPcm(u32),
+    /// Any other command whose argument count is known, e.g. writes to other chips
+    Command { command: u8, args: Vec<u8> },
+    /// A command that isn't defined by the VGM spec. Its argument count is unknown, so it's
+    /// assumed to have none.
+    Unknown(u8),
+    End,
+}
+
+impl VgmEvent {
+    /// Return the command byte that the event starts with when encoded. For waits, this is the
+    /// first command that `write_to` would use.
+    pub fn command(&self) -> u8 {
+        match self {
+            VgmEvent::PsgWrite(_) => Command::PSG_WRITE,
+            VgmEvent::GgStereo(_) => Command::GG_STEREO,
+            VgmEvent::Wait(samples @ 1 ..= 16) => Command::WAIT_1 + (*samples - 1) as u8,
+            VgmEvent::Wait(735) => Command::WAIT_NTSC_FRAME,
+            VgmEvent::Wait(882) => Command::WAIT_PAL_FRAME,
+            VgmEvent::Wait(_) => Command::WAIT_LONG,
+            VgmEvent::WaitOverride { .. } => Command::WAIT_OVERRIDE,
+            VgmEvent::Ym2612DacWrite { wait } => Command::YM2612_WRITE_LO_WAIT_0 | (wait & 0x0F),
+            VgmEvent::DataBlock { .. } => Command::DATA_BLOCK,
+            VgmEvent::SeekPcm(_) => Command::SEEK_PCM,
+            VgmEvent::Command { command, .. } => *command,
+            VgmEvent::Unknown(command) => *command,
+            VgmEvent::End => Command::END_OF_SOUND_DATA,
+        }
+    }
+
+    /// Return the number of samples that playback waits after the event
+    pub fn samples(&self) -> u32 {
+        match self {
+            VgmEvent::Wait(samples) => *samples,
+            VgmEvent::Ym2612DacWrite { wait } => *wait as u32,
+            _ => 0,
+        }
+    }
+
+    /// Append the VGM encoding of the event to `out`. Waits are encoded using the shortest
+    /// command(s) possible, so they won't necessarily come out the same way they were read.
+    pub fn write_to(&self, out: &mut Vec<u8>) {
+        match self {
+            VgmEvent::PsgWrite(value) => out.extend(&[Command::PSG_WRITE, *value]),
+            VgmEvent::GgStereo(value) => out.extend(&[Command::GG_STEREO, *value]),
+            VgmEvent::Wait(samples) => {
+                let mut remaining = *samples;
+                while remaining > 0 {
+                    match remaining {
+                        1 ..= 16 => {
+                            out.push(Command::WAIT_1 + (remaining - 1) as u8);
+                            remaining = 0;
+                        }
+                        735 => {
+                            out.push(Command::WAIT_NTSC_FRAME);
+                            remaining = 0;
+                        }
+                        882 => {
+                            out.push(Command::WAIT_PAL_FRAME);
+                            remaining = 0;
+                        }
+                        _ => {
+                            let n = core::cmp::min(remaining, 0xFFFF);
+                            out.push(Command::WAIT_LONG);
+                            out.extend(&(n as u16).to_le_bytes());
+                            remaining -= n;
+                        }
+                    }
+                }
+            }
+            VgmEvent::WaitOverride { command, samples } => {
+                out.extend(&[Command::WAIT_OVERRIDE, *command]);
+                out.extend(&samples.to_le_bytes());
+            }
+            VgmEvent::Ym2612DacWrite { wait } => out.push(Command::YM2612_WRITE_LO_WAIT_0 | (wait & 0x0F)),
+            VgmEvent::DataBlock { block_type, data } => {
+                out.extend(&[Command::DATA_BLOCK, 0x66, *block_type]);
+                out.extend(&(data.len() as u32).to_le_bytes());
+                out.extend(data);
+            }
+            VgmEvent::SeekPcm(offset) => {
+                out.push(Command::SEEK_PCM);
+                out.extend(&offset.to_le_bytes());
+            }
+            VgmEvent::Command { command, args } => {
+                out.push(*command);
+                out.extend(args);
+            }
+            VgmEvent::Unknown(command) => out.push(*command),
+            VgmEvent::End => out.push(Command::END_OF_SOUND_DATA),
+        }
+    }
+}
+
+/// Iterator over the events in a command stream, starting at the current position of the stream.
+/// Iteration stops after the `End` event, or after the first error.
+pub struct Events<'a> {
+    stream: &'a mut ByteStream,
+    frame_waits: [u32; 2],      // Durations of 0x62 and 0x63, which can be changed by 0x64
+    done: bool,
+}
+
+impl<'a> Events<'a> {
+    pub fn new(stream: &'a mut ByteStream) -> Self {
+        Events { stream, frame_waits: [735, 882], done: false }
+    }
+
+    /// Return the position in the stream of the next event
+    pub fn position(&self) -> usize {
+        self.stream.get_pos()
+    }
+
+    fn read_args(&mut self, command: u8, n: usize) -> Result<Vec<u8>, Error> {
+        if self.stream.available() < n {
+            return Err(Error::new(ErrorKind::UnexpectedEof,
+                format!("Command 0x{:02X} at offset 0x{:X} is truncated", command, self.stream.get_pos() - 1)));
+        }
+        Ok(self.stream.read_n(n))
+    }
+
+    fn read_event(&mut self) -> Result<VgmEvent, Error> {
+        let c = self.stream.read();
+        let event = match c {
+            Command::PSG_WRITE => VgmEvent::PsgWrite(self.read_args(c, 1)?[0]),
+            Command::GG_STEREO => VgmEvent::GgStereo(self.read_args(c, 1)?[0]),
+            Command::WAIT_LONG => {
+                let args = self.read_args(c, 2)?;
+                VgmEvent::Wait(u16::from_le_bytes([args[0], args[1]]) as u32)
+            }
+            Command::WAIT_NTSC_FRAME => VgmEvent::Wait(self.frame_waits[0]),
+            Command::WAIT_PAL_FRAME => VgmEvent::Wait(self.frame_waits[1]),
+            Command::WAIT_OVERRIDE => {
+                let args = self.read_args(c, 3)?;
+                let samples = u16::from_le_bytes([args[1], args[2]]);
+                match args[0] {
+                    Command::WAIT_NTSC_FRAME => self.frame_waits[0] = samples as u32,
+                    Command::WAIT_PAL_FRAME => self.frame_waits[1] = samples as u32,
+                    _ => {}
+                }
+                VgmEvent::WaitOverride { command: args[0], samples }
+            }
+            Command::WAIT_1 ..= Command::WAIT_16 => VgmEvent::Wait((c & 0x0F) as u32 + 1),
+            Command::YM2612_WRITE_LO_WAIT_0 ..= Command::YM2612_WRITE_LO_WAIT_15 => VgmEvent::Ym2612DacWrite { wait: c & 0x0F },
+            Command::END_OF_SOUND_DATA => VgmEvent::End,
+            Command::DATA_BLOCK => {
+                let header = self.read_args(c, 6)?;
+                if header[0] != 0x66 {
+                    return Err(Error::new(ErrorKind::InvalidData,
+                        format!("Illegal command: 0x67 0x{:X} at offset 0x{:X}", header[0], self.stream.get_pos() - 6)));
+                }
+                let size = u32::from_le_bytes([header[2], header[3], header[4], header[5]]) as usize;
+                VgmEvent::DataBlock { block_type: header[1], data: self.read_args(c, size)? }
+            }
+            Command::SEEK_PCM => {
+                let args = self.read_args(c, 4)?;
+                VgmEvent::SeekPcm(u32::from_le_bytes([args[0], args[1], args[2], args[3]]))
+            }
+            _ if command::is_undefined(c) => VgmEvent::Unknown(c),
+            _ => VgmEvent::Command { command: c, args: self.read_args(c, command::num_argument_bytes(c) as usize)? },
+        };
+        Ok(event)
+    }
+}
+
+impl<'a> Iterator for Events<'a> {
+    type Item = Result<VgmEvent, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        if self.stream.available() == 0 {
+            self.done = true;
+            return Some(Err(Error::new(ErrorKind::UnexpectedEof, "No end of sound data command found")));
+        }
+        let event = self.read_event();
+        self.done = !matches!(event, Ok(ref e) if *e != VgmEvent::End);
+        Some(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use super::*;
+
+    #[test]
+    fn test_events() {
+        let data = vec![Command::PSG_WRITE, 0x9F, Command::WAIT_LONG, 0x00, 0x01, Command::WAIT_1 + 3,
+                        Command::DATA_BLOCK, 0x66, 0x00, 2, 0, 0, 0, 0xAA, 0xBB, Command::YM2612_WRITE_LO_WAIT_0 + 2,
+                        Command::YM2151_WRITE, 0x08, 0x00, 0x96, Command::END_OF_SOUND_DATA, Command::PSG_WRITE];
+        let mut stream = ByteStream::new(data.clone());
+        let events: Vec<VgmEvent> = Events::new(&mut stream).map(|e| e.unwrap()).collect();
+        assert_eq!(events, vec![
+            VgmEvent::PsgWrite(0x9F),
+            VgmEvent::Wait(256),
+            VgmEvent::Wait(4),
+            VgmEvent::DataBlock { block_type: 0, data: vec![0xAA, 0xBB] },
+            VgmEvent::Ym2612DacWrite { wait: 2 },
+            VgmEvent::Command { command: Command::YM2151_WRITE, args: vec![0x08, 0x00] },
+            VgmEvent::Unknown(0x96),
+            VgmEvent::End,
+        ]);
+        assert_eq!(stream.get_pos(), data.len() - 1);
+
+        let mut encoded = Vec::new();
+        for event in &events {
+            event.write_to(&mut encoded);
+        }
+        assert_eq!(&encoded[..], &data[..data.len() - 1]);
+        assert_eq!(events.iter().map(VgmEvent::command).collect::<Vec<_>>(),
+                   vec![0x50, 0x61, 0x73, 0x67, 0x82, 0x54, 0x96, 0x66]);
+        assert_eq!(events.iter().map(VgmEvent::samples).sum::<u32>(), 256 + 4 + 2);
+    }
+
+    #[test]
+    fn test_truncated() {
+        let mut stream = ByteStream::new(vec![Command::WAIT_1, Command::YM2612_LO_WRITE, 0x28]);
+        let mut events = Events::new(&mut stream);
+        assert_eq!(events.next().unwrap().unwrap(), VgmEvent::Wait(1));
+        assert_eq!(events.next().unwrap().unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        assert!(events.next().is_none());
+    }
+
+    #[test]
+    fn test_wait_override() {
+        let mut stream = ByteStream::new(vec![Command::WAIT_NTSC_FRAME, Command::WAIT_OVERRIDE, Command::WAIT_NTSC_FRAME, 0x20, 0x03,
+                                              Command::WAIT_NTSC_FRAME, Command::WAIT_PAL_FRAME, Command::END_OF_SOUND_DATA]);
+        let events: Vec<VgmEvent> = Events::new(&mut stream).map(|e| e.unwrap()).collect();
+        assert_eq!(events, vec![
+            VgmEvent::Wait(735),
+            VgmEvent::WaitOverride { command: Command::WAIT_NTSC_FRAME, samples: 800 },
+            VgmEvent::Wait(800),
+            VgmEvent::Wait(882),
+            VgmEvent::End,
+        ]);
+        let mut out = Vec::new();
+        events[2].write_to(&mut out);
+        assert_eq!(out, vec![Command::WAIT_LONG, 0x20, 0x03]);
+    }
+
+    #[test]
+    fn test_write_long_wait() {
+        let mut out = Vec::new();
+        VgmEvent::Wait(0x10000 + 735).write_to(&mut out);
+        assert_eq!(out, vec![Command::WAIT_LONG, 0xFF, 0xFF, Command::WAIT_LONG, 0xE0, 0x02]);
+    }
+}
@@ -0,0 +1,18 @@
+//!
+//! The parts of the converter that only need `core` and `alloc`: the byte stream, the VGM command
+//! table, the command stream parser, the codecs and the PSG register tracking. They can be used without std (e.g. in
+//! flashcart menu firmware that compresses songs on the fly), given a global allocator. The
+//! `nostd` crate in the workspace builds this module with `#![no_std]` to keep it that way.
+//!
+//! File IO, preprocessing and SPC assembly stay outside of this module, and convert `Error` into
+//! `std::io::Error` where needed.
+//!
+
+#![deny(clippy::std_instead_of_core, clippy::std_instead_of_alloc)]
+
+pub mod bytestream;
+pub mod codec;
+pub mod command;
+pub mod error;
+pub mod events;
+pub mod psg;
@@ -0,0 +1,61 @@
+//!
+//! Tracking of the SN76489 registers, for code that needs to know the state of the chip at some
+//! point in the command stream.
+//!
+
+/// SN76489 register state
+#[derive(Clone, Debug, PartialEq)]
+pub struct PsgState {
+    pub periods: [u16; 3],
+    pub noise: u8,
+    pub attenuation: [u8; 4],
+    pub latched_channel: usize,
+    pub latched_volume: bool,
+}
+
+impl Default for PsgState {
+    /// All channels silent, with the tone registers latched for channel 0
+    fn default() -> Self {
+        PsgState { periods: [0; 3], noise: 0, attenuation: [0x0F; 4], latched_channel: 0, latched_volume: false }
+    }
+}
+
+impl PsgState {
+    /// Apply the PSG write `value`
+    pub fn write(&mut self, value: u8) {
+        if (value & 0x80) != 0 {
+            self.latched_channel = ((value >> 5) & 3) as usize;
+            self.latched_volume = (value & 0x10) != 0;
+        }
+        let channel = self.latched_channel;
+        if self.latched_volume {
+            self.attenuation[channel] = value & 0x0F;
+        } else if channel == 3 {
+            self.noise = value & 0x07;
+        } else if (value & 0x80) != 0 {
+            self.periods[channel] = (self.periods[channel] & 0x3F0) | (value & 0x0F) as u16;
+        } else {
+            self.periods[channel] = (self.periods[channel] & 0x00F) | ((value & 0x3F) as u16) << 4;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write() {
+        let mut psg = PsgState::default();
+        // Channel 1 period 0x123, noise mode 6, channel 2 volume 5
+        for &value in &[0xA3, 0x12, 0xE6, 0xD5] {
+            psg.write(value);
+        }
+        assert_eq!(psg.periods, [0, 0x123, 0]);
+        assert_eq!(psg.attenuation, [0x0F, 0x0F, 5, 0x0F]);
+        assert_eq!(psg.noise, 6);
+        // A data byte after a volume latch changes the volume
+        psg.write(0x07);
+        assert_eq!(psg.attenuation[2], 7);
+    }
+}
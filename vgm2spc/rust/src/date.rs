@@ -0,0 +1,95 @@
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A calendar date, as stored in the ID666 dump date field
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Date {
+    pub year: u32,
+    pub month: u32,
+    pub day: u32,
+}
+
+impl Date {
+    pub fn new(year: u32, month: u32, day: u32) -> Option<Self> {
+        if month == 0 || month > 12 || day == 0 || day > days_in_month(year, month) {
+            None
+        } else {
+            Some(Date { year, month, day })
+        }
+    }
+
+    /// Return the current date (UTC)
+    pub fn today() -> Self {
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        Self::from_days_since_epoch((secs / 86400) as i64)
+    }
+
+    /// Parse a date on the form YYYY-MM-DD
+    pub fn parse(s: &str) -> Option<Self> {
+        let parts: Vec<&str> = s.split('-').collect();
+        if parts.len() != 3 || parts[0].len() != 4 || parts[1].len() != 2 || parts[2].len() != 2 {
+            return None;
+        }
+        let year = parts[0].parse().ok()?;
+        let month = parts[1].parse().ok()?;
+        let day = parts[2].parse().ok()?;
+        Self::new(year, month, day)
+    }
+
+    /// Convert a day count relative to 1970-01-01 into a date
+    /// (see http://howardhinnant.github.io/date_algorithms.html#civil_from_days)
+    fn from_days_since_epoch(days: i64) -> Self {
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = (yoe + era * 400 + if month <= 2 { 1 } else { 0 }) as u32;
+        Date { year, month, day }
+    }
+}
+
+/// Format the date the way ID666 text tags expect it (MM/DD/YYYY)
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02}/{:02}/{:04}", self.month, self.day, self.year)
+    }
+}
+
+fn days_in_month(year: u32, month: u32) -> u32 {
+    match month {
+        2 if (year.is_multiple_of(4) && !year.is_multiple_of(100)) || year.is_multiple_of(400) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Date::parse("2019-05-12"), Some(Date { year: 2019, month: 5, day: 12 }));
+        assert_eq!(Date::parse("2019-02-29"), None);
+        assert_eq!(Date::parse("2020-02-29"), Some(Date { year: 2020, month: 2, day: 29 }));
+        assert_eq!(Date::parse("2019-5-12"), None);
+        assert_eq!(Date::parse("12/05/2019"), None);
+    }
+
+    #[test]
+    fn test_from_days_since_epoch() {
+        assert_eq!(Date::from_days_since_epoch(0), Date { year: 1970, month: 1, day: 1 });
+        assert_eq!(Date::from_days_since_epoch(18028), Date { year: 2019, month: 5, day: 12 });
+        assert_eq!(Date::from_days_since_epoch(11016), Date { year: 2000, month: 2, day: 29 });
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Date::new(1990, 1, 1).unwrap().to_string(), "01/01/1990");
+    }
+}
@@ -0,0 +1,531 @@
+//!
+//! Native S-DSP playback of SN76489 songs.
+//!
+//! Instead of emulating the PSG in software, the PSG register writes are translated into writes to
+//! the S-DSP voice registers: voices 0-2 play a synthesized BRR square wave for the tone channels,
+//! and voice 3 uses the S-DSP noise generator for the noise channel (or the square wave, for
+//! periodic noise). Game Gear stereo writes (0x4F) turn the left and right volume of the voices
+//! on and off. The resulting register writes are played by a small built-in driver, which leaves
+//! the S-SMP idle most of the time and lets the song use the S-DSP echo.
+//!
+//! The driver reads a stream of 2- or 3-byte records, starting right after the driver in RAM:
+//!
+//! ```text
+//! rr vv       Write vv to S-DSP register rr ($00-$7F)
+//! 80 nn       Wait nn ticks (1-255) of 2 ms
+//! 81 ll hh    Continue at address $hhll (the loop point)
+//! 82          Stop
+//! ```
+//!
+//! The register writes can also be saved as a log (see `write_log`), for use with other drivers.
+//!
+
+use crate::core::psg::PsgState;
+use crate::player::{Manifest, Player};
+use crate::vgm::events::VgmEvent;
+
+/// Address of the driver in SPC RAM. The BRR directory follows on the next page.
+pub const DRIVER_ADDRESS: u16 = 0x0200;
+
+/// Page of the BRR sample directory (the S-DSP DIR register)
+const DIRECTORY_PAGE: u8 = 0x03;
+
+/// Rate of the driver's timer ticks. Timer 0 runs at 8 kHz, and the driver counts every 16th step.
+pub const TICK_RATE: u32 = 500;
+const TIMER_TARGET: u8 = (8000 / TICK_RATE) as u8;
+
+/// Stream commands, which are told apart from register writes by bit 7
+pub const STREAM_WAIT: u8 = 0x80;
+pub const STREAM_LOOP: u8 = 0x81;
+pub const STREAM_STOP: u8 = 0x82;
+
+/// S-DSP registers
+pub mod register {
+    pub const VOLL: u8 = 0x00;
+    pub const VOLR: u8 = 0x01;
+    pub const PL: u8 = 0x02;
+    pub const PH: u8 = 0x03;
+    pub const SRCN: u8 = 0x04;
+    pub const ADSR1: u8 = 0x05;
+    pub const GAIN: u8 = 0x07;
+    pub const MVOLL: u8 = 0x0C;
+    pub const MVOLR: u8 = 0x1C;
+    pub const EVOLL: u8 = 0x2C;
+    pub const EVOLR: u8 = 0x3C;
+    pub const KON: u8 = 0x4C;
+    pub const KOF: u8 = 0x5C;
+    pub const FLG: u8 = 0x6C;
+    pub const EFB: u8 = 0x0D;
+    pub const NON: u8 = 0x3D;
+    pub const EON: u8 = 0x4D;
+    pub const DIR: u8 = 0x5D;
+    pub const C0: u8 = 0x0F;
+
+    /// Return the address of register `reg` (e.g. `VOLL`) of voice `voice`
+    pub fn voice(voice: usize, reg: u8) -> u8 {
+        (voice as u8) << 4 | reg
+    }
+}
+
+/// One BRR block holding two periods of a square wave, which loops onto itself
+const SQUARE_BRR: [u8; 9] = [0xC3, 0x77, 0x77, 0x99, 0x99, 0x77, 0x77, 0x99, 0x99];
+
+/// Samples per period of the square wave in `SQUARE_BRR`
+const SQUARE_PERIOD: u32 = 8;
+
+/// Output frequencies (Hz) of the S-DSP noise generator for each value of FLG bits 0-4
+const NOISE_RATES: [u32; 32] = [0, 16, 21, 25, 31, 42, 50, 63, 83, 100, 125, 167, 200, 250, 333, 400, 500, 667, 800,
+                                1000, 1300, 1600, 2000, 2700, 3200, 4000, 5300, 6400, 8000, 10700, 16000, 32000];
+
+/// Voice volume at 0 dB attenuation. Four voices at this volume add up to just below full scale.
+const MAX_VOLUME: f64 = 64.0;
+
+/// Voice used for the noise channel
+const NOISE_VOICE: usize = 3;
+
+/// A write to an S-DSP register, `time` samples (at 44.1 kHz) into the song
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DspWrite {
+    pub time: u32,
+    pub register: u8,
+    pub value: u8,
+}
+
+/// The S-DSP register writes that play a song
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DspLog {
+    pub writes: Vec<DspWrite>,
+    pub loop_index: Option<usize>,  // The write that playback jumps to when looping
+    pub length: u32,                // Length of the song in samples
+}
+
+/// Translates PSG writes into S-DSP register writes, leaving out writes that wouldn't change the
+/// value of the register.
+///
+/// The S-DSP noise generator only makes white noise. Periodic noise, where the PSG outputs a pulse
+/// once every 16 shifts, is approximated by playing the square wave on the noise voice at 1/16 of
+/// the shift rate: the pitch is right, but the timbre is softer than the PSG's narrow pulse.
+struct Translator {
+    psg: PsgState,
+    gg_stereo: u8,                  // Game Gear stereo mask: bits 4-7 enable the channels on the left, bits 0-3 on the right
+    psg_clock: u32,
+    flg: u8,                        // FLG without the noise clock
+    registers: [Option<u8>; 128],   // The last value written to each register
+    log: DspLog,
+}
+
+impl Translator {
+    fn write(&mut self, register: u8, value: u8) {
+        if self.registers[register as usize] != Some(value) {
+            self.registers[register as usize] = Some(value);
+            self.log.writes.push(DspWrite { time: self.log.length, register, value });
+        }
+    }
+
+    fn volume(attenuation: u8) -> u8 {
+        if attenuation >= 0x0F {
+            0
+        } else {
+            // 2 dB per step
+            (MAX_VOLUME * 10f64.powf(-(attenuation as f64) / 10.0)).round() as u8
+        }
+    }
+
+    /// Return the S-DSP pitch that plays the square wave at the frequency of PSG tone period `period`
+    fn pitch(&self, period: u16) -> u16 {
+        let period = if period == 0 { 0x400 } else { period as u64 };
+        let frequency = self.psg_clock as u64 * 0x1000 * SQUARE_PERIOD as u64 / (32 * period * 32000);
+        std::cmp::min(frequency, 0x3FFF) as u16
+    }
+
+    /// Return the shift rate of the PSG noise channel as a tone period, i.e. in units of 32 clocks
+    fn noise_divider(&self) -> u32 {
+        match self.psg.noise & 3 {
+            3 => if self.psg.periods[2] == 0 { 0x400 } else { self.psg.periods[2] as u32 },
+            n => 0x10 << n,
+        }
+    }
+
+    /// Return the noise clock whose rate is closest to the shift rate of the PSG noise channel
+    fn noise_clock(&self) -> u8 {
+        let rate = self.psg_clock as f64 / (32 * self.noise_divider()) as f64;
+        (1..NOISE_RATES.len()).min_by(|&a, &b| {
+            let distance = |i: usize| (NOISE_RATES[i] as f64 / rate).ln().abs();
+            distance(a).partial_cmp(&distance(b)).unwrap()
+        }).unwrap() as u8
+    }
+
+    /// Write the registers that reflect the current state of PSG channel `channel`
+    fn update_voice(&mut self, channel: usize) {
+        let volume = Self::volume(self.psg.attenuation[channel]);
+        let side = |bit: usize| if self.gg_stereo & (1 << bit) != 0 { volume } else { 0 };
+        let (left, right) = (side(channel + 4), side(channel));
+        self.write(register::voice(channel, register::VOLL), left);
+        self.write(register::voice(channel, register::VOLR), right);
+        if channel == NOISE_VOICE {
+            let flg = self.flg | self.noise_clock();
+            self.write(register::FLG, flg);
+            // Bit 2 of the noise register selects white noise
+            let white = self.psg.noise & 4 != 0;
+            self.write(register::NON, if white { 1 << NOISE_VOICE } else { 0 });
+            if !white {
+                let [lo, hi] = self.pitch((self.noise_divider() * 16) as u16).to_le_bytes();
+                self.write(register::voice(channel, register::PL), lo);
+                self.write(register::voice(channel, register::PH), hi);
+            }
+        } else {
+            let [lo, hi] = self.pitch(self.psg.periods[channel]).to_le_bytes();
+            self.write(register::voice(channel, register::PL), lo);
+            self.write(register::voice(channel, register::PH), hi);
+        }
+    }
+}
+
+/// Translate the PSG writes in `events` into S-DSP register writes. `loop_index` is the event that
+/// playback jumps to when looping. If `echo` is set, the voices are sent to the echo buffer (which
+/// has to be set up through ESA/EDL), with a short feedback.
+pub fn translate(events: &[VgmEvent], loop_index: Option<usize>, psg_clock: u32, echo: bool) -> DspLog {
+    let mut translator = Translator {
+        psg: PsgState::default(),
+        gg_stereo: 0xFF,
+        psg_clock,
+        flg: if echo { 0x00 } else { 0x20 },
+        registers: [None; 128],
+        log: DspLog::default(),
+    };
+
+    translator.write(register::KOF, 0x00);
+    translator.write(register::DIR, DIRECTORY_PAGE);
+    translator.write(register::MVOLL, 0x7F);
+    translator.write(register::MVOLR, 0x7F);
+    for voice in 0..4 {
+        translator.write(register::voice(voice, register::SRCN), 0);
+        translator.write(register::voice(voice, register::ADSR1), 0x00);    // Use GAIN
+        translator.write(register::voice(voice, register::GAIN), 0x7F);
+        translator.update_voice(voice);
+    }
+    if echo {
+        translator.write(register::EON, 0x0F);
+        translator.write(register::EVOLL, 0x20);
+        translator.write(register::EVOLR, 0x20);
+        translator.write(register::EFB, 0x30);
+        translator.write(register::C0, 0x7F);
+    }
+    translator.write(register::KON, 0x0F);
+
+    for (i, event) in events.iter().enumerate() {
+        if loop_index == Some(i) {
+            // The registers may have other values when playback gets back here, so write them all
+            translator.log.loop_index = Some(translator.log.writes.len());
+            translator.registers = [None; 128];
+            for voice in 0..4 {
+                translator.update_voice(voice);
+            }
+        }
+        match event {
+            VgmEvent::PsgWrite(value) => {
+                translator.psg.write(*value);
+                translator.update_voice(translator.psg.latched_channel);
+                if translator.psg.latched_channel == 2 {
+                    // The noise channel can be clocked by tone channel 2
+                    translator.update_voice(NOISE_VOICE);
+                }
+            }
+            VgmEvent::GgStereo(mask) => {
+                translator.gg_stereo = *mask;
+                for voice in 0..4 {
+                    translator.update_voice(voice);
+                }
+            }
+            event => translator.log.length += event.samples(),
+        }
+    }
+    translator.log
+}
+
+/// Convert a time in samples to driver ticks
+fn ticks(time: u32) -> u32 {
+    ((time as u64 * TICK_RATE as u64 + 22050) / 44100) as u32
+}
+
+/// Encode `log` as a driver stream that is loaded at `address`
+pub fn encode(log: &DspLog, address: u16) -> Vec<u8> {
+    let mut stream = Vec::new();
+    let mut tick = 0;
+    let mut wait_until = |stream: &mut Vec<u8>, time: u32| {
+        let target = ticks(time);
+        while tick < target {
+            let n = std::cmp::min(target - tick, 0xFF);
+            stream.extend(&[STREAM_WAIT, n as u8]);
+            tick += n;
+        }
+    };
+
+    let mut loop_address = None;
+    for (i, write) in log.writes.iter().enumerate() {
+        wait_until(&mut stream, write.time);
+        if log.loop_index == Some(i) {
+            loop_address = Some(address as usize + stream.len());
+        }
+        stream.extend(&[write.register, write.value]);
+    }
+    wait_until(&mut stream, log.length);
+    match loop_address {
+        Some(loop_address) => {
+            stream.push(STREAM_LOOP);
+            stream.extend(&(loop_address as u16).to_le_bytes());
+        }
+        None => stream.push(STREAM_STOP),
+    }
+    stream
+}
+
+/// Magic bytes at the start of an S-DSP register log
+pub const LOG_MAGIC: &[u8; 4] = b"DSPL";
+
+/// Version of the register log layout
+pub const LOG_VERSION: u32 = 1;
+
+/// Size of the register log header
+const LOG_HEADER_SIZE: usize = 0x20;
+
+/// Return `log` as an S-DSP register log. All values are little-endian:
+///
+/// ```text
+/// 0x00  4   "DSPL"
+/// 0x04  4   Version (1)
+/// 0x08  4   Number of writes (n)
+/// 0x0C  4   Index of the write that playback loops back to, or 0xFFFFFFFF if the song doesn't loop
+/// 0x10  4   Length of the song in samples (44.1 kHz)
+/// 0x14  9   The BRR sample that the voices play: one looping block with a square wave
+/// 0x1D  3   Reserved (0)
+/// 0x20  6n  Writes: time in samples (4 bytes), register (1 byte), value (1 byte)
+/// ```
+///
+/// The first writes set up the S-DSP, with the sample directory at page 3 and the sample as
+/// sample 0 (SRCN). The sample can be placed anywhere, as long as the directory points to it.
+pub fn write_log(log: &DspLog) -> Vec<u8> {
+    let mut data = Vec::with_capacity(LOG_HEADER_SIZE + log.writes.len() * 6);
+    data.extend(LOG_MAGIC);
+    data.extend(&LOG_VERSION.to_le_bytes());
+    data.extend(&(log.writes.len() as u32).to_le_bytes());
+    data.extend(&log.loop_index.map_or(0xFFFFFFFF, |index| index as u32).to_le_bytes());
+    data.extend(&log.length.to_le_bytes());
+    data.extend(&SQUARE_BRR);
+    data.resize(LOG_HEADER_SIZE, 0);
+    for write in &log.writes {
+        data.extend(&write.time.to_le_bytes());
+        data.extend(&[write.register, write.value]);
+    }
+    data
+}
+
+/// Return the driver code, which reads the stream at `stream_address`
+fn driver_code(stream_address: u16) -> Vec<u8> {
+    let [lo, hi] = stream_address.to_le_bytes();
+    vec![
+        0x8F, 0x00, 0xF1,       //         mov $F1,#$00        ; Stop the timers
+        0x8F, TIMER_TARGET, 0xFA, //       mov $FA,#TIMER_TARGET
+        0x8F, 0x01, 0xF1,       //         mov $F1,#$01        ; Start timer 0
+        0x8F, lo, 0x00,         //         mov $00,#<stream
+        0x8F, hi, 0x01,         //         mov $01,#>stream
+        0x8D, 0x00,             //         mov y,#0
+        0xF7, 0x00,             // next:   mov a,[$00]+y
+        0x3A, 0x00,             //         incw $00
+        0x30, 0x0A,             //         bmi command
+        0xC4, 0xF2,             //         mov $F2,a
+        0xF7, 0x00,             //         mov a,[$00]+y
+        0x3A, 0x00,             //         incw $00
+        0xC4, 0xF3,             //         mov $F3,a
+        0x2F, 0xF0,             //         bra next
+        0x68, STREAM_WAIT,      // command: cmp a,#STREAM_WAIT
+        0xD0, 0x0E,             //         bne not_wait
+        0xF7, 0x00,             //         mov a,[$00]+y
+        0x3A, 0x00,             //         incw $00
+        0x5D,                   //         mov x,a
+        0xE4, 0xFD,             // tick:   mov a,$FD           ; Timer 0 steps since the last read
+        0xF0, 0xFC,             //         beq tick
+        0x1D,                   //         dec x
+        0xD0, 0xF9,             //         bne tick
+        0x2F, 0xDE,             //         bra next
+        0x68, STREAM_LOOP,      // not_wait: cmp a,#STREAM_LOOP
+        0xD0, 0x0D,             //         bne stop
+        0xF7, 0x00,             //         mov a,[$00]+y
+        0x5D,                   //         mov x,a
+        0x3A, 0x00,             //         incw $00
+        0xF7, 0x00,             //         mov a,[$00]+y
+        0xC4, 0x01,             //         mov $01,a
+        0xD8, 0x00,             //         mov $00,x
+        0x2F, 0xCD,             //         bra next
+        0x2F, 0xFE,             // stop:   bra stop
+    ]
+}
+
+/// Return the built-in driver as a player: the driver code, followed by the BRR sample directory
+/// and the square wave. The stream is placed right after it.
+pub fn driver() -> Player {
+    let directory = (DIRECTORY_PAGE as usize) << 8;
+    let size = directory - DRIVER_ADDRESS as usize + 4 + SQUARE_BRR.len();
+    let mut binary = driver_code((DRIVER_ADDRESS as usize + size) as u16);
+    binary.resize(directory - DRIVER_ADDRESS as usize, 0);
+    let sample = (directory + 4) as u16;
+    binary.extend(&sample.to_le_bytes());   // Start
+    binary.extend(&sample.to_le_bytes());   // Loop point
+    binary.extend(&SQUARE_BRR);
+    Player {
+        binary,
+        manifest: Manifest { load_address: DRIVER_ADDRESS, entry_point: DRIVER_ADDRESS, codecs: Vec::new(), ..Manifest::default() },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run the driver in `ram` until it stops or `max_ticks` ticks have passed, and return the
+    /// S-DSP writes it made, with the tick they were made on. Only the instructions used by the
+    /// driver are implemented, and every read of the timer returns one step.
+    fn run_driver(ram: &mut [u8], max_ticks: u32) -> Vec<(u32, u8, u8)> {
+        let mut pc = DRIVER_ADDRESS as usize;
+        let (mut a, mut x, mut y) = (0u8, 0u8, 0u8);
+        let (mut n, mut z) = (false, false);
+        let mut tick = 0;
+        let mut writes = Vec::new();
+        let nz = |value: u8, n: &mut bool, z: &mut bool| { *n = value & 0x80 != 0; *z = value == 0; };
+        while tick < max_ticks {
+            let op = ram[pc];
+            let arg = ram[pc + 1] as usize;
+            let branch = |taken: bool| if taken { (pc as isize + 2 + arg as u8 as i8 as isize) as usize } else { pc + 2 };
+            pc = match op {
+                0x8F => { ram[ram[pc + 2] as usize] = arg as u8; pc + 3 }
+                0x8D => { y = arg as u8; nz(y, &mut n, &mut z); pc + 2 }
+                0xF7 => {
+                    let pointer = u16::from_le_bytes([ram[arg], ram[arg + 1]]) as usize + y as usize;
+                    a = ram[pointer];
+                    nz(a, &mut n, &mut z);
+                    pc + 2
+                }
+                0x3A => {
+                    let value = u16::from_le_bytes([ram[arg], ram[arg + 1]]).wrapping_add(1);
+                    ram[arg..arg + 2].copy_from_slice(&value.to_le_bytes());
+                    pc + 2
+                }
+                0x30 => branch(n),
+                0xF0 => branch(z),
+                0xD0 => branch(!z),
+                0x2F => {
+                    if arg == 0xFE {
+                        break;
+                    }
+                    branch(true)
+                }
+                0xC4 => {
+                    ram[arg] = a;
+                    if arg == 0xF3 {
+                        writes.push((tick, ram[0xF2], a));
+                    }
+                    pc + 2
+                }
+                0x68 => { nz(a.wrapping_sub(arg as u8), &mut n, &mut z); pc + 2 }
+                0x5D => { x = a; nz(x, &mut n, &mut z); pc + 1 }
+                0xE4 => {
+                    a = if arg == 0xFD { tick += 1; 1 } else { ram[arg] };
+                    nz(a, &mut n, &mut z);
+                    pc + 2
+                }
+                0x1D => { x = x.wrapping_sub(1); nz(x, &mut n, &mut z); pc + 1 }
+                0xD8 => { ram[arg] = x; pc + 2 }
+                _ => panic!("Unimplemented opcode {:02X} at {:04X}", op, pc),
+            };
+        }
+        writes
+    }
+
+    #[test]
+    fn test_translate() {
+        let events = vec![VgmEvent::PsgWrite(0x80 | 0x0E), VgmEvent::PsgWrite(0x0F), VgmEvent::PsgWrite(0x90),
+                          VgmEvent::Wait(44100), VgmEvent::PsgWrite(0x9F), VgmEvent::Wait(441)];
+        let log = translate(&events, Some(4), 3579545, false);
+        assert_eq!(log.length, 44100 + 441);
+        let pitch = log.writes.iter().rev().find(|w| w.register == register::PH).unwrap().value as u16 * 0x100
+                  + log.writes.iter().rev().find(|w| w.register == register::PL).unwrap().value as u16;
+        // 3579545 / (32 * 0xFE) = 440 Hz
+        assert_eq!(pitch, 450);
+        let loop_index = log.loop_index.unwrap();
+        assert_eq!(log.writes[loop_index].time, 44100);
+        assert_eq!(log.writes.last().unwrap(), &DspWrite { time: 44100, register: register::VOLR, value: 0 });
+    }
+
+    #[test]
+    fn test_gg_stereo() {
+        // Channel 0 on the left only, noise on the right only
+        let events = vec![VgmEvent::PsgWrite(0x90), VgmEvent::PsgWrite(0xF0), VgmEvent::GgStereo(0x18)];
+        let log = translate(&events, None, 3579545, false);
+        let last = |register: u8| log.writes.iter().rev().find(|w| w.register == register).unwrap().value;
+        assert_eq!(last(register::voice(0, register::VOLL)), 64);
+        assert_eq!(last(register::voice(0, register::VOLR)), 0);
+        assert_eq!(last(register::voice(NOISE_VOICE, register::VOLL)), 0);
+        assert_eq!(last(register::voice(NOISE_VOICE, register::VOLR)), 64);
+    }
+
+    #[test]
+    fn test_noise_clock() {
+        let events = vec![VgmEvent::PsgWrite(0xE0), VgmEvent::PsgWrite(0xF0)];
+        let log = translate(&events, None, 3579545, false);
+        // Clock / 512 = 6991 Hz
+        assert_eq!(log.writes.iter().rev().find(|w| w.register == register::FLG).unwrap().value, 0x20 | 27);
+    }
+
+    #[test]
+    fn test_periodic_noise() {
+        // Periodic noise at clock / 512 plays the square wave at 1/16 of that rate: 437 Hz
+        let events = vec![VgmEvent::PsgWrite(0xE0), VgmEvent::PsgWrite(0xF0)];
+        let log = translate(&events, None, 3579545, false);
+        let last = |register: u8| log.writes.iter().rev().find(|w| w.register == register).unwrap().value;
+        assert_eq!(last(register::NON), 0);
+        let pitch = u16::from_le_bytes([last(register::voice(NOISE_VOICE, register::PL)), last(register::voice(NOISE_VOICE, register::PH))]);
+        assert_eq!(pitch, (437 * SQUARE_PERIOD * 0x1000 / 32000) as u16);
+
+        // Switching to white noise hands the voice back to the noise generator
+        let events = vec![VgmEvent::PsgWrite(0xE0), VgmEvent::PsgWrite(0xE4)];
+        let log = translate(&events, None, 3579545, false);
+        assert_eq!(log.writes.iter().rev().find(|w| w.register == register::NON).unwrap().value, 1 << NOISE_VOICE);
+    }
+
+    #[test]
+    fn test_driver() {
+        let events = vec![VgmEvent::PsgWrite(0x90), VgmEvent::Wait(4410), VgmEvent::PsgWrite(0x9F), VgmEvent::Wait(4410)];
+        let log = translate(&events, Some(2), 3579545, false);
+        let player = driver();
+        let stream = encode(&log, player.data_address() as u16);
+        let mut ram = player.ram_image(&stream);
+        assert_eq!(u16::from_le_bytes([ram[0x300], ram[0x301]]), 0x0304);
+
+        let writes = run_driver(&mut ram, 150);
+        let expected: Vec<(u32, u8, u8)> = log.writes.iter().map(|w| (ticks(w.time), w.register, w.value)).collect();
+        assert_eq!(writes[..expected.len()], expected[..]);
+        // After the first 100 ticks, playback continues from the loop point
+        let looped: Vec<(u32, u8, u8)> = log.writes[log.loop_index.unwrap()..].iter().map(|w| (100, w.register, w.value)).collect();
+        assert_eq!(writes[expected.len()..], looped[..]);
+    }
+
+    #[test]
+    fn test_write_log() {
+        let log = translate(&[VgmEvent::Wait(441), VgmEvent::PsgWrite(0x90)], Some(1), 3579545, false);
+        let data = write_log(&log);
+        assert_eq!(&data[0..4], LOG_MAGIC);
+        assert_eq!(data.len(), LOG_HEADER_SIZE + log.writes.len() * 6);
+        assert_eq!(u32::from_le_bytes([data[0x0C], data[0x0D], data[0x0E], data[0x0F]]) as usize, log.loop_index.unwrap());
+        let last = &data[data.len() - 6..];
+        assert_eq!(last, &[0xB9, 0x01, 0x00, 0x00, register::VOLR, 64]);
+    }
+
+    #[test]
+    fn test_stop() {
+        let log = translate(&[VgmEvent::Wait(441)], None, 3579545, false);
+        let stream = encode(&log, 0x1000);
+        assert_eq!(&stream[stream.len() - 3..], &[STREAM_WAIT, 5, STREAM_STOP]);
+        let mut ram = driver().ram_image(&stream);
+        assert_eq!(run_driver(&mut ram, 1000).len(), log.writes.len());
+    }
+}
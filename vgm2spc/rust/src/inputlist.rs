@@ -0,0 +1,76 @@
+//!
+//! Input list files, given as `@list.txt` on the command line. Each line holds an input path,
+//! optionally followed by per-file overrides separated by `|`:
+//!
+//!     # Comments and blank lines are ignored
+//!     intro.vgm | title=Opening | loops=1
+//!     stage1.vgz
+//!
+//! Relative paths are relative to the directory of the list file. The overrides are for the tag
+//! of the output, which is based on the first input, so only the first input can have them.
+//!
+
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+/// One line of an input list
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ListEntry {
+    pub path: String,
+    pub title: Option<String>,
+    pub loops: Option<u32>,
+}
+
+/// Parse the text of an input list. `base` is the directory that relative paths are resolved
+/// against.
+pub fn parse(text: &str, base: &Path) -> Result<Vec<ListEntry>, String> {
+    let mut entries = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split('|').map(str::trim);
+        let path = fields.next().unwrap_or_default();
+        if path.is_empty() {
+            return Err(format!("Line {}: missing input path", number + 1));
+        }
+        let mut entry = ListEntry { path: base.join(path).to_string_lossy().into_owned(), ..Default::default() };
+        for field in fields {
+            let (key, value) = field.split_once('=').ok_or_else(|| format!("Line {}: expected key=value, got {}", number + 1, field))?;
+            match key.trim() {
+                "title" => entry.title = Some(value.trim().to_owned()),
+                "loops" => entry.loops = Some(value.trim().parse().map_err(|_| format!("Line {}: invalid number of loops: {}", number + 1, value))?),
+                other => return Err(format!("Line {}: unknown setting: {}", number + 1, other)),
+            }
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Read the input list at `path`
+pub fn read(path: &Path) -> Result<Vec<ListEntry>, Error> {
+    let text = fs::read_to_string(path)?;
+    let base = path.parent().unwrap_or_else(|| Path::new(""));
+    parse(&text, base).map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let text = "# A set\n\nintro.vgm | title=Opening Theme | loops=1\n  /abs/stage1.vgz  \n";
+        let entries = parse(text, Path::new("songs")).unwrap();
+        assert_eq!(entries, vec![
+            ListEntry { path: Path::new("songs").join("intro.vgm").to_string_lossy().into_owned(), title: Some(String::from("Opening Theme")), loops: Some(1) },
+            ListEntry { path: String::from("/abs/stage1.vgz"), title: None, loops: None },
+        ]);
+        assert!(parse("a.vgm | loops=x", Path::new("")).is_err());
+        assert!(parse("a.vgm | fade=3", Path::new("")).is_err());
+        assert!(parse("| title=x", Path::new("")).is_err());
+    }
+}
@@ -1,59 +1,514 @@
-/// vgm2spc
-/// Mic, 2010,2019
+//! vgm2spc
+//! Mic, 2010,2019
 
-#[macro_use]
-extern crate bitflags;
+extern crate alloc;
+#[cfg(feature = "vgz")]
 extern crate flate2;
 
 use std::env;
+use std::fs;
+use std::io::Write;
 use std::path::Path;
 use std::process;
-use converter::*;
-mod bytestream;
-mod codec;
+use asm::AsmSyntax;
+use converter::Converter;
+use date::Date;
+use observer::ConsoleObserver;
+use options::{Codec, DualPsgMapping, OutputFormat};
+use passes::PassKind;
+use spc::Id666Format;
+mod analysis;
+mod asm;
+mod commands;
 mod converter;
+mod core;
+mod date;
+mod dsp;
+mod inputlist;
+mod observer;
+mod options;
+mod passes;
+mod player;
+mod sfc;
+mod spc;
 mod vgm;
+mod watch;
+
+/// Append a line describing the last conversion to the CSV file at `path`, starting the file with
+/// a header line if it's new
+fn append_csv_row(path: &str, input: &str, converter: &Converter) -> std::io::Result<()> {
+    let header = converter.header();
+    let output_size = converter.packed_data().len();
+    let row = analysis::CsvRow {
+        file: input.to_string(),
+        chips: header.used_chips(),
+        input_size: header.eof_offset as usize + 4,
+        output_size,
+        fits: converter.load_player().is_ok_and(|player| output_size <= converter.capacity(&player)),
+        loop_samples: header.loop_samples,
+        total_samples: header.total_samples,
+    };
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+    if file.metadata()?.len() == 0 {
+        writeln!(file, "{}", analysis::CSV_HEADER)?;
+    }
+    writeln!(file, "{}", row)
+}
+
+/// Print the version, or with `json` set, the version and capabilities as a JSON object for
+/// frontends
+fn show_version(json: bool) {
+    if !json {
+        println!("vgm2spc {}", env!("CARGO_PKG_VERSION"));
+        return;
+    }
+
+    #[derive(serde::Serialize)]
+    struct PlayerInfo {
+        name: String,
+        size: usize,
+        crc32: Option<String>,         // Identifies the player binary, which has no version number
+        codecs: Vec<String>,
+    }
+    #[derive(serde::Serialize)]
+    struct Capabilities {
+        version: &'static str,
+        codecs: Vec<&'static str>,
+        chips: &'static [&'static str],
+        output_formats: &'static [&'static str],
+        passes: Vec<&'static str>,
+        players: Vec<PlayerInfo>,
+        features: Vec<&'static str>,    // Cargo features the binary was built with
+    }
+
+    let mut players = Vec::new();
+    let default_player = player::Player::find_default().and_then(|path| Ok((player::Player::load(&path)?, path)));
+    if let Ok((player, path)) = default_player {
+        players.push(PlayerInfo {
+            name: path.display().to_string(),
+            size: player.size(),
+            crc32: Some(format!("{:08X}", crc32fast::hash(&player.binary))),
+            codecs: player.manifest.codecs.clone(),
+        });
+    }
+    players.push(PlayerInfo { name: String::from("native-dsp"), size: dsp::driver().size(), crc32: None, codecs: Vec::new() });
+    let capabilities = Capabilities {
+        version: env!("CARGO_PKG_VERSION"),
+        codecs: Codec::ALL.iter().map(|codec| codec.name()).collect(),
+        chips: player::SUPPORTED_CHIPS,
+        output_formats: OutputFormat::NAMES,
+        passes: PassKind::ALL.iter().map(|pass| pass.name()).collect(),
+        players,
+        features: if cfg!(feature = "vgz") { vec!["vgz"] } else { Vec::new() },
+    };
+    println!("{}", serde_json::to_string_pretty(&capabilities).unwrap());
+}
 
 fn show_help() {
-    println!("Usage: vgm2spc <input> <output>");
+    println!("Usage: vgm2spc [options] <input> <output>");
+    println!("       vgm2spc [options] -multi <input1> <input2> ... <output>");
+    println!("       vgm2spc [options] -join <input1> <input2> ... <output>");
+    println!("       vgm2spc check <file.spc> [<source.vgm>]");
+    println!("       vgm2spc tags show|edit <file.spc> [options]");
+    println!("       vgm2spc extract <file.spc> [-o <data.bin>]");
+    println!("       vgm2spc play <song.vgm> [-player <command>]");
+    println!("       vgm2spc diff <a.spc> <b.spc>");
+    println!("       vgm2spc bench <dir>");
+    println!("       vgm2spc analyze <song.vgm>");
+    println!("       vgm2spc disasm <song.vgm>");
+    println!("       vgm2spc disasm-c <file.spc|data.bin>");
+    println!("       vgm2spc replace <file.spc> <song.vgm>");
+    println!("       vgm2spc repatch <file.spc> [-player <player.bin>]");
+    println!("       vgm2spc selftest <corpus_dir> [-update]");
+    println!("       vgm2spc --version [--json]");
+    println!("       Inputs can be VGM, VGZ, GYM or S98 files.");
+    println!("       An input can be given as @<file>, a text file with one input path per line. Each path can be");
+    println!("       followed by overrides for the tag: intro.vgm | title=Opening | loops=2");
+    println!("       The output has a single tag, which is based on the first input, so only that one can have overrides.");
+    println!("Options:");
+    println!("  -vgz                  Treat the input as gzip compressed (VGZ), regardless of its name");
+    println!("  -multi                Pack several songs into one output. The song is selected through IO port 0");
+    println!("  -join                 Play several songs one after another, as a medley");
+    println!("  -loop-last            Make a joined medley loop back to the start of the last song");
+    println!("  -loop-all             Make songs without a loop point loop from the start, instead of stopping");
+    println!("  -format <name>        Output format: spc, sfc, raw, data-only, vgm or dsplog. By default, the format is picked from the");
+    println!("                        output extension (.spc, .sfc/.smc, .bin/.vgmc, .vgm/.vgz, .dsplog), or spc for other extensions");
+    println!("                        dsplog is a log of the S-DSP register writes that -native-dsp would make");
+    println!("  -raw                  Output only the packed VGM data, without player or SPC header (same as -format raw)");
+    println!("  -data-only            Like -raw, but replace the VGM header with an 8-byte descriptor and drop the GD3 tag");
+    println!("  -sfc                  Output a SNES ROM image that plays the song, for testing on hardware");
+    println!("  -codec <name>         Codec to compress the VGM data with: auto (default), psg, psg-runs or none");
+    println!("                        psg-runs needs a player that supports it (see the player manifest)");
+    println!("  -native-dsp           Play the PSG channels on S-DSP voices with a small built-in driver, instead of the PSG emulator");
+    println!("  -strip-gd3            Leave the GD3 tag out of the packed data (the ID666 tag is still filled in)");
+    println!("  -auto-fit             If the song doesn't fit, retry with settings that make it smaller until it does");
+    println!("  -wait-tolerance <n>   Encode long waits with a LUT entry up to <n> samples off, when there's no exact match (PSG codec)");
+    println!("  -seek-table <s>       Add a table of positions every <s> seconds, for players that can seek");
+    println!("  -repair               Convert truncated files by ending the song after the last complete command");
+    println!("  -trim-silence         Remove silence at the start and end of the song");
+    println!("  -reset-chips          Silence all PSG channels at the start of the song and at the loop point");
+    println!("  -psg-only             Remove writes to other chips than the PSG, and all data blocks");
+    println!("  -dual-psg <mapping>   What to do with the second PSG of dual-PSG files: {} (default: drop).", DualPsgMapping::NAMES.join(", "));
+    println!("                        The player plays one PSG, so mix and pan need -format vgm");
+    println!("  -passes <list>        Comma-separated preprocessing passes to run, in order (default: {})",
+             PassKind::DEFAULT.iter().map(|p| p.name()).collect::<Vec<_>>().join(","));
+    println!("                        Available: {}", PassKind::ALL.iter().map(|p| p.name()).collect::<Vec<_>>().join(", "));
+    println!("  -no-preprocess        Run no preprocessing passes (same as -passes \"\"). -psg-only still strips other chips");
+    println!("  -skip-pass <name>     Don't run the given preprocessing pass. Can be given several times");
+    println!("  -keep-timers          Keep all YM2612 timer and mode writes (same as skipping ym2612-timers and ym2612-mode-writes)");
+    println!("  -check-invariants     Check the offsets in the packed data after conversion (for debugging)");
+    println!("  -strict               Fail on reserved or undefined VGM commands instead of skipping them");
+    println!("  -watch                Convert again whenever an input file changes");
+    println!("  -player <command>     Open the output with <command> after converting (see the play subcommand)");
+    println!("  -player-bin <file>    Include the player binary <file> in the output (default: the first {} found in", player::PLAYER_BINARY_PATH);
+    println!("                        ${}, the current directory, the directory of vgm2spc or the user data directory)",
+             player::PLAYER_PATH_VARIABLE);
+    println!("  -max-writes <n>       Warn about frames with more than <n> PSG writes (default: {})", analysis::DEFAULT_MAX_FRAME_WRITES);
+    println!("  -echo <edl>           Reserve an echo buffer for echo delay <edl> (0-15) at the top of RAM, and enable echo writes");
+    println!("  -port <n>=<value>[,...]");
+    println!("                        Set the value that the player reads from IO port <n> (0-3) at startup, e.g. -port 0=2");
+    println!("                        to start a multi-song SPC at the third song");
+    println!("  -patch <addr>=<value>[,...]");
+    println!("                        Write bytes to the SPC RAM image, e.g. -patch 0xF4=1. Numbers are decimal, or hex with 0x or $");
+    println!("  -v                    Report the space available for the packed data, and how much of it is used");
+    println!("  -stats                Report the size of each section of the packed data (intro, loop, data blocks, etc),");
+    println!("                        and the command types, data blocks and long waits that take up the most space");
+    println!("  -timing               Report the time spent in each stage of the conversion");
+    println!("  -csv <file>           Append a line with the chips, sizes, length and whether the song fits to the CSV file <file>");
+    println!("  -export-tags <file>   Write the GD3 tag of the input to <file> as JSON");
+    println!("  -dump-header <file>   Write the VGM header and GD3 tag of the input to <file> as JSON");
+    println!("  -emit-preprocessed <file>");
+    println!("                        Write the song to <file> as a VGM (or VGZ) file, as it is after preprocessing");
+    println!("  -emit-asm <file>      Write the packed data to <file> as an assembly include file");
+    println!("  -asm-syntax <name>    Assembler syntax for -emit-asm: ca65 (default), wla-dx or 64tass");
+    println!("  -dumper <name>        Set the ID666 \"dumped by\" field (default: Unknown)");
+    println!("  -title <text>         Set the ID666 song title (default: the GD3 track name)");
+    println!("  -comment <text>       Set the ID666 comment field (default: Created with VGM2SPC)");
+    println!("  -gd3-provenance       Use the GD3 ripper and notes as dumper and comment, unless given explicitly");
+    println!("  -date <YYYY-MM-DD>    Set the ID666 dump date (default: today)");
+    println!("  -loops <n>            Set the ID666 play length to the intro plus <n> loops");
+    println!("  -binary-id666         Write the ID666 tag in binary format instead of text format");
     process::exit(0);
 }
 
+/// Return the value following the option `opt`
+fn option_value(opt: &str, args: &mut dyn Iterator<Item = String>) -> String {
+    match args.next() {
+        Some(value) => value,
+        None => panic!("Missing value for option: {}", opt),
+    }
+}
+
 fn main() {
+    let first_arg = env::args().nth(1);
+    if first_arg.as_deref() == Some("--version") || first_arg.as_deref() == Some("-version") {
+        show_version(env::args().any(|arg| arg == "--json" || arg == "-json"));
+        return;
+    }
     println!("VGM to SPC Converter by Mic, 2019");
 
-    let mut flags = converter::ConverterFlags::empty();
-    let mut input_path = String::from("");
-    let mut output_path = String::from("");
-    
-    let args: Vec<String> = env::args().collect();
-    for (i, arg) in args.iter().enumerate() {
-        if i == 0 {
-            // Ignore args[0] (the executable)
-        } else if arg.starts_with("-") {
+    let mut builder = Converter::builder();
+    let mut paths: Vec<String> = Vec::new();
+    let mut multi = false;
+    let mut output_format = None;
+    let mut join = false;
+    let mut show_timing = false;
+    let mut show_stats = false;
+    let mut verbose = false;
+    let mut watch = false;
+    let mut player = String::from("");
+    let mut tags_path = String::from("");
+    let mut csv_path = String::from("");
+    let mut header_path = String::from("");
+    let mut asm_path = String::from("");
+    let mut preprocessed_path = String::from("");
+    let mut asm_syntax = AsmSyntax::Ca65;
+
+    // Ignore args[0] (the executable)
+    let all_args: Vec<String> = env::args().skip(1).collect();
+    if let Some(subcommand) = all_args.first() {
+        if commands::run(subcommand, &all_args[1..]) {
+            return;
+        }
+    }
+
+    let mut args = all_args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg.starts_with('-') {
             if arg == "-h" || arg == "-help" || arg == "-?" {
                 show_help()
+            } else if arg == "-format" {
+                let value = option_value(&arg, &mut args);
+                output_format = Some(OutputFormat::from_name(&value).unwrap_or_else(|| panic!("Unknown output format: {}", value)));
             } else if arg == "-raw" {
-                flags |= converter::ConverterFlags::RAW_OUTPUT;
+                output_format = Some(OutputFormat::Raw);
+            } else if arg == "-data-only" {
+                output_format = Some(OutputFormat::DataOnly);
+            } else if arg == "-sfc" {
+                output_format = Some(OutputFormat::Sfc);
+            } else if arg == "-vgz" {
+                builder = builder.assume_vgz(true);
+            } else if arg == "-multi" {
+                multi = true;
+            } else if arg == "-join" {
+                join = true;
+            } else if arg == "-loop-last" {
+                builder = builder.loop_last(true);
+            } else if arg == "-loop-all" {
+                builder = builder.loop_all(true);
+            } else if arg == "-repair" {
+                builder = builder.repair(true);
+            } else if arg == "-trim-silence" {
+                builder = builder.trim_silence(true);
+            } else if arg == "-reset-chips" {
+                builder = builder.reset_chips(true);
+            } else if arg == "-native-dsp" {
+                builder = builder.native_dsp(true);
+            } else if arg == "-psg-only" {
+                builder = builder.psg_only(true);
+            } else if arg == "-dual-psg" {
+                let value = option_value(&arg, &mut args);
+                builder = builder.dual_psg(DualPsgMapping::from_name(&value).unwrap_or_else(|| panic!("Unknown dual-PSG mapping: {}", value)));
+            } else if arg == "-passes" {
+                let value = option_value(&arg, &mut args);
+                let passes: Vec<PassKind> = value.split(',').filter(|name| !name.is_empty())
+                    .map(|name| PassKind::from_name(name).unwrap_or_else(|| panic!("Unknown pass: {}", name)))
+                    .collect();
+                builder = builder.passes(&passes);
+            } else if arg == "-no-preprocess" {
+                builder = builder.passes(&[]);
+            } else if arg == "-skip-pass" {
+                let value = option_value(&arg, &mut args);
+                builder = builder.without_pass(PassKind::from_name(&value).unwrap_or_else(|| panic!("Unknown pass: {}", value)));
+            } else if arg == "-keep-timers" {
+                builder = builder.without_pass(PassKind::Ym2612Timers).without_pass(PassKind::Ym2612ModeWrites);
+            } else if arg == "-check-invariants" {
+                builder = builder.check_invariants(true);
+            } else if arg == "-strict" {
+                builder = builder.strict(true);
+            } else if arg == "-watch" {
+                watch = true;
+            } else if arg == "-player" {
+                player = option_value(&arg, &mut args);
+            } else if arg == "-player-bin" {
+                builder = builder.player_binary(Path::new(&option_value(&arg, &mut args)));
+            } else if arg == "-max-writes" {
+                let value = option_value(&arg, &mut args);
+                match value.parse() {
+                    Ok(writes) => builder = builder.max_frame_writes(writes),
+                    Err(_) => panic!("Invalid number of writes: {}", value),
+                }
+            } else if arg == "-echo" {
+                let value = option_value(&arg, &mut args);
+                match value.parse() {
+                    Ok(edl) => builder = builder.echo(edl),
+                    Err(_) => panic!("Invalid echo delay: {}", value),
+                }
+            } else if arg == "-port" {
+                let value = option_value(&arg, &mut args);
+                for (port, byte) in options::parse_io_ports(&value).unwrap_or_else(|e| panic!("{}", e)) {
+                    builder = builder.io_port(port, byte);
+                }
+            } else if arg == "-patch" {
+                let value = option_value(&arg, &mut args);
+                for (address, byte) in options::parse_patches(&value).unwrap_or_else(|e| panic!("{}", e)) {
+                    builder = builder.patch(address, byte);
+                }
+            } else if arg == "-v" {
+                verbose = true;
+            } else if arg == "-stats" {
+                show_stats = true;
+            } else if arg == "-timing" {
+                show_timing = true;
+            } else if arg == "-csv" {
+                csv_path = option_value(&arg, &mut args);
+            } else if arg == "-export-tags" {
+                tags_path = option_value(&arg, &mut args);
+            } else if arg == "-dump-header" {
+                header_path = option_value(&arg, &mut args);
+            } else if arg == "-emit-preprocessed" {
+                preprocessed_path = option_value(&arg, &mut args);
+                builder = builder.keep_preprocessed(true);
+            } else if arg == "-emit-asm" {
+                asm_path = option_value(&arg, &mut args);
+            } else if arg == "-asm-syntax" {
+                let value = option_value(&arg, &mut args);
+                match AsmSyntax::from_name(&value) {
+                    Some(syntax) => asm_syntax = syntax,
+                    None => panic!("Unknown assembler syntax: {}", value),
+                }
+            } else if arg == "-loops" {
+                let value = option_value(&arg, &mut args);
+                match value.parse() {
+                    Ok(count) => builder = builder.loops(count),
+                    Err(_) => panic!("Invalid number of loops: {}", value),
+                }
+            } else if arg == "-strip-gd3" {
+                builder = builder.strip_gd3(true);
+            } else if arg == "-auto-fit" {
+                builder = builder.auto_fit(true);
+            } else if arg == "-wait-tolerance" {
+                let value = option_value(&arg, &mut args);
+                match value.parse() {
+                    Ok(samples) => builder = builder.wait_tolerance(samples),
+                    Err(_) => panic!("Invalid wait tolerance: {}", value),
+                }
+            } else if arg == "-seek-table" {
+                let value = option_value(&arg, &mut args);
+                match value.parse() {
+                    Ok(seconds) => builder = builder.seek_table(seconds),
+                    Err(_) => panic!("Invalid seek table interval: {}", value),
+                }
+            } else if arg == "-codec" {
+                let value = option_value(&arg, &mut args);
+                builder = match value.as_str() {
+                    "auto" => builder.codec(Codec::Auto),
+                    "psg" => builder.codec(Codec::Psg),
+                    "psg-runs" => builder.codec(Codec::PsgRuns),
+                    "none" => builder.codec(Codec::Null),
+                    _ => panic!("Unknown codec: {}", value),
+                };
+            } else if arg == "-dumper" {
+                builder = builder.dumper(&option_value(&arg, &mut args));
+            } else if arg == "-title" {
+                builder = builder.title(&option_value(&arg, &mut args));
+            } else if arg == "-comment" {
+                builder = builder.comment(&option_value(&arg, &mut args));
+            } else if arg == "-date" {
+                let value = option_value(&arg, &mut args);
+                match Date::parse(&value) {
+                    Some(date) => builder = builder.dump_date(date),
+                    None => panic!("Invalid date: {} (expected YYYY-MM-DD)", value),
+                }
+            } else if arg == "-binary-id666" {
+                builder = builder.id666_format(Id666Format::Binary);
+            } else if arg == "-gd3-provenance" {
+                builder = builder.gd3_provenance(true);
             } else {
                 panic!("Unknown option: {}", arg);
             }
-        } else if input_path.is_empty() {
-            input_path = arg.to_owned();
-        } else if output_path.is_empty() {
-            output_path = arg.to_owned();
+        } else if let Some(list_path) = arg.strip_prefix('@') {
+            let entries = inputlist::read(Path::new(list_path)).unwrap_or_else(|e| panic!("Failed to read input list: {}", e));
+            for entry in entries {
+                // The tag is based on the first song, so the other songs have nothing to override
+                if entry.title.is_some() || entry.loops.is_some() {
+                    if !paths.is_empty() {
+                        panic!("{}: overrides can only be given for the first input, since the output has a single tag", entry.path);
+                    }
+                    if let Some(title) = &entry.title {
+                        builder = builder.title(title);
+                    }
+                    if let Some(loops) = entry.loops {
+                        builder = builder.loops(loops);
+                    }
+                }
+                paths.push(entry.path);
+            }
+        } else if paths.len() < 2 || multi || join {
+            paths.push(arg);
         } else {
             panic!("Unknown option: {}", arg);
         }
     }
 
-    if input_path.is_empty() || output_path.is_empty() {
+    if paths.len() < 2 {
         show_help();
-    }    
- 
-    flags |= ConverterFlags::PSG_CODEC;
+    }
+    if paths.len() > 2 && !multi && !join {
+        panic!("Several input files were given. Use -multi or -join to convert them together");
+    }
+    let output_path = paths.pop().unwrap();
+    let input_path = paths[0].clone();
+
+    let output_format = output_format.or_else(|| OutputFormat::from_extension(Path::new(&output_path)));
+    builder = builder.output_format(output_format.unwrap_or(OutputFormat::Spc));
+    let mut converter = builder.build().expect("Invalid options").with_observer(Box::new(ConsoleObserver));
+    let input_paths: Vec<&Path> = paths.iter().map(Path::new).collect();
+    let convert = |converter: &mut Converter| {
+        if join {
+            converter.convert_joined(&input_paths, Path::new(&output_path))
+        } else if multi {
+            converter.convert_multi(&input_paths, Path::new(&output_path))
+        } else {
+            converter.convert(input_paths[0], Path::new(&output_path))
+        }
+    };
+    let result = convert(&mut converter);
+    if !csv_path.is_empty() && !converter.packed_data().is_empty() {
+        append_csv_row(&csv_path, &paths.join(" + "), &converter).expect("Failed to write CSV file");
+    }
+    let result = result.expect("Failed");
+    if !result.warnings.is_empty() {
+        println!("{} warning{}:", result.warnings.len(), if result.warnings.len() == 1 { "" } else { "s" });
+        for warning in &result.warnings {
+            println!("  {}", warning);
+        }
+    }
+    let mut player_process = watch::PlayerProcess::default();
+    if !player.is_empty() {
+        player_process.restart(&player, Path::new(&output_path));
+    }
 
-    let mut converter = converter::Converter::new();
-    converter.convert(Path::new(&input_path), Path::new(&output_path), flags).expect("Failed");
+    if verbose {
+        match (converter.load_player(), result.free_ram) {
+            (Ok(player), Some(free)) => {
+                println!("Player: {} bytes, capacity: {} bytes, used: {} bytes ({} bytes free)", player.size(), converter.capacity(&player), result.size, free);
+            }
+            (Ok(_), None) => println!("The output isn't loaded into SPC RAM, so its size isn't limited"),
+            (Err(e), _) => println!("Warning: couldn't read the player binary: {}", e),
+        }
+    }
+    if show_stats {
+        match converter.section_sizes() {
+            Some(sizes) => println!("{}", sizes),
+            None => println!("Section sizes are not available for multi-song conversions"),
+        }
+        if let Some(breakdown) = converter.size_breakdown() {
+            println!("{}", breakdown);
+        }
+        if let Some(load) = converter.cpu_load() {
+            println!("{}", load);
+        }
+    }
+    if show_timing {
+        for timing in converter.timings() {
+            println!("{:<14} {:>9.3} ms {:>10.2} MB/s", timing.stage, timing.elapsed.as_secs_f64() * 1000.0, timing.throughput() / 1_000_000.0);
+        }
+    }
+
+    if !tags_path.is_empty() {
+        fs::write(&tags_path, converter.gd3().to_json(&input_path)).expect("Failed to write tags");
+    }
+    if !header_path.is_empty() {
+        #[derive(serde::Serialize)]
+        struct Metadata<'a> {
+            source: &'a str,
+            header: &'a vgm::specification::FileHeader,
+            gd3: &'a vgm::Gd3,
+        }
+        let metadata = Metadata { source: &input_path, header: converter.header(), gd3: converter.gd3() };
+        fs::write(&header_path, serde_json::to_string_pretty(&metadata).unwrap() + "\n").expect("Failed to write header");
+    }
+    if !preprocessed_path.is_empty() {
+        let vgm = converter.preprocessed_vgm().expect("The preprocessed song wasn't kept");
+        vgm::writer::write_vgm_file(Path::new(&preprocessed_path), vgm).expect("Failed to write preprocessed VGM");
+    }
+    if !asm_path.is_empty() {
+        let source = asm::emit(converter.packed_data(), converter.layout(), asm_syntax, &input_path);
+        fs::write(&asm_path, source).expect("Failed to write assembly file");
+    }
+    if watch {
+        watch::watch(&input_paths, || {
+            match convert(&mut converter) {
+                Ok(_) => {
+                    println!("Packed size: {} bytes", converter.packed_data().len());
+                    if !player.is_empty() {
+                        player_process.restart(&player, Path::new(&output_path));
+                    }
+                }
+                Err(e) => println!("Conversion failed: {}", e),
+            }
+        });
+    }
     println!("Done");
 }
@@ -0,0 +1,97 @@
+//!
+//! Progress reports from the converter. A converter tells its observer about everything that it
+//! would otherwise print, so that embedders can show it in their own UI or log.
+//!
+
+use crate::converter::Warning;
+
+/// Sizes of a packed song
+#[derive(Clone, Debug, PartialEq)]
+pub struct SongStats {
+    pub input_size: usize,              // Size of the decompressed VGM file
+    pub output_size: usize,
+    pub dsp_writes: Option<usize>,      // Number of S-DSP writes, in native S-DSP mode
+}
+
+/// Receives progress reports from a converter. All methods do nothing by default.
+pub trait Observer: Send + Sync {
+    /// Called when a stage of the conversion starts. The stages are named as in `StageTiming`.
+    fn on_stage_start(&self, _stage: &'static str) {}
+
+    /// Called with a message about what the converter does, e.g. the codec that it picked
+    fn on_message(&self, _message: &str) {}
+
+    /// Called for each warning as it's raised. With `-auto-fit`, the warnings of a song are
+    /// reported again each time it's packed; the conversion result only holds those of the last try.
+    fn on_warning(&self, _warning: &Warning) {}
+
+    /// Called when a song has been packed
+    fn on_stats(&self, _stats: &SongStats) {}
+}
+
+/// Prints messages and sizes to stdout. Warnings aren't printed, since the command line tool
+/// prints all of them at the end.
+pub struct ConsoleObserver;
+
+impl Observer for ConsoleObserver {
+    fn on_message(&self, message: &str) {
+        println!("{}", message);
+    }
+
+    fn on_stats(&self, stats: &SongStats) {
+        match stats.dsp_writes {
+            Some(writes) => println!("Input size: {} bytes, {} S-DSP writes, output size: {} bytes", stats.input_size, writes, stats.output_size),
+            None => println!("Input size: {} bytes, output size: {} bytes ({}%)", stats.input_size, stats.output_size,
+                             100 * stats.output_size / stats.input_size),
+        }
+    }
+}
+
+/// Ignores everything
+pub struct SilentObserver;
+
+impl Observer for SilentObserver {}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::converter::{Converter, WarningKind};
+    use crate::options::{Codec, OutputFormat};
+    use crate::passes::PassKind;
+    use crate::vgm::Command;
+    use crate::vgm::testing::vgm_file;
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<Warning>>);
+
+    impl Observer for Arc<Recorder> {
+        fn on_warning(&self, warning: &Warning) {
+            self.0.lock().unwrap().push(warning.clone());
+        }
+    }
+
+    #[test]
+    fn test_pass_reports_reach_observer() {
+        // An FM write for -psg-only to strip, and a GG stereo write that repeats the current mask
+        let vgm = vgm_file(&[Command::GG_STEREO, 0xFF, Command::YM2612_LO_WRITE, 0x28, 0xF0, Command::PSG_WRITE, 0x90,
+                             Command::WAIT_NTSC_FRAME, Command::GG_STEREO, 0xFF, Command::WAIT_NTSC_FRAME,
+                             Command::END_OF_SOUND_DATA], 2 * 735);
+        let input = std::env::temp_dir().join(format!("vgm2spc-observer-{}.vgm", std::process::id()));
+        std::fs::write(&input, vgm).unwrap();
+
+        let recorder = Arc::new(Recorder::default());
+        let mut converter = Converter::builder().codec(Codec::Psg).psg_only(true).passes(&[PassKind::GgStereo])
+            .output_format(OutputFormat::Raw).build().unwrap().with_observer(Box::new(recorder.clone()));
+        let result = converter.pack_file(&input).map(|_| ());
+        std::fs::remove_file(&input).unwrap();
+        result.unwrap();
+
+        let warnings = recorder.0.lock().unwrap();
+        let messages: Vec<(WarningKind, &str)> = warnings.iter().map(|w| (w.kind, w.message.as_str())).collect();
+        assert_eq!(messages, vec![(WarningKind::DroppedCommands, "removed 3 bytes of non-PSG data"),
+                                  (WarningKind::DroppedCommands, "removed 1 redundant GG stereo commands")]);
+        assert!(warnings.iter().all(|w| w.file.as_deref() == Some(input.as_path())));
+    }
+}
@@ -0,0 +1,532 @@
+//!
+//! Conversion settings, and the builder used to create a `Converter` from them.
+//!
+
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use crate::analysis;
+use crate::converter::Converter;
+use crate::date::Date;
+use crate::passes::PassKind;
+use crate::spc::Id666Format;
+
+/// How the VGM command stream is compressed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Codec {
+    /// Pick `Psg` or `Null` based on the chips used by the song
+    Auto,
+    /// Compress PSG writes and waits. Only usable for songs that don't use any other chips.
+    Psg,
+    /// Like `Psg`, with runs of PSG writes encoded without flag bytes. The player has to list
+    /// "psg-runs" in its manifest.
+    PsgRuns,
+    /// Store the commands as they are
+    Null,
+}
+
+impl Codec {
+    /// The codecs that the bundled player can play, and `Auto`. `PsgRuns` is left out, since it's
+    /// only usable with a player that supports it, and `Auto` never selects it.
+    pub const ALL: &'static [Codec] = &[Codec::Auto, Codec::Psg, Codec::Null];
+
+    /// Returns true if the codec is one of the PSG codecs
+    pub fn is_psg(self) -> bool {
+        self == Codec::Psg || self == Codec::PsgRuns
+    }
+
+    /// Return the name used for the codec by `-codec` and in player manifests
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::Auto => "auto",
+            Codec::Psg => "psg",
+            Codec::PsgRuns => "psg-runs",
+            Codec::Null => "none",
+        }
+    }
+}
+
+/// What happens to the second SN76489 of dual-PSG files (commands 0x30 and 0x3F). The player only
+/// plays one PSG, so `Mix` and `Pan` can only be used for VGM output.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DualPsgMapping {
+    /// Play both chips in the center
+    Mix,
+    /// Play the first chip on the left, and the second one on the right
+    Pan,
+    /// Remove the writes to the second chip
+    Drop,
+}
+
+impl DualPsgMapping {
+    /// Names accepted by `from_name`
+    pub const NAMES: &'static [&'static str] = &["mix", "pan", "drop"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "mix" => Some(DualPsgMapping::Mix),
+            "pan" => Some(DualPsgMapping::Pan),
+            "drop" => Some(DualPsgMapping::Drop),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    /// An SPC file with the player, packed data and ID666 tag
+    Spc,
+    /// A SNES ROM that uploads the player and packed data to the S-SMP
+    Sfc,
+    /// Only the packed data
+    Raw,
+    /// Like `Raw`, but with the VGM header replaced by a data-only descriptor, and without GD3 tag
+    DataOnly,
+    /// The song as a VGM file, as it is after preprocessing. Compressed if the name ends with ".vgz".
+    Vgm,
+    /// The song as a log of S-DSP register writes, as played in native S-DSP mode (see `dsp::write_log`)
+    DspLog,
+}
+
+impl OutputFormat {
+    /// Names accepted by `from_name`
+    pub const NAMES: &'static [&'static str] = &["spc", "sfc", "raw", "data-only", "vgm", "dsplog"];
+
+    /// Returns true if the output contains an ID666 tag
+    pub fn has_tag(self) -> bool {
+        self == OutputFormat::Spc
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "spc" => Some(OutputFormat::Spc),
+            "sfc" => Some(OutputFormat::Sfc),
+            "raw" => Some(OutputFormat::Raw),
+            "data-only" => Some(OutputFormat::DataOnly),
+            "vgm" => Some(OutputFormat::Vgm),
+            "dsplog" => Some(OutputFormat::DspLog),
+            _ => None,
+        }
+    }
+
+    /// Return the format implied by the extension of `path`, if it's one we know
+    pub fn from_extension(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "spc" => Some(OutputFormat::Spc),
+            "sfc" | "smc" => Some(OutputFormat::Sfc),
+            "bin" | "vgmc" => Some(OutputFormat::Raw),
+            "vgm" | "vgz" => Some(OutputFormat::Vgm),
+            "dsplog" => Some(OutputFormat::DspLog),
+            _ => None,
+        }
+    }
+}
+
+/// Parse a number given as decimal, or as hexadecimal with a "0x" or "$" prefix
+fn parse_number(s: &str) -> Option<u32> {
+    if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")).or_else(|| s.strip_prefix('$')) {
+        u32::from_str_radix(hex, 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+/// Parse a comma-separated list of `key=value` pairs, where the keys are at most `max_key` and
+/// the values are bytes. `what` names the keys in error messages.
+fn parse_byte_assignments(list: &str, what: &str, max_key: u32) -> Result<Vec<(u32, u8)>, String> {
+    list.split(',').map(|item| {
+        let (key, value) = item.split_once('=').ok_or_else(|| format!("Invalid {}: {} (expected {}=value)", what, item, what))?;
+        let key = parse_number(key.trim()).filter(|&k| k <= max_key).ok_or_else(|| format!("Invalid {}: {}", what, key))?;
+        let value = parse_number(value.trim()).filter(|&v| v <= 0xFF).ok_or_else(|| format!("Invalid value: {}", value))?;
+        Ok((key, value as u8))
+    }).collect()
+}
+
+/// Parse a comma-separated list of RAM patches, each of the form `address=value`
+pub fn parse_patches(list: &str) -> Result<Vec<(u16, u8)>, String> {
+    let patches = parse_byte_assignments(list, "address", 0xFFFF)?;
+    Ok(patches.into_iter().map(|(address, value)| (address as u16, value)).collect())
+}
+
+/// Parse a comma-separated list of IO port values, each of the form `port=value`
+pub fn parse_io_ports(list: &str) -> Result<Vec<(usize, u8)>, String> {
+    let ports = parse_byte_assignments(list, "port", 3)?;
+    Ok(ports.into_iter().map(|(port, value)| (port as usize, value)).collect())
+}
+
+/// The settings of a `Converter`. Create one with `Converter::builder()`.
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub codec: Codec,
+    pub output_format: OutputFormat,
+    pub id666_format: Id666Format,
+    pub assume_vgz: bool,
+    pub gd3_provenance: bool,       // Use the GD3 ripper/notes as the ID666 dumper/comment
+    pub strict: bool,               // Fail on reserved or undefined commands instead of skipping them
+    pub psg_only: bool,             // Remove writes to chips other than the PSG, and all data blocks
+    pub dual_psg: DualPsgMapping,   // What to do with the second PSG of dual-PSG files
+    pub loop_last: bool,            // Loop joined songs back to the start of the last song
+    pub loop_all: bool,             // Loop songs without a loop point back to the start
+    pub repair: bool,               // Terminate truncated files instead of failing
+    pub trim_silence: bool,         // Remove leading and trailing silence
+    pub reset_chips: bool,          // Silence the PSG at the start and at the loop point
+    pub passes: Vec<PassKind>,      // Preprocessing passes, in the order they're run
+    pub keep_preprocessed: bool,    // Keep the preprocessed song as a VGM file, for inspection
+    pub check_invariants: bool,     // Check the offsets in the packed data after conversion
+    pub strip_gd3: bool,            // Leave the GD3 tag out of the packed data (the ID666 tag is still filled in from it)
+    pub auto_fit: bool,             // Retry with more aggressive settings when the packed data doesn't fit
+    pub wait_tolerance: u16,        // Max difference in samples for a long wait to use a LUT entry for another duration
+    pub max_frame_writes: u32,      // Max number of PSG writes in a frame before it's reported as busy
+    pub seek_interval: Option<u16>, // Seconds between the entries of the seek table, if one is added
+    pub native_dsp: bool,           // Translate PSG writes to S-DSP writes, played by the built-in driver
+    pub echo: Option<u8>,           // Echo delay (EDL) to reserve an echo buffer for
+    pub io_ports: [Option<u8>; 4],  // Initial values of the IO ports ($F4-$F7), as seen by the player
+    pub patches: Vec<(u16, u8)>,    // Bytes to write to the SPC RAM image, as (address, value)
+    pub player_binary: Option<PathBuf>, // Player to include in the output, instead of the default one
+    pub loops: Option<u32>,         // Number of loops to play before fading out, for the ID666 play length
+    pub title: Option<String>,      // Song title for the ID666 tag, instead of the GD3 track name
+    pub dumper: Option<String>,
+    pub comment: Option<String>,
+    pub dump_date: Option<Date>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            codec: Codec::Auto,
+            output_format: OutputFormat::Spc,
+            id666_format: Id666Format::Text,
+            assume_vgz: false,
+            gd3_provenance: false,
+            strict: false,
+            psg_only: false,
+            dual_psg: DualPsgMapping::Drop,
+            loop_last: false,
+            loop_all: false,
+            repair: false,
+            trim_silence: false,
+            reset_chips: false,
+            passes: PassKind::DEFAULT.to_vec(),
+            keep_preprocessed: false,
+            check_invariants: false,
+            strip_gd3: false,
+            auto_fit: false,
+            wait_tolerance: 0,
+            max_frame_writes: analysis::DEFAULT_MAX_FRAME_WRITES,
+            seek_interval: None,
+            native_dsp: false,
+            echo: None,
+            io_ports: [None; 4],
+            patches: Vec::new(),
+            player_binary: None,
+            loops: None,
+            title: None,
+            dumper: None,
+            comment: None,
+            dump_date: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ConverterBuilder {
+    options: Options,
+}
+
+impl ConverterBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn codec(mut self, codec: Codec) -> Self {
+        self.options.codec = codec;
+        self
+    }
+
+    pub fn output_format(mut self, format: OutputFormat) -> Self {
+        self.options.output_format = format;
+        self
+    }
+
+    pub fn id666_format(mut self, format: Id666Format) -> Self {
+        self.options.id666_format = format;
+        self
+    }
+
+    /// Treat the input as VGZ even if it doesn't look like it
+    pub fn assume_vgz(mut self, enable: bool) -> Self {
+        self.options.assume_vgz = enable;
+        self
+    }
+
+    /// Use the GD3 ripper and notes as the ID666 dumper and comment, unless set explicitly
+    pub fn gd3_provenance(mut self, enable: bool) -> Self {
+        self.options.gd3_provenance = enable;
+        self
+    }
+
+    pub fn strict(mut self, enable: bool) -> Self {
+        self.options.strict = enable;
+        self
+    }
+
+    pub fn psg_only(mut self, enable: bool) -> Self {
+        self.options.psg_only = enable;
+        self
+    }
+
+    /// Choose what happens to the second PSG of dual-PSG files. By default, it's dropped.
+    pub fn dual_psg(mut self, mapping: DualPsgMapping) -> Self {
+        self.options.dual_psg = mapping;
+        self
+    }
+
+    pub fn loop_last(mut self, enable: bool) -> Self {
+        self.options.loop_last = enable;
+        self
+    }
+
+    /// Make songs without a loop point loop the whole song, instead of stopping at the end
+    pub fn loop_all(mut self, enable: bool) -> Self {
+        self.options.loop_all = enable;
+        self
+    }
+
+    /// Repair files whose command stream has been truncated, by cutting the stream after the last
+    /// complete command and terminating it, instead of failing
+    pub fn repair(mut self, enable: bool) -> Self {
+        self.options.repair = enable;
+        self
+    }
+
+    pub fn trim_silence(mut self, enable: bool) -> Self {
+        self.options.trim_silence = enable;
+        self
+    }
+
+    pub fn reset_chips(mut self, enable: bool) -> Self {
+        self.options.reset_chips = enable;
+        self
+    }
+
+    /// Set the preprocessing passes to run, in order. `psg_only` adds the `StripNonPsg` pass in
+    /// front of these unless it's already in the list.
+    pub fn passes(mut self, passes: &[PassKind]) -> Self {
+        self.options.passes = passes.to_vec();
+        self
+    }
+
+    /// Remove a pass from the list of preprocessing passes
+    pub fn without_pass(mut self, pass: PassKind) -> Self {
+        self.options.passes.retain(|&p| p != pass);
+        self
+    }
+
+    /// Keep the song as it was after preprocessing, see `Converter::preprocessed_vgm`
+    pub fn keep_preprocessed(mut self, enable: bool) -> Self {
+        self.options.keep_preprocessed = enable;
+        self
+    }
+
+    /// Check that the offsets written to the header of each packed song point at the right places,
+    /// and fail the conversion if they don't. Meant for debugging the converter.
+    pub fn check_invariants(mut self, enable: bool) -> Self {
+        self.options.check_invariants = enable;
+        self
+    }
+
+    /// Leave the GD3 tag out of the packed data, to save space. The ID666 tag is still based on it.
+    pub fn strip_gd3(mut self, enable: bool) -> Self {
+        self.options.strip_gd3 = enable;
+        self
+    }
+
+    /// When the packed data doesn't fit in SPC RAM, retry with settings that make it smaller
+    /// without changing what's played (stripping the GD3 tag, compressing with the codec selected
+    /// for the song, removing PCM data and other chips) until it fits or there's nothing left to try
+    pub fn auto_fit(mut self, enable: bool) -> Self {
+        self.options.auto_fit = enable;
+        self
+    }
+
+    /// Let the PSG codec encode long waits with a LUT entry that's at most `samples` off, when
+    /// there's no exact match. This changes the timing of the song slightly.
+    pub fn wait_tolerance(mut self, samples: u16) -> Self {
+        self.options.wait_tolerance = samples;
+        self
+    }
+
+    /// Warn about frames with more than `writes` PSG writes, which the player may not be able to
+    /// service in time
+    pub fn max_frame_writes(mut self, writes: u32) -> Self {
+        self.options.max_frame_writes = writes;
+        self
+    }
+
+    /// Add a seek table to the extradata, with an entry every `seconds` seconds (see
+    /// `player::seek_table_block`), so that players can start playback in the middle of the song
+    pub fn seek_table(mut self, seconds: u16) -> Self {
+        self.options.seek_interval = Some(seconds);
+        self
+    }
+
+    /// Translate the PSG writes to writes to the S-DSP voice registers, and play them with the
+    /// built-in driver (see `dsp`) instead of the player. Writes to other chips are dropped.
+    pub fn native_dsp(mut self, enable: bool) -> Self {
+        self.options.native_dsp = enable;
+        self
+    }
+
+    /// Reserve an echo buffer for the echo delay `edl` (0-15) at the top of RAM, and set up the
+    /// DSP echo registers (ESA, EDL and FLG) for it. The packed data must fit below the buffer.
+    pub fn echo(mut self, edl: u8) -> Self {
+        self.options.echo = Some(edl);
+        self
+    }
+
+    /// Set the value that the player reads from IO port `port` (0-3) at startup, e.g. the song
+    /// number for multi-song SPCs
+    pub fn io_port(mut self, port: usize, value: u8) -> Self {
+        self.options.io_ports[port] = Some(value);
+        self
+    }
+
+    /// Write `value` to `address` in the SPC RAM image, after the player and packed data have been
+    /// placed. Later patches to the same address take precedence.
+    pub fn patch(mut self, address: u16, value: u8) -> Self {
+        self.options.patches.push((address, value));
+        self
+    }
+
+    /// Include the player binary at `path` in the output, instead of looking for the default one
+    pub fn player_binary(mut self, path: &Path) -> Self {
+        self.options.player_binary = Some(path.to_path_buf());
+        self
+    }
+
+    /// Set the ID666 play length to the intro plus `count` loops
+    pub fn loops(mut self, count: u32) -> Self {
+        self.options.loops = Some(count);
+        self
+    }
+
+    /// Set the song title ID666 field, instead of taking it from the GD3 tag
+    pub fn title(mut self, title: &str) -> Self {
+        self.options.title = Some(title.to_owned());
+        self
+    }
+
+    /// Set the "dumped by" ID666 field. Takes precedence over `gd3_provenance`.
+    pub fn dumper(mut self, dumper: &str) -> Self {
+        self.options.dumper = Some(dumper.to_owned());
+        self
+    }
+
+    /// Set the comment ID666 field. Takes precedence over `gd3_provenance`.
+    pub fn comment(mut self, comment: &str) -> Self {
+        self.options.comment = Some(comment.to_owned());
+        self
+    }
+
+    /// Set the ID666 dump date. If no date is set, the date of the conversion is used.
+    pub fn dump_date(mut self, date: Date) -> Self {
+        self.options.dump_date = Some(date);
+        self
+    }
+
+    /// Check that the settings make sense together, and create the converter
+    pub fn build(self) -> Result<Converter, Error> {
+        let o = &self.options;
+        if o.loops == Some(0) {
+            return Err(Error::new(ErrorKind::InvalidInput, "The number of loops must be at least 1"));
+        }
+        if o.wait_tolerance > 0 && o.codec == Codec::Null {
+            return Err(Error::new(ErrorKind::InvalidInput, "A wait tolerance can't be used without the PSG codec, which has no long wait LUT"));
+        }
+        if o.dual_psg != DualPsgMapping::Drop && (o.psg_only || o.native_dsp) {
+            return Err(Error::new(ErrorKind::InvalidInput, "The second PSG is always dropped with -psg-only and in the native S-DSP mode"));
+        }
+        if o.dual_psg != DualPsgMapping::Drop && o.output_format != OutputFormat::Vgm {
+            return Err(Error::new(ErrorKind::InvalidInput, "The player only plays one PSG. The second PSG can only be mixed or panned in VGM output"));
+        }
+        if o.seek_interval == Some(0) {
+            return Err(Error::new(ErrorKind::InvalidInput, "The seek table interval must be at least 1 second"));
+        }
+        if o.seek_interval.is_some() && (o.native_dsp || matches!(o.output_format, OutputFormat::Vgm | OutputFormat::DspLog)) {
+            return Err(Error::new(ErrorKind::InvalidInput, "A seek table can only be added to packed VGM data"));
+        }
+        if !o.patches.is_empty() && !matches!(o.output_format, OutputFormat::Spc | OutputFormat::Sfc) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("RAM patches can't be used with {:?} output, which has no RAM image", o.output_format)));
+        }
+        if o.echo.is_some_and(|edl| edl > 15) {
+            return Err(Error::new(ErrorKind::InvalidInput, "The echo delay must be between 0 and 15"));
+        }
+        if o.native_dsp && !matches!(o.output_format, OutputFormat::Spc | OutputFormat::Sfc) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("The native S-DSP mode can't be used with {:?} output", o.output_format)));
+        }
+        if o.echo.is_some() && o.output_format != OutputFormat::Spc {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("An echo buffer can't be set up with {:?} output", o.output_format)));
+        }
+        if o.player_binary.is_some() && o.native_dsp {
+            return Err(Error::new(ErrorKind::InvalidInput, "A player binary can't be used in the native S-DSP mode, which has its own driver"));
+        }
+        if o.player_binary.is_some() && !matches!(o.output_format, OutputFormat::Spc | OutputFormat::Sfc) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("A player binary can't be used with {:?} output, which has no player", o.output_format)));
+        }
+        if o.io_ports.iter().any(Option::is_some) && o.output_format != OutputFormat::Spc {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("IO port values can't be used with {:?} output", o.output_format)));
+        }
+        let tag_settings = o.id666_format != Id666Format::Text || o.gd3_provenance || o.loops.is_some()
+                           || o.title.is_some() || o.dumper.is_some() || o.comment.is_some() || o.dump_date.is_some();
+        if tag_settings && !o.output_format.has_tag() {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("ID666 settings can't be used with {:?} output, which has no tag", o.output_format)));
+        }
+        Ok(Converter::with_options(self.options))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validation() {
+        assert!(ConverterBuilder::new().codec(Codec::Psg).loops(2).build().is_ok());
+        assert!(ConverterBuilder::new().loops(0).build().is_err());
+        assert!(ConverterBuilder::new().seek_table(0).build().is_err());
+        assert!(ConverterBuilder::new().dual_psg(DualPsgMapping::Pan).psg_only(true).build().is_err());
+        assert!(ConverterBuilder::new().dual_psg(DualPsgMapping::Drop).psg_only(true).build().is_ok());
+        assert!(ConverterBuilder::new().dual_psg(DualPsgMapping::Mix).build().is_err());
+        assert!(ConverterBuilder::new().dual_psg(DualPsgMapping::Mix).output_format(OutputFormat::Vgm).build().is_ok());
+        assert!(ConverterBuilder::new().seek_table(5).native_dsp(true).build().is_err());
+        assert!(ConverterBuilder::new().seek_table(5).output_format(OutputFormat::Raw).build().is_ok());
+        assert!(ConverterBuilder::new().output_format(OutputFormat::Raw).dumper("Someone").build().is_err());
+        assert!(ConverterBuilder::new().output_format(OutputFormat::Sfc).id666_format(Id666Format::Binary).build().is_err());
+        assert!(ConverterBuilder::new().output_format(OutputFormat::DataOnly).psg_only(true).build().is_ok());
+        assert!(ConverterBuilder::new().codec(Codec::Null).wait_tolerance(4).build().is_err());
+        assert!(ConverterBuilder::new().output_format(OutputFormat::Raw).patch(0xF4, 1).build().is_err());
+        assert!(ConverterBuilder::new().output_format(OutputFormat::Sfc).io_port(0, 1).build().is_err());
+        assert!(ConverterBuilder::new().echo(16).build().is_err());
+        assert!(ConverterBuilder::new().native_dsp(true).output_format(OutputFormat::Raw).build().is_err());
+    }
+
+    #[test]
+    fn test_parse_patches() {
+        assert_eq!(parse_patches("0xF4=1,$1234=0xFF, 100 = $0a"), Ok(vec![(0xF4, 1), (0x1234, 0xFF), (100, 0x0A)]));
+        assert!(parse_patches("0xF4").is_err());
+        assert!(parse_patches("0x10000=1").is_err());
+        assert!(parse_patches("0xF4=256").is_err());
+        assert_eq!(parse_io_ports("0=3,3=$80"), Ok(vec![(0, 3), (3, 0x80)]));
+        assert!(parse_io_ports("4=1").is_err());
+    }
+
+    #[test]
+    fn test_output_format_from_extension() {
+        assert_eq!(OutputFormat::from_extension(Path::new("song.SPC")), Some(OutputFormat::Spc));
+        assert_eq!(OutputFormat::from_extension(Path::new("out/song.vgmc")), Some(OutputFormat::Raw));
+        assert_eq!(OutputFormat::from_extension(Path::new("song.vgz")), Some(OutputFormat::Vgm));
+        assert_eq!(OutputFormat::from_extension(Path::new("song")), None);
+        assert!(OutputFormat::NAMES.iter().all(|name| OutputFormat::from_name(name).is_some()));
+    }
+}
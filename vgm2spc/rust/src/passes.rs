@@ -0,0 +1,584 @@
+//!
+//! Preprocessing passes, which remove or merge commands in the VGM command stream before it's
+//! handed to the codec.
+//!
+//! Each pass is independent of the others and works on an `EventList`. The converter runs the
+//! passes listed in its options, in order, after applying the dual-PSG mapping with `DualPsg`.
+//!
+
+use std::io::Error;
+
+use crate::options::{Codec, DualPsgMapping};
+use crate::player;
+use crate::vgm::Command;
+use crate::vgm::edit;
+use crate::vgm::events::VgmEvent;
+use crate::vgm::specification;
+
+/// Length of the final wait added by `EventList::end_in_silence`
+pub const SILENT_ENDING_SAMPLES: u32 = 0xFFFF;
+
+/// The command stream of a song, and the event that playback jumps to when looping
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EventList {
+    pub events: Vec<VgmEvent>,
+    pub loop_index: Option<usize>,
+}
+
+impl EventList {
+    /// Rebuild the event list. `f` is called with the events that haven't been consumed yet, and
+    /// appends the replacement for the first of them to the output vector. It returns the number
+    /// of events it consumed.
+    ///
+    /// The intro and the loop body are rewritten separately, so `f` never sees events on both
+    /// sides of the loop point, and the loop point stays where it was.
+    pub fn rewrite<F>(&mut self, mut f: F)
+        where F: FnMut(&[VgmEvent], &mut Vec<VgmEvent>) -> usize {
+        let split = self.loop_index.unwrap_or(self.events.len());
+        let mut output = Vec::with_capacity(self.events.len());
+        Self::rewrite_section(&self.events[..split], &mut output, &mut f);
+        let loop_index = output.len();
+        let mut loop_body = Vec::new();
+        Self::rewrite_section(&self.events[split..], &mut loop_body, &mut f);
+        output.extend(loop_body);
+
+        self.loop_index = self.loop_index.map(|_| loop_index);
+        self.events = output;
+    }
+
+    /// Remove all events. The storage is kept, so that the list can be reused for the next song
+    /// without allocating it again.
+    pub fn clear(&mut self) {
+        self.events.clear();
+        self.loop_index = None;
+    }
+
+    /// Return the length of the song in samples, without loops
+    pub fn samples(&self) -> u64 {
+        self.events.iter().map(|e| e.samples() as u64).sum()
+    }
+
+    /// Return the number of samples before the loop point, and from the loop point to the end
+    pub fn section_samples(&self) -> (u64, u64) {
+        let loop_index = self.loop_index.unwrap_or(self.events.len()).min(self.events.len());
+        let samples = |events: &[VgmEvent]| events.iter().map(|e| e.samples() as u64).sum();
+        (samples(&self.events[..loop_index]), samples(&self.events[loop_index..]))
+    }
+
+    /// Make a song without a loop point end in silence. The PSG channels are muted, and a loop
+    /// point is added at a final long wait, so a player that always loops keeps waiting there
+    /// instead of jumping back into the song.
+    pub fn end_in_silence(&mut self) {
+        let end = self.events.iter().rposition(|e| *e == VgmEvent::End).unwrap_or(self.events.len());
+        let mut tail = edit::psg_silence_events();
+        tail.push(VgmEvent::Wait(SILENT_ENDING_SAMPLES));
+        self.loop_index = Some(end + tail.len() - 1);
+        self.events.splice(end..end, tail);
+    }
+
+    /// Add `steps` (2 dB each) to the attenuation of every SN76489 volume write, clamped to the
+    /// valid range. Writes that mute a channel are left alone, so silence stays silent. Returns
+    /// the number of writes that were changed, and the number of writes that were clipped at full
+    /// volume.
+    pub fn adjust_psg_attenuation(&mut self, steps: i32) -> (usize, usize) {
+        let mut latched_volume = false;
+        let mut changed = 0;
+        let mut clipped = 0;
+        for event in self.events.iter_mut() {
+            if let VgmEvent::PsgWrite(value) = event {
+                if *value & 0x80 != 0 {
+                    latched_volume = *value & 0x10 != 0;
+                }
+                if !latched_volume {
+                    continue;
+                }
+                let attenuation = (*value & 0x0F) as i32;
+                if attenuation == 0x0F {
+                    continue;
+                }
+                let adjusted = (attenuation + steps).clamp(0, 0x0F) as u8;
+                if adjusted as i32 != attenuation {
+                    *value = (*value & 0xF0) | adjusted;
+                    changed += 1;
+                }
+                if attenuation + steps < 0 {
+                    clipped += 1;
+                }
+            }
+        }
+        (changed, clipped)
+    }
+
+    fn rewrite_section<F>(events: &[VgmEvent], output: &mut Vec<VgmEvent>, f: &mut F)
+        where F: FnMut(&[VgmEvent], &mut Vec<VgmEvent>) -> usize {
+        let mut i = 0;
+        while i < events.len() {
+            i += std::cmp::max(1, f(&events[i..], output));
+        }
+    }
+}
+
+pub trait Pass {
+    fn name(&self) -> &'static str;
+
+    fn run(&mut self, song: &mut EventList) -> Result<(), Error>;
+
+    /// Return the non-fatal problems found by the last run
+    fn warnings(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// The passes that the converter knows how to create
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PassKind {
+    StripNonPsg,
+    Ym2612Timers,
+    Ym2612ModeWrites,
+    MergeDacWaits,
+    GgStereo,
+    GroupPsgWrites,
+    StripUnsupported,
+}
+
+impl PassKind {
+    /// The passes that are run unless another list is given
+    pub const DEFAULT: &'static [PassKind] = &[PassKind::Ym2612Timers, PassKind::Ym2612ModeWrites, PassKind::MergeDacWaits, PassKind::StripUnsupported];
+
+    pub const ALL: &'static [PassKind] = &[PassKind::StripNonPsg, PassKind::Ym2612Timers, PassKind::Ym2612ModeWrites, PassKind::MergeDacWaits, PassKind::GgStereo, PassKind::GroupPsgWrites, PassKind::StripUnsupported];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PassKind::StripNonPsg => "strip-non-psg",
+            PassKind::Ym2612Timers => "ym2612-timers",
+            PassKind::Ym2612ModeWrites => "ym2612-mode-writes",
+            PassKind::MergeDacWaits => "merge-dac-waits",
+            PassKind::GgStereo => "gg-stereo",
+            PassKind::GroupPsgWrites => "group-psg-writes",
+            PassKind::StripUnsupported => "strip-unsupported",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<PassKind> {
+        Self::ALL.iter().copied().find(|kind| kind.name() == name)
+    }
+
+    /// Create the pass, for a song that will be compressed with `codec`
+    pub fn create(self, codec: Codec) -> Box<dyn Pass> {
+        match self {
+            PassKind::StripNonPsg => Box::new(StripNonPsg { warnings: Vec::new() }),
+            PassKind::Ym2612Timers => Box::new(Ym2612Timers),
+            PassKind::Ym2612ModeWrites => Box::new(Ym2612ModeWrites),
+            PassKind::MergeDacWaits => Box::new(MergeDacWaits),
+            PassKind::GgStereo => Box::new(GgStereo { warnings: Vec::new() }),
+            PassKind::GroupPsgWrites => Box::new(GroupPsgWrites),
+            PassKind::StripUnsupported => Box::new(StripUnsupported { keep_raw_commands: codec == Codec::Null, warnings: Vec::new() }),
+        }
+    }
+}
+
+/// Remove everything that only matters to chips other than the ones the player supports, including
+/// data blocks. YM2612 DAC writes are replaced by their wait.
+pub struct StripNonPsg {
+    warnings: Vec<String>,
+}
+
+impl Pass for StripNonPsg {
+    fn name(&self) -> &'static str {
+        PassKind::StripNonPsg.name()
+    }
+
+    fn run(&mut self, song: &mut EventList) -> Result<(), Error> {
+        let mut stripped_bytes = 0;
+        song.rewrite(|events, output| {
+            let event = &events[0];
+            let keep = match event {
+                VgmEvent::DataBlock { .. } => false,
+                VgmEvent::Ym2612DacWrite { wait } => {
+                    if *wait != 0 {
+                        output.push(VgmEvent::Wait(*wait as u32));
+                    }
+                    false
+                }
+                VgmEvent::Command { command: Command::PCM_WRITE | Command::DAC_STREAM_SETUP ..= Command::DAC_STREAM_START_FAST, .. } => false,
+                _ => !matches!(specification::command_chip(event.command()), Some(chip) if !player::SUPPORTED_CHIPS.contains(&chip.name)),
+            };
+            if keep {
+                output.push(event.clone());
+            } else {
+                let mut bytes = Vec::new();
+                event.write_to(&mut bytes);
+                stripped_bytes += bytes.len();
+            }
+            1
+        });
+        self.warnings.clear();
+        if stripped_bytes > 0 {
+            self.warnings.push(format!("removed {} bytes of non-PSG data", stripped_bytes));
+        }
+        Ok(())
+    }
+
+    fn warnings(&self) -> Vec<String> {
+        self.warnings.clone()
+    }
+}
+
+/// Remove YM2612 timer writes (registers 0x25 and 0x26). The timers aren't used for playback.
+pub struct Ym2612Timers;
+
+impl Pass for Ym2612Timers {
+    fn name(&self) -> &'static str {
+        PassKind::Ym2612Timers.name()
+    }
+
+    fn run(&mut self, song: &mut EventList) -> Result<(), Error> {
+        song.rewrite(|events, output| {
+            match &events[0] {
+                VgmEvent::Command { command: Command::YM2612_LO_WRITE, args } if args[0] == 0x25 || args[0] == 0x26 => {}
+                event => output.push(event.clone()),
+            }
+            1
+        });
+        Ok(())
+    }
+}
+
+/// Remove writes to YM2612 register 0x27 that don't change the CH3 mode. The other bits of the
+/// register control the timers.
+pub struct Ym2612ModeWrites;
+
+impl Pass for Ym2612ModeWrites {
+    fn name(&self) -> &'static str {
+        PassKind::Ym2612ModeWrites.name()
+    }
+
+    fn run(&mut self, song: &mut EventList) -> Result<(), Error> {
+        let mut ch3_mode: u8 = 0;
+        song.rewrite(|events, output| {
+            match &events[0] {
+                VgmEvent::Command { command: Command::YM2612_LO_WRITE, args } if args[0] == 0x27 => {
+                    if (args[1] >> 6) != ch3_mode {
+                        ch3_mode = args[1] >> 6;
+                        output.push(events[0].clone());
+                    }
+                }
+                event => output.push(event.clone()),
+            }
+            1
+        });
+        Ok(())
+    }
+}
+
+/// Merge YM2612 DAC writes (0x8n) with adjacent short waits
+pub struct MergeDacWaits;
+
+impl Pass for MergeDacWaits {
+    fn name(&self) -> &'static str {
+        PassKind::MergeDacWaits.name()
+    }
+
+    fn run(&mut self, song: &mut EventList) -> Result<(), Error> {
+        song.rewrite(|events, output| {
+            let mut wait = match events[0] {
+                VgmEvent::Ym2612DacWrite { wait } => wait as u32,
+                ref event => {
+                    output.push(event.clone());
+                    return 1;
+                }
+            };
+            // Merge as many of the following waits as possible into the 0x8n command. If there
+            // are none, try merging with a preceding wait instead.
+            let mut consumed = 1;
+            if let Some(VgmEvent::Wait(1 ..= 16)) = events.get(1) {
+                while let Some(VgmEvent::Wait(samples)) = events.get(consumed) {
+                    if wait + samples >= 0x10 {
+                        break;
+                    }
+                    wait += samples;
+                    consumed += 1;
+                }
+            } else if let Some(VgmEvent::Wait(samples)) = output.last() {
+                if wait + samples < 0x10 {
+                    wait += samples;
+                    output.pop();
+                }
+            }
+            output.push(VgmEvent::Ym2612DacWrite { wait: wait as u8 });
+            consumed
+        });
+        Ok(())
+    }
+}
+
+/// Remove Game Gear stereo writes (0x4F) that have no effect: ones followed by another stereo
+/// write before the next wait, and ones that repeat the current stereo mask. The mask isn't known
+/// at the loop point, so the first write of the loop body is always kept. Not run by default, since
+/// it changes the output for existing Game Gear rips.
+pub struct GgStereo {
+    warnings: Vec<String>,
+}
+
+impl Pass for GgStereo {
+    fn name(&self) -> &'static str {
+        PassKind::GgStereo.name()
+    }
+
+    fn run(&mut self, song: &mut EventList) -> Result<(), Error> {
+        let loop_index = song.loop_index;
+        let mut position = 0;
+        let mut mask = None;
+        let mut removed = 0;
+        song.rewrite(|events, output| {
+            if Some(position) == loop_index {
+                mask = None;
+            }
+            position += 1;
+            match events[0] {
+                VgmEvent::GgStereo(value) => {
+                    let overwritten = events[1..].iter()
+                        .take_while(|e| e.samples() == 0)
+                        .any(|e| matches!(e, VgmEvent::GgStereo(_)));
+                    if overwritten || mask == Some(value) {
+                        removed += 1;
+                    } else {
+                        mask = Some(value);
+                        output.push(events[0].clone());
+                    }
+                }
+                ref event => output.push(event.clone()),
+            }
+            1
+        });
+        self.warnings.clear();
+        if removed > 0 {
+            self.warnings.push(format!("removed {} redundant GG stereo commands", removed));
+        }
+        Ok(())
+    }
+
+    fn warnings(&self) -> Vec<String> {
+        self.warnings.clone()
+    }
+}
+
+/// Move the PSG writes of each frame (the commands between two waits) ahead of the other commands
+/// of the frame, so that the flag bytes of the PSG codec cover runs of PSG writes instead of
+/// interleaved ones. The order of the PSG writes, and of the other commands, is kept.
+pub struct GroupPsgWrites;
+
+impl Pass for GroupPsgWrites {
+    fn name(&self) -> &'static str {
+        PassKind::GroupPsgWrites.name()
+    }
+
+    fn run(&mut self, song: &mut EventList) -> Result<(), Error> {
+        song.rewrite(|events, output| {
+            let frame = events.iter().take_while(|e| e.samples() == 0 && **e != VgmEvent::End).count();
+            if frame == 0 {
+                output.push(events[0].clone());
+                return 1;
+            }
+            let is_psg_write = |e: &&VgmEvent| matches!(e, VgmEvent::PsgWrite(_));
+            output.extend(events[..frame].iter().filter(is_psg_write).cloned());
+            output.extend(events[..frame].iter().filter(|e| !is_psg_write(e)).cloned());
+            frame
+        });
+        Ok(())
+    }
+}
+
+/// Apply the dual-PSG mapping to the commands for the second SN76489: its writes (0x30) and stereo
+/// masks (0x3F). With `Pan`, the stereo masks of the first chip are limited to the left side and
+/// those of the second chip to the right side, and with `Drop`, the second chip's commands are
+/// removed.
+pub struct DualPsg {
+    pub mapping: DualPsgMapping,
+}
+
+impl Pass for DualPsg {
+    fn name(&self) -> &'static str {
+        "dual-psg"
+    }
+
+    fn run(&mut self, song: &mut EventList) -> Result<(), Error> {
+        let mapping = self.mapping;
+        song.rewrite(|events, output| {
+            match (&events[0], mapping) {
+                (VgmEvent::Command { command: Command::PSG2_WRITE | Command::PSG2_GG_STEREO, .. }, DualPsgMapping::Drop) => {}
+                // The left channel enables are in the high nibble
+                (VgmEvent::GgStereo(mask), DualPsgMapping::Pan) => output.push(VgmEvent::GgStereo(mask & 0xF0)),
+                (VgmEvent::Command { command: Command::PSG2_GG_STEREO, args }, DualPsgMapping::Pan) =>
+                    output.push(VgmEvent::Command { command: Command::PSG2_GG_STEREO, args: vec![args[0] & 0x0F] }),
+                (event, _) => output.push(event.clone()),
+            }
+            1
+        });
+        Ok(())
+    }
+}
+
+/// Remove commands that the player doesn't support: AY8910 stereo masks, PCM seeks, and the
+/// commands whose bytes the PSG codec uses for itself (0x4E for padding, and 0x90-0x9F for LUT
+/// waits), i.e. DAC stream control and reserved or undefined commands. They're only kept when the
+/// output is raw VGM data, where someone else might play them.
+pub struct StripUnsupported {
+    pub keep_raw_commands: bool,
+    warnings: Vec<String>,
+}
+
+impl Pass for StripUnsupported {
+    fn name(&self) -> &'static str {
+        PassKind::StripUnsupported.name()
+    }
+
+    fn run(&mut self, song: &mut EventList) -> Result<(), Error> {
+        let keep = self.keep_raw_commands;
+        let mut stripped_ay_stereo_masks = 0;
+        let mut stripped_codec_commands = 0;
+        song.rewrite(|events, output| {
+            match &events[0] {
+                VgmEvent::Command { command: Command::AY8910_STEREO_MASK, .. } if !keep => stripped_ay_stereo_masks += 1,
+                event if !keep && matches!(event.command(), Command::NOP | 0x90 ..= 0x9F) => stripped_codec_commands += 1,
+                VgmEvent::SeekPcm(pcm_offset) if *pcm_offset == 0 || !keep => {}
+                event => output.push(event.clone()),
+            }
+            1
+        });
+        self.warnings.clear();
+        if stripped_ay_stereo_masks > 0 {
+            self.warnings.push(format!("removed {} AY8910 stereo mask commands (0x31)", stripped_ay_stereo_masks));
+        }
+        if stripped_codec_commands > 0 {
+            self.warnings.push(format!("removed {} commands that share their command byte with the PSG codec (0x4E, 0x90-0x9F)", stripped_codec_commands));
+        }
+        Ok(())
+    }
+
+    fn warnings(&self) -> Vec<String> {
+        self.warnings.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dac(wait: u8) -> VgmEvent {
+        VgmEvent::Ym2612DacWrite { wait }
+    }
+
+    #[test]
+    fn test_merge_dac_waits() {
+        let mut song = EventList {
+            events: vec![dac(0), VgmEvent::Wait(4), VgmEvent::Wait(8), VgmEvent::Wait(4), VgmEvent::Wait(2), dac(1),
+                         VgmEvent::PsgWrite(0x9F), VgmEvent::Wait(3), dac(2), VgmEvent::End],
+            loop_index: Some(8),
+        };
+        MergeDacWaits.run(&mut song).unwrap();
+        // The wait before the loop point isn't merged into the loop body
+        assert_eq!(song.events, vec![dac(12), VgmEvent::Wait(4), dac(3), VgmEvent::PsgWrite(0x9F), VgmEvent::Wait(3), dac(2), VgmEvent::End]);
+        assert_eq!(song.loop_index, Some(5));
+    }
+
+    #[test]
+    fn test_gg_stereo() {
+        let mut song = EventList {
+            events: vec![VgmEvent::GgStereo(0xFF), VgmEvent::PsgWrite(0x9F), VgmEvent::GgStereo(0xF0), VgmEvent::Wait(735),
+                         VgmEvent::GgStereo(0xF0), VgmEvent::Wait(735), VgmEvent::GgStereo(0x0F), VgmEvent::Wait(735),
+                         VgmEvent::GgStereo(0x0F), VgmEvent::End],
+            loop_index: Some(8),
+        };
+        let mut pass = GgStereo { warnings: Vec::new() };
+        pass.run(&mut song).unwrap();
+        assert_eq!(song.events, vec![VgmEvent::PsgWrite(0x9F), VgmEvent::GgStereo(0xF0), VgmEvent::Wait(735), VgmEvent::Wait(735),
+                                     VgmEvent::GgStereo(0x0F), VgmEvent::Wait(735), VgmEvent::GgStereo(0x0F), VgmEvent::End]);
+        assert_eq!(song.loop_index, Some(6));
+        assert_eq!(pass.warnings(), vec![String::from("removed 2 redundant GG stereo commands")]);
+    }
+
+    #[test]
+    fn test_group_psg_writes() {
+        let fm = |reg: u8| VgmEvent::Command { command: Command::YM2413_WRITE, args: vec![reg, 0] };
+        let mut song = EventList {
+            events: vec![VgmEvent::PsgWrite(0x80), fm(1), VgmEvent::PsgWrite(0x01), fm(2), VgmEvent::PsgWrite(0x9F), VgmEvent::Wait(735),
+                         fm(3), VgmEvent::PsgWrite(0xBF), VgmEvent::End],
+            loop_index: Some(6),
+        };
+        GroupPsgWrites.run(&mut song).unwrap();
+        assert_eq!(song.events, vec![VgmEvent::PsgWrite(0x80), VgmEvent::PsgWrite(0x01), VgmEvent::PsgWrite(0x9F), fm(1), fm(2),
+                                     VgmEvent::Wait(735), VgmEvent::PsgWrite(0xBF), fm(3), VgmEvent::End]);
+        assert_eq!(song.loop_index, Some(6));
+    }
+
+    #[test]
+    fn test_strip_unsupported() {
+        let command = |command: u8, args: &[u8]| VgmEvent::Command { command, args: args.to_vec() };
+        let events = vec![command(Command::AY8910_STEREO_MASK, &[0]), command(Command::DAC_STREAM_SETUP, &[0, 2, 0, 0x2A]),
+                          command(Command::DAC_STREAM_STOP, &[0]), VgmEvent::Unknown(0x96), command(Command::NOP, &[0, 0]), VgmEvent::SeekPcm(0x10),
+                          VgmEvent::PsgWrite(0x9F), VgmEvent::End];
+        let mut song = EventList { events: events.clone(), loop_index: None };
+        let mut pass = PassKind::StripUnsupported.create(Codec::Psg);
+        pass.run(&mut song).unwrap();
+        assert_eq!(song.events, vec![VgmEvent::PsgWrite(0x9F), VgmEvent::End]);
+        assert_eq!(pass.warnings().len(), 2);
+        let mut song = EventList { events: events.clone(), loop_index: None };
+        let mut pass = PassKind::StripUnsupported.create(Codec::Null);
+        pass.run(&mut song).unwrap();
+        assert_eq!(song.events, events);
+        assert!(pass.warnings().is_empty());
+    }
+
+    #[test]
+    fn test_dual_psg() {
+        let psg2 = |command: u8, value: u8| VgmEvent::Command { command, args: vec![value] };
+        let events = vec![VgmEvent::GgStereo(0xFF), psg2(Command::PSG2_GG_STEREO, 0xFF), VgmEvent::PsgWrite(0x90),
+                          psg2(Command::PSG2_WRITE, 0x9F), VgmEvent::Wait(735), VgmEvent::End];
+        let run = |mapping| {
+            let mut song = EventList { events: events.clone(), loop_index: None };
+            DualPsg { mapping }.run(&mut song).unwrap();
+            song.events
+        };
+        assert_eq!(run(DualPsgMapping::Mix), events);
+        assert_eq!(run(DualPsgMapping::Pan), vec![VgmEvent::GgStereo(0xF0), psg2(Command::PSG2_GG_STEREO, 0x0F), VgmEvent::PsgWrite(0x90),
+                                                  psg2(Command::PSG2_WRITE, 0x9F), VgmEvent::Wait(735), VgmEvent::End]);
+        assert_eq!(run(DualPsgMapping::Drop), vec![VgmEvent::GgStereo(0xFF), VgmEvent::PsgWrite(0x90), VgmEvent::Wait(735), VgmEvent::End]);
+    }
+
+    #[test]
+    fn test_end_in_silence() {
+        let mut song = EventList { events: vec![VgmEvent::PsgWrite(0x90), VgmEvent::Wait(100), VgmEvent::End], loop_index: None };
+        song.end_in_silence();
+        assert_eq!(song.events.len(), 8);
+        assert_eq!(song.events[2], VgmEvent::PsgWrite(0x9F));
+        assert_eq!(song.loop_index, Some(6));
+        assert_eq!(song.events[6..], [VgmEvent::Wait(0xFFFF), VgmEvent::End]);
+    }
+
+    #[test]
+    fn test_adjust_psg_attenuation() {
+        let psg = |values: &[u8]| values.iter().map(|&v| VgmEvent::PsgWrite(v)).collect::<Vec<_>>();
+        // Tone latch + data, volume latches (one muted), then a data byte for the volume register
+        let mut song = EventList { events: psg(&[0x85, 0x12, 0x92, 0xBF, 0xD0, 0x03, 0xFE]), loop_index: None };
+        assert_eq!(song.adjust_psg_attenuation(-3), (3, 2));
+        assert_eq!(song.events, psg(&[0x85, 0x12, 0x90, 0xBF, 0xD0, 0x00, 0xFB]));
+        assert_eq!(song.adjust_psg_attenuation(20), (4, 0));
+        assert_eq!(song.events, psg(&[0x85, 0x12, 0x9F, 0xBF, 0xDF, 0x0F, 0xFF]));
+    }
+
+    #[test]
+    fn test_ym2612_timer_passes() {
+        let write = |reg: u8, value: u8| VgmEvent::Command { command: Command::YM2612_LO_WRITE, args: vec![reg, value] };
+        let mut song = EventList {
+            events: vec![write(0x25, 1), write(0x27, 0x05), write(0x27, 0x45), write(0x27, 0x40), write(0x28, 0xF0), VgmEvent::End],
+            loop_index: Some(2),
+        };
+        Ym2612Timers.run(&mut song).unwrap();
+        assert_eq!(song.events, vec![write(0x27, 0x05), write(0x27, 0x45), write(0x27, 0x40), write(0x28, 0xF0), VgmEvent::End]);
+        assert_eq!(song.loop_index, Some(1));
+        Ym2612ModeWrites.run(&mut song).unwrap();
+        assert_eq!(song.events, vec![write(0x27, 0x45), write(0x28, 0xF0), VgmEvent::End]);
+        assert_eq!(song.loop_index, Some(0));
+    }
+}
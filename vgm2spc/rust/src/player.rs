@@ -0,0 +1,464 @@
+//!
+//! Data shared between the converter and the S-SMP player.
+//!
+//! The converter places a config record in the extradata area (right after the long wait LUT, if
+//! any). It's stored as a VGM data block so that players which don't know about it can skip it.
+//!
+//! The player binary can come with a manifest: a JSON file with the same name and a ".json"
+//! extension, which tells the converter how to assemble the SPC. All fields are optional:
+//!
+//! ```text
+//! {
+//!     "load_address": 0,          // Where the binary is loaded. The packed data follows it
+//!     "entry_point": 768,         // Initial PC
+//!     "data_pointer": null,       // If set, the address of the packed data is written here (u16)
+//!     "codecs": ["psg", "none"],  // Codecs that the player can decode
+//!     "reserved": [{"start": 65024, "length": 512}]  // RAM that the packed data must not overlap
+//! }
+//! ```
+//!
+//! Without a manifest, the defaults describe the player that comes with the converter.
+//!
+//! Several songs can be packed into one SPC. In that case the data area starts with a song index
+//! table instead of a VGM header. The player reads the song number from IO port 0 ($F4) at startup,
+//! and switches to another song whenever the S-CPU writes a new value to that port.
+//!
+
+use std::convert::TryFrom;
+use std::env;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::core::psg::PsgState;
+use crate::vgm::Command;
+use crate::vgm::specification::FileHeader;
+
+/// Address at which the player starts executing
+pub const ENTRY_POINT: u16 = 0x0300;
+
+/// SPC RAM available for the player and the packed data. The top 64 bytes are where the IPL ROM
+/// is mapped.
+pub const RAM_LIMIT: usize = 0xFFC0;
+
+/// Address of the first IO port in SPC RAM. In an SPC file, $F4-$F7 hold the values that the
+/// S-SMP reads from the ports.
+pub const IO_PORTS: usize = 0xF4;
+
+/// Return the RAM used by an echo buffer with the delay `edl` (0-15), placed as high up in RAM
+/// as possible. The buffer is 2 kB per EDL step (4 bytes for EDL 0), and starts on a page boundary.
+pub fn echo_region(edl: u8) -> RamRegion {
+    let length = if edl == 0 { 4 } else { edl as usize * 0x800 };
+    let start = (0x10000 - length) & !0xFF;
+    RamRegion { start: start as u16, length: length as u16 }
+}
+
+/// File name of the player binary
+pub const PLAYER_BINARY_PATH: &str = "s-smp_player.bin";
+
+/// Environment variable that can point at the player binary, or at the directory holding it
+pub const PLAYER_PATH_VARIABLE: &str = "VGM2SPC_PLAYER";
+
+/// Return the places where the player binary is looked for, in order: the path in
+/// `$VGM2SPC_PLAYER`, the current directory, the directory of the executable, and the per-user
+/// data directory
+pub fn player_search_paths() -> Vec<PathBuf> {
+    let exe_dir = env::current_exe().ok().and_then(|exe| exe.parent().map(Path::to_path_buf));
+    search_paths(env::var_os(PLAYER_PATH_VARIABLE).map(PathBuf::from), exe_dir, data_dir())
+}
+
+fn search_paths(variable: Option<PathBuf>, exe_dir: Option<PathBuf>, data_dir: Option<PathBuf>) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    if let Some(path) = variable.filter(|path| !path.as_os_str().is_empty()) {
+        paths.push(if path.is_dir() { path.join(PLAYER_BINARY_PATH) } else { path });
+    }
+    paths.push(PathBuf::from(PLAYER_BINARY_PATH));
+    paths.extend(exe_dir.into_iter().chain(data_dir).map(|dir| dir.join(PLAYER_BINARY_PATH)));
+    paths
+}
+
+/// Return the per-user data directory of vgm2spc: %APPDATA%\vgm2spc on Windows, ~/Library/
+/// Application Support/vgm2spc on macOS, and $XDG_DATA_HOME/vgm2spc (~/.local/share/vgm2spc)
+/// elsewhere
+fn data_dir() -> Option<PathBuf> {
+    let variable = |name| env::var_os(name).filter(|value| !value.is_empty()).map(PathBuf::from);
+    let dir = if cfg!(windows) {
+        variable("APPDATA")
+    } else if cfg!(target_os = "macos") {
+        variable("HOME").map(|home| home.join("Library").join("Application Support"))
+    } else {
+        variable("XDG_DATA_HOME").or_else(|| variable("HOME").map(|home| home.join(".local").join("share")))
+    };
+    dir.map(|dir| dir.join("vgm2spc"))
+}
+
+/// A range of SPC RAM
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub struct RamRegion {
+    pub start: u16,
+    pub length: u16,
+}
+
+impl RamRegion {
+    pub fn end(&self) -> usize {
+        self.start as usize + self.length as usize
+    }
+}
+
+/// How to assemble an SPC for a player binary (see the module documentation)
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Manifest {
+    pub load_address: u16,
+    pub entry_point: u16,
+    pub data_pointer: Option<u16>,
+    pub codecs: Vec<String>,
+    pub reserved: Vec<RamRegion>,
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Manifest {
+            load_address: 0,
+            entry_point: ENTRY_POINT,
+            data_pointer: None,
+            codecs: vec![String::from("psg"), String::from("none")],
+            reserved: Vec::new(),
+        }
+    }
+}
+
+/// The S-SMP player program, and its manifest
+#[derive(Clone, Debug, Default)]
+pub struct Player {
+    pub binary: Vec<u8>,
+    pub manifest: Manifest,
+}
+
+impl Player {
+    /// Load the player binary at `path`, and its manifest if there is one
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let binary = fs::read(path)?;
+        let manifest_path = path.with_extension("json");
+        let manifest = if manifest_path.exists() {
+            let json = fs::read_to_string(&manifest_path)?;
+            serde_json::from_str(&json).map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", manifest_path.display(), e)))?
+        } else {
+            Manifest::default()
+        };
+        let player = Player { binary, manifest };
+        player.validate().map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?;
+        Ok(player)
+    }
+
+    /// Check that the player, the data pointer and the reserved regions fit in SPC RAM, and that
+    /// the entry point is in the player
+    fn validate(&self) -> Result<(), String> {
+        let manifest = &self.manifest;
+        let load_address = manifest.load_address as usize;
+        let data_address = self.data_address();
+        if data_address > RAM_LIMIT {
+            return Err(String::from("the player doesn't fit in SPC RAM"));
+        }
+        if !(load_address..data_address).contains(&(manifest.entry_point as usize)) {
+            return Err(format!("the entry point (${:04X}) is outside of the player", manifest.entry_point));
+        }
+        // The pointer is written after the packed data is placed, so it must not be in the data area
+        if let Some(pointer) = manifest.data_pointer {
+            if pointer as usize + 2 > data_address {
+                return Err(format!("the data pointer (${:04X}) is outside of the player", pointer));
+            }
+        }
+        for region in &manifest.reserved {
+            if region.end() > 0x10000 {
+                return Err(format!("the reserved region at ${:04X} extends past the end of RAM", region.start));
+            }
+            if (region.start as usize) < data_address && region.end() > load_address {
+                return Err(format!("the reserved region at ${:04X} overlaps the player", region.start));
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the path of the first player binary found in `player_search_paths`
+    pub fn find_default() -> Result<PathBuf, Error> {
+        let paths = player_search_paths();
+        paths.iter().find(|path| path.is_file()).cloned().ok_or_else(|| {
+            let tried: Vec<String> = paths.iter().map(|path| path.display().to_string()).collect();
+            Error::new(ErrorKind::NotFound, format!("No player binary found. Tried: {}", tried.join(", ")))
+        })
+    }
+
+    /// Load the first player binary found in `player_search_paths`
+    pub fn load_default() -> Result<Self, Error> {
+        Self::load(&Self::find_default()?)
+    }
+
+    pub fn size(&self) -> usize {
+        self.binary.len()
+    }
+
+    /// Return the address that the packed data is loaded at, right after the player
+    pub fn data_address(&self) -> usize {
+        self.manifest.load_address as usize + self.size()
+    }
+
+    /// Return the number of bytes available for the packed data: from `data_address` up to the
+    /// first reserved region, or the IPL ROM area
+    pub fn data_capacity(&self) -> usize {
+        let start = self.data_address();
+        let end = self.manifest.reserved.iter().filter(|region| region.end() > start)
+            .map(|region| region.start as usize)
+            .fold(RAM_LIMIT, std::cmp::min);
+        end.saturating_sub(start)
+    }
+
+    /// Returns true if the manifest lists `codec` (a name as accepted by `-codec`) as supported
+    pub fn supports_codec(&self, codec: &str) -> bool {
+        self.manifest.codecs.iter().any(|c| c == codec)
+    }
+
+    /// Return a 64 kB SPC RAM image with the player and the packed data
+    pub fn ram_image(&self, packed: &[u8]) -> Vec<u8> {
+        let mut ram = vec![0u8; 0x10000];
+        let load_address = self.manifest.load_address as usize;
+        ram[load_address..load_address + self.size()].copy_from_slice(&self.binary);
+        let data_address = self.data_address();
+        ram[data_address..data_address + packed.len()].copy_from_slice(packed);
+        if let Some(pointer) = self.manifest.data_pointer {
+            let pointer = pointer as usize;
+            ram[pointer..pointer + 2].copy_from_slice(&(data_address as u16).to_le_bytes());
+        }
+        ram[0xF0] = 0x0A;   // SPC_TEST = 0x0A (enable timers, enable spc700)
+        ram
+    }
+}
+
+/// Chips that the player can play. Writes to any other chip are ignored by the player.
+pub const SUPPORTED_CHIPS: &[&str] = &["SN76489"];
+
+/// Data block type used for the player config record
+pub const CONFIG_BLOCK_TYPE: u8 = 0x03;
+
+/// Version of the config record layout. Fields are only ever appended, and the version bumped.
+pub const CONFIG_VERSION: u8 = 4;
+
+/// Data block type used for the seek table
+pub const SEEK_TABLE_BLOCK_TYPE: u8 = 0x05;
+
+/// SN76489 defaults for VGM files older than 1.10, which don't specify these
+const DEFAULT_PSG_FEEDBACK: u16 = 0x0009;
+const DEFAULT_PSG_LFSR_WIDTH: u8 = 16;
+
+/// Magic bytes at the start of a song index table
+pub const SONG_INDEX_MAGIC: &[u8; 4] = b"SIDX";
+
+/// Max number of songs in a song index table
+pub const MAX_SONGS: usize = u8::MAX as usize;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlayerConfig {
+    pub psg_clock: u32,
+    pub psg_feedback: u16,
+    pub psg_lfsr_width: u8,
+    pub psg_flags: u8,
+    pub stop_at_end: bool,      // The song has no loop point, and playback should stop at the end
+    pub seek_table: bool,       // The extradata has a seek table
+    pub psg_pan: [i8; 2],       // Panning of the first and second PSG of dual-PSG songs
+}
+
+impl PlayerConfig {
+    pub fn from_header(header: &FileHeader) -> Self {
+        let version = header.version;
+        if version >= 0x00000110 {
+            PlayerConfig {
+                psg_clock: header.psg_clock,
+                psg_feedback: header.psg_feedback,
+                psg_lfsr_width: header.psg_lfsr_width,
+                psg_flags: if version >= 0x00000151 { header.psg_flags } else { 0 },
+                stop_at_end: header.loop_offset == 0,
+                seek_table: false,
+                psg_pan: [0; 2],
+            }
+        } else {
+            PlayerConfig {
+                psg_clock: header.psg_clock,
+                psg_feedback: DEFAULT_PSG_FEEDBACK,
+                psg_lfsr_width: DEFAULT_PSG_LFSR_WIDTH,
+                psg_flags: 0,
+                stop_at_end: header.loop_offset == 0,
+                seek_table: false,
+                psg_pan: [0; 2],
+            }
+        }
+    }
+
+    /// Return the config record as a VGM data block:
+    ///
+    /// 0x67 0x66 CONFIG_BLOCK_TYPE <size:u32>
+    /// <version:u8> <psg_clock:u32> <psg_feedback:u16> <psg_lfsr_width:u8> <psg_flags:u8>
+    /// <end_behavior:u8> <seek_table:u8> <psg_pan:i8>*2
+    ///
+    /// `end_behavior` is 0 to loop, or 1 to stop (added in version 2). `seek_table` is 1 if a seek
+    /// table block (see `seek_table_block`) precedes the config record (added in version 3).
+    /// `psg_pan` is the panning of the first and second PSG, from -127 (left) to 127 (right), where
+    /// 0 is the center (added in version 4). The player only plays the first PSG for now, so the
+    /// converter leaves both at 0.
+    pub fn to_data_block(&self) -> Vec<u8> {
+        let mut record = vec![CONFIG_VERSION];
+        record.extend(&self.psg_clock.to_le_bytes());
+        record.extend(&self.psg_feedback.to_le_bytes());
+        record.push(self.psg_lfsr_width);
+        record.push(self.psg_flags);
+        record.push(self.stop_at_end as u8);
+        record.push(self.seek_table as u8);
+        record.extend(self.psg_pan.iter().map(|&pan| pan as u8));
+
+        let mut block = vec![Command::DATA_BLOCK, 0x66, CONFIG_BLOCK_TYPE];
+        block.extend(&(record.len() as u32).to_le_bytes());
+        block.extend(record);
+        block
+    }
+}
+
+/// A point in the command stream where playback can start, with the PSG state at that point
+#[derive(Clone, Debug, PartialEq)]
+pub struct SeekPoint {
+    pub time: u32,              // Position in the song, in samples
+    pub offset: u32,            // Offset of the command to continue with, from the start of the command stream
+    pub psg: PsgState,
+    pub gg_stereo: u8,
+}
+
+/// Return a seek table for a song as a VGM data block:
+///
+/// 0x67 0x66 SEEK_TABLE_BLOCK_TYPE <size:u32>
+/// <interval:u16> <count:u16>
+/// (<time:u32> <offset:u32> <period:u16>*3 <noise:u8> <attenuation:u8>*4 <gg_stereo:u8>)*count
+///
+/// Entry n is for the first command at or after n * `interval` seconds, so a player seeks to
+/// time t by loading the PSG registers from entry t / `interval` and continuing at its offset.
+/// The codec is flushed at each entry, so the offsets are at the start of a command group.
+pub fn seek_table_block(interval: u16, points: &[SeekPoint]) -> Vec<u8> {
+    let mut table = interval.to_le_bytes().to_vec();
+    table.extend(&(points.len() as u16).to_le_bytes());
+    for point in points {
+        table.extend(&point.time.to_le_bytes());
+        table.extend(&point.offset.to_le_bytes());
+        for period in &point.psg.periods {
+            table.extend(&period.to_le_bytes());
+        }
+        table.push(point.psg.noise);
+        table.extend(&point.psg.attenuation);
+        table.push(point.gg_stereo);
+    }
+
+    let mut block = vec![Command::DATA_BLOCK, 0x66, SEEK_TABLE_BLOCK_TYPE];
+    block.extend(&(table.len() as u32).to_le_bytes());
+    block.extend(table);
+    block
+}
+
+/// Return a song index table for the packed songs in `songs`, which are assumed to be placed
+/// right after the table, in order:
+///
+/// "SIDX" <count:u8> <offset:u16>*count
+///
+/// Each offset is relative to the start of the table. Fails if there are more than `MAX_SONGS`
+/// songs, or if they don't fit in 64 KB.
+pub fn song_index_table(songs: &[Vec<u8>]) -> Result<Vec<u8>, Error> {
+    let count = u8::try_from(songs.len())
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, format!("{} songs don't fit in the song index, which holds up to {}", songs.len(), MAX_SONGS)))?;
+    let mut table = SONG_INDEX_MAGIC.to_vec();
+    table.push(count);
+    let mut offset = table.len() + songs.len() * 2;
+    for song in songs {
+        let song_offset = u16::try_from(offset).map_err(|_| Error::new(ErrorKind::InvalidInput, "The songs don't fit in SPC RAM"))?;
+        table.extend(&song_offset.to_le_bytes());
+        offset += song.len();
+    }
+    Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_search_paths() {
+        let paths = search_paths(Some(PathBuf::from("/opt/custom.bin")), Some(PathBuf::from("/usr/bin")), None);
+        assert_eq!(paths, vec![PathBuf::from("/opt/custom.bin"), PathBuf::from(PLAYER_BINARY_PATH),
+                               Path::new("/usr/bin").join(PLAYER_BINARY_PATH)]);
+        let dir = env::temp_dir();
+        let paths = search_paths(Some(dir.clone()), None, Some(PathBuf::from("data")));
+        assert_eq!(paths, vec![dir.join(PLAYER_BINARY_PATH), PathBuf::from(PLAYER_BINARY_PATH),
+                               Path::new("data").join(PLAYER_BINARY_PATH)]);
+        assert_eq!(search_paths(Some(PathBuf::new()), None, None), vec![PathBuf::from(PLAYER_BINARY_PATH)]);
+    }
+
+    #[test]
+    fn test_to_data_block() {
+        let config = PlayerConfig { psg_clock: 3579545, psg_feedback: 0x0009, psg_lfsr_width: 16, psg_flags: 0, stop_at_end: true, seek_table: false,
+                                   psg_pan: [-127, 127] };
+        assert_eq!(config.to_data_block(),
+                   vec![0x67, 0x66, CONFIG_BLOCK_TYPE, 13, 0, 0, 0,
+                        CONFIG_VERSION, 0x99, 0x9E, 0x36, 0x00, 0x09, 0x00, 16, 0, 1, 0, 0x81, 0x7F]);
+    }
+
+    #[test]
+    fn test_seek_table_block() {
+        let mut psg = PsgState::default();
+        psg.periods[1] = 0x123;
+        psg.attenuation[0] = 2;
+        let points = vec![SeekPoint { time: 0, offset: 0, psg: PsgState::default(), gg_stereo: 0xFF },
+                          SeekPoint { time: 44100 * 5 + 10, offset: 0x456, psg, gg_stereo: 0xF0 }];
+        let block = seek_table_block(5, &points);
+        assert_eq!(&block[..7], &[0x67, 0x66, SEEK_TABLE_BLOCK_TYPE, 44, 0, 0, 0]);
+        assert_eq!(&block[7..11], &[5, 0, 2, 0]);
+        assert_eq!(&block[31..], &[0x5E, 0x5D, 0x03, 0x00, 0x56, 0x04, 0x00, 0x00, 0x00, 0x00, 0x23, 0x01, 0x00, 0x00, 0x00,
+                                   0x02, 0x0F, 0x0F, 0x0F, 0xF0]);
+    }
+
+    #[test]
+    fn test_echo_region() {
+        assert_eq!(echo_region(0), RamRegion { start: 0xFF00, length: 4 });
+        assert_eq!(echo_region(1), RamRegion { start: 0xF800, length: 0x800 });
+        assert_eq!(echo_region(15).end(), 0x10000);
+    }
+
+    #[test]
+    fn test_manifest() {
+        let manifest: Manifest = serde_json::from_str(r#"{"load_address": 512, "data_pointer": 1024,
+                                                          "reserved": [{"start": 61440, "length": 4096}]}"#).unwrap();
+        assert_eq!(manifest.entry_point, ENTRY_POINT);
+        let player = Player { binary: vec![0xAA; 0x1000], manifest };
+        assert_eq!(player.data_address(), 0x1200);
+        assert_eq!(player.data_capacity(), 0xF000 - 0x1200);
+
+        let ram = player.ram_image(&[1, 2, 3]);
+        assert_eq!(ram[0x1FF], 0);
+        assert_eq!(ram[0x200], 0xAA);
+        assert_eq!(&ram[0x1200..0x1203], &[1, 2, 3]);
+        assert_eq!(&ram[0x400..0x402], &[0x00, 0x12]);
+        assert_eq!(player.validate(), Ok(()));
+
+        let invalid = |manifest: Manifest, size: usize| Player { binary: vec![0; size], manifest }.validate().is_err();
+        assert!(invalid(Manifest::default(), RAM_LIMIT + 1));
+        assert!(invalid(Manifest { entry_point: 0x1000, ..Manifest::default() }, 0x1000));
+        assert!(invalid(Manifest { data_pointer: Some(0xFFFF), ..Manifest::default() }, 0x1000));
+        assert!(invalid(Manifest { reserved: vec![RamRegion { start: 0xFF00, length: 0x200 }], ..Manifest::default() }, 0x1000));
+        assert!(invalid(Manifest { reserved: vec![RamRegion { start: 0x0800, length: 0x100 }], ..Manifest::default() }, 0x1000));
+    }
+
+    #[test]
+    fn test_song_index_table() {
+        let songs = vec![vec![0; 0x100], vec![0; 0x20], vec![0; 0x10]];
+        assert_eq!(song_index_table(&songs).unwrap(),
+                   vec![b'S', b'I', b'D', b'X', 3, 11, 0x00, 11, 0x01, 43, 0x01]);
+        assert_eq!(song_index_table(&vec![vec![0; 4]; MAX_SONGS]).unwrap()[4], 255);
+        assert_eq!(song_index_table(&vec![vec![0; 4]; MAX_SONGS + 1]).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert!(song_index_table(&[vec![0; 0x10000], vec![0; 4]]).is_err());
+    }
+}
@@ -0,0 +1,195 @@
+//!
+//! Generation of a minimal LoROM SNES cartridge image that uploads an SPC RAM image to the
+//! S-SMP through the IPL boot ROM protocol and starts it. Meant for testing the player on real
+//! hardware or flashcarts.
+//!
+//! ROM layout:
+//!   bank 0   ($00:8000)   Uploader code, block table at $8100, header at $FFC0
+//!   bank 1.. ($nn:8000)   The SPC RAM image, from $0200 and up, in 32 kB chunks
+//!
+//! The direct page, stack page and IO registers ($0000-$01FF) aren't uploaded, since the IPL
+//! ROM uses them during the transfer. The player is expected to initialize them itself.
+//!
+
+/// Total size of the generated ROM (1 Mbit)
+pub const ROM_SIZE: usize = 0x20000;
+
+const BANK_SIZE: usize = 0x8000;
+const UPLOAD_START: usize = 0x0200;
+const BLOCK_TABLE: usize = 0x0100;      // Offset within bank 0
+const HEADER: usize = 0x7FC0;           // Offset within bank 0
+
+// Direct page variables used by the uploader
+const DP_SRC: u8 = 0x00;        // 24-bit source pointer
+const DP_LEN: u8 = 0x04;        // 16-bit block length
+const DP_KICK: u8 = 0x06;       // Value to write to APUIO0 to start the next IPL command
+
+/// Return a ROM image which uploads `ram` (a 64 kB SPC RAM image, or the used part of one)
+/// to the S-SMP and jumps to `entry_point`.
+pub fn build_test_rom(ram: &[u8], entry_point: u16, title: &str) -> Vec<u8> {
+    let mut rom = vec![0u8; ROM_SIZE];
+    let upload_end = std::cmp::min(ram.len(), 0x10000);
+
+    // Block table: <spc_dest:u16> <length:u16> <rom_bank:u8>, terminated by a zero length
+    let mut table: Vec<u8> = Vec::new();
+    let mut spc_addr = UPLOAD_START;
+    let mut bank = 1;
+    while spc_addr < upload_end {
+        let length = std::cmp::min(BANK_SIZE, upload_end - spc_addr);
+        rom[bank * BANK_SIZE..bank * BANK_SIZE + length].copy_from_slice(&ram[spc_addr..spc_addr + length]);
+        table.extend(&(spc_addr as u16).to_le_bytes());
+        table.extend(&(length as u16).to_le_bytes());
+        table.push(bank as u8);
+        spc_addr += length;
+        bank += 1;
+    }
+    table.extend(&[0; 5]);
+    rom[BLOCK_TABLE..BLOCK_TABLE + table.len()].copy_from_slice(&table);
+
+    let code = uploader_code(entry_point);
+    rom[..code.len()].copy_from_slice(&code);
+
+    write_header(&mut rom, title);
+    rom
+}
+
+/// Assemble the 65816 uploader. Runs with 8-bit A and 16-bit X/Y.
+fn uploader_code(entry_point: u16) -> Vec<u8> {
+    let table = 0x8000 + BLOCK_TABLE as u16;
+    let mut c: Vec<u8> = Vec::new();
+
+    c.extend(&[0x78]);                              // sei
+    c.extend(&[0x18, 0xFB]);                        // clc; xce
+    c.extend(&[0xC2, 0x18]);                        // rep #$18
+    c.extend(&[0xE2, 0x20]);                        // sep #$20
+    c.extend(&[0xA2, 0xFF, 0x1F, 0x9A]);            // ldx #$1FFF; txs
+    c.extend(&[0xA9, 0x80, 0x8D, 0x00, 0x21]);      // lda #$80; sta INIDISP (force blank)
+    c.extend(&[0x9C, 0x00, 0x42]);                  // stz NMITIMEN
+
+    // Wait for the IPL ROM to signal that it's ready ($AA/$BB in APUIO0/1)
+    c.extend(&[0xA2, 0xAA, 0xBB]);                  // ldx #$BBAA
+    let wait_ipl = c.len();
+    c.extend(&[0xEC, 0x40, 0x21]);                  // cpx APUIO0
+    branch(&mut c, 0xD0, wait_ipl);                 // bne wait_ipl
+    c.extend(&[0xA9, 0xCC, 0x85, DP_KICK]);         // lda #$CC; sta kick
+
+    c.extend(&[0xA2, table as u8, (table >> 8) as u8]);     // ldx #table
+    let block_loop = c.len();
+    c.extend(&[0xBD, 0x02, 0x00, 0x85, DP_LEN]);            // lda 2,x; sta len
+    c.extend(&[0xBD, 0x03, 0x00, 0x85, DP_LEN + 1]);        // lda 3,x; sta len+1
+    c.extend(&[0x05, DP_LEN]);                              // ora len
+    let beq_done = forward_branch(&mut c, 0xF0);            // beq done
+    c.extend(&[0xBD, 0x04, 0x00, 0x85, DP_SRC + 2]);        // lda 4,x; sta src+2
+    c.extend(&[0x64, DP_SRC, 0xA9, 0x80, 0x85, DP_SRC + 1]);    // stz src; lda #$80; sta src+1
+    c.extend(&[0xBD, 0x00, 0x00, 0x8D, 0x42, 0x21]);        // lda 0,x; sta APUIO2
+    c.extend(&[0xBD, 0x01, 0x00, 0x8D, 0x43, 0x21]);        // lda 1,x; sta APUIO3
+    c.extend(&[0xA9, 0x01, 0x8D, 0x41, 0x21]);              // lda #1; sta APUIO1 (transfer)
+    c.extend(&[0xA5, DP_KICK, 0x8D, 0x40, 0x21]);           // lda kick; sta APUIO0
+    let wait_kick = c.len();
+    c.extend(&[0xCD, 0x40, 0x21]);                          // cmp APUIO0
+    branch(&mut c, 0xD0, wait_kick);                        // bne wait_kick
+
+    c.extend(&[0xA0, 0x00, 0x00]);                          // ldy #0
+    let byte_loop = c.len();
+    c.extend(&[0xB7, DP_SRC, 0x8D, 0x41, 0x21]);            // lda [src],y; sta APUIO1
+    c.extend(&[0x98, 0x8D, 0x40, 0x21]);                    // tya; sta APUIO0
+    let wait_byte = c.len();
+    c.extend(&[0xCD, 0x40, 0x21]);                          // cmp APUIO0
+    branch(&mut c, 0xD0, wait_byte);                        // bne wait_byte
+    c.extend(&[0xC8, 0xC4, DP_LEN]);                        // iny; cpy len
+    branch(&mut c, 0xD0, byte_loop);                        // bne byte_loop
+
+    // The next kick value must be at least the last counter value + 2, and non-zero
+    c.extend(&[0x98, 0x1A, 0xD0, 0x01, 0x1A]);              // tya; inc; bne +1; inc
+    c.extend(&[0x85, DP_KICK]);                             // sta kick
+    c.extend(&[0xE8, 0xE8, 0xE8, 0xE8, 0xE8]);              // inx x5
+    branch(&mut c, 0x80, block_loop);                       // bra block_loop
+
+    // All blocks uploaded; tell the IPL ROM to jump to the entry point
+    patch_branch(&mut c, beq_done);
+    c.extend(&[0xA9, entry_point as u8, 0x8D, 0x42, 0x21]);         // lda #<entry; sta APUIO2
+    c.extend(&[0xA9, (entry_point >> 8) as u8, 0x8D, 0x43, 0x21]);  // lda #>entry; sta APUIO3
+    c.extend(&[0x9C, 0x41, 0x21]);                                  // stz APUIO1 (jump)
+    c.extend(&[0xA5, DP_KICK, 0x8D, 0x40, 0x21]);                   // lda kick; sta APUIO0
+    let wait_start = c.len();
+    c.extend(&[0xCD, 0x40, 0x21]);                                  // cmp APUIO0
+    branch(&mut c, 0xD0, wait_start);                               // bne wait_start
+    // Clear the ports so that the player sees song number 0
+    c.extend(&[0x9C, 0x40, 0x21, 0x9C, 0x41, 0x21]);                // stz APUIO0; stz APUIO1
+    c.extend(&[0x9C, 0x42, 0x21, 0x9C, 0x43, 0x21]);                // stz APUIO2; stz APUIO3
+    let forever = c.len();
+    branch(&mut c, 0x80, forever);                                  // bra forever
+
+    assert!(c.len() <= BLOCK_TABLE, "Uploader code overlaps the block table");
+    c
+}
+
+/// Emit a relative branch to the (already known) offset `target`
+fn branch(code: &mut Vec<u8>, opcode: u8, target: usize) {
+    let displacement = target as isize - (code.len() as isize + 2);
+    code.extend(&[opcode, displacement as i8 as u8]);
+}
+
+/// Emit a relative branch whose target isn't known yet, returning the offset to pass to `patch_branch`
+fn forward_branch(code: &mut Vec<u8>, opcode: u8) -> usize {
+    code.extend(&[opcode, 0]);
+    code.len() - 2
+}
+
+/// Make the branch at `offset` jump to the current end of `code`
+fn patch_branch(code: &mut [u8], offset: usize) {
+    let displacement = code.len() - (offset + 2);
+    assert!(displacement < 0x80, "Branch out of range");
+    code[offset + 1] = displacement as u8;
+}
+
+/// Write the internal cartridge header, interrupt vectors and checksum
+fn write_header(rom: &mut [u8], title: &str) {
+    let mut name: Vec<u8> = title.bytes().filter(|b| (0x20..0x7F).contains(b)).take(21).collect();
+    name.resize(21, b' ');
+    rom[HEADER..HEADER + 21].copy_from_slice(&name);
+    rom[HEADER + 0x15] = 0x20;              // LoROM, SlowROM
+    rom[HEADER + 0x16] = 0x00;              // ROM only
+    rom[HEADER + 0x17] = 0x07;              // ROM size: 1 Mbit
+    rom[HEADER + 0x18] = 0x00;              // No SRAM
+    rom[HEADER + 0x19] = 0x01;              // North America
+    rom[HEADER + 0x1A] = 0x00;
+    rom[HEADER + 0x1B] = 0x00;              // Version
+
+    // All vectors point at the reset code, except the interrupt vectors which point at an RTI
+    let rti: u16 = 0xFFBF;
+    rom[HEADER - 1] = 0x40;
+    for vector in [0xFFE4, 0xFFE6, 0xFFE8, 0xFFEA, 0xFFEE, 0xFFF4, 0xFFF8, 0xFFFA, 0xFFFE].iter() {
+        let offset = vector - 0x8000;
+        rom[offset..offset + 2].copy_from_slice(&rti.to_le_bytes());
+    }
+    rom[0x7FFC..0x7FFE].copy_from_slice(&0x8000u16.to_le_bytes());
+
+    rom[HEADER + 0x1C..HEADER + 0x20].copy_from_slice(&[0xFF, 0xFF, 0x00, 0x00]);
+    let checksum = rom.iter().fold(0u16, |sum, &b| sum.wrapping_add(b as u16));
+    rom[HEADER + 0x1C..HEADER + 0x1E].copy_from_slice(&(!checksum).to_le_bytes());
+    rom[HEADER + 0x1E..HEADER + 0x20].copy_from_slice(&checksum.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_test_rom() {
+        let mut ram = vec![0u8; 0x9000];
+        ram[0x200] = 0x12;
+        ram[0x8200] = 0x34;
+        let rom = build_test_rom(&ram, 0x0300, "Test");
+        assert_eq!(rom.len(), ROM_SIZE);
+        assert_eq!(&rom[BLOCK_TABLE..BLOCK_TABLE + 15], &[0x00, 0x02, 0x00, 0x80, 1, 0x00, 0x82, 0x00, 0x0E, 2, 0, 0, 0, 0, 0]);
+        assert_eq!(rom[BANK_SIZE], 0x12);
+        assert_eq!(rom[2 * BANK_SIZE], 0x34);
+        assert_eq!(&rom[0x7FFC..0x7FFE], &[0x00, 0x80]);
+
+        let checksum = u16::from_le_bytes([rom[0x7FDE], rom[0x7FDF]]);
+        let complement = u16::from_le_bytes([rom[0x7FDC], rom[0x7FDD]]);
+        assert_eq!(checksum ^ complement, 0xFFFF);
+        assert_eq!(rom.iter().fold(0u16, |sum, &b| sum.wrapping_add(b as u16)), checksum);
+    }
+}
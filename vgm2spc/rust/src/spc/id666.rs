@@ -0,0 +1,417 @@
+//!
+//! ID666 tag stored in the SPC file header (offsets 0x2E-0xFF).
+//!
+//! The tag exists in a text and a binary variant which share the string fields but differ in
+//! how the dump date, play length and fade length are stored (and in the position of the artist
+//! field). There's no flag in the file that says which variant is used, so players have to guess
+//! based on the contents of the numeric fields.
+//!
+
+use crate::date::Date;
+use crate::spc::xid6::{self, Xid6};
+
+/// Offset of the tag within the SPC file
+pub const ID666_OFFSET: usize = 0x2E;
+/// Size of the tag, up to the end of the SPC header
+pub const ID666_SIZE: usize = 0x100 - ID666_OFFSET;
+
+/// Sizes of the string fields, in bytes
+const TITLE_SIZE: usize = 32;
+const DUMPER_SIZE: usize = 16;
+const COMMENT_SIZE: usize = 32;
+const ARTIST_SIZE: usize = 32;
+
+/// Value for the "has ID666 tag" byte at offset 0x23 of the SPC header
+pub const HAS_ID666: u8 = 26;
+pub const NO_ID666: u8 = 27;
+
+/// Marker placed in the reserved area of the tag (in both formats) when it contains the CRC32
+/// of the VGM data that the SPC was created from. The CRC follows the marker.
+const SOURCE_CRC_MARKER: &[u8; 4] = b"VCRC";
+const SOURCE_CRC_OFFSET: usize = 0xF8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Id666Format {
+    Text,
+    Binary,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Id666 {
+    pub song_title: String,
+    pub game_title: String,
+    pub dumper: String,
+    pub comment: String,
+    pub dump_date: Option<Date>,
+    pub play_seconds: u32,          // Seconds to play before fading out (0 = no limit)
+    pub fade_ms: u32,               // Length of the fade in milliseconds
+    pub artist: String,
+    pub channel_disables: u8,
+    pub emulator: u8,
+    pub source_crc: Option<u32>,    // CRC32 of the (decompressed) VGM data the SPC was created from
+}
+
+impl Id666 {
+    pub fn new() -> Self {
+        Id666 {
+            song_title: String::from(""),
+            game_title: String::from(""),
+            dumper: String::from(""),
+            comment: String::from(""),
+            dump_date: None,
+            play_seconds: 0,
+            fade_ms: 0,
+            artist: String::from(""),
+            channel_disables: 0,
+            emulator: 0,
+            source_crc: None,
+        }
+    }
+
+    /// Return the string fields that don't fit in the tag, as the name and size of the field and
+    /// the text that is stored
+    pub fn cut_fields(&self) -> Vec<(&'static str, usize, String)> {
+        let fields = [("title", &self.song_title, TITLE_SIZE), ("game", &self.game_title, TITLE_SIZE), ("dumper", &self.dumper, DUMPER_SIZE),
+                      ("comment", &self.comment, COMMENT_SIZE), ("artist", &self.artist, ARTIST_SIZE)];
+        fields.iter()
+            .filter(|(_, text, size)| to_ascii(text).len() > *size)
+            .map(|&(name, text, size)| (name, size, String::from_utf8_lossy(&text_field(text, size)).into_owned()))
+            .collect()
+    }
+
+    /// Return the tag as it should be stored at `ID666_OFFSET` in the SPC file. The string fields
+    /// are converted to ASCII (see `to_ascii`).
+    pub fn to_bytes(&self, format: Id666Format) -> Vec<u8> {
+        let mut result: Vec<u8> = Vec::with_capacity(ID666_SIZE);
+        result.extend(text_field(&self.song_title, TITLE_SIZE));
+        result.extend(text_field(&self.game_title, TITLE_SIZE));
+        result.extend(text_field(&self.dumper, DUMPER_SIZE));
+        result.extend(text_field(&self.comment, COMMENT_SIZE));
+
+        match format {
+            Id666Format::Text => {
+                let date = self.dump_date.map(|d| d.to_string()).unwrap_or_default();
+                result.extend(as_fixed_field(&date, 11));
+                result.extend(as_fixed_field(&as_text_number(self.play_seconds, 3), 3));
+                result.extend(as_fixed_field(&as_text_number(self.fade_ms, 5), 5));
+                result.extend(text_field(&self.artist, ARTIST_SIZE));
+                result.push(self.channel_disables);
+                result.push(self.emulator);
+            }
+            Id666Format::Binary => {
+                // Date: day, month, year (little-endian word), followed by 7 unused bytes
+                match self.dump_date {
+                    Some(d) => result.extend(&[d.day as u8, d.month as u8, d.year as u8, (d.year >> 8) as u8]),
+                    None => result.extend(&[0; 4]),
+                }
+                result.extend(&[0; 7]);
+                result.extend(&self.play_seconds.to_le_bytes()[..3]);
+                result.extend(&self.fade_ms.to_le_bytes());
+                result.extend(text_field(&self.artist, ARTIST_SIZE));
+                result.push(self.channel_disables);
+                result.push(self.emulator);
+            }
+        }
+
+        result.resize(ID666_SIZE, 0);
+        if let Some(crc) = self.source_crc {
+            let offset = SOURCE_CRC_OFFSET - ID666_OFFSET;
+            result[offset..offset + 4].copy_from_slice(SOURCE_CRC_MARKER);
+            result[offset + 4..offset + 8].copy_from_slice(&crc.to_le_bytes());
+        }
+        result
+    }
+
+    /// Return an xid6 chunk with the full text of the string fields that the ID666 tag can't hold
+    /// as they are, because they aren't ASCII or are too long. Returns `None` if there are none.
+    pub fn to_xid6(&self) -> Option<Xid6> {
+        let fields = [(xid6::ID_SONG_NAME, &self.song_title, TITLE_SIZE), (xid6::ID_GAME_NAME, &self.game_title, TITLE_SIZE),
+                      (xid6::ID_ARTIST, &self.artist, ARTIST_SIZE), (xid6::ID_DUMPER, &self.dumper, DUMPER_SIZE),
+                      (xid6::ID_COMMENTS, &self.comment, COMMENT_SIZE)];
+        let mut chunk = Xid6::default();
+        for (id, text, size) in fields.iter() {
+            if !text.is_ascii() || text.len() > *size {
+                chunk.set_string(*id, text);
+            }
+        }
+        Some(chunk).filter(|chunk| !chunk.items.is_empty())
+    }
+
+    /// Parse the tag in an SPC file header, guessing which format it's in.
+    /// `spc_header` must hold at least the first 0x100 bytes of the file.
+    pub fn parse(spc_header: &[u8]) -> (Id666, Id666Format) {
+        let format = detect_format(spc_header);
+        let field = |offset: usize, len: usize| &spc_header[offset..offset + len];
+        let mut tag = Id666 {
+            song_title: from_fixed_field(field(0x2E, 32)),
+            game_title: from_fixed_field(field(0x4E, 32)),
+            dumper: from_fixed_field(field(0x6E, 16)),
+            comment: from_fixed_field(field(0x7E, 32)),
+            source_crc: read_source_crc(spc_header),
+            ..Id666::new()
+        };
+        match format {
+            Id666Format::Text => {
+                tag.dump_date = parse_text_date(&from_fixed_field(field(0x9E, 11)));
+                tag.play_seconds = from_fixed_field(field(0xA9, 3)).parse().unwrap_or(0);
+                tag.fade_ms = from_fixed_field(field(0xAC, 5)).parse().unwrap_or(0);
+                tag.artist = from_fixed_field(field(0xB1, 32));
+                tag.channel_disables = spc_header[0xD1];
+                tag.emulator = spc_header[0xD2];
+            }
+            Id666Format::Binary => {
+                let d = field(0x9E, 4);
+                tag.dump_date = Date::new(u16::from_le_bytes([d[2], d[3]]) as u32, d[1] as u32, d[0] as u32);
+                tag.play_seconds = u32::from_le_bytes([spc_header[0xA9], spc_header[0xAA], spc_header[0xAB], 0]);
+                tag.fade_ms = u32::from_le_bytes([spc_header[0xAC], spc_header[0xAD], spc_header[0xAE], spc_header[0xAF]]);
+                tag.artist = from_fixed_field(field(0xB0, 32));
+                tag.channel_disables = spc_header[0xD0];
+                tag.emulator = spc_header[0xD1];
+            }
+        }
+        (tag, format)
+    }
+}
+
+/// Guess the format of the tag in `spc_header`. The text format only has digits (and date
+/// separators) in the date and length fields, and the binary format has the first character of
+/// the artist field where the text format has the last digit of the fade length.
+pub fn detect_format(spc_header: &[u8]) -> Id666Format {
+    let is_date_char = |b: &u8| *b == 0 || b.is_ascii_digit() || b"/-. ".contains(b);
+    let is_digit = |b: &u8| *b == 0 || b.is_ascii_digit();
+    if spc_header[0x9E..0xA9].iter().all(is_date_char) && spc_header[0xA9..0xB1].iter().all(is_digit) {
+        Id666Format::Text
+    } else {
+        Id666Format::Binary
+    }
+}
+
+/// Parse a text format dump date (MM/DD/YYYY)
+fn parse_text_date(text: &str) -> Option<Date> {
+    let parts: Vec<u32> = text.split(['/', '-', '.']).filter_map(|p| p.trim().parse().ok()).collect();
+    match parts[..] {
+        [month, day, year] => Date::new(year, month, day),
+        _ => None,
+    }
+}
+
+/// Return the text in a fixed-length, zero-padded field
+fn from_fixed_field(bytes: &[u8]) -> String {
+    let text: Vec<u8> = bytes.iter().cloned().take_while(|&b| b != 0).collect();
+    String::from_utf8_lossy(&text).trim_end().to_owned()
+}
+
+/// Return the source CRC stored in an SPC file header, if there is one.
+/// `spc_header` must hold at least the first 0x100 bytes of the file.
+pub fn read_source_crc(spc_header: &[u8]) -> Option<u32> {
+    let marker = &spc_header[SOURCE_CRC_OFFSET..SOURCE_CRC_OFFSET + 4];
+    if marker == SOURCE_CRC_MARKER {
+        let crc = &spc_header[SOURCE_CRC_OFFSET + 4..SOURCE_CRC_OFFSET + 8];
+        Some(u32::from_le_bytes([crc[0], crc[1], crc[2], crc[3]]))
+    } else {
+        None
+    }
+}
+
+impl Default for Id666 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Return the decimal representation of `n`, or nothing if `n` is zero or doesn't fit in
+/// `max_digits` digits
+fn as_text_number(n: u32, max_digits: usize) -> String {
+    let text = n.to_string();
+    if n == 0 || text.len() > max_digits {
+        String::new()
+    } else {
+        text
+    }
+}
+
+/// Convert `text` to ASCII for the ID666 string fields. Accented Latin letters lose their
+/// accents, typographic punctuation and full-width forms are replaced by their ASCII equivalents,
+/// and other characters (e.g. kana and kanji) are dropped.
+pub fn to_ascii(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            ' ' ..= '~' => result.push(c),
+            '\u{FF01}' ..= '\u{FF5E}' => result.push((c as u32 - 0xFF01 + 0x21) as u8 as char),
+            '\u{3000}' | '\u{00A0}' => result.push(' '),
+            _ => result.push_str(transliterate(c)),
+        }
+    }
+    // Dropped characters can leave runs of spaces behind
+    result.split(' ').filter(|word| !word.is_empty()).collect::<Vec<_>>().join(" ")
+}
+
+/// Return the ASCII replacement for the non-ASCII character `c`, or "" if there is none
+fn transliterate(c: char) -> &'static str {
+    match c {
+        'À' ..= 'Å' | 'Ā' | 'Ă' | 'Ą' => "A",
+        'à' ..= 'å' | 'ā' | 'ă' | 'ą' => "a",
+        'Æ' => "AE",
+        'æ' => "ae",
+        'Ç' | 'Ć' | 'Č' => "C",
+        'ç' | 'ć' | 'č' => "c",
+        'Ð' | 'Ď' => "D",
+        'ð' | 'ď' => "d",
+        'È' ..= 'Ë' | 'Ē' | 'Ė' | 'Ę' | 'Ě' => "E",
+        'è' ..= 'ë' | 'ē' | 'ė' | 'ę' | 'ě' => "e",
+        'Ğ' => "G",
+        'ğ' => "g",
+        'Ì' ..= 'Ï' | 'Ī' | 'İ' => "I",
+        'ì' ..= 'ï' | 'ī' | 'ı' => "i",
+        'Ł' => "L",
+        'ł' => "l",
+        'Ñ' | 'Ń' | 'Ň' => "N",
+        'ñ' | 'ń' | 'ň' => "n",
+        'Ò' ..= 'Ö' | 'Ø' | 'Ō' | 'Ő' => "O",
+        'ò' ..= 'ö' | 'ø' | 'ō' | 'ő' => "o",
+        'Œ' => "OE",
+        'œ' => "oe",
+        'Ř' => "R",
+        'ř' => "r",
+        'Ś' | 'Ş' | 'Š' => "S",
+        'ś' | 'ş' | 'š' => "s",
+        'ß' => "ss",
+        'Ť' => "T",
+        'ť' => "t",
+        'Þ' => "Th",
+        'þ' => "th",
+        'Ù' ..= 'Ü' | 'Ū' | 'Ů' | 'Ű' => "U",
+        'ù' ..= 'ü' | 'ū' | 'ů' | 'ű' => "u",
+        'Ý' | 'Ÿ' => "Y",
+        'ý' | 'ÿ' => "y",
+        'Ź' | 'Ż' | 'Ž' => "Z",
+        'ź' | 'ż' | 'ž' => "z",
+        '‘' | '’' | '′' | '´' => "'",
+        '“' | '”' | '″' | '«' | '»' => "\"",
+        '‐' ..= '―' | '−' | 'ー' => "-",
+        '…' => "...",
+        '・' | '·' => ".",
+        '×' => "x",
+        '「' | '『' | '【' => "[",
+        '」' | '』' | '】' => "]",
+        '、' => ",",
+        '。' => ".",
+        '～' | '〜' => "~",
+        _ => "",
+    }
+}
+
+/// Return a string field of the tag as ASCII (see `to_ascii`), in a field of `size` bytes
+fn text_field(text: &str, size: usize) -> Vec<u8> {
+    as_fixed_field(&to_ascii(text), size)
+}
+
+/// Return `text` in a zero-padded field of `size` bytes. Text that doesn't fit is cut at the last
+/// character boundary that does, so no partial UTF-8 sequences are left in the field.
+pub fn as_fixed_field(text: &str, size: usize) -> Vec<u8> {
+    let mut end = std::cmp::min(text.len(), size);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    let mut result = text.as_bytes()[..end].to_vec();
+    result.resize(size, 0);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_tag() -> Id666 {
+        let mut tag = Id666::new();
+        tag.song_title = String::from("Song");
+        tag.artist = String::from("Artist");
+        tag.dump_date = Date::new(2019, 5, 12);
+        tag.play_seconds = 180;
+        tag.fade_ms = 10000;
+        tag
+    }
+
+    #[test]
+    fn test_text_layout() {
+        let bytes = test_tag().to_bytes(Id666Format::Text);
+        assert_eq!(bytes.len(), ID666_SIZE);
+        assert_eq!(&bytes[0..4], b"Song");
+        assert_eq!(&bytes[0x9E - ID666_OFFSET..0xA8 - ID666_OFFSET], b"05/12/2019");
+        assert_eq!(&bytes[0xA9 - ID666_OFFSET..0xAC - ID666_OFFSET], b"180");
+        assert_eq!(&bytes[0xAC - ID666_OFFSET..0xB1 - ID666_OFFSET], b"10000");
+        assert_eq!(&bytes[0xB1 - ID666_OFFSET..0xB7 - ID666_OFFSET], b"Artist");
+    }
+
+    #[test]
+    fn test_cut_fields() {
+        let mut tag = test_tag();
+        assert!(tag.cut_fields().is_empty());
+        tag.comment = "x".repeat(COMMENT_SIZE + 1);
+        assert_eq!(tag.cut_fields(), vec![("comment", COMMENT_SIZE, "x".repeat(COMMENT_SIZE))]);
+    }
+
+    #[test]
+    fn test_source_crc() {
+        let mut header = vec![0u8; ID666_OFFSET];
+        let mut tag = test_tag();
+        assert_eq!(read_source_crc(&[header.clone(), tag.to_bytes(Id666Format::Text)].concat()), None);
+        tag.source_crc = Some(0x12345678);
+        header.extend(tag.to_bytes(Id666Format::Binary));
+        assert_eq!(read_source_crc(&header), Some(0x12345678));
+    }
+
+    #[test]
+    fn test_parse() {
+        for format in [Id666Format::Text, Id666Format::Binary].iter() {
+            let mut header = vec![0u8; ID666_OFFSET];
+            header.extend(test_tag().to_bytes(*format));
+            assert_eq!(detect_format(&header), *format);
+            assert_eq!(Id666::parse(&header), (test_tag(), *format));
+        }
+    }
+
+    #[test]
+    fn test_to_ascii() {
+        assert_eq!(to_ascii("Plain text"), "Plain text");
+        assert_eq!(to_ascii("Pokémon – Ending"), "Pokemon - Ending");
+        assert_eq!(to_ascii("“Straße” …"), "\"Strasse\" ...");
+        assert_eq!(to_ascii("ＳＴＡＧＥ　１"), "STAGE 1");
+        assert_eq!(to_ascii("Stage 1 「森」"), "Stage 1 []");
+        assert_eq!(to_ascii("ゲーム"), "-");
+    }
+
+    #[test]
+    fn test_to_xid6() {
+        let mut tag = test_tag();
+        assert_eq!(tag.to_xid6(), None);
+        tag.song_title = String::from("Pokémon");
+        tag.dumper = String::from("Someone with a long name");
+        let bytes = tag.to_bytes(Id666Format::Text);
+        assert_eq!(&bytes[0..8], b"Pokemon\0");
+        let chunk = tag.to_xid6().unwrap();
+        assert_eq!(chunk.items.len(), 2);
+        assert_eq!(chunk.get_string(xid6::ID_SONG_NAME).unwrap(), "Pokémon");
+        assert_eq!(chunk.get_string(xid6::ID_DUMPER).unwrap(), "Someone with a long name");
+    }
+
+    #[test]
+    fn test_as_fixed_field() {
+        assert_eq!(as_fixed_field("abc", 5), b"abc\0\0");
+        assert_eq!(as_fixed_field("abcdef", 5), b"abcde");
+        // "é" is two bytes, and would be split at 5 bytes
+        assert_eq!(as_fixed_field("abcdé", 5), b"abcd\0");
+        assert_eq!(as_fixed_field("", 2), b"\0\0");
+    }
+
+    #[test]
+    fn test_binary_layout() {
+        let bytes = test_tag().to_bytes(Id666Format::Binary);
+        assert_eq!(bytes.len(), ID666_SIZE);
+        assert_eq!(&bytes[0x9E - ID666_OFFSET..0xA2 - ID666_OFFSET], &[12, 5, 0xE3, 0x07]);
+        assert_eq!(&bytes[0xA9 - ID666_OFFSET..0xAC - ID666_OFFSET], &[180, 0, 0]);
+        assert_eq!(&bytes[0xAC - ID666_OFFSET..0xB0 - ID666_OFFSET], &[0x10, 0x27, 0, 0]);
+        assert_eq!(&bytes[0xB0 - ID666_OFFSET..0xB6 - ID666_OFFSET], b"Artist");
+    }
+}
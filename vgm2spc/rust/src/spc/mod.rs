@@ -0,0 +1,9 @@
+pub use self::id666::{Id666, Id666Format};
+pub use self::reader::SpcFile;
+pub use self::writer::SpcWriter;
+pub use self::xid6::Xid6;
+
+pub mod id666;
+pub mod reader;
+pub mod writer;
+pub mod xid6;
@@ -0,0 +1,157 @@
+//!
+//! Reads existing SPC files. The layout of the file is:
+//!
+//!     $00000  Header ("SNES-SPC700 Sound File Data v0.30", 26, 26, <has ID666>, <minor version>)
+//!     $00025  SPC700 registers (PC, A, X, Y, PSW, SP) and 2 reserved bytes
+//!     $0002E  ID666 tag
+//!     $00100  64 kB RAM image
+//!     $10100  DSP registers
+//!     $10180  Unused
+//!     $101C0  The RAM under the IPL ROM ($FFC0-$FFFF)
+//!     $10200  xid6 chunk (optional)
+//!
+
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+use crate::spc::id666::{self, Id666, Id666Format};
+use crate::spc::xid6::{Xid6, XID6_OFFSET};
+
+/// Start of the file. The version number that follows isn't checked, since older files use v0.10.
+pub const SPC_MAGIC: &[u8] = b"SNES-SPC700 Sound File Data";
+/// Offset of the 64 kB RAM image within the SPC file
+pub const RAM_OFFSET: usize = 0x100;
+pub const RAM_SIZE: usize = 0x10000;
+/// Offset of the DSP registers within the SPC file
+pub const DSP_OFFSET: usize = RAM_OFFSET + RAM_SIZE;
+pub const DSP_SIZE: usize = 0x80;
+/// Offset of the copy of the RAM under the IPL ROM
+pub const IPL_RAM_OFFSET: usize = 0x101C0;
+pub const IPL_RAM_SIZE: usize = 0x40;
+
+const HAS_ID666_OFFSET: usize = 0x23;
+const VERSION_OFFSET: usize = 0x24;
+const REGISTERS_OFFSET: usize = 0x25;
+
+/// The SPC700 registers at the time of the snapshot
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Registers {
+    pub pc: u16,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub psw: u8,
+    pub sp: u8,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpcFile {
+    pub version: u8,                            // Minor version number, normally 30
+    pub registers: Registers,
+    pub id666: Option<(Id666, Id666Format)>,    // None if the header says that there's no tag
+    pub ram: Vec<u8>,
+    pub dsp: [u8; DSP_SIZE],
+    pub ipl_ram: [u8; IPL_RAM_SIZE],            // All zeroes if the file ends after the DSP registers
+    pub xid6: Option<Xid6>,
+}
+
+impl SpcFile {
+    /// An SPC file with an empty RAM image and no tag
+    pub fn new() -> Self {
+        SpcFile {
+            version: 30,
+            registers: Registers::default(),
+            id666: None,
+            ram: vec![0; RAM_SIZE],
+            dsp: [0; DSP_SIZE],
+            ipl_ram: [0; IPL_RAM_SIZE],
+            xid6: None,
+        }
+    }
+
+    /// The tag, or an empty tag if there's none
+    pub fn tag(&self) -> Id666 {
+        self.id666.as_ref().map(|(tag, _)| tag.clone()).unwrap_or_default()
+    }
+}
+
+impl Default for SpcFile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Parse the contents of an SPC file
+pub fn read(data: &[u8]) -> Result<SpcFile, Error> {
+    if !data.starts_with(SPC_MAGIC) || data.len() < DSP_OFFSET + DSP_SIZE {
+        return Err(Error::new(ErrorKind::InvalidData, "Not an SPC file"));
+    }
+    let r = &data[REGISTERS_OFFSET..];
+    let registers = Registers { pc: u16::from_le_bytes([r[0], r[1]]), a: r[2], x: r[3], y: r[4], psw: r[5], sp: r[6] };
+    let id666 = Some(Id666::parse(&data[..RAM_OFFSET])).filter(|_| data[HAS_ID666_OFFSET] == id666::HAS_ID666);
+
+    let mut dsp = [0; DSP_SIZE];
+    dsp.copy_from_slice(&data[DSP_OFFSET..DSP_OFFSET + DSP_SIZE]);
+    let mut ipl_ram = [0; IPL_RAM_SIZE];
+    if let Some(bytes) = data.get(IPL_RAM_OFFSET..IPL_RAM_OFFSET + IPL_RAM_SIZE) {
+        ipl_ram.copy_from_slice(bytes);
+    }
+    Ok(SpcFile {
+        version: data[VERSION_OFFSET],
+        registers,
+        id666,
+        ram: data[RAM_OFFSET..RAM_OFFSET + RAM_SIZE].to_vec(),
+        dsp,
+        ipl_ram,
+        xid6: data.get(XID6_OFFSET..).and_then(|chunk| Xid6::parse(chunk).ok()),
+    })
+}
+
+/// Read the SPC file at `path`
+pub fn read_file(path: &Path) -> Result<SpcFile, Error> {
+    let data = fs::read(path)?;
+    read(&data).map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spc::xid6::{self, Item};
+
+    #[test]
+    fn test_read() {
+        let mut tag = Id666::new();
+        tag.song_title = String::from("Title");
+        let mut data = SPC_MAGIC.to_vec();
+        data.extend_from_slice(b" v0.30");
+        data.extend_from_slice(&[26, 26, id666::HAS_ID666, 30, 0x00, 0x04, 1, 2, 3, 0x02, 0xEF, 0, 0]);
+        data.extend_from_slice(&tag.to_bytes(Id666Format::Text));
+        data.resize(DSP_OFFSET + DSP_SIZE, 0);
+        data[RAM_OFFSET + 0x400] = 0xE8;
+        data[DSP_OFFSET + 0x6C] = 0x20;
+
+        let spc = read(&data).unwrap();
+        assert_eq!(spc.registers, Registers { pc: 0x0400, a: 1, x: 2, y: 3, psw: 0x02, sp: 0xEF });
+        assert_eq!(spc.version, 30);
+        assert_eq!(spc.tag().song_title, "Title");
+        assert_eq!(spc.ram.len(), RAM_SIZE);
+        assert_eq!(spc.ram[0x400], 0xE8);
+        assert_eq!(spc.dsp[0x6C], 0x20);
+        assert_eq!(spc.ipl_ram, [0; IPL_RAM_SIZE]);
+        assert!(spc.xid6.is_none());
+
+        let chunk = Xid6 { items: vec![Item { id: xid6::ID_SONG_NAME, kind: xid6::TYPE_STRING, data: b"Long title".to_vec() }] };
+        data.resize(XID6_OFFSET, 0);
+        data[IPL_RAM_OFFSET] = 0xCD;
+        data.extend_from_slice(&chunk.to_bytes());
+        data[HAS_ID666_OFFSET] = id666::NO_ID666;
+        let spc = read(&data).unwrap();
+        assert_eq!(spc.ipl_ram[0], 0xCD);
+        assert_eq!(spc.xid6, Some(chunk));
+        assert!(spc.id666.is_none());
+
+        assert!(read(&data[..DSP_OFFSET]).is_err());
+        assert!(read(&[0; DSP_OFFSET + DSP_SIZE]).is_err());
+    }
+}
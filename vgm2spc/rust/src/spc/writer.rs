@@ -0,0 +1,158 @@
+//!
+//! Writes SPC files. See `reader` for the layout of the file.
+//!
+
+use std::io::{self, Write};
+
+use crate::spc::id666::{self, Id666, Id666Format, ID666_SIZE};
+use crate::spc::reader::{Registers, SpcFile, DSP_SIZE, RAM_SIZE, SPC_MAGIC};
+
+/// Minor version number written to the header
+const VERSION: u8 = 30;
+
+/// Builds an SPC file. Everything that isn't set explicitly is zero.
+#[derive(Clone, Debug)]
+pub struct SpcWriter {
+    registers: Registers,
+    id666: Option<(Id666, Id666Format)>,
+    ram: Vec<u8>,
+    dsp: [u8; DSP_SIZE],
+}
+
+impl SpcWriter {
+    pub fn new() -> SpcWriter {
+        SpcWriter {
+            registers: Registers::default(),
+            id666: None,
+            ram: vec![0; RAM_SIZE],
+            dsp: [0; DSP_SIZE],
+        }
+    }
+
+    pub fn set_registers(&mut self, registers: Registers) -> &mut Self {
+        self.registers = registers;
+        self
+    }
+
+    /// Set the ID666 tag. Fields that don't fit in the tag are also written to an xid6 chunk.
+    pub fn set_id666(&mut self, tag: Id666, format: Id666Format) -> &mut Self {
+        self.id666 = Some((tag, format));
+        self
+    }
+
+    /// Copy `bytes` into RAM at `address`. Panics if they don't fit below $10000.
+    pub fn load_ram(&mut self, address: usize, bytes: &[u8]) -> &mut Self {
+        self.ram[address..address + bytes.len()].copy_from_slice(bytes);
+        self
+    }
+
+    pub fn set_dsp(&mut self, register: u8, value: u8) -> &mut Self {
+        self.dsp[register as usize] = value;
+        self
+    }
+
+    pub fn write_to(&self, out: &mut impl Write) -> Result<(), io::Error> {
+        out.write_all(SPC_MAGIC)?;
+        out.write_all(b" v0.30")?;
+        let has_id666 = if self.id666.is_some() { id666::HAS_ID666 } else { id666::NO_ID666 };
+        out.write_all(&[26, 26, has_id666, VERSION])?;
+
+        let r = &self.registers;
+        out.write_all(&r.pc.to_le_bytes())?;
+        out.write_all(&[r.a, r.x, r.y, r.psw, r.sp, 0x00, 0x00])?;
+
+        match &self.id666 {
+            Some((tag, format)) => out.write_all(&tag.to_bytes(*format))?,
+            None => out.write_all(&[0; ID666_SIZE])?,
+        }
+        out.write_all(&self.ram)?;
+        out.write_all(&self.dsp)?;
+        // Unused area and the RAM under the IPL ROM
+        out.write_all(&[0; 128])?;
+        if let Some(xid6) = self.id666.as_ref().and_then(|(tag, _)| tag.to_xid6()) {
+            out.write_all(&xid6.to_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for SpcWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Return the contents of an SPC file parsed by `reader::read`, e.g. after changing it. The parts
+/// of the file that the reader skips (the unused areas, and anything after the DSP registers that
+/// isn't an xid6 chunk) are zero or left out.
+pub fn write(spc: &SpcFile) -> Vec<u8> {
+    let mut data = SPC_MAGIC.to_vec();
+    data.extend_from_slice(b" v0.30");
+    let has_id666 = if spc.id666.is_some() { id666::HAS_ID666 } else { id666::NO_ID666 };
+    data.extend_from_slice(&[26, 26, has_id666, spc.version]);
+
+    let r = &spc.registers;
+    data.extend_from_slice(&r.pc.to_le_bytes());
+    data.extend_from_slice(&[r.a, r.x, r.y, r.psw, r.sp, 0x00, 0x00]);
+    match &spc.id666 {
+        Some((tag, format)) => data.extend(tag.to_bytes(*format)),
+        None => data.extend_from_slice(&[0; ID666_SIZE]),
+    }
+    data.extend_from_slice(&spc.ram);
+    data.extend_from_slice(&spc.dsp);
+    data.extend_from_slice(&[0; 64]);
+    data.extend_from_slice(&spc.ipl_ram);
+    if let Some(xid6) = &spc.xid6 {
+        data.extend(xid6.to_bytes());
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spc::reader::{self, DSP_OFFSET, IPL_RAM_OFFSET, IPL_RAM_SIZE};
+    use crate::spc::xid6::XID6_OFFSET;
+
+    #[test]
+    fn test_round_trip() {
+        let mut tag = Id666::new();
+        tag.song_title = String::from("A title that is too long for the 32 bytes of the ID666 tag");
+        let registers = Registers { pc: 0x0400, a: 1, x: 2, y: 3, psw: 0x02, sp: 0xEF };
+        let mut writer = SpcWriter::new();
+        writer.set_registers(registers)
+            .set_id666(tag.clone(), Id666Format::Binary)
+            .load_ram(0x400, &[0xE8, 0x00])
+            .set_dsp(0x6C, 0x20);
+        let mut data = Vec::new();
+        writer.write_to(&mut data).unwrap();
+        assert!(data.len() > XID6_OFFSET);
+        assert_eq!(&data[0x21..0x25], &[26, 26, id666::HAS_ID666, 30]);
+
+        let spc = reader::read(&data).unwrap();
+        assert_eq!(spc.registers, registers);
+        assert!(tag.song_title.starts_with(&spc.tag().song_title));
+        assert_eq!(&spc.ram[0x3FF..0x402], &[0x00, 0xE8, 0x00]);
+        assert_eq!(spc.dsp[0x6C], 0x20);
+        assert_eq!(spc.xid6, tag.to_xid6());
+
+        let mut data = Vec::new();
+        SpcWriter::new().write_to(&mut data).unwrap();
+        assert_eq!(data.len(), IPL_RAM_OFFSET + IPL_RAM_SIZE);
+        assert!(data[DSP_OFFSET..].iter().all(|&b| b == 0));
+        assert!(reader::read(&data).unwrap().id666.is_none());
+    }
+
+    #[test]
+    fn test_write() {
+        let mut data = Vec::new();
+        SpcWriter::new().set_id666(Id666::new(), Id666Format::Text).load_ram(0x200, &[0x8F]).write_to(&mut data).unwrap();
+        let mut spc = reader::read(&data).unwrap();
+        assert_eq!(write(&spc), data);
+
+        spc.registers.pc = 0x0200;
+        spc.ipl_ram[0] = 0xCD;
+        spc.xid6 = Id666 { song_title: "x".repeat(40), ..Id666::new() }.to_xid6();
+        assert_eq!(reader::read(&write(&spc)).unwrap(), spc);
+    }
+}
@@ -0,0 +1,153 @@
+//!
+//! Extended ID666 (xid6) chunk, stored after the 64 kB RAM image and DSP registers of an SPC file.
+//!
+//! The chunk consists of a "xid6" marker and a 32-bit size, followed by a list of items:
+//!
+//! <id:u8> <type:u8> <length:u16> <data>
+//!
+//! Items of type 0 store their value in the length field and have no data. For the other types
+//! the data is padded to a multiple of 4 bytes.
+//!
+
+use std::io::{Error, ErrorKind};
+
+/// Offset of the chunk within the SPC file
+pub const XID6_OFFSET: usize = 0x10200;
+
+const XID6_MARKER: &[u8; 4] = b"xid6";
+
+pub const TYPE_DATA: u8 = 0;
+pub const TYPE_STRING: u8 = 1;
+pub const TYPE_INTEGER: u8 = 4;
+
+pub const ID_SONG_NAME: u8 = 0x01;
+pub const ID_GAME_NAME: u8 = 0x02;
+pub const ID_ARTIST: u8 = 0x03;
+pub const ID_DUMPER: u8 = 0x04;
+pub const ID_COMMENTS: u8 = 0x07;
+pub const ID_INTRO_LENGTH: u8 = 0x30;
+pub const ID_FADE_LENGTH: u8 = 0x33;
+
+/// Number of xid6 length ticks per second
+pub const TICKS_PER_SECOND: u32 = 64000;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Item {
+    pub id: u8,
+    pub kind: u8,
+    pub data: Vec<u8>,      // The value of the length field for TYPE_DATA items, unpadded otherwise
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Xid6 {
+    pub items: Vec<Item>,
+}
+
+impl Xid6 {
+    /// Parse the chunk at the start of `data`
+    pub fn parse(data: &[u8]) -> Result<Xid6, Error> {
+        if data.len() < 8 || &data[0..4] != XID6_MARKER {
+            return Err(Error::new(ErrorKind::InvalidData, "No xid6 chunk found"));
+        }
+        let size = u32::from_le_bytes([data[4], data[5], data[6], data[7]]) as usize;
+        let end = std::cmp::min(8 + size, data.len());
+
+        let mut items = Vec::new();
+        let mut pos = 8;
+        while pos + 4 <= end {
+            let (id, kind) = (data[pos], data[pos + 1]);
+            let length = u16::from_le_bytes([data[pos + 2], data[pos + 3]]) as usize;
+            pos += 4;
+            if kind == TYPE_DATA {
+                items.push(Item { id, kind, data: data[pos - 2..pos].to_vec() });
+            } else {
+                if pos + length > end {
+                    return Err(Error::new(ErrorKind::UnexpectedEof, format!("xid6 item 0x{:02X} is truncated", id)));
+                }
+                items.push(Item { id, kind, data: data[pos..pos + length].to_vec() });
+                pos += (length + 3) & !3;
+            }
+        }
+        Ok(Xid6 { items })
+    }
+
+    /// Return the chunk, including the marker and size
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        for item in &self.items {
+            body.push(item.id);
+            body.push(item.kind);
+            if item.kind == TYPE_DATA {
+                body.extend(&item.data[..2]);
+            } else {
+                body.extend(&(item.data.len() as u16).to_le_bytes());
+                body.extend(&item.data);
+                body.resize((body.len() + 3) & !3, 0);
+            }
+        }
+        let mut chunk = XID6_MARKER.to_vec();
+        chunk.extend(&(body.len() as u32).to_le_bytes());
+        chunk.extend(body);
+        chunk
+    }
+
+    pub fn get(&self, id: u8) -> Option<&Item> {
+        self.items.iter().find(|item| item.id == id)
+    }
+
+    /// Return the value of string item `id`, without the terminating NUL
+    pub fn get_string(&self, id: u8) -> Option<String> {
+        self.get(id).filter(|item| item.kind == TYPE_STRING).map(|item| {
+            let text: Vec<u8> = item.data.iter().cloned().take_while(|&b| b != 0).collect();
+            String::from_utf8_lossy(&text).into_owned()
+        })
+    }
+
+    pub fn get_integer(&self, id: u8) -> Option<u32> {
+        self.get(id).filter(|item| item.kind == TYPE_INTEGER && item.data.len() == 4)
+            .map(|item| u32::from_le_bytes([item.data[0], item.data[1], item.data[2], item.data[3]]))
+    }
+
+    /// Replace item `id` with `item`, or add it at the end if there's no such item
+    fn set(&mut self, item: Item) {
+        match self.items.iter_mut().find(|i| i.id == item.id) {
+            Some(existing) => *existing = item,
+            None => self.items.push(item),
+        }
+    }
+
+    pub fn set_string(&mut self, id: u8, value: &str) {
+        let mut data = value.as_bytes().to_vec();
+        data.push(0);
+        self.set(Item { id, kind: TYPE_STRING, data });
+    }
+
+    pub fn set_integer(&mut self, id: u8, value: u32) {
+        self.set(Item { id, kind: TYPE_INTEGER, data: value.to_le_bytes().to_vec() });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut xid6 = Xid6::default();
+        xid6.set_string(ID_SONG_NAME, "A song with a long name");
+        xid6.set_integer(ID_INTRO_LENGTH, 180 * TICKS_PER_SECOND);
+        xid6.items.push(Item { id: 0x11, kind: TYPE_DATA, data: vec![1, 0] });
+
+        let bytes = xid6.to_bytes();
+        assert_eq!(&bytes[0..4], b"xid6");
+        assert_eq!(bytes.len() % 4, 0);
+        let parsed = Xid6::parse(&bytes).unwrap();
+        assert_eq!(parsed, xid6);
+        assert_eq!(parsed.get_string(ID_SONG_NAME).unwrap(), "A song with a long name");
+        assert_eq!(parsed.get_integer(ID_INTRO_LENGTH), Some(180 * TICKS_PER_SECOND));
+
+        xid6.set_string(ID_SONG_NAME, "Short");
+        assert_eq!(xid6.items.len(), 3);
+        assert_eq!(xid6.get_string(ID_SONG_NAME).unwrap(), "Short");
+    }
+}
@@ -0,0 +1,326 @@
+//!
+//! Transformations of whole (decompressed) VGM files, applied before conversion.
+//!
+
+use std::io::{Error, ErrorKind};
+
+use crate::core::bytestream::ByteStream;
+use crate::core::error;
+use crate::vgm::Command;
+use crate::vgm::Gd3;
+use crate::vgm::events::{read_stream, Events, VgmEvent};
+use crate::vgm::specification::FileHeader;
+use crate::vgm::writer;
+
+/// Writes that set all four SN76489 channels to maximum attenuation
+pub fn psg_silence() -> Vec<u8> {
+    let mut commands = Vec::new();
+    for event in psg_silence_events() {
+        event.write_to(&mut commands);
+    }
+    commands
+}
+
+pub fn psg_silence_events() -> Vec<VgmEvent> {
+    [0x9F, 0xBF, 0xDF, 0xFF].iter().map(|&value| VgmEvent::PsgWrite(value)).collect()
+}
+
+/// Writes that silence all SN76489 channels and reset the noise register (which also resets the
+/// noise generator's shift register)
+pub fn psg_reset() -> Vec<u8> {
+    let mut commands = psg_silence();
+    commands.extend(&[Command::PSG_WRITE, 0xE0]);
+    commands
+}
+
+/// Insert `psg_reset` at the start of the song and at the loop point, so that no notes are left
+/// hanging when looping. The loop offset is moved to the start of the inserted commands.
+pub fn inject_reset(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let header = FileHeader::parse(data)?;
+    let start = header.data_offset();
+    let mut insert_at = vec![start];
+    if header.loop_offset != 0 {
+        let loop_pos = header.loop_offset as usize + 0x1C;
+        if loop_pos > start && loop_pos < data.len() {
+            insert_at.push(loop_pos);
+        }
+    }
+
+    let reset = psg_reset();
+    let mut result = data[..start].to_vec();
+    let mut prev = start;
+    for &pos in &insert_at {
+        result.extend_from_slice(&data[prev..pos]);
+        result.extend(&reset);
+        prev = pos;
+    }
+    result.extend_from_slice(&data[prev..]);
+
+    let added = (insert_at.len() * reset.len()) as u32;
+    let mut set_u32 = |offset: usize, value: u32| result[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    set_u32(0x04, header.eof_offset + added);
+    if header.gd3_offset != 0 {
+        set_u32(0x14, header.gd3_offset + added);
+    }
+    if header.loop_offset != 0 {
+        // The loop now starts at the reset commands inserted at the old loop point (or the start)
+        set_u32(0x1C, header.loop_offset + added - reset.len() as u32);
+    }
+    Ok(result)
+}
+
+/// Tracks the attenuation of the SN76489 channels
+struct PsgVolumes {
+    attenuation: [u8; 4],
+    latched_channel: Option<usize>,     // Channel whose volume was latched by the last write, if any
+}
+
+impl PsgVolumes {
+    fn new() -> Self {
+        PsgVolumes { attenuation: [0x0F; 4], latched_channel: None }
+    }
+
+    fn write(&mut self, value: u8) {
+        if (value & 0x80) != 0 {
+            let channel = ((value >> 5) & 3) as usize;
+            self.latched_channel = if (value & 0x10) != 0 { Some(channel) } else { None };
+        }
+        if let Some(channel) = self.latched_channel {
+            self.attenuation[channel] = value & 0x0F;
+        }
+    }
+
+    fn audible(&self) -> bool {
+        self.attenuation.iter().any(|&a| a != 0x0F)
+    }
+}
+
+/// Remove the waits at the start of the song before any PSG channel becomes audible, and (for
+/// songs that don't loop) the waits at the end after all channels have been silenced. All other
+/// commands are kept. Leading silence is never trimmed past the loop point.
+///
+/// Returns the trimmed file, and the number of samples removed from the start and end.
+pub fn trim_silence(data: &[u8]) -> Result<(Vec<u8>, u32, u32), Error> {
+    let header = FileHeader::parse(data)?;
+    let (events, loop_index) = read_stream(data, &header)?;
+
+    // Whether any channel is audible after each event
+    let mut volumes = PsgVolumes::new();
+    let audible: Vec<bool> = events.iter().map(|event| {
+        if let VgmEvent::PsgWrite(value) = event {
+            volumes.write(*value);
+        }
+        volumes.audible()
+    }).collect();
+    let last_audible = match audible.iter().rposition(|&a| a) {
+        Some(index) => index,
+        None => return Ok((data.to_vec(), 0, 0)),
+    };
+    let first_audible = audible.iter().position(|&a| a).unwrap();
+
+    let mut kept = Vec::with_capacity(events.len());
+    let mut new_loop_index = None;
+    let (mut leading, mut trailing) = (0, 0);
+    for (i, event) in events.iter().enumerate() {
+        if loop_index == Some(i) {
+            new_loop_index = Some(kept.len());
+        }
+        if let VgmEvent::Wait(samples) = event {
+            if i < first_audible && loop_index.is_none_or(|l| i < l) {
+                leading += samples;
+                continue;
+            } else if i > last_audible && loop_index.is_none() {
+                trailing += samples;
+                continue;
+            }
+        }
+        kept.push(event.clone());
+    }
+
+    let result = writer::write_vgm(&data[..header.data_offset()], &kept, new_loop_index, read_gd3(data, &header).as_ref());
+    Ok((result, leading, trailing))
+}
+
+/// Repair a VGM file whose command stream ends without an end of sound data command, e.g. because
+/// the file has been truncated. The stream is cut after the last complete command and terminated,
+/// and the EOF offset, sample counts and loop offset are recalculated (the loop is dropped if the
+/// loop point was cut off).
+///
+/// Returns `None` if the file doesn't need repairing.
+pub fn repair_truncated(data: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+    let header = FileHeader::parse(data)?;
+    let gd3 = read_gd3(data, &header);
+    let stream_end = if gd3.is_some() { header.gd3_offset as usize + 0x14 } else { data.len() };
+    let start = std::cmp::min(header.data_offset(), stream_end);
+    let loop_position = if header.loop_offset != 0 { Some(header.loop_offset as usize + 0x1C) } else { None };
+
+    let mut stream = ByteStream::new(data[..stream_end].to_vec());
+    stream.skip(start);
+    let mut events = Events::new(&mut stream);
+    let mut kept = Vec::new();
+    let mut loop_index = None;
+    loop {
+        if Some(events.position()) == loop_position {
+            loop_index = Some(kept.len());
+        }
+        match events.next() {
+            Some(Ok(VgmEvent::End)) => return Ok(None),
+            Some(Ok(event)) => kept.push(event),
+            Some(Err(e)) if e.kind() == error::ErrorKind::UnexpectedEof => break,
+            Some(Err(e)) => return Err(e.into()),
+            None => break,
+        }
+    }
+    // The truncated command (if any) wasn't read, so it can't be where the loop starts
+    if loop_index == Some(kept.len()) {
+        loop_index = None;
+    }
+    Ok(Some(writer::write_vgm(&data[..start], &kept, loop_index, gd3.as_ref())))
+}
+
+/// Make sure that the loop offset of `data` points to the start of a command. A loop offset that
+/// points into the middle of a command is moved back to the start of that command.
+///
+/// Returns the old and new (absolute) loop positions if the loop offset was moved.
+pub fn align_loop_offset(data: &mut [u8]) -> Result<Option<(usize, usize)>, Error> {
+    let header = FileHeader::parse(data)?;
+    if header.loop_offset == 0 {
+        return Ok(None);
+    }
+    let loop_position = header.loop_offset as usize + 0x1C;
+
+    let mut stream = ByteStream::new(data.to_vec());
+    stream.skip(std::cmp::min(header.data_offset(), data.len()));
+    let mut events = Events::new(&mut stream);
+    let mut command_start = events.position();
+    while events.position() < loop_position {
+        command_start = events.position();
+        match events.next() {
+            Some(Ok(_)) => {}
+            // The loop offset is past the end of the stream, which the header validation reports
+            _ => return Ok(None),
+        }
+    }
+    if events.position() == loop_position {
+        return Ok(None);
+    }
+    let loop_offset = (command_start - 0x1C) as u32;
+    data[0x1C..0x20].copy_from_slice(&loop_offset.to_le_bytes());
+    Ok(Some((loop_position, command_start)))
+}
+
+/// Return the GD3 tag of `data`, if it has a valid one
+pub fn read_gd3(data: &[u8], header: &FileHeader) -> Option<Gd3> {
+    if header.gd3_offset == 0 {
+        return None;
+    }
+    data.get(header.gd3_offset as usize + 0x14..).and_then(|tag| Gd3::parse(tag).ok())
+}
+
+/// Concatenate several VGM files into one. The header and GD3 tag are taken from the first file.
+/// All PSG channels are silenced between the songs, so that notes don't carry over from one to
+/// the next. If `loop_last` is true, the result loops back to the start of the last song;
+/// otherwise it doesn't loop. All songs are played with the PSG clock of the first one.
+pub fn join(files: &[Vec<u8>], loop_last: bool) -> Result<Vec<u8>, Error> {
+    if files.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "No files to join"));
+    }
+    let first_header = FileHeader::parse(&files[0])?;
+    let mut events = Vec::new();
+    let mut last_song_start = 0;
+
+    for (i, file) in files.iter().enumerate() {
+        let header = FileHeader::parse(file)?;
+        if i > 0 {
+            events.extend(psg_silence_events());
+        }
+        last_song_start = events.len();
+        let (song, _) = read_stream(file, &header)?;
+        events.extend(song.into_iter().filter(|event| *event != VgmEvent::End));
+    }
+    events.push(VgmEvent::End);
+
+    let loop_index = if loop_last { Some(last_song_start) } else { None };
+    let gd3 = read_gd3(&files[0], &first_header);
+    Ok(writer::write_vgm(&files[0][..first_header.data_offset()], &events, loop_index, gd3.as_ref()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vgm::testing::VgmBuilder;
+
+    fn vgm(commands: &[u8], total_samples: u32) -> Vec<u8> {
+        VgmBuilder::new().psg().total_samples(total_samples).commands(commands).commands(&[Command::END_OF_SOUND_DATA]).build()
+    }
+
+    #[test]
+    fn test_trim_silence() {
+        let data = vgm(&[Command::WAIT_NTSC_FRAME, Command::PSG_WRITE, 0x80, Command::WAIT_1,
+                         Command::PSG_WRITE, 0x90, Command::WAIT_NTSC_FRAME, Command::PSG_WRITE, 0x9F,
+                         Command::WAIT_LONG, 0x10, 0x00, Command::WAIT_16], 735 + 1 + 735 + 16 + 16);
+        let (trimmed, leading, trailing) = trim_silence(&data).unwrap();
+        assert_eq!((leading, trailing), (736, 32));
+        assert_eq!(&trimmed[0x40..], &[Command::PSG_WRITE, 0x80, Command::PSG_WRITE, 0x90, Command::WAIT_NTSC_FRAME,
+                                        Command::PSG_WRITE, 0x9F, Command::END_OF_SOUND_DATA]);
+        let header = FileHeader::parse(&trimmed).unwrap();
+        assert_eq!(header.total_samples, 735);
+        assert_eq!(header.eof_offset as usize, trimmed.len() - 4);
+    }
+
+    #[test]
+    fn test_repair_truncated() {
+        let data = vgm(&[Command::PSG_WRITE, 0x90, Command::WAIT_NTSC_FRAME], 735);
+        assert_eq!(repair_truncated(&data).unwrap(), None);
+
+        let mut truncated = data[..0x43].to_vec();
+        truncated.extend(&[Command::WAIT_LONG, 0x10]);
+        let repaired = repair_truncated(&truncated).unwrap().unwrap();
+        assert_eq!(&repaired[0x40..], &[Command::PSG_WRITE, 0x90, Command::WAIT_NTSC_FRAME, Command::END_OF_SOUND_DATA]);
+        let header = FileHeader::parse(&repaired).unwrap();
+        assert_eq!(header.eof_offset as usize, repaired.len() - 4);
+        assert_eq!(header.total_samples, 735);
+    }
+
+    #[test]
+    fn test_align_loop_offset() {
+        let mut data = vgm(&[Command::PSG_WRITE, 0x90, Command::WAIT_LONG, 0x00, 0x01, Command::WAIT_NTSC_FRAME], 256 + 735);
+        data[0x1C..0x20].copy_from_slice(&(0x42u32 - 0x1C).to_le_bytes());
+        assert_eq!(align_loop_offset(&mut data).unwrap(), None);
+        data[0x1C..0x20].copy_from_slice(&(0x44u32 - 0x1C).to_le_bytes());
+        assert_eq!(align_loop_offset(&mut data).unwrap(), Some((0x44, 0x42)));
+        assert_eq!(FileHeader::parse(&data).unwrap().loop_offset as usize + 0x1C, 0x42);
+    }
+
+    #[test]
+    fn test_inject_reset() {
+        let mut data = vgm(&[Command::PSG_WRITE, 0x90, Command::WAIT_NTSC_FRAME], 735);
+        data[0x1C..0x20].copy_from_slice(&(0x42u32 - 0x1C).to_le_bytes());
+        let result = inject_reset(&data).unwrap();
+        let reset = psg_reset();
+        let header = FileHeader::parse(&result).unwrap();
+        assert_eq!(header.eof_offset as usize, result.len() - 4);
+        assert_eq!(header.loop_offset as usize + 0x1C, 0x42 + reset.len());
+        assert_eq!(&result[0x40..0x40 + reset.len()], &reset[..]);
+        assert_eq!(&result[0x42 + reset.len()..0x42 + 2 * reset.len()], &reset[..]);
+        assert_eq!(&result[0x42 + 2 * reset.len()..], &[Command::WAIT_NTSC_FRAME, Command::END_OF_SOUND_DATA]);
+    }
+
+    #[test]
+    fn test_join() {
+        let a = vgm(&[Command::PSG_WRITE, 0x80, Command::WAIT_NTSC_FRAME], 735);
+        let b = vgm(&[Command::WAIT_PAL_FRAME], 882);
+        let joined = join(&[a, b], true).unwrap();
+        let header = FileHeader::parse(&joined).unwrap();
+        assert_eq!(header.total_samples, 735 + 882);
+        assert_eq!(header.loop_samples, 882);
+        assert_eq!(header.gd3_offset, 0);
+        assert_eq!(header.eof_offset as usize, joined.len() - 4);
+
+        let mut commands = vec![Command::PSG_WRITE, 0x80, Command::WAIT_NTSC_FRAME];
+        commands.extend(psg_silence());
+        assert_eq!(header.loop_offset as usize + 0x1C, 0x40 + commands.len());
+        commands.extend(&[Command::WAIT_PAL_FRAME, Command::END_OF_SOUND_DATA]);
+        assert_eq!(&joined[0x40..], &commands[..]);
+    }
+}
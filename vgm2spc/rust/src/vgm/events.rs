@@ -0,0 +1,41 @@
+//!
+//! Reading the command stream of a VGM file into `VgmEvent`s (see `core::events`).
+//!
+
+use std::io::Error;
+
+use crate::core::bytestream::ByteStream;
+use crate::core::error;
+use crate::vgm::specification::FileHeader;
+
+pub use crate::core::events::{Events, VgmEvent};
+
+impl From<error::Error> for Error {
+    fn from(e: error::Error) -> Self {
+        let kind = match e.kind() {
+            error::ErrorKind::UnexpectedEof => std::io::ErrorKind::UnexpectedEof,
+            error::ErrorKind::InvalidData => std::io::ErrorKind::InvalidData,
+        };
+        Error::new(kind, e.to_string())
+    }
+}
+
+/// Read the command stream of the VGM file `data`, up to and including the end of sound data
+/// command. Also returns the index of the event at the loop offset, if there is one.
+pub fn read_stream(data: &[u8], header: &FileHeader) -> Result<(Vec<VgmEvent>, Option<usize>), Error> {
+    let loop_position = if header.loop_offset != 0 { Some(header.loop_offset as usize + 0x1C) } else { None };
+    let mut stream = ByteStream::new(data.to_vec());
+    stream.skip(std::cmp::min(header.data_offset(), data.len()));
+    let mut events = Events::new(&mut stream);
+    let mut result = Vec::new();
+    let mut loop_index = None;
+    loop {
+        if Some(events.position()) == loop_position {
+            loop_index = Some(result.len());
+        }
+        match events.next() {
+            Some(event) => result.push(event?),
+            None => return Ok((result, loop_index)),
+        }
+    }
+}
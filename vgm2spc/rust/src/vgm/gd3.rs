@@ -0,0 +1,144 @@
+use std::io::{Error, ErrorKind};
+
+use serde::Serialize;
+
+pub const GD3_MAGIC: &[u8; 4] = b"Gd3 ";
+pub const GD3_VERSION: u32 = 0x100;
+
+/// Size of the GD3 header: magic, version and length of the strings
+pub const GD3_HEADER_SIZE: usize = 12;
+
+/// The strings of a GD3 tag, in the order they are stored in the file
+/// (see https://vgmrips.net/wiki/GD3_Specification)
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Gd3 {
+    pub track_name: String,
+    pub track_name_jp: String,
+    pub game_name: String,
+    pub game_name_jp: String,
+    pub system_name: String,
+    pub system_name_jp: String,
+    pub author: String,
+    pub author_jp: String,
+    pub release_date: String,
+    pub ripper: String,
+    pub notes: String,
+}
+
+impl Gd3 {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Parse the GD3 tag at the start of `data`. Strings that aren't valid UTF-16 are read with
+    /// replacement characters; a truncated tag is an error.
+    pub fn parse(data: &[u8]) -> Result<Gd3, Error> {
+        if !data.starts_with(GD3_MAGIC) {
+            return Err(Error::new(ErrorKind::InvalidData, "Invalid GD3 tag"));
+        }
+        let length = data.get(8..GD3_HEADER_SIZE).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize);
+        let strings = match length.and_then(|length| data.get(GD3_HEADER_SIZE..GD3_HEADER_SIZE + length)) {
+            Some(strings) => strings,
+            None => return Err(Error::new(ErrorKind::UnexpectedEof, "The GD3 tag is truncated")),
+        };
+
+        let mut units = strings.chunks_exact(2).map(|b| u16::from_le_bytes([b[0], b[1]]));
+        let mut gd3 = Gd3::new();
+        for field in gd3.fields_mut().iter_mut() {
+            let string: Vec<u16> = units.by_ref().take_while(|&unit| unit != 0).collect();
+            field.extend(std::char::decode_utf16(string).map(|c| c.unwrap_or(std::char::REPLACEMENT_CHARACTER)));
+        }
+        Ok(gd3)
+    }
+
+    /// Return the tag as it's stored in a VGM file, including the GD3 header
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut strings: Vec<u8> = Vec::new();
+        for field in self.fields().iter() {
+            for unit in field.encode_utf16().chain(std::iter::once(0)) {
+                strings.extend(&unit.to_le_bytes());
+            }
+        }
+        let mut tag = GD3_MAGIC.to_vec();
+        tag.extend(&GD3_VERSION.to_le_bytes());
+        tag.extend(&(strings.len() as u32).to_le_bytes());
+        tag.extend(strings);
+        tag
+    }
+
+    /// Return references to all fields, in file order
+    pub fn fields(&self) -> [&String; 11] {
+        [&self.track_name, &self.track_name_jp,
+         &self.game_name, &self.game_name_jp,
+         &self.system_name, &self.system_name_jp,
+         &self.author, &self.author_jp,
+         &self.release_date, &self.ripper, &self.notes]
+    }
+
+    /// Return mutable references to all fields, in file order
+    pub fn fields_mut(&mut self) -> [&mut String; 11] {
+        [&mut self.track_name, &mut self.track_name_jp,
+         &mut self.game_name, &mut self.game_name_jp,
+         &mut self.system_name, &mut self.system_name_jp,
+         &mut self.author, &mut self.author_jp,
+         &mut self.release_date, &mut self.ripper, &mut self.notes]
+    }
+
+    /// Return the tag as a JSON object. `source` is stored alongside the tag fields so that
+    /// sidecar files can be matched with the VGM they were created from.
+    pub fn to_json(&self, source: &str) -> String {
+        #[derive(Serialize)]
+        struct Sidecar<'a> {
+            source: &'a str,
+            #[serde(flatten)]
+            tag: &'a Gd3,
+        }
+        serde_json::to_string_pretty(&Sidecar { source, tag: self }).unwrap() + "\n"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gd3_tag(strings: &[&str]) -> Vec<u8> {
+        let mut body: Vec<u8> = Vec::new();
+        for s in strings {
+            for unit in s.encode_utf16().chain(std::iter::once(0)) {
+                body.extend(&unit.to_le_bytes());
+            }
+        }
+        let mut tag = GD3_MAGIC.to_vec();
+        tag.extend(&GD3_VERSION.to_le_bytes());
+        tag.extend(&(body.len() as u32).to_le_bytes());
+        tag.extend(body);
+        tag
+    }
+
+    #[test]
+    fn test_parse() {
+        let tag = gd3_tag(&["Title", "タイトル", "Game", "", "System", "", "Author", "", "1990", "Ripper", "Notes"]);
+        let gd3 = Gd3::parse(&tag).unwrap();
+        assert_eq!(gd3.track_name, "Title");
+        assert_eq!(gd3.track_name_jp, "タイトル");
+        assert_eq!(gd3.ripper, "Ripper");
+        assert_eq!(gd3.notes, "Notes");
+
+        // Missing strings are left empty
+        assert_eq!(Gd3::parse(&gd3_tag(&["Title"])).unwrap().game_name, "");
+        assert!(Gd3::parse(&tag[..tag.len() - 2]).is_err());
+        assert!(Gd3::parse(b"Vgm ").is_err());
+        assert_eq!(gd3.to_bytes(), tag);
+    }
+
+    #[test]
+    fn test_to_json() {
+        let mut gd3 = Gd3::new();
+        gd3.track_name = String::from("Title");
+        gd3.notes = String::from("Line 1\nLine 2");
+        let json = gd3.to_json("song.vgz");
+        assert!(json.contains("\"source\": \"song.vgz\""));
+        assert!(json.contains("\"track_name\": \"Title\""));
+        assert!(json.contains("\"notes\": \"Line 1\\nLine 2\""));
+    }
+}
@@ -0,0 +1,200 @@
+//!
+//! GYM files: register logs of the YM2612 and PSG of the Genesis, played at 60 frames per second.
+//!
+//! The log is a sequence of commands:
+//!
+//!     0x00        Wait for the next frame
+//!     0x01 rr dd  YM2612 port 0 write
+//!     0x02 rr dd  YM2612 port 1 write
+//!     0x03 dd     PSG write
+//!
+//! GYMX files start with a 428 byte header that holds the tag, the loop point (a frame number,
+//! where 1 is the first frame, or 0 if the song doesn't loop), and the size of the log before
+//! compression if it's zlib compressed (0 if it isn't).
+//!
+//! GYM files are converted into VGM files for a YM2612 and PSG with the clocks of the Genesis.
+//!
+
+use std::io::{Error, ErrorKind};
+#[cfg(feature = "vgz")]
+use std::io::Read;
+
+#[cfg(feature = "vgz")]
+use flate2::read::ZlibDecoder;
+
+use crate::vgm::events::VgmEvent;
+use crate::vgm::gd3::Gd3;
+use crate::vgm::specification::{Command, VGM_MAGIC};
+use crate::vgm::writer;
+
+pub const GYMX_MAGIC: &[u8; 4] = b"GYMX";
+const GYMX_HEADER_SIZE: usize = 428;
+
+/// Clocks and PSG noise settings of the Genesis
+const PSG_CLOCK: u32 = 3579545;
+const YM2612_CLOCK: u32 = 7670453;
+const PSG_FEEDBACK: u16 = 0x0009;
+const PSG_LFSR_WIDTH: u8 = 16;
+
+/// Number of samples in a frame
+const FRAME_SAMPLES: u32 = 735;
+
+/// Convert the GYM file `data` into a VGM file
+pub fn to_vgm(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let (log, loop_frame, gd3) = if data.starts_with(GYMX_MAGIC) {
+        let header = data.get(..GYMX_HEADER_SIZE).ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "The GYMX header is truncated"))?;
+        let u32_at = |offset: usize| u32::from_le_bytes([header[offset], header[offset + 1], header[offset + 2], header[offset + 3]]);
+        let log = match u32_at(0x1A8) {
+            0 => data[GYMX_HEADER_SIZE..].to_vec(),
+            size => decompress(&data[GYMX_HEADER_SIZE..], size as usize)?,
+        };
+        (log, u32_at(0x1A4), Some(parse_tag(header)))
+    } else {
+        (data.to_vec(), 0, None)
+    };
+
+    let (events, loop_index) = parse_log(&log, loop_frame)?;
+    let mut header = VGM_MAGIC.as_bytes().to_vec();
+    header.resize(0x40, 0);
+    let mut set_u32 = |offset: usize, value: u32| header[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    set_u32(0x08, 0x150);
+    set_u32(0x0C, PSG_CLOCK);
+    set_u32(0x24, 60);
+    set_u32(0x2C, YM2612_CLOCK);
+    set_u32(0x34, 0x40 - 0x34);
+    header[0x28..0x2A].copy_from_slice(&PSG_FEEDBACK.to_le_bytes());
+    header[0x2A] = PSG_LFSR_WIDTH;
+    Ok(writer::write_vgm(&header, &events, loop_index, gd3.as_ref()))
+}
+
+/// Read the commands of the log. Also returns the index of the event that the frame `loop_frame`
+/// starts with.
+fn parse_log(log: &[u8], loop_frame: u32) -> Result<(Vec<VgmEvent>, Option<usize>), Error> {
+    let mut events = Vec::new();
+    let mut loop_index = None;
+    let mut frame = 1;
+    let mut pos = 0;
+    while pos < log.len() {
+        if frame == loop_frame && loop_index.is_none() {
+            loop_index = Some(events.len());
+        }
+        let args = |n: usize| log.get(pos + 1..pos + 1 + n).ok_or_else(|| Error::new(ErrorKind::UnexpectedEof,
+            format!("Command 0x{:02X} at offset 0x{:X} is truncated", log[pos], pos)));
+        let (event, length) = match log[pos] {
+            0x00 => {
+                frame += 1;
+                (VgmEvent::Wait(FRAME_SAMPLES), 1)
+            }
+            0x01 => (VgmEvent::Command { command: Command::YM2612_LO_WRITE, args: args(2)?.to_vec() }, 3),
+            0x02 => (VgmEvent::Command { command: Command::YM2612_HI_WRITE, args: args(2)?.to_vec() }, 3),
+            0x03 => (VgmEvent::PsgWrite(args(1)?[0]), 2),
+            other => return Err(Error::new(ErrorKind::InvalidData, format!("Invalid GYM command 0x{:02X} at offset 0x{:X}", other, pos))),
+        };
+        events.push(event);
+        pos += length;
+    }
+    Ok((events, loop_index))
+}
+
+/// Read the tag in a GYMX header into a GD3 tag. The publisher and emulator fields are dropped.
+fn parse_tag(header: &[u8]) -> Gd3 {
+    let field = |offset: usize, size: usize| {
+        let bytes = &header[offset..offset + size];
+        let end = bytes.iter().position(|&b| b == 0).unwrap_or(size);
+        // The strings are usually ASCII, and otherwise most likely Latin-1
+        bytes[..end].iter().map(|&b| b as char).collect::<String>().trim().to_string()
+    };
+    Gd3 {
+        track_name: field(0x04, 32),
+        game_name: field(0x24, 32),
+        system_name: String::from("Sega Mega Drive / Genesis"),
+        ripper: field(0x84, 32),
+        notes: field(0xA4, 256),
+        ..Gd3::new()
+    }
+}
+
+#[cfg(feature = "vgz")]
+fn decompress(data: &[u8], size: usize) -> Result<Vec<u8>, Error> {
+    // The size comes from the file, so it's only trusted as far as the compressed data could go
+    let mut log = Vec::with_capacity(size.min(data.len() * 16));
+    ZlibDecoder::new(data).take(size as u64 + 1).read_to_end(&mut log)?;
+    if log.len() != size {
+        return Err(Error::new(ErrorKind::InvalidData, format!("The GYM log doesn't have the {} bytes given in the header after decompression", size)));
+    }
+    Ok(log)
+}
+
+#[cfg(not(feature = "vgz"))]
+fn decompress(_data: &[u8], _size: usize) -> Result<Vec<u8>, Error> {
+    Err(Error::new(ErrorKind::Unsupported, "Compressed GYM input is not supported in this build"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vgm::specification::FileHeader;
+    use crate::vgm::events::read_stream;
+
+    #[test]
+    fn test_to_vgm() {
+        let log = [0x03, 0x9F, 0x01, 0x28, 0xF0, 0x00, 0x02, 0x30, 0x71, 0x00, 0x00];
+        let vgm = to_vgm(&log).unwrap();
+        let header = FileHeader::parse(&vgm).unwrap();
+        assert_eq!((header.psg_clock, header.ym2612_clock, header.total_samples), (PSG_CLOCK, YM2612_CLOCK, 3 * FRAME_SAMPLES));
+        assert_eq!(header.loop_offset, 0);
+        let (events, _) = read_stream(&vgm, &header).unwrap();
+        assert_eq!(events, vec![
+            VgmEvent::PsgWrite(0x9F),
+            VgmEvent::Command { command: Command::YM2612_LO_WRITE, args: vec![0x28, 0xF0] },
+            VgmEvent::Wait(FRAME_SAMPLES),
+            VgmEvent::Command { command: Command::YM2612_HI_WRITE, args: vec![0x30, 0x71] },
+            VgmEvent::Wait(FRAME_SAMPLES),
+            VgmEvent::Wait(FRAME_SAMPLES),
+            VgmEvent::End,
+        ]);
+
+        let mut gymx = GYMX_MAGIC.to_vec();
+        gymx.extend(b"Title");
+        gymx.resize(GYMX_HEADER_SIZE, 0);
+        gymx[0x24..0x28].copy_from_slice(b"Game");
+        gymx[0x1A4] = 2;
+        gymx.extend(&log);
+        let vgm = to_vgm(&gymx).unwrap();
+        let header = FileHeader::parse(&vgm).unwrap();
+        let gd3 = Gd3::parse(&vgm[header.gd3_offset as usize + 0x14..]).unwrap();
+        assert_eq!((gd3.track_name.as_str(), gd3.game_name.as_str()), ("Title", "Game"));
+        // The loop starts with the write after the first wait
+        assert_eq!(vgm[header.loop_offset as usize + 0x1C], Command::YM2612_HI_WRITE);
+        assert_eq!(header.loop_samples, 2 * FRAME_SAMPLES);
+
+        assert_eq!(to_vgm(&[0x01, 0x28]).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(to_vgm(&[0x04]).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[cfg(feature = "vgz")]
+    #[test]
+    fn test_compressed() {
+        use std::io::Write;
+        use flate2::Compression;
+        use flate2::write::ZlibEncoder;
+
+        let log = [0x03, 0x9F, 0x00, 0x00];
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(&log).unwrap();
+        let mut gymx = GYMX_MAGIC.to_vec();
+        gymx.resize(GYMX_HEADER_SIZE, 0);
+        gymx.extend(encoder.finish().unwrap());
+        let gymx_with_size = |size: u32| {
+            let mut gymx = gymx.clone();
+            gymx[0x1A8..0x1AC].copy_from_slice(&size.to_le_bytes());
+            gymx
+        };
+        let header = FileHeader::parse(&to_vgm(&gymx_with_size(4)).unwrap()).unwrap();
+        assert_eq!(header.total_samples, 2 * FRAME_SAMPLES);
+        // Sizes that don't match the compressed log, including one far larger than the file
+        for size in [3, 5, u32::MAX] {
+            assert_eq!(to_vgm(&gymx_with_size(size)).unwrap_err().kind(), ErrorKind::InvalidData);
+        }
+    }
+}
@@ -1,5 +1,14 @@
 pub use self::specification::Command;
 pub use self::reader::read_vgm_file;
+pub use self::gd3::Gd3;
 
 pub mod specification;
-pub mod reader;
\ No newline at end of file
+pub mod reader;
+pub mod gd3;
+pub mod gym;
+pub mod s98;
+pub mod edit;
+pub mod events;
+pub mod writer;
+#[cfg(test)]
+pub mod testing;
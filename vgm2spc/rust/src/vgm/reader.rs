@@ -1,52 +1,151 @@
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::prelude::*;
+use std::io::{Error, ErrorKind};
 use std::path::Path;
-use flate2::read::GzDecoder;
-use crate::vgm::specification;
+#[cfg(feature = "vgz")]
+use flate2::read::MultiGzDecoder;
+use crate::vgm::{gym, s98, specification};
+
+/// Magic number of zip archives (a local file header)
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+
+/// The sizes of a file read by `read_vgm_file`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InputSizes {
+    pub file_size: u64,         // Size of the file on disk
+    pub data_size: usize,       // Size of the VGM data, after decompression or conversion
+    pub decompressed: bool,     // True if the file was a compressed VGM file
+}
 
 /// Reads the VGM file given by `input_path` into the vector `out_data`.
 ///
-/// Both compressed (VGZ) and uncompressed VGM files are supported.
+/// Both compressed (VGZ) and uncompressed VGM files are supported. Compressed files need the
+/// `vgz` feature; without it, reading one fails with `ErrorKind::Unsupported`.
 /// The flag `assume_vgz` can be used to force the file to be treated as compressed. Otherwise the
-/// function will try to detect the compression by itself.
-pub fn read_vgm_file(input_path: &Path, out_data: &mut Vec<u8>, assume_vgz: bool) -> Result<usize, std::io::Error> {
-    let prev_size = out_data.len();
-
-    let is_vgz = if assume_vgz {
-        Ok(true)
+/// function will try to detect the compression by itself. Files named .gym and .s98 are read as GYM and S98
+/// logs, and converted into VGM (see `gym` and `s98`). Nothing is printed; the caller can report
+/// the returned sizes.
+pub fn read_vgm_file(input_path: &Path, out_data: &mut Vec<u8>, assume_vgz: bool) -> Result<InputSizes, std::io::Error> {
+    let has_extension = |extension: &str| input_path.extension().is_some_and(|e| e.eq_ignore_ascii_case(extension));
+    let file_size = fs::metadata(input_path)?.len();
+    let data = if has_extension("gym") {
+        gym::to_vgm(&fs::read(input_path)?)?
+    } else if has_extension("s98") {
+        s98::to_vgm(&fs::read(input_path)?)?
     } else {
-        detect_compression(input_path)
+        let is_vgz = assume_vgz || has_extension("vgz");
+        read_vgm(File::open(input_path)?, is_vgz)?
     };
-   
-    if !(is_vgz?) {
-        let mut file = File::open(input_path)?;
-        file.read_to_end(out_data)?;
+    let data_size = data.len();
+    // Uncompressed data is read as is, so a size change means that the file was decompressed
+    let decompressed = !has_extension("gym") && !has_extension("s98") && data_size as u64 != file_size;
+    if out_data.is_empty() {
+        *out_data = data;
     } else {
-        print!("Deflating..");
-        let mut gz_data = Vec::new();
-        let mut file = File::open(input_path)?;
-        file.read_to_end(&mut gz_data)?;
-        let mut gz_slice = gz_data.as_slice();
-        let mut gz_decoder = GzDecoder::new(&mut gz_slice);
-        gz_decoder.read_to_end(out_data)?;
-        println!(" done ({} -> {} bytes).", gz_data.len(), out_data.len() - prev_size);
+        out_data.extend(data);
     }
-    
-    Ok(out_data.len() - prev_size)
+    Ok(InputSizes { file_size, data_size, decompressed })
 }
 
-fn detect_compression(input_path: &Path) -> Result<bool, std::io::Error> {
-    let is_vgz = if input_path.to_str().unwrap().to_lowercase().ends_with(".vgz") {
-        true
-    } else {  
-        // Try to auto-detect .vgz files that have been named .vgm based on the first four bytes
-        let mut file = File::open(input_path)?;
-        let mut magic = vec![0u8; 4];
-        file.read_exact(&mut magic)?;
-        match String::from_utf8(magic) {
-            Ok(s) => s != specification::VGM_MAGIC,
-            Err(_) => true,
+/// Reads a VGM file from `reader`, which can be compressed or uncompressed like the files read by
+/// `read_vgm_file`. Compressed data is decompressed while reading.
+pub fn read_vgm(mut reader: impl Read, assume_vgz: bool) -> Result<Vec<u8>, std::io::Error> {
+    // A single read can return less than the magic number, so keep reading until there's all of it
+    let mut magic = Vec::with_capacity(4);
+    reader.by_ref().take(4).read_to_end(&mut magic)?;
+    if magic.starts_with(ZIP_MAGIC) {
+        return Err(Error::new(ErrorKind::InvalidData,
+            "The input is a zip archive. Extract it, or pass the path of the VGM file in the archive"));
+    }
+    // Try to auto-detect .vgz files that have been named .vgm based on the first four bytes
+    let is_vgz = assume_vgz || !magic.starts_with(specification::VGM_MAGIC.as_bytes());
+    let mut reader = magic.as_slice().chain(reader);
+
+    let mut data = Vec::new();
+    if is_vgz {
+        decompress(reader, &mut data)?;
+    } else {
+        reader.read_to_end(&mut data)?;
+    }
+    Ok(data)
+}
+
+#[cfg(feature = "vgz")]
+fn decompress(reader: impl Read, out_data: &mut Vec<u8>) -> Result<usize, std::io::Error> {
+    // Some loggers write the file as several concatenated gzip members
+    MultiGzDecoder::new(reader).read_to_end(out_data)
+}
+
+#[cfg(not(feature = "vgz"))]
+fn decompress(_reader: impl Read, _out_data: &mut Vec<u8>) -> Result<usize, std::io::Error> {
+    Err(Error::new(ErrorKind::Unsupported, "Compressed (VGZ) input is not supported in this build"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vgm::writer;
+
+    #[test]
+    fn test_read_vgm() {
+        let data = b"Vgm \x00\x01\x02\x03".to_vec();
+        assert_eq!(read_vgm(&data[..], false).unwrap(), data);
+        assert_eq!(read_vgm(&b"PK\x03\x04\x14\x00"[..], false).unwrap_err().kind(), ErrorKind::InvalidData);
+        // The magic number is found even if it arrives a byte at a time
+        struct Trickle<'a>(&'a [u8]);
+        impl Read for Trickle<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let n = buf.len().min(self.0.len()).min(1);
+                buf[..n].copy_from_slice(&self.0[..n]);
+                self.0 = &self.0[n..];
+                Ok(n)
+            }
         }
-    };
-    Ok(is_vgz)
-}
\ No newline at end of file
+        assert_eq!(read_vgm(Trickle(&data), false).unwrap(), data);
+        assert_eq!(read_vgm(Trickle(b"PK\x03\x04"), false).unwrap_err().kind(), ErrorKind::InvalidData);
+        if !cfg!(feature = "vgz") {
+            assert_eq!(read_vgm(&data[..], true).unwrap_err().kind(), ErrorKind::Unsupported);
+            return;
+        }
+
+        let mut members = writer::compress(&data[..4]).unwrap();
+        members.extend(writer::compress(&data[4..]).unwrap());
+        assert_eq!(read_vgm(&members[..], false).unwrap(), data);
+    }
+
+    #[test]
+    fn test_read_vgm_file() {
+        let data = b"Vgm \x00\x01\x02\x03".to_vec();
+        let path = std::env::temp_dir().join(format!("vgm2spc-reader-{}.vgm", std::process::id()));
+        fs::write(&path, &data).unwrap();
+        let mut out_data = Vec::new();
+        let sizes = read_vgm_file(&path, &mut out_data, false);
+        let compressed = cfg!(feature = "vgz").then(|| {
+            fs::write(&path, writer::compress(&data).unwrap()).unwrap();
+            read_vgm_file(&path, &mut Vec::new(), false).unwrap()
+        });
+        fs::remove_file(&path).unwrap();
+        assert_eq!(sizes.unwrap(), InputSizes { file_size: 8, data_size: 8, decompressed: false });
+        assert_eq!(out_data, data);
+        if let Some(sizes) = compressed {
+            assert!(sizes.decompressed);
+            assert_eq!(sizes.data_size, 8);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_path() {
+        use std::os::unix::ffi::OsStrExt;
+        let mut name = b"vgm2spc-reader-\xFF-".to_vec();
+        name.extend(format!("{}.VGM", std::process::id()).bytes());
+        let path = std::env::temp_dir().join(std::ffi::OsStr::from_bytes(&name));
+        let data = b"Vgm \x00\x01\x02\x03".to_vec();
+        fs::write(&path, &data).unwrap();
+        let mut out_data = Vec::new();
+        let sizes = read_vgm_file(&path, &mut out_data, false);
+        fs::remove_file(&path).unwrap();
+        assert!(!sizes.unwrap().decompressed);
+        assert_eq!(out_data, data);
+    }
+}
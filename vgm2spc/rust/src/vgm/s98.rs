@@ -0,0 +1,365 @@
+//!
+//! S98 files: register logs of the sound chips of Japanese home computers.
+//!
+//! The header holds the length of a tick ("sync") as a fraction of a second, the offsets of the
+//! tag, the log and the loop point, and (from version 3) a list of devices with their clocks.
+//! Versions 1 and 2, and version 3 files without devices, have a single YM2608 (OPNA). The log is
+//! a sequence of commands:
+//!
+//!     0x00-0x7F aa dd     Write dd to register aa of device n/2, port n&1
+//!     0xFF                Wait for one tick
+//!     0xFE n              Wait for n+2 ticks, where n is stored 7 bits at a time, lowest first
+//!     0xFD                End of the log (playback jumps to the loop point, if there is one)
+//!
+//! S98 files are converted into VGM files. The PSGs (AY-3-8910, YM2149 and SN76489) become the
+//! AY8910 and SN76489 of the VGM file. The SSG part of the YM2203 and YM2608 becomes an AY8910 too,
+//! unless the file has a PSG of its own. The FM chips are written to the VGM file as they are,
+//! and are left to the converter to deal with. Each chip can only be used once in a VGM file, so
+//! the writes to any later devices of the same kind are dropped.
+//!
+
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind};
+
+use crate::vgm::events::VgmEvent;
+use crate::vgm::gd3::Gd3;
+use crate::vgm::specification::{Command, VGM_MAGIC};
+use crate::vgm::writer;
+
+pub const S98_MAGIC: &[u8; 3] = b"S98";
+const HEADER_SIZE: usize = 0x20;
+const DEVICE_INFO_SIZE: usize = 16;
+
+/// Marker at the start of a version 3 tag, which is followed by lines of key=value pairs
+const TAG_MARKER: &[u8] = b"[S98]";
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// The device of version 1 and 2 files
+const DEFAULT_DEVICE: Device = Device { kind: OPNA, clock: 7987200 };
+
+const PSG: u32 = 1;             // YM2149
+const OPN: u32 = 2;             // YM2203
+const OPN2: u32 = 3;            // YM2612
+const OPNA: u32 = 4;            // YM2608
+const OPM: u32 = 5;             // YM2151
+const OPLL: u32 = 6;            // YM2413
+const OPL: u32 = 7;             // YM3526
+const OPL2: u32 = 8;            // YM3812
+const OPL3: u32 = 9;            // YMF262
+const AY: u32 = 15;             // AY-3-8910
+const DCSG: u32 = 16;           // SN76489
+
+/// Offsets of the AY8910 clock and chip type in the VGM header
+const AY8910_CLOCK_OFFSET: usize = 0x74;
+const AY8910_TYPE_OFFSET: usize = 0x78;
+const AY8910_TYPE_YM2149: u8 = 0x10;
+
+/// Size of the VGM header, which covers the AY8910 fields
+const VGM_HEADER_SIZE: usize = 0x80;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Device {
+    kind: u32,
+    clock: u32,
+}
+
+/// Where the writes to a device go in the VGM file
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Route {
+    fm: Option<[u8; 2]>,        // The VGM commands for ports 0 and 1
+    psg: bool,                  // An AY-3-8910 or YM2149 (or the SSG registers, for an OPN(A))
+    dcsg: bool,
+}
+
+/// Decide where the writes to each device go, and fill in the clocks in the VGM `header`
+fn route_devices(devices: &[Device], header: &mut [u8]) -> Vec<Route> {
+    let set_u32 = |header: &mut [u8], offset: usize, value: u32| {
+        let free = header[offset..offset + 4] == [0; 4];
+        if free {
+            header[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        }
+        free
+    };
+    // PSGs go first, so that they take precedence over the SSG of an OPN(A)
+    let mut routes = vec![Route::default(); devices.len()];
+    for (device, route) in devices.iter().zip(routes.iter_mut()).filter(|(device, _)| matches!(device.kind, PSG | AY)) {
+        route.psg = set_u32(header, AY8910_CLOCK_OFFSET, device.clock);
+        if route.psg && device.kind == PSG {
+            header[AY8910_TYPE_OFFSET] = AY8910_TYPE_YM2149;
+        }
+    }
+    for (device, route) in devices.iter().zip(routes.iter_mut()) {
+        let (clock_offset, commands) = match device.kind {
+            DCSG => {
+                route.dcsg = set_u32(header, 0x0C, device.clock);
+                continue;
+            }
+            OPN => (0x44, [Command::YM2203_WRITE; 2]),
+            OPN2 => (0x2C, [Command::YM2612_LO_WRITE, Command::YM2612_HI_WRITE]),
+            OPNA => (0x48, [Command::YM2608_LO_WRITE, Command::YM2608_HI_WRITE]),
+            OPM => (0x30, [Command::YM2151_WRITE; 2]),
+            OPLL => (0x10, [Command::YM2413_WRITE; 2]),
+            OPL => (0x54, [Command::YM3526_WRITE; 2]),
+            OPL2 => (0x50, [Command::YM3812_WRITE; 2]),
+            OPL3 => (0x5C, [Command::YMF262_LO_WRITE, Command::YMF262_HI_WRITE]),
+            _ => continue,
+        };
+        if !set_u32(header, clock_offset, device.clock) {
+            continue;
+        }
+        route.fm = Some(commands);
+        // The SSG runs like an AY8910 clocked at half (OPN) or a quarter (OPNA) of the chip's clock
+        let ssg_clock = match device.kind {
+            OPN => Some(device.clock / 2),
+            OPNA => Some(device.clock / 4),
+            _ => None,
+        };
+        if let Some(clock) = ssg_clock {
+            route.psg = set_u32(header, AY8910_CLOCK_OFFSET, clock);
+            if route.psg {
+                header[AY8910_TYPE_OFFSET] = AY8910_TYPE_YM2149;
+            }
+        }
+    }
+    routes
+}
+
+/// Convert the S98 file `data` into a VGM file
+pub fn to_vgm(data: &[u8]) -> Result<Vec<u8>, Error> {
+    if !data.starts_with(S98_MAGIC) {
+        return Err(Error::new(ErrorKind::InvalidData, "The file is not an S98 file"));
+    }
+    if data.len() < HEADER_SIZE {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "The file is too small to contain an S98 header"));
+    }
+    let u32_at = |offset: usize| u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]);
+    let version = data[3];
+    let tick_numerator = match u32_at(0x04) { 0 => 10, n => n } as u64;
+    let tick_denominator = match u32_at(0x08) { 0 => 1000, n => n } as u64;
+    let (tag_offset, log_offset, loop_offset) = (u32_at(0x10) as usize, u32_at(0x14) as usize, u32_at(0x18) as usize);
+
+    let mut devices = Vec::new();
+    if version == b'3' {
+        for i in 0..u32_at(0x1C) as usize {
+            let offset = HEADER_SIZE + i * DEVICE_INFO_SIZE;
+            if offset + DEVICE_INFO_SIZE > data.len() {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "The S98 device list is truncated"));
+            }
+            devices.push(Device { kind: u32_at(offset), clock: u32_at(offset + 4) });
+        }
+    }
+    if devices.is_empty() {
+        devices.push(DEFAULT_DEVICE);
+    }
+
+    let mut header = VGM_MAGIC.as_bytes().to_vec();
+    header.resize(VGM_HEADER_SIZE, 0);
+    header[0x08..0x0C].copy_from_slice(&0x151u32.to_le_bytes());
+    header[0x34..0x38].copy_from_slice(&(VGM_HEADER_SIZE as u32 - 0x34).to_le_bytes());
+    let routes = route_devices(&devices, &mut header);
+    if routes.iter().any(|route| route.dcsg) {
+        header[0x28..0x2A].copy_from_slice(&0x0009u16.to_le_bytes());
+        header[0x2A] = 16;
+    }
+
+    let log = data.get(log_offset..).ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "The S98 log offset is outside of the file"))?;
+    let (events, loop_index) = parse_log(log, log_offset, loop_offset, &routes,
+                                         |ticks| u64::try_from(ticks as u128 * 44100 * tick_numerator as u128 / tick_denominator as u128).ok())?;
+    let gd3 = match tag_offset {
+        0 => None,
+        offset => data.get(offset..).map(|tag| parse_tag(tag, version)),
+    };
+    Ok(writer::write_vgm(&header, &events, loop_index, gd3.as_ref()))
+}
+
+/// Read the commands of the log, which starts at `log_offset` in the file. Also returns the index
+/// of the event at the file offset `loop_offset`. `samples` converts a number of ticks into samples,
+/// or returns `None` if there are too many.
+fn parse_log(log: &[u8], log_offset: usize, loop_offset: usize, routes: &[Route], samples: impl Fn(u64) -> Option<u64>)
+             -> Result<(Vec<VgmEvent>, Option<usize>), Error> {
+    let mut events = Vec::new();
+    let mut loop_index = None;
+    let mut ticks = 0u64;
+    let mut pos = 0;
+    let truncated = |pos: usize| Error::new(ErrorKind::UnexpectedEof, format!("The S98 command at offset 0x{:X} is truncated", log_offset + pos));
+    loop {
+        if loop_offset != 0 && log_offset + pos == loop_offset {
+            loop_index = Some(events.len());
+        }
+        let command = *log.get(pos).ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "No end of the S98 log found"))?;
+        let waited = match command {
+            0xFD => break,
+            0xFF => {
+                pos += 1;
+                1
+            }
+            0xFE => {
+                let mut n = 0u64;
+                let mut shift = 0;
+                loop {
+                    pos += 1;
+                    let b = *log.get(pos).ok_or_else(|| truncated(pos))?;
+                    n |= ((b & 0x7F) as u64) << shift;
+                    shift += 7;
+                    if b & 0x80 == 0 || shift > 56 {
+                        break;
+                    }
+                }
+                pos += 1;
+                n + 2
+            }
+            _ => {
+                let route = routes.get(command as usize / 2)
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("Invalid S98 command 0x{:02X} at offset 0x{:X}", command, log_offset + pos)))?;
+                let args = log.get(pos + 1..pos + 3).ok_or_else(|| truncated(pos))?;
+                let (port, register, value) = (command as usize & 1, args[0], args[1]);
+                if route.dcsg {
+                    events.push(VgmEvent::PsgWrite(value));
+                } else if route.psg && port == 0 && register < 0x10 {
+                    events.push(VgmEvent::Command { command: Command::AY8910_WRITE, args: vec![register, value] });
+                } else if let Some(commands) = route.fm {
+                    events.push(VgmEvent::Command { command: commands[port], args: vec![register, value] });
+                }
+                pos += 3;
+                0
+            }
+        };
+        if waited > 0 {
+            let too_long = || Error::new(ErrorKind::InvalidData, format!("The S98 log is too long at offset 0x{:X}", log_offset + pos));
+            let end = ticks.checked_add(waited).ok_or_else(too_long)?;
+            let mut wait = samples(end).ok_or_else(too_long)? - samples(ticks).ok_or_else(too_long)?;
+            ticks = end;
+            // Waits longer than a wait event can hold are split
+            while wait > 0 {
+                let part = wait.min(u32::MAX as u64);
+                events.push(VgmEvent::Wait(part as u32));
+                wait -= part;
+            }
+        }
+    }
+    Ok((events, loop_index))
+}
+
+/// Read the tag at the start of `data` into a GD3 tag. Version 3 tags are lines of key=value pairs
+/// in UTF-8 (if they start with a BOM) or Shift-JIS, other tags are just a title. Shift-JIS text
+/// can't be decoded, so anything but ASCII is lost.
+fn parse_tag(data: &[u8], version: u8) -> Gd3 {
+    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    let data = &data[..end];
+    let decode = |bytes: &[u8]| match bytes.strip_prefix(UTF8_BOM) {
+        Some(text) => String::from_utf8_lossy(text).into_owned(),
+        None => bytes.iter().map(|&b| if b.is_ascii() { b as char } else { '?' }).collect(),
+    };
+    let mut gd3 = Gd3::new();
+    let text = match data.strip_prefix(TAG_MARKER) {
+        Some(text) if version == b'3' => decode(text),
+        _ => {
+            gd3.track_name = decode(data).trim().to_string();
+            return gd3;
+        }
+    };
+    for (key, value) in text.lines().filter_map(|line| line.split_once('=')) {
+        let field = match key.trim().to_lowercase().as_str() {
+            "title" => &mut gd3.track_name,
+            "game" => &mut gd3.game_name,
+            "artist" => &mut gd3.author,
+            "year" => &mut gd3.release_date,
+            "system" => &mut gd3.system_name,
+            "s98by" => &mut gd3.ripper,
+            "comment" => &mut gd3.notes,
+            _ => continue,
+        };
+        *field = value.trim().to_string();
+    }
+    gd3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vgm::events::read_stream;
+    use crate::vgm::specification::FileHeader;
+
+    fn s98_file(devices: &[Device], log: &[u8], loop_offset: u32, tag: &[u8]) -> Vec<u8> {
+        let mut data = b"S983".to_vec();
+        data.extend(&1u32.to_le_bytes());
+        data.extend(&60u32.to_le_bytes());
+        data.resize(HEADER_SIZE, 0);
+        data[0x1C..0x20].copy_from_slice(&(devices.len() as u32).to_le_bytes());
+        for device in devices {
+            data.extend(&device.kind.to_le_bytes());
+            data.extend(&device.clock.to_le_bytes());
+            data.resize(data.len() + 8, 0);
+        }
+        let log_offset = data.len() as u32;
+        data[0x14..0x18].copy_from_slice(&log_offset.to_le_bytes());
+        if loop_offset != 0 {
+            data[0x18..0x1C].copy_from_slice(&(log_offset + loop_offset).to_le_bytes());
+        }
+        data.extend(log);
+        if !tag.is_empty() {
+            let tag_offset = data.len() as u32;
+            data[0x10..0x14].copy_from_slice(&tag_offset.to_le_bytes());
+            data.extend(tag);
+        }
+        data
+    }
+
+    #[test]
+    fn test_to_vgm() {
+        let devices = [Device { kind: AY, clock: 1789773 }, Device { kind: OPN, clock: 3993600 }, Device { kind: DCSG, clock: 3579545 }];
+        let log = [0x00, 0x07, 0x38, 0x02, 0x00, 0x10, 0x02, 0x28, 0xF0, 0xFF, 0x04, 0x00, 0x9F, 0xFE, 0x01, 0xFD];
+        let data = s98_file(&devices, &log, 9, b"[S98]\xEF\xBB\xBFtitle=Title\ngame=Game\n\0");
+        let vgm = to_vgm(&data).unwrap();
+        let header = FileHeader::parse(&vgm).unwrap();
+        assert_eq!(header.used_chips(), vec!["SN76489", "YM2203", "AY8910"]);
+        assert_eq!(u32::from_le_bytes([vgm[0x74], vgm[0x75], vgm[0x76], vgm[0x77]]), 1789773);
+        assert_eq!(vgm[AY8910_TYPE_OFFSET], 0);
+
+        // A tick is 1/60 s. The AY-3-8910 takes the place of the SSG, so SSG writes go to the YM2203.
+        let (events, loop_index) = read_stream(&vgm, &header).unwrap();
+        assert_eq!(events, vec![
+            VgmEvent::Command { command: Command::AY8910_WRITE, args: vec![0x07, 0x38] },
+            VgmEvent::Command { command: Command::YM2203_WRITE, args: vec![0x00, 0x10] },
+            VgmEvent::Command { command: Command::YM2203_WRITE, args: vec![0x28, 0xF0] },
+            VgmEvent::Wait(735),
+            VgmEvent::PsgWrite(0x9F),
+            VgmEvent::Wait(3 * 735),
+            VgmEvent::End,
+        ]);
+        assert_eq!(loop_index, Some(3));
+        let gd3 = Gd3::parse(&vgm[header.gd3_offset as usize + 0x14..]).unwrap();
+        assert_eq!((gd3.track_name.as_str(), gd3.game_name.as_str()), ("Title", "Game"));
+
+        // Without devices, the file has an OPNA, whose SSG becomes an AY8910
+        let vgm = to_vgm(&s98_file(&[], &[0x00, 0x08, 0x0F, 0x01, 0x30, 0x71, 0xFD], 0, b"")).unwrap();
+        let header = FileHeader::parse(&vgm).unwrap();
+        assert_eq!(header.used_chips(), vec!["YM2608", "AY8910"]);
+        assert_eq!(vgm[AY8910_TYPE_OFFSET], AY8910_TYPE_YM2149);
+        assert_eq!(read_stream(&vgm, &header).unwrap().0[..2], [
+            VgmEvent::Command { command: Command::AY8910_WRITE, args: vec![0x08, 0x0F] },
+            VgmEvent::Command { command: Command::YM2608_HI_WRITE, args: vec![0x30, 0x71] },
+        ]);
+
+        assert_eq!(to_vgm(&s98_file(&devices, &[0x06, 0x00, 0x00, 0xFD], 0, b"")).unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(to_vgm(&s98_file(&devices, &[0xFF], 0, b"")).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        // A wait of 2^63 ticks has more samples than fit in 64 bits
+        let wait = [0xFE, 0xFE, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x7F, 0xFD];
+        assert_eq!(to_vgm(&s98_file(&devices, &wait, 0, b"")).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_long_wait() {
+        // A wait of 2^33 + 1 ticks, at one sample per tick
+        let log = [0xFE, 0xFF, 0xFF, 0xFF, 0xFF, 0x1F, 0xFF, 0xFD];
+        let (events, _) = parse_log(&log, 0, 0, &[], Some).unwrap();
+        assert_eq!(events, vec![VgmEvent::Wait(u32::MAX), VgmEvent::Wait(u32::MAX), VgmEvent::Wait(3), VgmEvent::Wait(1)]);
+    }
+
+    #[test]
+    fn test_parse_tag() {
+        let gd3 = parse_tag(b"[S98]title=T\xE3\x81\x82\nartist=A\n", b'3');
+        assert_eq!((gd3.track_name.as_str(), gd3.author.as_str()), ("T???", "A"));
+        assert_eq!(parse_tag(b"Old title\0", b'1').track_name, "Old title");
+    }
+}
@@ -1,41 +1,80 @@
-/// Partial enumeration of VGM commands (see https://vgmrips.net/wiki/VGM_Specification)
-pub mod Command {
-    pub const UNDEFINED: u8 = 0;          // not part of the VGM spec
-    pub const NOP: u8 = 0x4E;             // not part of the VGM spec
-	pub const GG_STEREO: u8 = 0x4F;
-	pub const PSG_WRITE: u8 = 0x50;
-	pub const YM2413_WRITE: u8 = 0x51;
-	pub const YM2612_LO_WRITE: u8 = 0x52;
-	pub const YM2612_HI_WRITE: u8 = 0x53;
-	pub const YM2151_WRITE: u8 = 0x54;
-	pub const WAIT_LONG: u8 = 0x61;
-	pub const WAIT_NTSC_FRAME: u8 = 0x62;
-	pub const WAIT_PAL_FRAME: u8 = 0x63;
-	pub const END_OF_SOUND_DATA: u8 = 0x66;
-	pub const DATA_BLOCK: u8 = 0x67;
-	pub const PCM_WRITE: u8 = 0x68;
-	pub const WAIT_1: u8 = 0x70;
-	pub const WAIT_16: u8 = 0x7F;
-	pub const YM2612_WRITE_LO_WAIT_0: u8 = 0x80; 
-	pub const YM2612_WRITE_LO_WAIT_15: u8 = 0x8F;
-    pub const WAIT_LONG_THRU_LUT: u8 = 0x90; // not part of the VGM spec
-	pub const SEEK_PCM: u8 = 0xE0;
+use std::io::{Error, ErrorKind};
+
+use serde::Serialize;
+
+use crate::vgm::gd3::GD3_MAGIC;
+
+pub use crate::core::command::{Command, is_reserved, is_undefined};
+
+/// A sound chip that VGM commands can write to, and what a listener would miss if its writes
+/// were dropped
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Chip {
+    pub name: &'static str,
+    pub channels: &'static str,
+}
+
+const fn chip(name: &'static str, channels: &'static str) -> Option<Chip> {
+    Some(Chip { name, channels })
 }
 
-/// Returns the number of argument bytes expected by VGM command `cmd`.
-pub fn num_argument_bytes(cmd: u8) -> u32 {
+/// Returns the chip written to by command `cmd`, or `None` for commands that don't write to a chip
+/// (waits, data blocks, DAC stream control, etc).
+pub fn command_chip(cmd: u8) -> Option<Chip> {
     match cmd {
-        Command::GG_STEREO | Command::PSG_WRITE => 1,
-        Command::YM2413_WRITE ... Command::YM2612_HI_WRITE => 2,
-        Command::WAIT_LONG => 2,
-        Command::SEEK_PCM => 4,
-        _ => 0,
+        0x30 | 0x3F => chip("SN76489 #2", "PSG channels"),
+        0x4F | 0x50 => chip("SN76489", "PSG channels"),
+        0x51 => chip("YM2413", "FM channels"),
+        0x52 | 0x53 | 0x80 ..= 0x8F | 0xE0 => chip("YM2612", "FM channels"),
+        0x54 => chip("YM2151", "FM channels"),
+        0x55 => chip("YM2203", "FM channels"),
+        0x56 | 0x57 => chip("YM2608", "FM channels"),
+        0x58 | 0x59 => chip("YM2610", "FM channels"),
+        0x5A => chip("YM3812", "FM channels"),
+        0x5B => chip("YM3526", "FM channels"),
+        0x5C => chip("Y8950", "FM channels"),
+        0x5D => chip("YMZ280B", "PCM channels"),
+        0x5E | 0x5F => chip("YMF262", "FM channels"),
+        0xA0 | 0x31 => chip("AY8910", "PSG channels"),
+        0xB0 | 0xC1 => chip("RF5C68", "PCM channels"),
+        0xB1 | 0xC2 => chip("RF5C164", "PCM channels"),
+        0xB2 => chip("PWM", "PCM channels"),
+        0xB3 => chip("GameBoy DMG", "PSG channels"),
+        0xB4 => chip("NES APU", "PSG channels"),
+        0xB5 | 0xC3 => chip("MultiPCM", "PCM channels"),
+        0xB6 => chip("uPD7759", "PCM channels"),
+        0xB7 => chip("OKIM6258", "PCM channels"),
+        0xB8 => chip("OKIM6295", "PCM channels"),
+        0xB9 => chip("HuC6280", "wavetable channels"),
+        0xBA => chip("K053260", "PCM channels"),
+        0xBB => chip("Pokey", "PSG channels"),
+        0xBC | 0xC6 => chip("WonderSwan", "wavetable channels"),
+        0xBD => chip("SAA1099", "PSG channels"),
+        0xBE | 0xD6 => chip("ES5506", "PCM channels"),
+        0xBF => chip("GA20", "PCM channels"),
+        0xC0 => chip("SegaPCM", "PCM channels"),
+        0xC4 => chip("QSound", "PCM channels"),
+        0xC5 => chip("SCSP", "PCM channels"),
+        0xC7 => chip("VSU", "wavetable channels"),
+        0xC8 => chip("X1-010", "PCM channels"),
+        0xD0 => chip("YMF278B", "FM channels"),
+        0xD1 => chip("YMF271", "FM channels"),
+        0xD2 => chip("K051649", "wavetable channels"),
+        0xD3 => chip("K054539", "PCM channels"),
+        0xD4 => chip("C140", "PCM channels"),
+        0xD5 => chip("ES5503", "wavetable channels"),
+        0xE1 => chip("C352", "PCM channels"),
+        _ => None,
     }
 }
 
-pub const VGM_MAGIC: &'static str = "Vgm ";
+pub const VGM_MAGIC: &str = "Vgm ";
 
-#[repr(C, packed)]
+/// The VGM file header, up to and including the 1.50 fields.
+///
+/// Any version from 1.00 and up is accepted. Fields that don't exist in the file's version are
+/// zero. Newer versions have additional fields after `vgm_data_offset`, which aren't parsed.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
 pub struct FileHeader {
     pub magic: u32,
 	pub eof_offset: u32,
@@ -45,6 +84,7 @@ pub struct FileHeader {
 	pub gd3_offset: u32,
 	pub total_samples: u32,
 	pub loop_offset: u32,
+	pub loop_samples: u32,
 	// 1.01
 	pub rate: u32,
 	// 1.10
@@ -54,5 +94,253 @@ pub struct FileHeader {
 	pub ym2612_clock: u32,
 	pub ym2151_clock: u32,
     // 1.50
-	pub vgm_data_offset: u32
-}
\ No newline at end of file
+	pub vgm_data_offset: u32,
+	// 1.51 and later: the chips with a nonzero clock, other than the ones above
+	pub other_chip_clocks: Vec<(&'static str, u32)>,
+	// 1.60
+	pub volume_modifier: u8,
+}
+
+/// Offsets of the chip clocks added to the header in VGM 1.51 and later
+const OTHER_CHIP_CLOCK_OFFSETS: &[(usize, &str)] = &[
+    (0x38, "SegaPCM"), (0x40, "RF5C68"), (0x44, "YM2203"), (0x48, "YM2608"), (0x4C, "YM2610"),
+    (0x50, "YM3812"), (0x54, "YM3526"), (0x58, "Y8950"), (0x5C, "YMF262"), (0x60, "YMF278B"),
+    (0x64, "YMF271"), (0x68, "YMZ280B"), (0x6C, "RF5C164"), (0x70, "PWM"), (0x74, "AY8910"),
+    (0x80, "GameBoy DMG"), (0x84, "NES APU"), (0x88, "MultiPCM"), (0x8C, "uPD7759"), (0x90, "OKIM6258"),
+    (0x98, "OKIM6295"), (0x9C, "K051649"), (0xA0, "K054539"), (0xA4, "HuC6280"), (0xA8, "C140"),
+    (0xAC, "K053260"), (0xB0, "Pokey"), (0xB4, "QSound"), (0xB8, "SCSP"), (0xC0, "WonderSwan"),
+    (0xC4, "VSU"), (0xC8, "SAA1099"), (0xCC, "ES5503"), (0xD0, "ES5506"), (0xD8, "X1-010"),
+    (0xDC, "C352"), (0xE0, "GA20"),
+];
+
+/// Size of the header for versions prior to 1.50, which is also where the VGM data starts
+pub const HEADER_SIZE_PRE_150: usize = 0x40;
+
+impl FileHeader {
+    /// Parse the header at the start of `data`
+    pub fn parse(data: &[u8]) -> Result<FileHeader, Error> {
+        if data.len() < HEADER_SIZE_PRE_150 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "The file is too small to contain a VGM header"));
+        }
+        if &data[0..4] != VGM_MAGIC.as_bytes() {
+            return Err(Error::new(ErrorKind::InvalidData, "The file is not a VGM file"));
+        }
+
+        let u32_at = |offset: usize| u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]);
+        let version = u32_at(0x08);
+        let mut header = FileHeader {
+            magic: u32_at(0x00),
+            eof_offset: u32_at(0x04),
+            version,
+            psg_clock: u32_at(0x0C),
+            ym2413_clock: u32_at(0x10),
+            gd3_offset: u32_at(0x14),
+            total_samples: u32_at(0x18),
+            loop_offset: u32_at(0x1C),
+            loop_samples: u32_at(0x20),
+            ..Default::default()
+        };
+        if version >= 0x00000101 {
+            header.rate = u32_at(0x24);
+        }
+        if version >= 0x00000110 {
+            header.psg_feedback = (data[0x28] as u16) | ((data[0x29] as u16) << 8);
+            header.psg_lfsr_width = data[0x2A];
+            header.ym2612_clock = u32_at(0x2C);
+            header.ym2151_clock = u32_at(0x30);
+        }
+        if version >= 0x00000151 {
+            header.psg_flags = data[0x2B];
+        }
+        if version >= 0x00000150 {
+            header.vgm_data_offset = u32_at(0x34);
+        }
+        if version >= 0x00000151 {
+            // The header ends where the VGM data starts
+            let header_end = std::cmp::min(header.data_offset(), data.len());
+            header.other_chip_clocks = OTHER_CHIP_CLOCK_OFFSETS.iter()
+                .filter(|(offset, _)| offset + 4 <= header_end)
+                .map(|&(offset, name)| (name, u32_at(offset)))
+                .filter(|&(_, clock)| clock != 0)
+                .collect();
+        }
+        if version >= 0x00000160 && std::cmp::min(header.data_offset(), data.len()) > 0x7C {
+            header.volume_modifier = data[0x7C];
+        }
+        Ok(header)
+    }
+
+    /// Returns true if the file uses two SN76489s (bit 30 of the PSG clock)
+    pub fn is_dual_psg(&self) -> bool {
+        self.psg_clock & 0x4000_0000 != 0
+    }
+
+    /// Return the names of all chips with a nonzero clock
+    pub fn used_chips(&self) -> Vec<&'static str> {
+        let mut chips: Vec<&'static str> = [("SN76489", self.psg_clock), ("YM2413", self.ym2413_clock),
+                                            ("YM2612", self.ym2612_clock), ("YM2151", self.ym2151_clock)]
+            .iter()
+            .filter(|&&(_, clock)| clock != 0)
+            .map(|&(name, _)| name)
+            .collect();
+        chips.extend(self.other_chip_clocks.iter().map(|&(name, _)| name));
+        chips
+    }
+
+    /// Check that the offsets in the header point inside the file that the header was parsed from,
+    /// and that the GD3 offset points at a GD3 tag. `data` can be shorter than the EOF offset says
+    /// if the file is truncated. The conversion then ends the song early, with a warning, so only
+    /// what's left of the file is checked.
+    pub fn validate(&self, data: &[u8]) -> Result<(), Error> {
+        let invalid = |message: String| Err(Error::new(ErrorKind::InvalidData, message));
+        let eof = self.eof_offset as usize + 0x04;
+        let data_offset = self.data_offset();
+        if data_offset >= std::cmp::min(eof, data.len()) {
+            return invalid(format!("The VGM data offset points past the end of the file ({:#x} >= {:#x})", data_offset, std::cmp::min(eof, data.len())));
+        }
+        if self.gd3_offset != 0 {
+            let gd3 = self.gd3_offset as usize + 0x14;
+            if gd3 < data_offset || gd3 >= eof {
+                return invalid(format!("The GD3 offset points outside of the file ({:#x})", gd3));
+            }
+            if gd3 + GD3_MAGIC.len() <= data.len() && !data[gd3..].starts_with(GD3_MAGIC) {
+                return invalid(format!("The GD3 offset doesn't point at a GD3 tag ({:#x})", gd3));
+            }
+        }
+        if self.loop_offset != 0 {
+            let loop_start = self.loop_offset as usize + 0x1C;
+            if loop_start < data_offset || loop_start >= eof {
+                return invalid(format!("The loop offset points outside of the VGM data ({:#x})", loop_start));
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the gain in dB requested by the volume modifier. The modifier is a signed value in
+    /// the range -63..192 (0xC1..0xC0), and the volume is multiplied by 2^(modifier / 32). -63 is
+    /// treated as -64, so that a factor of 0.25 is possible.
+    pub fn volume_gain_db(&self) -> f64 {
+        let modifier = match self.volume_modifier {
+            0xC1 => -64,
+            m if m > 0xC0 => m as i32 - 0x100,
+            m => m as i32,
+        };
+        modifier as f64 / 32.0 * 20.0 * 2f64.log10()
+    }
+
+    /// Return the absolute offset of the VGM data
+    pub fn data_offset(&self) -> usize {
+        if self.version >= 0x00000150 && self.vgm_data_offset != 0 {
+            std::cmp::max(0x34 + self.vgm_data_offset as usize, HEADER_SIZE_PRE_150)
+        } else {
+            HEADER_SIZE_PRE_150
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header_data(version: u32) -> Vec<u8> {
+        let mut data = vec![0xFFu8; 0x80];
+        data[0..4].copy_from_slice(VGM_MAGIC.as_bytes());
+        data[0x08..0x0C].copy_from_slice(&version.to_le_bytes());
+        data[0x34..0x38].copy_from_slice(&0x4Cu32.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn test_parse_version_100() {
+        let header = FileHeader::parse(&header_data(0x100)).unwrap();
+        assert_eq!(header.loop_samples, 0xFFFFFFFF);
+        assert_eq!(header.rate, 0);
+        assert_eq!(header.psg_feedback, 0);
+        assert_eq!(header.ym2612_clock, 0);
+        assert_eq!(header.data_offset(), 0x40);
+    }
+
+    #[test]
+    fn test_parse_version_150() {
+        let header = FileHeader::parse(&header_data(0x150)).unwrap();
+        assert_eq!(header.psg_feedback, 0xFFFF);
+        assert_eq!(header.psg_flags, 0);
+        assert_eq!(header.data_offset(), 0x80);
+    }
+
+    #[test]
+    fn test_used_chips() {
+        let mut data = header_data(0x171);
+        data[0x0C..0x40].iter_mut().for_each(|b| *b = 0);
+        data[0x34] = 0x4C;
+        data[0x40..0x80].iter_mut().for_each(|b| *b = 0);
+        data[0x0C] = 1;
+        assert_eq!(FileHeader::parse(&data).unwrap().used_chips(), vec!["SN76489"]);
+        data[0x74] = 1;
+        data[0x7C] = 1;     // Volume modifier, not a clock
+        assert_eq!(FileHeader::parse(&data).unwrap().used_chips(), vec!["SN76489", "AY8910"]);
+    }
+
+    #[test]
+    fn test_volume_modifier() {
+        let mut data = header_data(0x150);
+        data[0x7C] = 0x20;
+        assert_eq!(FileHeader::parse(&data).unwrap().volume_modifier, 0);
+        data[0x08] = 0x60;
+        data[0x09] = 0x01;
+        let header = FileHeader::parse(&data).unwrap();
+        assert_eq!(header.volume_modifier, 0x20);
+        assert!((header.volume_gain_db() - 6.02).abs() < 0.01);
+        let gain = |modifier| FileHeader { volume_modifier: modifier, ..Default::default() }.volume_gain_db();
+        assert_eq!(gain(0), 0.0);
+        assert!((gain(0xC0) - 36.12).abs() < 0.01);
+        assert!((gain(0xC1) + 12.04).abs() < 0.01);
+        assert!((gain(0xE0) + 6.02).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_command_chip() {
+        assert_eq!(command_chip(Command::PSG_WRITE).map(|c| c.name), Some("SN76489"));
+        assert_eq!(command_chip(Command::YM2612_WRITE_LO_WAIT_15).map(|c| c.name), Some("YM2612"));
+        assert_eq!(command_chip(Command::YM2151_WRITE).map(|c| c.channels), Some("FM channels"));
+        assert_eq!(command_chip(Command::WAIT_LONG), None);
+        assert_eq!(command_chip(Command::DAC_STREAM_START), None);
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut data = header_data(0x150);
+        data[0x04..0x08].copy_from_slice(&0x7Cu32.to_le_bytes());
+        data[0x14..0x20].iter_mut().for_each(|b| *b = 0);
+        let mut header = FileHeader::parse(&data).unwrap();
+        assert!(header.validate(&data[..0x7F]).is_err());
+        // A truncated file is fine as long as the VGM data starts inside it
+        data[0x04..0x08].copy_from_slice(&0x100u32.to_le_bytes());
+        header = FileHeader::parse(&data).unwrap();
+        assert!(header.validate(&data).is_err());
+        data.push(Command::PSG_WRITE);
+        assert!(header.validate(&data).is_ok());
+        data.pop();
+        data.extend(&[Command::END_OF_SOUND_DATA]);
+        data.extend(GD3_MAGIC);
+        let eof_offset = data.len() as u32 - 4;
+        data[0x04..0x08].copy_from_slice(&eof_offset.to_le_bytes());
+        header = FileHeader::parse(&data).unwrap();
+        assert!(header.validate(&data).is_ok());
+
+        header.gd3_offset = 0x80 - 0x14;
+        assert!(header.validate(&data).is_err());
+        header.gd3_offset = 0x81 - 0x14;
+        assert!(header.validate(&data).is_ok());
+        header.loop_offset = 0x10;
+        assert!(header.validate(&data).is_err());
+        header.loop_offset = 0x80 - 0x1C;
+        assert!(header.validate(&data).is_ok());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(FileHeader::parse(&[0; 0x20]).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(FileHeader::parse(&[0; 0x40]).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,82 @@
+//!
+//! VGM files for the tests. `VgmBuilder` makes a file with a 1.50 header and the given command
+//! stream, and fills in the header fields that depend on the layout of the file.
+//!
+
+use crate::vgm::specification::{HEADER_SIZE_PRE_150, VGM_MAGIC};
+
+/// NTSC Master System / Genesis SN76489 clock
+const PSG_CLOCK: u32 = 3579545;
+
+pub struct VgmBuilder {
+    header: Vec<u8>,
+    commands: Vec<u8>,
+    loop_offset: Option<usize>,
+}
+
+impl VgmBuilder {
+    /// A version 1.50 header with no chips, followed by no commands
+    pub fn new() -> Self {
+        let mut header = VGM_MAGIC.as_bytes().to_vec();
+        header.resize(HEADER_SIZE_PRE_150, 0);
+        let mut builder = VgmBuilder { header, commands: Vec::new(), loop_offset: None };
+        builder.set_u32(0x08, 0x150);
+        builder.set_u32(0x34, (HEADER_SIZE_PRE_150 - 0x34) as u32);
+        builder
+    }
+
+    pub fn version(mut self, version: u32) -> Self {
+        self.set_u32(0x08, version);
+        self
+    }
+
+    /// Set the PSG clock to the NTSC one
+    pub fn psg(mut self) -> Self {
+        self.set_u32(0x0C, PSG_CLOCK);
+        self
+    }
+
+    pub fn total_samples(mut self, samples: u32) -> Self {
+        self.set_u32(0x18, samples);
+        self
+    }
+
+    /// Make playback loop back to `offset` in the file
+    pub fn loop_at(mut self, offset: usize) -> Self {
+        self.loop_offset = Some(offset);
+        self
+    }
+
+    /// Append `commands` to the command stream
+    pub fn commands(mut self, commands: &[u8]) -> Self {
+        self.commands.extend_from_slice(commands);
+        self
+    }
+
+    /// Return the file, with the end of file and loop offsets filled in
+    pub fn build(mut self) -> Vec<u8> {
+        let eof_offset = self.header.len() + self.commands.len() - 4;
+        self.set_u32(0x04, eof_offset as u32);
+        if let Some(offset) = self.loop_offset {
+            self.set_u32(0x1C, offset as u32 - 0x1C);
+        }
+        let mut data = self.header;
+        data.extend(self.commands);
+        data
+    }
+
+    fn set_u32(&mut self, offset: usize, value: u32) {
+        self.header[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+impl Default for VgmBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Return a PSG song with the given commands, which should end with the end of sound data command
+pub fn vgm_file(commands: &[u8], total_samples: u32) -> Vec<u8> {
+    VgmBuilder::new().psg().total_samples(total_samples).commands(commands).build()
+}
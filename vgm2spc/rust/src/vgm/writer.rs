@@ -0,0 +1,130 @@
+//!
+//! Serialization of VGM files.
+//!
+//! The header of the output is copied from an existing file, so that fields the converter doesn't
+//! know about (e.g. the clocks of other chips) are kept. Only the offsets and sample counts are
+//! recalculated from the command stream.
+//!
+
+use std::fs;
+use std::io::Error;
+use std::path::Path;
+
+#[cfg(feature = "vgz")]
+use flate2::Compression;
+#[cfg(feature = "vgz")]
+use flate2::write::GzEncoder;
+
+use crate::vgm::events::VgmEvent;
+use crate::vgm::gd3::Gd3;
+
+/// Build a VGM file from `header` (the raw header of the file the events were read from, up to
+/// the start of the command stream), the command stream `events`, and an optional GD3 tag.
+/// `loop_index` is the event that playback jumps to when looping.
+///
+/// `WaitOverride` events are dropped, since `Events` already applies them to the waits that
+/// follow. An end of sound data command is added if `events` doesn't end with one.
+pub fn write_vgm(header: &[u8], events: &[VgmEvent], loop_index: Option<usize>, gd3: Option<&Gd3>) -> Vec<u8> {
+    let mut data = header.to_vec();
+    let mut total_samples: u32 = 0;
+    let mut loop_offset = None;
+    let mut loop_samples: u32 = 0;
+    for (i, event) in events.iter().enumerate() {
+        if loop_index == Some(i) {
+            loop_offset = Some(data.len());
+        }
+        if let VgmEvent::WaitOverride { .. } = event {
+            continue;
+        }
+        total_samples = total_samples.saturating_add(event.samples());
+        if loop_offset.is_some() {
+            loop_samples = loop_samples.saturating_add(event.samples());
+        }
+        event.write_to(&mut data);
+    }
+    if events.last() != Some(&VgmEvent::End) {
+        VgmEvent::End.write_to(&mut data);
+    }
+
+    let gd3_offset = match gd3 {
+        Some(gd3) => {
+            let offset = data.len();
+            data.extend(gd3.to_bytes());
+            offset - 0x14
+        }
+        None => 0,
+    };
+    let eof_offset = data.len() - 4;
+
+    let mut set_u32 = |offset: usize, value: u32| data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    set_u32(0x04, eof_offset as u32);
+    set_u32(0x14, gd3_offset as u32);
+    set_u32(0x18, total_samples);
+    set_u32(0x1C, loop_offset.map_or(0, |offset| offset - 0x1C) as u32);
+    set_u32(0x20, if loop_offset.is_some() { loop_samples } else { 0 });
+    data
+}
+
+/// Compress a VGM file into VGZ format
+#[cfg(feature = "vgz")]
+pub fn compress(data: &[u8]) -> Result<Vec<u8>, Error> {
+    use std::io::Write;
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+#[cfg(not(feature = "vgz"))]
+pub fn compress(_data: &[u8]) -> Result<Vec<u8>, Error> {
+    Err(Error::new(std::io::ErrorKind::Unsupported, "Compressed (VGZ) output is not supported in this build"))
+}
+
+/// Write a VGM file to `path`. The file is compressed if the name ends with ".vgz".
+pub fn write_vgm_file(path: &Path, data: &[u8]) -> Result<(), Error> {
+    let is_vgz = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("vgz"));
+    if is_vgz {
+        fs::write(path, compress(data)?)
+    } else {
+        fs::write(path, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vgm::Command;
+    use crate::vgm::specification::FileHeader;
+    use crate::vgm::testing::VgmBuilder;
+
+    #[test]
+    fn test_write_vgm() {
+        let events = vec![VgmEvent::PsgWrite(0x90), VgmEvent::Wait(735),
+                          VgmEvent::WaitOverride { command: Command::WAIT_PAL_FRAME, samples: 800 },
+                          VgmEvent::Wait(800), VgmEvent::End];
+        let mut gd3 = Gd3::new();
+        gd3.track_name = String::from("Title");
+        let data = write_vgm(&VgmBuilder::new().build(), &events, Some(2), Some(&gd3));
+
+        assert_eq!(&data[0x40..0x47], &[Command::PSG_WRITE, 0x90, Command::WAIT_NTSC_FRAME,
+                                        Command::WAIT_LONG, 0x20, 0x03, Command::END_OF_SOUND_DATA]);
+        let header = FileHeader::parse(&data).unwrap();
+        assert_eq!(header.eof_offset as usize, data.len() - 4);
+        assert_eq!(header.total_samples, 735 + 800);
+        assert_eq!(header.loop_offset as usize + 0x1C, 0x43);
+        assert_eq!(header.loop_samples, 800);
+        assert_eq!(Gd3::parse(&data[header.gd3_offset as usize + 0x14..]).unwrap(), gd3);
+    }
+
+    #[test]
+    #[cfg(feature = "vgz")]
+    fn test_compress() {
+        use std::io::Read;
+        use flate2::read::GzDecoder;
+
+        let data = write_vgm(&VgmBuilder::new().build(), &[VgmEvent::Wait(100)], None, None);
+        let compressed = compress(&data).unwrap();
+        let mut decompressed = Vec::new();
+        GzDecoder::new(&compressed[..]).read_to_end(&mut decompressed).unwrap();
+        assert_eq!(decompressed, data);
+    }
+}
@@ -0,0 +1,61 @@
+//!
+//! Re-running the conversion when the input files change, for `-watch`. The files are polled,
+//! since there's no portable file change notification in the standard library.
+//!
+
+use std::fs;
+use std::path::Path;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::commands::play::player_command;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Return the modification time of each of `paths`, or `None` for files that can't be read
+fn modified_times(paths: &[&Path]) -> Vec<Option<SystemTime>> {
+    paths.iter().map(|path| fs::metadata(path).and_then(|m| m.modified()).ok()).collect()
+}
+
+/// Call `on_change` whenever one of `paths` is modified. Never returns.
+pub fn watch<F: FnMut()>(paths: &[&Path], mut on_change: F) -> ! {
+    println!("Watching {} file(s) for changes. Press Ctrl+C to stop", paths.len());
+    let mut last = modified_times(paths);
+    loop {
+        thread::sleep(POLL_INTERVAL);
+        let current = modified_times(paths);
+        if current != last {
+            // Give the program writing the file a moment to finish
+            thread::sleep(POLL_INTERVAL);
+            last = modified_times(paths);
+            on_change();
+        }
+    }
+}
+
+/// An external player that is restarted each time the output is re-created
+#[derive(Default)]
+pub struct PlayerProcess {
+    child: Option<Child>,
+}
+
+impl PlayerProcess {
+    /// Stop the previous player, if it's still running, and open `path` with `player` (see
+    /// `commands::play` for the command format)
+    pub fn restart(&mut self, player: &str, path: &Path) {
+        self.stop();
+        let command_line = player_command(player, &path.to_string_lossy());
+        match Command::new(&command_line[0]).args(&command_line[1..]).spawn() {
+            Ok(child) => self.child = Some(child),
+            Err(e) => println!("Warning: failed to run {}: {}", command_line[0], e),
+        }
+    }
+
+    fn stop(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}